@@ -15,7 +15,8 @@ fn main() {
     const TWO_PI: f64 = 2.0 * std::f64::consts::PI;
 
     let mut table = String::new();
-    table.push_str("pub const SINE_TABLE: [f32; ");
+    table.push_str("#[allow(clippy::excessive_precision, clippy::approx_constant)]\n");
+    table.push_str("pub static SINE_TABLE: [f32; ");
     table.push_str(&(SINE_TABLE_SIZE + 1).to_string());
     table.push_str("] = [\n");
 
@@ -28,5 +29,64 @@ fn main() {
 
     f.write_all(table.as_bytes()).unwrap();
 
+    // Generate equal-loudness contours
+    let dest_path = Path::new(&out_dir).join("equal_loudness.rs");
+    let mut f = File::create(&dest_path).unwrap();
+    f.write_all(equal_loudness_table().as_bytes()).unwrap();
+
     // AAudio bindings are now manually defined in the source
 }
+
+/// Sound pressure levels of the ISO 226:2003 equal-loudness contours from 0
+/// to 90 phon in steps of 10, at the standard's frequencies.
+fn equal_loudness_table() -> String {
+    const FREQUENCIES_HZ: [f64; 29] = [
+        20.0, 25.0, 31.5, 40.0, 50.0, 63.0, 80.0, 100.0, 125.0, 160.0, 200.0, 250.0, 315.0, 400.0,
+        500.0, 630.0, 800.0, 1000.0, 1250.0, 1600.0, 2000.0, 2500.0, 3150.0, 4000.0, 5000.0,
+        6300.0, 8000.0, 10000.0, 12500.0,
+    ];
+    // Exponent of loudness perception
+    const AF: [f64; 29] = [
+        0.532, 0.506, 0.480, 0.455, 0.432, 0.409, 0.387, 0.367, 0.349, 0.330, 0.315, 0.301, 0.288,
+        0.276, 0.267, 0.259, 0.253, 0.250, 0.246, 0.244, 0.243, 0.243, 0.243, 0.242, 0.242, 0.245,
+        0.254, 0.271, 0.301,
+    ];
+    // Magnitude of the linear transfer function normalized at 1 kHz
+    const LU: [f64; 29] = [
+        -31.6, -27.2, -23.0, -19.1, -15.9, -13.0, -10.3, -8.1, -6.2, -4.5, -3.1, -2.0, -1.1, -0.4,
+        0.0, 0.3, 0.5, 0.0, -2.7, -4.1, -1.0, 1.7, 2.5, 1.2, -2.1, -7.1, -11.2, -10.7, -3.1,
+    ];
+    // Threshold of hearing
+    const TF: [f64; 29] = [
+        78.5, 68.7, 59.5, 51.1, 44.0, 37.5, 31.5, 26.5, 22.1, 17.9, 14.4, 11.4, 8.6, 6.2, 4.4, 3.0,
+        2.2, 2.4, 3.5, 1.7, -1.3, -4.2, -6.0, -5.4, -1.5, 6.0, 12.6, 13.9, 12.3,
+    ];
+    const PHON_STEP: usize = 10;
+    const PHON_LEVELS: usize = 10;
+
+    let mut table = String::new();
+    table.push_str(&format!(
+        "pub(crate) const EQUAL_LOUDNESS_PHON_STEP: f32 = {PHON_STEP}.0;\n"
+    ));
+    table.push_str("pub(crate) static EQUAL_LOUDNESS_FREQUENCIES_HZ: [f32; 29] = [\n");
+    for frequency_hz in FREQUENCIES_HZ {
+        table.push_str(&format!("    {:.1}f32,\n", frequency_hz));
+    }
+    table.push_str("];\n");
+    table.push_str(&format!(
+        "pub(crate) static EQUAL_LOUDNESS_SPL_DB: [[f32; 29]; {PHON_LEVELS}] = [\n"
+    ));
+    for level in 0..PHON_LEVELS {
+        let phon = (level * PHON_STEP) as f64;
+        table.push_str("    [\n");
+        for i in 0..FREQUENCIES_HZ.len() {
+            let af = 4.47e-3 * (10.0_f64.powf(0.025 * phon) - 1.15)
+                + (0.4 * 10.0_f64.powf((TF[i] + LU[i]) / 10.0 - 9.0)).powf(AF[i]);
+            let spl_db = 10.0 / AF[i] * af.log10() - LU[i] + 94.0;
+            table.push_str(&format!("        {:.2}f32,\n", spl_db));
+        }
+        table.push_str("    ],\n");
+    }
+    table.push_str("];\n");
+    table
+}
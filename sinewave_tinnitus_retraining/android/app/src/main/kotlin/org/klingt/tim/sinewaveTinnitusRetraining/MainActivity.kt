@@ -122,6 +122,58 @@ class MainActivity :
                     result.success(null)
                 }
 
+                "setSilenceProbability" -> {
+                    val probability = call.argument<Double>("probability")?.toFloat() ?: 0.1f
+                    val intent = Intent(this, AudioPlaybackService::class.java)
+                    intent.action = AudioPlaybackService.ACTION_SET_SILENCE_PROBABILITY
+                    intent.putExtra("probability", probability)
+                    startService(intent)
+                    result.success(null)
+                }
+
+                "setToneDuration", "setPauseDuration" -> {
+                    val intent = Intent(this, AudioPlaybackService::class.java)
+                    intent.action =
+                        if (call.method == "setToneDuration") {
+                            AudioPlaybackService.ACTION_SET_TONE_DURATION
+                        } else {
+                            AudioPlaybackService.ACTION_SET_PAUSE_DURATION
+                        }
+                    intent.putExtra(
+                        "distribution",
+                        call.argument<Int>("distribution") ?: AudioPlaybackService.DISTRIBUTION_UNIFORM,
+                    )
+                    intent.putExtra("a", call.argument<Double>("a")?.toFloat() ?: 150.0f)
+                    intent.putExtra("b", call.argument<Double>("b")?.toFloat() ?: 400.0f)
+                    startService(intent)
+                    result.success(null)
+                }
+
+                "setSeed" -> {
+                    val intent = Intent(this, AudioPlaybackService::class.java)
+                    intent.action = AudioPlaybackService.ACTION_SET_SEED
+                    call.argument<Number>("seed")?.let { intent.putExtra("seed", it.toLong()) }
+                    startService(intent)
+                    result.success(null)
+                }
+
+                "getSeed" -> {
+                    if (isBound) {
+                        result.success(audioService?.lastSessionSeed())
+                    } else {
+                        result.success(null)
+                    }
+                }
+
+                "setTailFade" -> {
+                    val fadeMs = call.argument<Double>("fadeMs")?.toFloat() ?: 100.0f
+                    val intent = Intent(this, AudioPlaybackService::class.java)
+                    intent.action = AudioPlaybackService.ACTION_SET_TAIL_FADE
+                    intent.putExtra("fadeMs", fadeMs)
+                    startService(intent)
+                    result.success(null)
+                }
+
                 "getPlaybackState" -> {
                     if (isBound) {
                         result.success(audioService?.isPlaying())
@@ -18,6 +18,7 @@ class AudioPlaybackService : Service() {
     private val binder = LocalBinder()
     private var audioManager: AudioManager? = null
     private var isPlaying = false
+    private var isPaused = false
     private var isInitialized = false
 
     interface AudioServiceListener {
@@ -62,6 +63,22 @@ class AudioPlaybackService : Service() {
         const val ACTION_RESUME = "org.klingt.tim.sinewaveTinnitusRetraining.RESUME"
         const val ACTION_SET_GAIN = "org.klingt.tim.sinewaveTinnitusRetraining.SET_GAIN"
         const val ACTION_SET_FREQUENCY_RANGE = "org.klingt.tim.sinewaveTinnitusRetraining.SET_FREQUENCY_RANGE"
+        const val ACTION_SET_SILENCE_PROBABILITY = "org.klingt.tim.sinewaveTinnitusRetraining.SET_SILENCE_PROBABILITY"
+        const val ACTION_SET_TONE_DURATION = "org.klingt.tim.sinewaveTinnitusRetraining.SET_TONE_DURATION"
+        const val ACTION_SET_PAUSE_DURATION = "org.klingt.tim.sinewaveTinnitusRetraining.SET_PAUSE_DURATION"
+        const val ACTION_SET_SEED = "org.klingt.tim.sinewaveTinnitusRetraining.SET_SEED"
+        const val ACTION_SET_TAIL_FADE = "org.klingt.tim.sinewaveTinnitusRetraining.SET_TAIL_FADE"
+        const val CHANNEL_LEFT = 0
+        const val CHANNEL_RIGHT = 1
+        const val CHANNEL_BOTH = -1
+
+        // Kind of a duration distribution, see DurationDistribution in the Rust core
+        const val DISTRIBUTION_FIXED = 0
+        const val DISTRIBUTION_UNIFORM = 1
+        const val DISTRIBUTION_EXPONENTIAL = 2
+
+        // Status code of the native functions, see ErrorCode in the Rust core
+        private const val STATUS_OK = 0
         private const val TAG = "AudioPlaybackService"
     }
 
@@ -71,6 +88,19 @@ class AudioPlaybackService : Service() {
 
     fun isPlaying(): Boolean = isPlaying
 
+    /** Seed of the running or last session, 0 before the first start. */
+    fun lastSessionSeed(): Long {
+        if (!isInitialized) {
+            return 0L
+        }
+        return try {
+            getSeed()
+        } catch (e: UnsatisfiedLinkError) {
+            Log.w(TAG, "Native getSeed function not available")
+            0L
+        }
+    }
+
     override fun onCreate() {
         super.onCreate()
         createNotificationChannel()
@@ -105,13 +135,41 @@ class AudioPlaybackService : Service() {
 
             ACTION_SET_GAIN -> {
                 val gain = intent.getFloatExtra("gain", 0.0f)
-                setGainValue(gain)
+                val channel = intent.getIntExtra("channel", CHANNEL_BOTH)
+                setGainValue(channel, gain)
             }
 
             ACTION_SET_FREQUENCY_RANGE -> {
                 val minMidiNote = intent.getFloatExtra("minMidiNote", 69.0f)
                 val maxMidiNote = intent.getFloatExtra("maxMidiNote", 115.0f)
-                setFrequencyRangeValue(minMidiNote, maxMidiNote)
+                val channel = intent.getIntExtra("channel", CHANNEL_BOTH)
+                setFrequencyRangeValue(channel, minMidiNote, maxMidiNote)
+            }
+
+            ACTION_SET_SILENCE_PROBABILITY -> {
+                val probability = intent.getFloatExtra("probability", 0.1f)
+                setSilenceProbabilityValue(probability)
+            }
+
+            ACTION_SET_TONE_DURATION, ACTION_SET_PAUSE_DURATION -> {
+                // Fixed: a is the duration. Uniform: a..b. Exponential: mean a, at most b.
+                val distribution = intent.getIntExtra("distribution", DISTRIBUTION_UNIFORM)
+                val a = intent.getFloatExtra("a", 150.0f)
+                val b = intent.getFloatExtra("b", 400.0f)
+                setDurationDistributionValue(intent.action == ACTION_SET_TONE_DURATION, distribution, a, b)
+            }
+
+            ACTION_SET_SEED -> {
+                // Replays the sequence of an earlier session on the next start
+                if (intent.hasExtra("seed")) {
+                    setSeedValue(intent.getLongExtra("seed", 0L))
+                }
+            }
+
+            ACTION_SET_TAIL_FADE -> {
+                // Fade of stop, pause and resume
+                val fadeMs = intent.getFloatExtra("fadeMs", 100.0f)
+                setTailFadeValue(fadeMs)
             }
         }
         // Ensure the service stays running
@@ -127,11 +185,11 @@ class AudioPlaybackService : Service() {
 
             // Try to create the actual audio player using global static
             val result = create_audio_player()
-            if (result == 1) {
+            if (result == STATUS_OK) {
                 isInitialized = true
                 Log.d(TAG, "Audio player initialized successfully")
             } else {
-                Log.e(TAG, "Failed to create audio player")
+                Log.e(TAG, "Failed to create audio player: ${getLastErrorMessage()}")
             }
         } catch (e: UnsatisfiedLinkError) {
             Log.w(TAG, "Native function not available, using dummy implementation")
@@ -150,21 +208,80 @@ class AudioPlaybackService : Service() {
     @Suppress("ktlint:standard:function-naming")
     private external fun stop_audio_player(): Int
 
+    @Suppress("ktlint:standard:function-naming")
+    private external fun pause_audio_player(): Int
+
+    @Suppress("ktlint:standard:function-naming")
+    private external fun resume_audio_player(): Int
+
     @Suppress("ktlint:standard:function-naming")
     private external fun destroy_audio_player(): Int
 
     @Suppress("ktlint:standard:function-naming")
-    private external fun setGain(gainDb: Float)
+    private external fun setTailFadeMs(fadeMs: Float): Int
+
+    @Suppress("ktlint:standard:function-naming")
+    private external fun getLastErrorMessage(): String?
+
+    @Suppress("ktlint:standard:function-naming")
+    private external fun setGain(gainDb: Float): Int
 
     @Suppress("ktlint:standard:function-naming")
     private external fun setFrequencyRange(
         minMidiNote: Float,
         maxMidiNote: Float,
-    )
+    ): Int
+
+    @Suppress("ktlint:standard:function-naming")
+    private external fun setGainChannel(
+        channel: Int,
+        gainDb: Float,
+    ): Int
+
+    @Suppress("ktlint:standard:function-naming")
+    private external fun setFrequencyRangeChannel(
+        channel: Int,
+        minMidiNote: Float,
+        maxMidiNote: Float,
+    ): Int
 
-    private fun setGainValue(gain: Float) {
+    @Suppress("ktlint:standard:function-naming")
+    private external fun setSilenceProbability(probability: Float): Int
+
+    @Suppress("ktlint:standard:function-naming")
+    private external fun setToneDurationDistribution(
+        distribution: Int,
+        a: Float,
+        b: Float,
+    ): Int
+
+    @Suppress("ktlint:standard:function-naming")
+    private external fun setPauseDurationDistribution(
+        distribution: Int,
+        a: Float,
+        b: Float,
+    ): Int
+
+    @Suppress("ktlint:standard:function-naming")
+    private external fun setSeed(seed: Long): Int
+
+    @Suppress("ktlint:standard:function-naming")
+    private external fun getSeed(): Long
+
+    private fun setGainValue(
+        channel: Int,
+        gain: Float,
+    ) {
         try {
-            setGain(gain)
+            val status =
+                if (channel == CHANNEL_BOTH) {
+                    setGain(gain)
+                } else {
+                    setGainChannel(channel, gain)
+                }
+            if (status != STATUS_OK) {
+                Log.e(TAG, "Rejected gain $gain dB (status $status)")
+            }
         } catch (e: UnsatisfiedLinkError) {
             Log.w(TAG, "Native setGain function not available")
         } catch (e: Exception) {
@@ -173,11 +290,20 @@ class AudioPlaybackService : Service() {
     }
 
     private fun setFrequencyRangeValue(
+        channel: Int,
         minMidiNote: Float,
         maxMidiNote: Float,
     ) {
         try {
-            setFrequencyRange(minMidiNote, maxMidiNote)
+            val status =
+                if (channel == CHANNEL_BOTH) {
+                    setFrequencyRange(minMidiNote, maxMidiNote)
+                } else {
+                    setFrequencyRangeChannel(channel, minMidiNote, maxMidiNote)
+                }
+            if (status != STATUS_OK) {
+                Log.e(TAG, "Rejected frequency range $minMidiNote..$maxMidiNote (status $status)")
+            }
         } catch (e: UnsatisfiedLinkError) {
             Log.w(TAG, "Native setFrequencyRange function not available")
         } catch (e: Exception) {
@@ -185,6 +311,69 @@ class AudioPlaybackService : Service() {
         }
     }
 
+    private fun setSilenceProbabilityValue(probability: Float) {
+        try {
+            val status = setSilenceProbability(probability)
+            if (status != STATUS_OK) {
+                Log.e(TAG, "Rejected silence probability $probability (status $status)")
+            }
+        } catch (e: UnsatisfiedLinkError) {
+            Log.w(TAG, "Native setSilenceProbability function not available")
+        } catch (e: Exception) {
+            Log.e(TAG, "Error setting silence probability", e)
+        }
+    }
+
+    private fun setDurationDistributionValue(
+        tones: Boolean,
+        distribution: Int,
+        a: Float,
+        b: Float,
+    ) {
+        val segment = if (tones) "tone" else "pause"
+        try {
+            val status =
+                if (tones) {
+                    setToneDurationDistribution(distribution, a, b)
+                } else {
+                    setPauseDurationDistribution(distribution, a, b)
+                }
+            if (status != STATUS_OK) {
+                Log.e(TAG, "Rejected $segment duration distribution $distribution ($a, $b) (status $status)")
+            }
+        } catch (e: UnsatisfiedLinkError) {
+            Log.w(TAG, "Native $segment duration function not available")
+        } catch (e: Exception) {
+            Log.e(TAG, "Error setting $segment duration", e)
+        }
+    }
+
+    private fun setSeedValue(seed: Long) {
+        try {
+            val status = setSeed(seed)
+            if (status != STATUS_OK) {
+                Log.e(TAG, "Failed to set seed $seed (status $status)")
+            }
+        } catch (e: UnsatisfiedLinkError) {
+            Log.w(TAG, "Native setSeed function not available")
+        } catch (e: Exception) {
+            Log.e(TAG, "Error setting seed", e)
+        }
+    }
+
+    private fun setTailFadeValue(fadeMs: Float) {
+        try {
+            val status = setTailFadeMs(fadeMs)
+            if (status != STATUS_OK) {
+                Log.e(TAG, "Rejected tail fade $fadeMs ms (status $status)")
+            }
+        } catch (e: UnsatisfiedLinkError) {
+            Log.w(TAG, "Native setTailFadeMs function not available")
+        } catch (e: Exception) {
+            Log.e(TAG, "Error setting tail fade", e)
+        }
+    }
+
     fun isHeadphoneConnected(): Boolean {
         // AudioManager.GET_DEVICES_OUTPUT is 2
         val devices = audioManager?.getDevices(2) ?: return false
@@ -217,13 +406,14 @@ class AudioPlaybackService : Service() {
         // We do NOT request audio focus to allow mixing with other apps
         try {
             val status = start_audio_player()
-            if (status == 1) {
+            if (status == STATUS_OK) {
                 isPlaying = true
+                isPaused = false
                 listener?.onPlaybackStateChanged(true)
                 startForeground(NOTIFICATION_ID, createNotification())
                 Log.d(TAG, "Audio playback started")
             } else {
-                Log.e(TAG, "Failed to start audio playback")
+                Log.e(TAG, "Failed to start audio playback: ${getLastErrorMessage()}")
             }
         } catch (e: UnsatisfiedLinkError) {
             Log.w(TAG, "Native audio functions not available, service running in background mode only")
@@ -239,9 +429,10 @@ class AudioPlaybackService : Service() {
         if (isInitialized) {
             try {
                 val status = stop_audio_player()
-                if (status == 1) {
+                if (status == STATUS_OK) {
                     Log.d(TAG, "Audio playback stopped")
                 }
+                isPaused = false
             } catch (e: UnsatisfiedLinkError) {
                 Log.w(TAG, "Native audio functions not available")
             } catch (e: Exception) {
@@ -258,9 +449,11 @@ class AudioPlaybackService : Service() {
     private fun pauseAudioPlayback() {
         if (isInitialized && isPlaying) {
             try {
-                val status = stop_audio_player()
-                if (status == 1) {
+                // Fades out and keeps the position in the tone sequence
+                val status = pause_audio_player()
+                if (status == STATUS_OK) {
                     Log.d(TAG, "Audio playback paused")
+                    isPaused = true
                 }
             } catch (e: UnsatisfiedLinkError) {
                 Log.w(TAG, "Native audio functions not available")
@@ -280,9 +473,10 @@ class AudioPlaybackService : Service() {
                 return
             }
             try {
-                val status = start_audio_player()
-                if (status == 1) {
+                val status = if (isPaused) resume_audio_player() else start_audio_player()
+                if (status == STATUS_OK) {
                     Log.d(TAG, "Audio playback resumed")
+                    isPaused = false
                 }
             } catch (e: UnsatisfiedLinkError) {
                 Log.w(TAG, "Native audio functions not available")
@@ -329,7 +523,7 @@ class AudioPlaybackService : Service() {
         if (isInitialized) {
             try {
                 val status = destroy_audio_player()
-                if (status == 1) {
+                if (status == STATUS_OK) {
                     Log.d(TAG, "Audio player destroyed")
                 }
             } catch (e: UnsatisfiedLinkError) {
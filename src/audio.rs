@@ -1,78 +1,984 @@
+use crate::error::{validate_gain, validate_note_range};
+use crate::events::EventDispatcher;
+use crate::{
+    Audiogram, Audiometer, AudiometryPresentation, AudiometrySettings, CalibrationPoint,
+    CalibrationTable, Channel, ChannelParams, DoseLimit, DurationDistribution, Error,
+    EventCallback, EventQueue, FadeCurve, FrequencyMode, GainCurve, GainUnit, HeadphoneProfile,
+    HearingCompensation, MAX_GAIN_RAMP_MS, MAX_MIDI_NOTE, MAX_OUTPUT_CEILING_DB,
+    MAX_REFERENCE_LEVEL_DBFS, MAX_TAIL_FADE_MS, MAX_TONE_FADE_MS, MIN_MIDI_NOTE,
+    MIN_OUTPUT_CEILING_DB, NoiseDose, PitchMatchResult, PitchMatchSettings, PitchMatcher,
+    PitchResponse, PitchTrial, PlaybackEvent, Schedule, SharedParams, reference_threshold_spl_db,
+};
+
+use std::hash::{BuildHasher, RandomState};
+use std::path::Path;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Renders interleaved samples of the given format. The format changes if a
+/// backend rebuilds its stream on another device. Returns false once the
+/// output is finished, after which the backend stops the stream.
+pub type RenderCallback = Box<dyn FnMut(&mut [f32], StreamFormat) -> bool + Send + 'static>;
+
+/// Format of an opened output stream. Render callbacks receive interleaved
+/// `f32` frames of `channels` samples.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct StreamFormat {
+    pub sample_rate: u32,
+    pub channels: u16,
+}
+
+/// An output device as reported by `AudioPlayer::output_devices`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OutputDevice {
+    /// Identifies the device across restarts, as long as the set of devices
+    /// with the same name does not change.
+    pub id: String,
+    pub name: String,
+    /// Whether this is the system default, used when no device is selected.
+    pub is_default: bool,
+    pub sample_rates: Vec<u32>,
+    /// Supported channel counts.
+    pub channels: Vec<u16>,
+}
+
+/// State of the output stream as seen by the host app.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum StreamState {
+    Stopped = 0,
+    Playing = 1,
+    /// The stream was lost, e.g. because the device was unplugged, and the
+    /// backend is trying to rebuild it.
+    Reconnecting = 2,
+}
+
+/// Stream state shared between a backend and its stream threads.
+#[derive(Clone)]
+pub(crate) struct SharedStreamState(Arc<AtomicU32>);
+
+impl SharedStreamState {
+    pub(crate) fn new() -> Self {
+        Self(Arc::new(AtomicU32::new(StreamState::Stopped as u32)))
+    }
+
+    pub(crate) fn get(&self) -> StreamState {
+        match self.0.load(Ordering::Acquire) {
+            1 => StreamState::Playing,
+            2 => StreamState::Reconnecting,
+            _ => StreamState::Stopped,
+        }
+    }
+
+    pub(crate) fn set(&self, state: StreamState) {
+        self.0.store(state as u32, Ordering::Release);
+    }
+}
 
 pub trait AudioBackend: Send + Sync {
-    fn start(&mut self, f: Box<dyn FnMut(&mut [f32]) + Send + 'static>);
+    /// Opens the output stream and returns the format the device negotiated.
+    fn open(&mut self) -> Result<StreamFormat, Error>;
+    /// Starts rendering into the stream opened by `open`. If the stream is
+    /// lost later, the backend rebuilds it and keeps calling `f`.
+    fn start(&mut self, f: RenderCallback) -> Result<(), Error>;
     fn stop(&mut self);
+    fn stream_state(&self) -> StreamState;
+    /// Gives the backend the player's queue to report stream errors to.
+    fn set_event_queue(&mut self, _events: Arc<EventQueue>) {}
+    /// Selects the output device used by the next `open` by id. `None`
+    /// selects the system default.
+    fn set_output_device(&mut self, _id: Option<String>) {
+        log::warn!("Output device selection is not supported by this backend");
+    }
+    /// Lists the available output devices. Empty if the backend does not
+    /// support device selection.
+    fn output_devices(&self) -> Vec<OutputDevice> {
+        Vec::new()
+    }
 }
 
 pub struct AudioPlayer {
     backend: Box<dyn AudioBackend>,
-    linear_gain: Arc<AtomicU32>,
-    min_midi_note: Arc<AtomicU32>,
-    max_midi_note: Arc<AtomicU32>,
+    params: SharedParams,
+    // Seed of the next session, set with `set_seed`
+    next_seed: Option<u64>,
+    // Seed of the running session, or of the last one once stopped
+    session_seed: Option<u64>,
+    events: Arc<EventQueue>,
+    dispatcher: Option<EventDispatcher>,
+    started: bool,
+    limiter_pin: Option<u32>,
+    // Selected with `set_output_device`, `None` for the system default
+    output_device: Option<String>,
+    calibration: CalibrationTable,
+    pitch_matcher: Option<PitchMatcher>,
+    audiometer: Option<Audiometer>,
+    hearing_compensation: GainCurve,
+    // Target loudness of the equal-loudness weighting, `None` when it is off
+    equal_loudness_phon: Option<f32>,
+    headphone_profile: HeadphoneProfile,
 }
 
 impl AudioPlayer {
     pub fn new() -> Self {
+        #[cfg(target_os = "android")]
+        let backend = Box::new(aaudio_backend::AAudioBackend::new());
+        #[cfg(not(target_os = "android"))]
+        let backend = Box::new(cpal_backend::CpalBackend::new());
+
+        Self::with_backend(backend)
+    }
+
+    /// Creates a player that renders through `backend` instead of the
+    /// platform's audio device.
+    pub fn with_backend(mut backend: Box<dyn AudioBackend>) -> Self {
         let initial_gain_db = -12.0;
-        let initial_linear_gain = 10.0_f32.powf(initial_gain_db / 20.0);
         // Default frequency range: A4 (440Hz) to ~8000Hz
-        let initial_min_midi = 69.0_f32.to_bits(); // A4
-        let initial_max_midi = 115.0_f32.to_bits(); // ~8000Hz
+        let initial_min_midi = 69.0; // A4
+        let initial_max_midi = 115.0; // ~8000Hz
+        let channels = Channel::ALL
+            .map(|_| ChannelParams::new(initial_gain_db, initial_min_midi, initial_max_midi));
 
-        #[cfg(target_os = "android")]
-        {
-            Self {
-                backend: Box::new(aaudio_backend::AAudioBackend::new()),
-                linear_gain: Arc::new(AtomicU32::new(initial_linear_gain.to_bits())),
-                min_midi_note: Arc::new(AtomicU32::new(initial_min_midi)),
-                max_midi_note: Arc::new(AtomicU32::new(initial_max_midi)),
-            }
-        }
-        #[cfg(not(target_os = "android"))]
-        {
-            Self {
-                backend: Box::new(cpal_backend::CpalBackend::new()),
-                linear_gain: Arc::new(AtomicU32::new(initial_linear_gain.to_bits())),
-                min_midi_note: Arc::new(AtomicU32::new(initial_min_midi)),
-                max_midi_note: Arc::new(AtomicU32::new(initial_max_midi)),
-            }
+        let events = Arc::new(EventQueue::new());
+        backend.set_event_queue(events.clone());
+
+        Self {
+            backend,
+            params: SharedParams::new(channels),
+            next_seed: None,
+            session_seed: None,
+            events,
+            dispatcher: None,
+            started: false,
+            limiter_pin: None,
+            output_device: None,
+            calibration: CalibrationTable::default(),
+            pitch_matcher: None,
+            audiometer: None,
+            hearing_compensation: GainCurve::new(),
+            equal_loudness_phon: None,
+            headphone_profile: HeadphoneProfile::new(),
         }
     }
 
-    pub fn start(&mut self) {
-        let mut audio_state = crate::AudioState::new(
-            44100.0,
-            self.linear_gain.clone(),
-            self.min_midi_note.clone(),
-            self.max_midi_note.clone(),
+    pub fn start(&mut self) -> Result<(), Error> {
+        // Close a stream that is still fading out after `stop`, or that
+        // ended on its own
+        self.backend.stop();
+        if self.params.take_stop_request() {
+            self.events.push(PlaybackEvent::StreamStopped);
+        }
+        let format = self.backend.open()?;
+        log::info!(
+            "Audio output opened at {} Hz, {} channels",
+            format.sample_rate,
+            format.channels
         );
-        self.backend.start(Box::new(move |data| {
-            audio_state.fill(data);
-        }));
+
+        // Every session plays a new sequence unless a seed has been set
+        let seed = self.next_seed.take().unwrap_or_else(entropy_seed);
+        self.session_seed = Some(seed);
+        log::info!("Playing tone sequence with seed {seed}");
+        self.params.resume();
+
+        let mut audio_state = crate::AudioState::new(format, self.params.clone(), seed)
+            .with_events(self.events.clone());
+        self.backend.start(Box::new(crate::error::guard_render(
+            self.events.clone(),
+            move |data, format| audio_state.render(data, format),
+        )))?;
+        self.started = true;
+        Ok(())
     }
 
+    /// Fades out over the tail fade time and returns. The render callback
+    /// closes the stream once the output is silent and reports
+    /// `PlaybackEvent::StreamStopped`.
     pub fn stop(&mut self) {
+        if self.started && self.backend.stream_state() == StreamState::Playing {
+            self.started = false;
+            self.params.request_stop();
+            return;
+        }
         self.backend.stop();
+        if std::mem::take(&mut self.started) {
+            self.events.push(PlaybackEvent::StreamStopped);
+        }
+    }
+
+    /// Fades out and holds the sequence, keeping the stream open so that
+    /// `resume` continues where it paused.
+    pub fn pause(&self) {
+        self.params.pause();
+    }
+
+    pub fn resume(&self) {
+        self.params.resume();
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.params.is_paused()
+    }
+
+    pub fn tail_fade_ms(&self) -> f32 {
+        self.params.tail_fade_ms()
+    }
+
+    /// Sets the fade applied by `pause`, `resume` and `stop`. 0 cuts
+    /// immediately.
+    pub fn set_tail_fade_ms(&self, fade_ms: f32) -> Result<(), Error> {
+        if !(0.0..=MAX_TAIL_FADE_MS).contains(&fade_ms) {
+            return Err(Error::InvalidArgument("tail fade"));
+        }
+        self.params.set_tail_fade_ms(fade_ms);
+        Ok(())
+    }
+
+    pub fn stream_state(&self) -> StreamState {
+        self.backend.stream_state()
+    }
+
+    pub fn output_ceiling_db(&self) -> f32 {
+        self.params.output_ceiling_db()
+    }
+
+    /// Sets the highest level the output can reach in dBFS. Channel gains
+    /// above it are played at the ceiling, and peaks above it, e.g. from both
+    /// voices on a mono output, are limited.
+    pub fn set_output_ceiling_db(&self, ceiling_db: f32) -> Result<(), Error> {
+        if self.limiter_pin.is_some() {
+            return Err(Error::LimiterLocked);
+        }
+        if !(MIN_OUTPUT_CEILING_DB..=MAX_OUTPUT_CEILING_DB).contains(&ceiling_db) {
+            return Err(Error::InvalidArgument("output ceiling"));
+        }
+        self.params.set_output_ceiling_db(ceiling_db);
+        Ok(())
+    }
+
+    /// Locks the limiter settings until `unlock_limiter` is called with the
+    /// same `pin`, so that a ceiling set by a clinician cannot be changed
+    /// from the app.
+    pub fn lock_limiter(&mut self, pin: u32) -> Result<(), Error> {
+        if self.limiter_pin.is_some() {
+            return Err(Error::LimiterLocked);
+        }
+        self.limiter_pin = Some(pin);
+        Ok(())
+    }
+
+    pub fn unlock_limiter(&mut self, pin: u32) -> Result<(), Error> {
+        match self.limiter_pin {
+            Some(locked_pin) if locked_pin != pin => Err(Error::LimiterLocked),
+            _ => {
+                self.limiter_pin = None;
+                Ok(())
+            }
+        }
     }
 
-    pub fn set_gain_db(&self, gain_db: f32) {
-        let linear_gain = 10.0_f32.powf(gain_db / 20.0);
-        self.linear_gain
-            .store(linear_gain.to_bits(), Ordering::Relaxed);
+    pub fn is_limiter_locked(&self) -> bool {
+        self.limiter_pin.is_some()
     }
 
-    pub fn set_frequency_range(&self, min_midi_note: f32, max_midi_note: f32) {
-        self.min_midi_note
-            .store(min_midi_note.to_bits(), Ordering::Relaxed);
-        self.max_midi_note
-            .store(max_midi_note.to_bits(), Ordering::Relaxed);
+    pub fn full_scale_spl_db(&self) -> f32 {
+        self.params.dose().full_scale_spl_db()
+    }
+
+    /// Sets the level in dB SPL that a full-scale sine reaches on the current
+    /// output device, used to estimate the noise dose.
+    pub fn set_full_scale_spl_db(&self, spl_db: f32) -> Result<(), Error> {
+        self.params.dose().set_full_scale_spl_db(spl_db)
+    }
+
+    pub fn dose_limit(&self) -> DoseLimit {
+        self.params.dose().limit()
+    }
+
+    pub fn set_dose_limit(&self, limit: DoseLimit) -> Result<(), Error> {
+        self.params.dose().set_limit(limit)
+    }
+
+    /// Noise dose of the current or last session and of the day, as shares
+    /// of the daily budget.
+    pub fn noise_dose(&self) -> NoiseDose {
+        self.params.dose().dose()
+    }
+
+    /// Replaces the daily dose, with 0 at the start of a day or with the
+    /// value saved when the app was last closed. The core does not track
+    /// dates itself.
+    pub fn set_daily_dose(&self, dose: f64) -> Result<(), Error> {
+        self.params.dose().set_daily_dose(dose)
+    }
+
+    /// Length of the session, `None` if it plays until stopped.
+    pub fn session_duration(&self) -> Option<Duration> {
+        match self.params.session_duration_ms() {
+            0 => None,
+            ms => Some(Duration::from_millis(ms)),
+        }
+    }
+
+    /// Limits the session to `duration`. At the end the output fades out over
+    /// `SESSION_END_FADE_MS`, the stream stops and
+    /// `PlaybackEvent::SessionCompleted` is emitted. Takes effect immediately,
+    /// also during a session.
+    pub fn set_session_duration(&self, duration: Option<Duration>) {
+        let ms = duration.map_or(0, |d| d.as_millis().clamp(1, u64::MAX as u128) as u64);
+        self.params.set_session_duration_ms(ms);
+    }
+
+    /// Time played in the current or last session, counted in samples and
+    /// not including pauses.
+    pub fn session_elapsed(&self) -> Duration {
+        Duration::from_millis(self.params.session_elapsed_ms())
+    }
+
+    /// Time left in the session, `None` if it plays until stopped.
+    pub fn session_remaining(&self) -> Option<Duration> {
+        self.session_duration()
+            .map(|duration| duration.saturating_sub(self.session_elapsed()))
+    }
+
+    /// Removes the oldest pending playback event. Returns `None` while a
+    /// callback is registered, since the callback receives all events.
+    pub fn poll_event(&self) -> Option<PlaybackEvent> {
+        self.events.pop()
+    }
+
+    /// Registers a callback that receives the playback events on a separate
+    /// thread, replacing the previous one. `None` returns to polling.
+    pub fn set_event_callback(&mut self, callback: Option<EventCallback>) {
+        // Stop the previous dispatcher first so that events stay in order
+        self.dispatcher = None;
+        self.dispatcher = callback.map(|f| EventDispatcher::spawn(self.events.clone(), f));
+    }
+
+    /// Returns the seed of the running session, or of the last one once
+    /// stopped, so that it can be replayed with `set_seed`. `None` before the
+    /// first `start`.
+    pub fn last_session_seed(&self) -> Option<u64> {
+        self.session_seed
+    }
+
+    /// Sets the seed of the tone sequence played by the next `start`. Without
+    /// it, `start` draws a new seed from OS entropy.
+    pub fn set_seed(&mut self, seed: u64) {
+        self.next_seed = Some(seed);
+    }
+
+    pub fn output_devices(&self) -> Vec<OutputDevice> {
+        self.backend.output_devices()
+    }
+
+    /// Selects the output device by id (or name) for the next `start`. `None`
+    /// selects the system default. If the device disappears before the next
+    /// `start`, the system default is used instead.
+    pub fn set_output_device(&mut self, id: Option<&str>) -> Result<(), Error> {
+        if let Some(id) = id {
+            let devices = self.backend.output_devices();
+            if !devices.is_empty() && !devices.iter().any(|d| d.id == id || d.name == id) {
+                return Err(Error::UnknownDevice(id.to_string()));
+            }
+        }
+        self.output_device = id.map(str::to_owned);
+        self.backend.set_output_device(self.output_device.clone());
+        self.check_calibration_device();
+        Ok(())
+    }
+
+    /// Sets the gain of both channels.
+    pub fn set_gain_db(&self, gain_db: f32) -> Result<(), Error> {
+        self.set_gain(gain_db, GainUnit::Dbfs)
+    }
+
+    /// Sets the gain of both channels in dBFS, or their level in dB SPL or
+    /// dB HL using the calibration table. Uncalibrated ears assume the level
+    /// set with `set_full_scale_spl_db`.
+    pub fn set_gain(&self, gain_db: f32, unit: GainUnit) -> Result<(), Error> {
+        validate_gain(gain_db, unit)?;
+        for channel in Channel::ALL {
+            self.params.channel(channel).set_gain(gain_db, unit);
+        }
+        Ok(())
+    }
+
+    /// Sets the frequency range of both channels.
+    pub fn set_frequency_range(&self, min_midi_note: f32, max_midi_note: f32) -> Result<(), Error> {
+        validate_note_range(min_midi_note, max_midi_note)?;
+        for channel in Channel::ALL {
+            self.params
+                .channel(channel)
+                .set_frequency_range(min_midi_note, max_midi_note);
+        }
+        Ok(())
+    }
+
+    pub fn gain_ramp_ms(&self) -> f32 {
+        self.params.gain_ramp_ms()
+    }
+
+    /// Sets the time over which gain changes are ramped to avoid clicks. 0
+    /// applies them immediately.
+    pub fn set_gain_ramp_ms(&self, ramp_ms: f32) -> Result<(), Error> {
+        if !(0.0..=MAX_GAIN_RAMP_MS).contains(&ramp_ms) {
+            return Err(Error::InvalidArgument("gain ramp"));
+        }
+        self.params.set_gain_ramp_ms(ramp_ms);
+        Ok(())
+    }
+
+    pub fn tone_fade_ms(&self) -> f32 {
+        self.params.tone_fade_ms()
+    }
+
+    /// Sets the attack and release of each tone. Fades shorter than a sample
+    /// take one sample.
+    pub fn set_tone_fade_ms(&self, fade_ms: f32) -> Result<(), Error> {
+        if !(0.0..=MAX_TONE_FADE_MS).contains(&fade_ms) {
+            return Err(Error::InvalidArgument("tone fade"));
+        }
+        self.params.set_tone_fade_ms(fade_ms);
+        Ok(())
+    }
+
+    pub fn set_gain_db_channel(&self, channel: Channel, gain_db: f32) -> Result<(), Error> {
+        self.set_gain_channel(channel, gain_db, GainUnit::Dbfs)
+    }
+
+    pub fn set_gain_channel(
+        &self,
+        channel: Channel,
+        gain_db: f32,
+        unit: GainUnit,
+    ) -> Result<(), Error> {
+        validate_gain(gain_db, unit)?;
+        self.params.channel(channel).set_gain(gain_db, unit);
+        Ok(())
+    }
+
+    pub fn set_frequency_range_channel(
+        &self,
+        channel: Channel,
+        min_midi_note: f32,
+        max_midi_note: f32,
+    ) -> Result<(), Error> {
+        validate_note_range(min_midi_note, max_midi_note)?;
+        self.params
+            .channel(channel)
+            .set_frequency_range(min_midi_note, max_midi_note);
+        Ok(())
     }
 }
 
+impl AudioPlayer {
+    pub fn frequency_mode(&self) -> FrequencyMode {
+        self.params.frequency_mode()
+    }
+
+    pub fn set_frequency_mode(&self, mode: FrequencyMode) {
+        self.params.set_frequency_mode(mode);
+    }
+
+    pub fn fade_curve(&self) -> FadeCurve {
+        self.params.fade_curve()
+    }
+
+    pub fn set_fade_curve(&self, curve: FadeCurve) {
+        self.params.set_fade_curve(curve);
+    }
+
+    pub fn schedule(&self) -> Schedule {
+        self.params.schedule().snapshot()
+    }
+
+    pub fn set_schedule(&self, schedule: Schedule) -> Result<(), Error> {
+        Ok(self.params.schedule().set(schedule)?)
+    }
+
+    /// Sets the probability that a segment is a pause instead of a tone.
+    pub fn set_silence_probability(&self, probability: f32) -> Result<(), Error> {
+        Ok(self
+            .params
+            .schedule()
+            .update(|s| s.silence_probability = probability)?)
+    }
+
+    pub fn set_tone_duration(&self, distribution: DurationDistribution) -> Result<(), Error> {
+        Ok(self
+            .params
+            .schedule()
+            .update(|s| s.tone_duration = distribution)?)
+    }
+
+    pub fn set_pause_duration(&self, distribution: DurationDistribution) -> Result<(), Error> {
+        Ok(self
+            .params
+            .schedule()
+            .update(|s| s.pause_duration = distribution)?)
+    }
+}
+
+impl AudioPlayer {
+    /// Replaces the tone sequence with a steady tone on one ear, to be
+    /// measured with a sound level meter or coupler. The sequence continues
+    /// after `stop_reference_tone`. The output ceiling still applies.
+    pub fn play_reference_tone(
+        &self,
+        channel: Channel,
+        frequency_hz: f32,
+        level_dbfs: f32,
+    ) -> Result<(), Error> {
+        let min_hz = 440.0 * 2.0_f32.powf((MIN_MIDI_NOTE - 69.0) / 12.0);
+        let max_hz = 440.0 * 2.0_f32.powf((MAX_MIDI_NOTE - 69.0) / 12.0);
+        if !(min_hz..=max_hz).contains(&frequency_hz) {
+            return Err(Error::InvalidArgument("reference frequency"));
+        }
+        if !(level_dbfs.is_finite() && level_dbfs <= MAX_REFERENCE_LEVEL_DBFS) {
+            return Err(Error::InvalidArgument("reference level"));
+        }
+        self.params.test_tones().stop();
+        self.params
+            .calibration()
+            .play_reference_tone(channel, frequency_hz, level_dbfs);
+        Ok(())
+    }
+
+    pub fn stop_reference_tone(&self) {
+        self.params.calibration().stop_reference_tone();
+    }
+
+    /// Adds the level measured while the reference tone plays to the
+    /// calibration table, replacing an earlier measurement of the same ear
+    /// and frequency.
+    pub fn add_calibration_measurement(
+        &mut self,
+        measured_spl_db: f32,
+    ) -> Result<CalibrationPoint, Error> {
+        let (channel, frequency_hz, level_dbfs) = self
+            .params
+            .calibration()
+            .reference_tone()
+            .ok_or(Error::InvalidArgument("measurement without reference tone"))?;
+        let point = CalibrationPoint {
+            channel,
+            frequency_hz,
+            full_scale_spl_db: measured_spl_db - level_dbfs,
+        };
+        let mut table = self.calibration.clone();
+        if table.is_empty() {
+            table.device_id = self.output_device.clone();
+        }
+        table.set_point(point)?;
+        self.set_calibration_table(table)?;
+        Ok(point)
+    }
+
+    pub fn calibration_table(&self) -> &CalibrationTable {
+        &self.calibration
+    }
+
+    /// Replaces the calibration table. The loudest calibrated level also
+    /// becomes the full-scale level of the noise dose estimate.
+    pub fn set_calibration_table(&mut self, table: CalibrationTable) -> Result<(), Error> {
+        if let Some(spl_db) = table.max_full_scale_spl_db() {
+            self.set_full_scale_spl_db(spl_db)?;
+        }
+        self.params.calibration().set_table(&table);
+        self.calibration = table;
+        self.check_calibration_device();
+        Ok(())
+    }
+
+    fn check_calibration_device(&self) {
+        if self.calibration.device_id != self.output_device && !self.calibration.is_empty() {
+            log::warn!(
+                "The calibration was measured on output device {:?}, not on {:?}",
+                self.calibration.device_id,
+                self.output_device
+            );
+        }
+    }
+
+    /// Removes all measurements, so that dB SPL and dB HL gains assume the
+    /// level set with `set_full_scale_spl_db` again.
+    pub fn clear_calibration(&mut self) {
+        self.params
+            .calibration()
+            .set_table(&CalibrationTable::default());
+        self.calibration = CalibrationTable::new(self.output_device.clone());
+    }
+
+    /// Writes the calibration table to a file that can be loaded on another
+    /// install.
+    pub fn save_calibration(&self, path: impl AsRef<Path>) -> Result<(), Error> {
+        self.calibration.save(path)
+    }
+
+    pub fn load_calibration(&mut self, path: impl AsRef<Path>) -> Result<(), Error> {
+        self.set_calibration_table(CalibrationTable::load(path)?)
+    }
+}
+
+impl AudioPlayer {
+    /// Starts matching the tinnitus pitch and plays the first pair of tones
+    /// instead of the tone sequence. `PlaybackEvent::TestTonesPlayed` is
+    /// emitted when the patient can answer. Cancels a running threshold
+    /// test.
+    pub fn start_pitch_match(&mut self, settings: PitchMatchSettings) -> Result<(), Error> {
+        let matcher = PitchMatcher::new(settings)?;
+        self.params.calibration().stop_reference_tone();
+        self.stop_audiometry();
+        self.pitch_matcher = Some(matcher);
+        self.play_pitch_trial();
+        Ok(())
+    }
+
+    fn play_pitch_trial(&self) {
+        let Some(matcher) = &self.pitch_matcher else {
+            return;
+        };
+        match matcher.trial() {
+            Some(trial) => self.params.test_tones().play(&matcher.pattern(trial)),
+            None => self.params.test_tones().stop(),
+        }
+    }
+
+    /// The pair of tones the patient is asked about, `None` if no match is
+    /// running.
+    pub fn pitch_trial(&self) -> Option<PitchTrial> {
+        self.pitch_matcher.as_ref()?.trial()
+    }
+
+    /// Takes the patient's answer to the current pair and plays the next
+    /// one. The tone sequence resumes once the match has finished.
+    pub fn respond_pitch_match(&mut self, response: PitchResponse) -> Result<(), Error> {
+        self.pitch_matcher
+            .as_mut()
+            .ok_or(Error::InvalidArgument("response without pitch match"))?
+            .respond(response)?;
+        self.play_pitch_trial();
+        Ok(())
+    }
+
+    /// Plays the current pair again.
+    pub fn replay_pitch_pair(&self) -> Result<(), Error> {
+        if self.pitch_trial().is_none() {
+            return Err(Error::InvalidArgument("replay without pitch match"));
+        }
+        self.play_pitch_trial();
+        Ok(())
+    }
+
+    /// Cancels a running match and returns to the tone sequence.
+    pub fn stop_pitch_match(&mut self) {
+        if self.pitch_trial().is_some() {
+            self.params.test_tones().stop();
+            self.pitch_matcher = None;
+        }
+    }
+
+    /// Result of the last finished match.
+    pub fn pitch_match_result(&self) -> Option<PitchMatchResult> {
+        self.pitch_matcher.as_ref()?.result()
+    }
+}
+
+impl AudioPlayer {
+    /// Starts a pure-tone threshold test and plays the first pulsed tone
+    /// instead of the tone sequence. `PlaybackEvent::TestTonesPlayed` is
+    /// emitted after each presentation, from when the patient's answer is
+    /// expected. Levels are in dB HL using the calibration table. Cancels a
+    /// running pitch match.
+    pub fn start_audiometry(&mut self, settings: AudiometrySettings) -> Result<(), Error> {
+        let audiometer = Audiometer::new(settings, entropy_seed())?;
+        self.params.calibration().stop_reference_tone();
+        self.stop_pitch_match();
+        self.audiometer = Some(audiometer);
+        self.play_audiometry_presentation();
+        Ok(())
+    }
+
+    /// Loudest level in dB HL the device plays at `frequency_hz` under the
+    /// output ceiling.
+    fn output_limit_db_hl(&self, channel: Channel, frequency_hz: f32) -> f32 {
+        let full_scale_spl_db = self
+            .calibration
+            .full_scale_spl_db(channel, frequency_hz)
+            .unwrap_or_else(|| self.full_scale_spl_db());
+        full_scale_spl_db + self.output_ceiling_db() - reference_threshold_spl_db(frequency_hz)
+    }
+
+    fn play_audiometry_presentation(&mut self) {
+        let limit = self
+            .audiometer
+            .as_ref()
+            .and_then(Audiometer::current_test)
+            .map(|(channel, frequency_hz)| self.output_limit_db_hl(channel, frequency_hz));
+        let Some(audiometer) = &mut self.audiometer else {
+            return;
+        };
+        if let Some(limit) = limit {
+            audiometer.set_output_limit(limit);
+        }
+        match audiometer.presentation() {
+            Some(presentation) => self
+                .params
+                .test_tones()
+                .play(&Audiometer::pattern(&presentation)),
+            None => self.params.test_tones().stop(),
+        }
+    }
+
+    /// The tone the patient is asked about, `None` if no test is running.
+    pub fn audiometry_presentation(&self) -> Option<AudiometryPresentation> {
+        self.audiometer.as_ref()?.presentation()
+    }
+
+    /// Takes whether the patient heard the current tone and plays the next
+    /// one. The tone sequence resumes once the test has finished.
+    pub fn respond_audiometry(&mut self, heard: bool) -> Result<(), Error> {
+        self.audiometer
+            .as_mut()
+            .ok_or(Error::InvalidArgument("response without audiometry"))?
+            .respond(heard)?;
+        self.play_audiometry_presentation();
+        Ok(())
+    }
+
+    /// Plays the current tone again, e.g. if the patient was distracted.
+    pub fn replay_audiometry_tone(&mut self) -> Result<(), Error> {
+        if self.audiometry_presentation().is_none() {
+            return Err(Error::InvalidArgument("replay without audiometry"));
+        }
+        self.play_audiometry_presentation();
+        Ok(())
+    }
+
+    /// Cancels a running test and returns to the tone sequence. The
+    /// thresholds found so far remain available from `audiogram`.
+    pub fn stop_audiometry(&mut self) {
+        if let Some(audiometer) = &mut self.audiometer
+            && !audiometer.is_finished()
+        {
+            self.params.test_tones().stop();
+            audiometer.stop();
+        }
+    }
+
+    /// Thresholds of the last test, complete once it has finished.
+    pub fn audiogram(&self) -> Option<&Audiogram> {
+        Some(self.audiometer.as_ref()?.audiogram())
+    }
+
+    pub fn save_audiogram(&self, path: impl AsRef<Path>) -> Result<(), Error> {
+        self.audiogram()
+            .ok_or(Error::InvalidArgument("no audiogram"))?
+            .save(path)
+    }
+}
+
+impl AudioPlayer {
+    /// Raises each tone of the sequence by a gain for its frequency and ear
+    /// derived from `audiogram`, e.g. one imported with `Audiogram::load`.
+    /// The output ceiling still applies.
+    pub fn set_hearing_compensation(
+        &mut self,
+        audiogram: &Audiogram,
+        compensation: HearingCompensation,
+    ) -> Result<(), Error> {
+        let curve = GainCurve::from_audiogram(audiogram, compensation)?;
+        self.params.compensation().set_audiogram_curve(&curve);
+        self.hearing_compensation = curve;
+        Ok(())
+    }
+
+    /// Sets the hearing compensation from an audiogram file in CSV or JSON.
+    pub fn load_hearing_compensation(
+        &mut self,
+        path: impl AsRef<Path>,
+        compensation: HearingCompensation,
+    ) -> Result<(), Error> {
+        self.set_hearing_compensation(&Audiogram::load(path)?, compensation)
+    }
+
+    /// Sets the hearing compensation from the audiogram of the last
+    /// threshold test.
+    pub fn use_audiometry_for_compensation(
+        &mut self,
+        compensation: HearingCompensation,
+    ) -> Result<(), Error> {
+        let audiogram = self
+            .audiogram()
+            .cloned()
+            .ok_or(Error::InvalidArgument("no audiogram"))?;
+        self.set_hearing_compensation(&audiogram, compensation)
+    }
+
+    pub fn hearing_compensation(&self) -> &GainCurve {
+        &self.hearing_compensation
+    }
+
+    pub fn clear_hearing_compensation(&mut self) {
+        self.hearing_compensation = GainCurve::new();
+        self.params
+            .compensation()
+            .set_audiogram_curve(&self.hearing_compensation);
+    }
+
+    /// Scales each tone so that tones of all frequencies sound as loud as a
+    /// 1 kHz tone of the same level, following the ISO 226 contour of
+    /// `target_phon`. It adds to the hearing compensation, and the output
+    /// ceiling still applies. `None` turns the weighting off.
+    pub fn set_equal_loudness(&mut self, target_phon: Option<f32>) -> Result<(), Error> {
+        let curve = match target_phon {
+            Some(phon) => GainCurve::equal_loudness(phon)?,
+            None => GainCurve::new(),
+        };
+        self.params.compensation().set_equal_loudness_curve(&curve);
+        self.equal_loudness_phon = target_phon;
+        Ok(())
+    }
+
+    pub fn equal_loudness(&self) -> Option<f32> {
+        self.equal_loudness_phon
+    }
+
+    /// Corrects the frequency response of the headphone on `ears`, `None`
+    /// for both, with the equalization of `profile` for each ear. Each tone
+    /// is scaled by the gain of the equalization at its frequency, so tones
+    /// of all frequencies reach the ear at the same level. The output ceiling
+    /// still applies.
+    pub fn set_headphone_profile(&mut self, ears: Option<Channel>, profile: &HeadphoneProfile) {
+        for channel in ears.map_or(Channel::ALL.to_vec(), |c| vec![c]) {
+            *self.headphone_profile.ear_mut(channel) = profile.ear(channel).clone();
+        }
+        self.params
+            .compensation()
+            .set_headphone_curve(&GainCurve::from_headphone_profile(&self.headphone_profile));
+    }
+
+    /// Sets the headphone correction from an AutoEq profile for Equalizer
+    /// APO, with a parametric or a graphic EQ.
+    pub fn load_headphone_profile(
+        &mut self,
+        ears: Option<Channel>,
+        path: impl AsRef<Path>,
+    ) -> Result<(), Error> {
+        let profile = HeadphoneProfile::load(path)?;
+        self.set_headphone_profile(ears, &profile);
+        Ok(())
+    }
+
+    pub fn headphone_profile(&self) -> &HeadphoneProfile {
+        &self.headphone_profile
+    }
+
+    pub fn clear_headphone_profile(&mut self) {
+        self.set_headphone_profile(None, &HeadphoneProfile::new());
+    }
+}
+
+/// Draws a seed from the randomly keyed hasher of the standard library, which
+/// is initialised from OS entropy.
+pub fn entropy_seed() -> u64 {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos());
+    RandomState::new().hash_one(nanos)
+}
+
+impl Default for AudioPlayer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub mod null_backend;
+
 #[cfg(target_os = "android")]
 mod aaudio_backend;
 
 #[cfg(not(target_os = "android"))]
 mod cpal_backend;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::NullBackend;
+    use crate::test_util::{STEREO_48K, peak, wait_until_stopped};
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_stop_fades_out() {
+        let backend = NullBackend::new(STEREO_48K).with_realtime(true);
+        let handle = backend.handle();
+        let mut player = AudioPlayer::with_backend(Box::new(backend));
+        player.set_gain_db(0.0).unwrap();
+        player.set_tail_fade_ms(20.0).unwrap();
+        player
+            .set_tone_duration(DurationDistribution::Fixed { ms: 10000.0 })
+            .unwrap();
+        player.set_silence_probability(0.0).unwrap();
+
+        player.start().unwrap();
+        handle.wait_for_frames(36000, Duration::from_secs(10));
+        player.stop();
+        assert!(wait_until_stopped(&player));
+        let stopped = std::iter::from_fn(|| player.poll_event())
+            .filter(|&event| event == PlaybackEvent::StreamStopped)
+            .count();
+        assert_eq!(stopped, 1);
+
+        // The last tone fades out over 960 frames before the output is closed
+        let samples = handle.samples();
+        let last = samples.iter().step_by(2).rposition(|&s| s != 0.0).unwrap();
+        assert!(last < samples.len() / 2 - 1);
+        assert!(peak(&samples[(last - 959) * 2..(last - 859) * 2], 0) > 0.8);
+        assert!(peak(&samples[(last - 99) * 2..=last * 2], 0) < 0.11);
+
+        // Starting again cuts a fade that is still running
+        player.set_tail_fade_ms(5000.0).unwrap();
+        player.start().unwrap();
+        let frames = handle.frames_rendered();
+        handle.wait_for_frames(frames + 4800, Duration::from_secs(10));
+        player.stop();
+        player.start().unwrap();
+        assert_eq!(player.stream_state(), StreamState::Playing);
+        let stopped = std::iter::from_fn(|| player.poll_event())
+            .filter(|&event| event == PlaybackEvent::StreamStopped)
+            .count();
+        assert_eq!(stopped, 1);
+    }
+
+    #[test]
+    fn test_session_stops_player() {
+        let format = StreamFormat {
+            sample_rate: 44100,
+            channels: 2,
+        };
+        let backend = NullBackend::new(format);
+        let handle = backend.handle();
+        let mut player = AudioPlayer::with_backend(Box::new(backend));
+        assert_eq!(player.session_remaining(), None);
+        player.set_session_duration(Some(Duration::from_secs(1)));
+
+        player.start().unwrap();
+        for _ in 0..1000 {
+            if player.stream_state() == StreamState::Stopped {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(player.stream_state(), StreamState::Stopped);
+        let samples = handle.samples();
+        assert!(samples[22050 * 2..44100 * 2].iter().any(|&s| s != 0.0));
+        assert!(samples[44100 * 2..].iter().all(|&s| s == 0.0));
+        assert_eq!(player.session_elapsed(), Duration::from_secs(1));
+        assert_eq!(player.session_remaining(), Some(Duration::ZERO));
+        let events: Vec<_> = std::iter::from_fn(|| player.poll_event()).collect();
+        assert_eq!(events.last(), Some(&PlaybackEvent::SessionCompleted));
+    }
+}
@@ -1,5 +1,13 @@
-use crate::audio::AudioBackend;
-use std::sync::OnceLock;
+use crate::audio::{AudioBackend, RenderCallback, SharedStreamState, StreamFormat, StreamState};
+use crate::error::{catch_panic, lock, record_error};
+use crate::{Error, EventQueue, PlaybackEvent};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+use std::time::Duration;
+
+/// Interval between attempts to reopen a disconnected stream.
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
 
 mod bindings {
     #![allow(non_upper_case_globals)]
@@ -13,6 +21,7 @@ mod bindings {
     pub type aaudio_data_callback_result_t = i32;
 
     pub const AAUDIO_OK: aaudio_result_t = 0;
+    pub const AAUDIO_ERROR_DISCONNECTED: aaudio_result_t = -899;
     pub const AAUDIO_DIRECTION_OUTPUT: i32 = 0;
     pub const AAUDIO_FORMAT_PCM_FLOAT: i32 = 2;
     pub const AAUDIO_PERFORMANCE_MODE_POWER_SAVING: i32 = 11;
@@ -20,6 +29,7 @@ mod bindings {
     pub const AAUDIO_CONTENT_TYPE_MUSIC: i32 = 2;
     pub const AAUDIO_USAGE_MEDIA: i32 = 1;
     pub const AAUDIO_CALLBACK_RESULT_CONTINUE: aaudio_data_callback_result_t = 0;
+    pub const AAUDIO_CALLBACK_RESULT_STOP: aaudio_data_callback_result_t = 1;
 
     #[repr(C)]
     pub struct AAudioStreamStruct {
@@ -41,6 +51,14 @@ mod bindings {
             numFrames: i32,
         ) -> aaudio_data_callback_result_t,
     >;
+
+    pub type AAudioStream_errorCallback = ::std::option::Option<
+        unsafe extern "C" fn(
+            stream: *mut AAudioStream,
+            userData: *mut ::std::os::raw::c_void,
+            error: aaudio_result_t,
+        ),
+    >;
 }
 
 static AAUDIO_LIB: OnceLock<Option<libloading::Library>> = OnceLock::new();
@@ -68,10 +86,6 @@ aaudio_fn!(
     AAudioStreamBuilder_setDirection,
     unsafe extern "C" fn(*mut bindings::AAudioStreamBuilder, i32) -> bindings::aaudio_result_t
 );
-aaudio_fn!(
-    AAudioStreamBuilder_setSampleRate,
-    unsafe extern "C" fn(*mut bindings::AAudioStreamBuilder, i32) -> bindings::aaudio_result_t
-);
 aaudio_fn!(
     AAudioStreamBuilder_setChannelCount,
     unsafe extern "C" fn(*mut bindings::AAudioStreamBuilder, i32) -> bindings::aaudio_result_t
@@ -108,6 +122,14 @@ aaudio_fn!(
         *mut std::ffi::c_void,
     ) -> bindings::aaudio_result_t
 );
+aaudio_fn!(
+    AAudioStreamBuilder_setErrorCallback,
+    unsafe extern "C" fn(
+        *mut bindings::AAudioStreamBuilder,
+        bindings::AAudioStream_errorCallback,
+        *mut std::ffi::c_void,
+    )
+);
 aaudio_fn!(
     AAudioStreamBuilder_openStream,
     unsafe extern "C" fn(
@@ -131,6 +153,14 @@ aaudio_fn!(
     AAudioStream_close,
     unsafe extern "C" fn(*mut bindings::AAudioStream) -> bindings::aaudio_result_t
 );
+aaudio_fn!(
+    AAudioStream_getSampleRate,
+    unsafe extern "C" fn(*mut bindings::AAudioStream) -> i32
+);
+aaudio_fn!(
+    AAudioStream_getChannelCount,
+    unsafe extern "C" fn(*mut bindings::AAudioStream) -> i32
+);
 
 extern "C" fn data_callback(
     _stream: *mut bindings::AAudioStream,
@@ -139,149 +169,340 @@ extern "C" fn data_callback(
     num_frames: i32,
 ) -> i32 {
     unsafe {
-        let backend = user_data as *mut AAudioBackend;
-        if let Some(ref mut cb) = (*backend).callback {
-            let num_samples = (num_frames * 2) as usize; // stereo
-            let data = std::slice::from_raw_parts_mut(audio_data as *mut f32, num_samples);
-            cb(data);
+        let shared = &*(user_data as *const Shared);
+        let format = shared.format();
+        let num_samples = num_frames as usize * format.channels as usize;
+        let data = std::slice::from_raw_parts_mut(audio_data as *mut f32, num_samples);
+        // The player's callback catches its own panics, but nothing may
+        // unwind into the AAudio thread
+        let result = catch_panic(|| {
+            // Only contended while the stream is being reopened
+            Ok(match shared.callback.try_lock().as_deref_mut() {
+                Ok(Some(cb)) => cb(data, format),
+                _ => {
+                    data.fill(0.0);
+                    true
+                }
+            })
+        });
+        match result {
+            Ok(true) => bindings::AAUDIO_CALLBACK_RESULT_CONTINUE,
+            Ok(false) => {
+                shared.state.set(StreamState::Stopped);
+                bindings::AAUDIO_CALLBACK_RESULT_STOP
+            }
+            Err(e) => {
+                record_error(&e);
+                data.fill(0.0);
+                bindings::AAUDIO_CALLBACK_RESULT_CONTINUE
+            }
         }
-        bindings::AAUDIO_CALLBACK_RESULT_CONTINUE as i32
     }
 }
 
-pub struct AAudioBackend {
-    stream: Option<*mut bindings::AAudioStream>,
-    callback: Option<Box<dyn FnMut(&mut [f32]) + Send + 'static>>,
+extern "C" fn error_callback(
+    _stream: *mut bindings::AAudioStream,
+    user_data: *mut std::ffi::c_void,
+    error: bindings::aaudio_result_t,
+) {
+    // The stream must not be closed from its own callback, and the stream
+    // holds no reference of its own to keep `Shared` alive on another thread
+    let shared = unsafe {
+        let ptr = user_data as *const Shared;
+        Arc::increment_strong_count(ptr);
+        Arc::from_raw(ptr)
+    };
+    if error != bindings::AAUDIO_ERROR_DISCONNECTED {
+        shared.report(Error::AudioOutput(format!("AAudio stream error {error}")));
+        return;
+    }
+    shared.report(Error::AudioOutput(
+        "AAudio stream disconnected, reconnecting".to_string(),
+    ));
+    thread::spawn(move || shared.reconnect());
 }
 
-impl AAudioBackend {
-    pub fn new() -> Self {
-        log::info!("AAudioBackend::new()");
-        Self {
-            stream: None,
-            callback: None,
-        }
-    }
+/// State shared with the AAudio callbacks through their user data pointer.
+struct Shared {
+    stream: Mutex<Option<*mut bindings::AAudioStream>>,
+    sample_rate: AtomicU32,
+    channels: AtomicU32,
+    callback: Mutex<Option<RenderCallback>>,
+    started: AtomicBool,
+    state: SharedStreamState,
+    events: Mutex<Option<Arc<EventQueue>>>,
 }
 
-// Make AAudioBackend Send + Sync (required for static storage)
-unsafe impl Send for AAudioBackend {}
-unsafe impl Sync for AAudioBackend {}
+// The stream pointer is only used behind the mutex
+unsafe impl Send for Shared {}
+unsafe impl Sync for Shared {}
 
-impl AudioBackend for AAudioBackend {
-    fn start(&mut self, f: Box<dyn FnMut(&mut [f32]) + Send + 'static>) {
-        if self.stream.is_some() {
-            return; // already running
+impl Shared {
+    fn report(&self, error: Error) {
+        log::warn!("{error}");
+        if let Some(events) = lock(&self.events).as_ref() {
+            events.push(PlaybackEvent::StreamError(error.code()));
         }
+    }
 
-        self.callback = Some(Box::new(f));
+    fn format(&self) -> StreamFormat {
+        StreamFormat {
+            sample_rate: self.sample_rate.load(Ordering::Relaxed),
+            channels: self.channels.load(Ordering::Relaxed) as u16,
+        }
+    }
 
-        let mut builder: *mut bindings::AAudioStreamBuilder = std::ptr::null_mut();
-        unsafe {
-            let create_fn = match AAudio_createStreamBuilder() {
-                Some(f) => f,
-                None => {
-                    log::error!("AAudio_createStreamBuilder not available");
-                    return;
-                }
-            };
-            if create_fn(&mut builder) != bindings::AAUDIO_OK {
-                log::error!("Failed to create AAudio stream builder");
-                return;
-            }
+    fn set_format(&self, format: StreamFormat) {
+        self.sample_rate
+            .store(format.sample_rate, Ordering::Relaxed);
+        self.channels
+            .store(format.channels as u32, Ordering::Relaxed);
+    }
 
-            if let Some(set_dir_fn) = AAudioStreamBuilder_setDirection() {
-                set_dir_fn(builder, bindings::AAUDIO_DIRECTION_OUTPUT as i32);
-            }
-            if let Some(set_rate_fn) = AAudioStreamBuilder_setSampleRate() {
-                set_rate_fn(builder, 44100);
-            }
-            if let Some(set_ch_fn) = AAudioStreamBuilder_setChannelCount() {
-                set_ch_fn(builder, 2);
-            }
-            if let Some(set_fmt_fn) = AAudioStreamBuilder_setFormat() {
-                set_fmt_fn(builder, bindings::AAUDIO_FORMAT_PCM_FLOAT as i32);
-            }
-            if let Some(set_buf_fn) = AAudioStreamBuilder_setBufferCapacityInFrames() {
-                set_buf_fn(builder, 2048);
-            }
-            if let Some(set_perf_fn) = AAudioStreamBuilder_setPerformanceMode() {
-                set_perf_fn(builder, bindings::AAUDIO_PERFORMANCE_MODE_POWER_SAVING);
-            }
-            if let Some(set_sharing_fn) = AAudioStreamBuilder_setSharingMode() {
-                set_sharing_fn(builder, bindings::AAUDIO_SHARING_MODE_SHARED);
-            }
-            if let Some(set_content_fn) = AAudioStreamBuilder_setContentType() {
-                set_content_fn(builder, bindings::AAUDIO_CONTENT_TYPE_MUSIC);
-            }
-            if let Some(set_usage_fn) = AAudioStreamBuilder_setUsage() {
-                set_usage_fn(builder, bindings::AAUDIO_USAGE_MEDIA);
+    /// Closes the lost stream and opens a new one on the current default
+    /// device, retrying until it works or the backend is stopped.
+    fn reconnect(self: Arc<Self>) {
+        self.state.set(StreamState::Reconnecting);
+        loop {
+            let mut stream = lock(&self.stream);
+            if !self.started.load(Ordering::Acquire) {
+                return;
             }
-            if let Some(set_cb_fn) = AAudioStreamBuilder_setDataCallback() {
-                set_cb_fn(
-                    builder,
-                    Some(data_callback),
-                    self as *mut _ as *mut std::ffi::c_void,
-                );
+            if let Some(old) = stream.take() {
+                close_stream(old);
             }
-
-            let mut stream: *mut bindings::AAudioStream = std::ptr::null_mut();
-            let open_fn = match AAudioStreamBuilder_openStream() {
-                Some(f) => f,
-                None => {
-                    log::error!("AAudioStreamBuilder_openStream not available");
-                    if let Some(del_fn) = AAudioStreamBuilder_delete() {
-                        del_fn(builder);
+            let reopened = open_stream(&self).and_then(|(new, format)| {
+                self.set_format(format);
+                match request_start(new) {
+                    Ok(()) => Ok((new, format)),
+                    Err(e) => {
+                        close_stream(new);
+                        Err(e)
                     }
+                }
+            });
+            match reopened {
+                Ok((new, format)) => {
+                    *stream = Some(new);
+                    self.state.set(StreamState::Playing);
+                    log::info!(
+                        "AAudio stream reconnected: {} Hz, {} channels",
+                        format.sample_rate,
+                        format.channels
+                    );
                     return;
                 }
-            };
-            if open_fn(builder, &mut stream) != bindings::AAUDIO_OK {
-                log::error!("Failed to open AAudio stream");
+                Err(e) => log::warn!("{e}, retrying"),
+            }
+            drop(stream);
+            thread::sleep(RECONNECT_INTERVAL);
+        }
+    }
+}
+
+/// Opens an output stream whose callbacks render through `shared`.
+fn open_stream(shared: &Arc<Shared>) -> Result<(*mut bindings::AAudioStream, StreamFormat), Error> {
+    let user_data = Arc::as_ptr(shared) as *mut std::ffi::c_void;
+    let mut builder: *mut bindings::AAudioStreamBuilder = std::ptr::null_mut();
+    unsafe {
+        let create_fn = match AAudio_createStreamBuilder() {
+            Some(f) => f,
+            None => {
+                return Err(Error::AudioOutput(
+                    "AAudio_createStreamBuilder not available".to_string(),
+                ));
+            }
+        };
+        if create_fn(&mut builder) != bindings::AAUDIO_OK {
+            return Err(Error::AudioOutput(
+                "Failed to create AAudio stream builder".to_string(),
+            ));
+        }
+
+        // The sample rate is left unspecified so that the stream opens at
+        // the device's native rate; it is queried after opening.
+        if let Some(set_dir_fn) = AAudioStreamBuilder_setDirection() {
+            set_dir_fn(builder, bindings::AAUDIO_DIRECTION_OUTPUT as i32);
+        }
+        if let Some(set_ch_fn) = AAudioStreamBuilder_setChannelCount() {
+            set_ch_fn(builder, 2);
+        }
+        if let Some(set_fmt_fn) = AAudioStreamBuilder_setFormat() {
+            set_fmt_fn(builder, bindings::AAUDIO_FORMAT_PCM_FLOAT as i32);
+        }
+        if let Some(set_buf_fn) = AAudioStreamBuilder_setBufferCapacityInFrames() {
+            set_buf_fn(builder, 2048);
+        }
+        if let Some(set_perf_fn) = AAudioStreamBuilder_setPerformanceMode() {
+            set_perf_fn(builder, bindings::AAUDIO_PERFORMANCE_MODE_POWER_SAVING);
+        }
+        if let Some(set_sharing_fn) = AAudioStreamBuilder_setSharingMode() {
+            set_sharing_fn(builder, bindings::AAUDIO_SHARING_MODE_SHARED);
+        }
+        if let Some(set_content_fn) = AAudioStreamBuilder_setContentType() {
+            set_content_fn(builder, bindings::AAUDIO_CONTENT_TYPE_MUSIC);
+        }
+        if let Some(set_usage_fn) = AAudioStreamBuilder_setUsage() {
+            set_usage_fn(builder, bindings::AAUDIO_USAGE_MEDIA);
+        }
+        if let Some(set_cb_fn) = AAudioStreamBuilder_setDataCallback() {
+            set_cb_fn(builder, Some(data_callback), user_data);
+        }
+        if let Some(set_err_fn) = AAudioStreamBuilder_setErrorCallback() {
+            set_err_fn(builder, Some(error_callback), user_data);
+        }
+
+        let mut stream: *mut bindings::AAudioStream = std::ptr::null_mut();
+        let open_fn = match AAudioStreamBuilder_openStream() {
+            Some(f) => f,
+            None => {
                 if let Some(del_fn) = AAudioStreamBuilder_delete() {
                     del_fn(builder);
                 }
-                return;
+                return Err(Error::AudioOutput(
+                    "AAudioStreamBuilder_openStream not available".to_string(),
+                ));
             }
-
+        };
+        if open_fn(builder, &mut stream) != bindings::AAUDIO_OK {
             if let Some(del_fn) = AAudioStreamBuilder_delete() {
                 del_fn(builder);
             }
+            return Err(Error::AudioOutput(
+                "Failed to open AAudio stream".to_string(),
+            ));
+        }
 
-            let start_fn = match AAudioStream_requestStart() {
-                Some(f) => f,
-                None => {
-                    log::error!("AAudioStream_requestStart not available");
-                    if let Some(close_fn) = AAudioStream_close() {
-                        close_fn(stream);
-                    }
-                    return;
-                }
-            };
-            if start_fn(stream) != bindings::AAUDIO_OK {
-                log::error!("Failed to start AAudio stream");
-                if let Some(close_fn) = AAudioStream_close() {
-                    close_fn(stream);
-                }
-                return;
-            }
+        if let Some(del_fn) = AAudioStreamBuilder_delete() {
+            del_fn(builder);
+        }
+
+        let (Some(rate_fn), Some(channels_fn)) =
+            (AAudioStream_getSampleRate(), AAudioStream_getChannelCount())
+        else {
+            close_stream(stream);
+            return Err(Error::AudioOutput(
+                "AAudioStream_getSampleRate not available".to_string(),
+            ));
+        };
+        let sample_rate = rate_fn(stream);
+        let channels = channels_fn(stream);
+        if sample_rate <= 0 || channels <= 0 {
+            close_stream(stream);
+            return Err(Error::AudioOutput(
+                "AAudio stream reported invalid format".to_string(),
+            ));
+        }
+
+        let format = StreamFormat {
+            sample_rate: sample_rate as u32,
+            channels: channels as u16,
+        };
+        Ok((stream, format))
+    }
+}
+
+fn request_start(stream: *mut bindings::AAudioStream) -> Result<(), Error> {
+    unsafe {
+        let Some(start_fn) = AAudioStream_requestStart() else {
+            return Err(Error::AudioOutput(
+                "AAudioStream_requestStart not available".to_string(),
+            ));
+        };
+        if start_fn(stream) != bindings::AAUDIO_OK {
+            return Err(Error::AudioOutput(
+                "Failed to start AAudio stream".to_string(),
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn close_stream(stream: *mut bindings::AAudioStream) {
+    unsafe {
+        if let Some(stop_fn) = AAudioStream_requestStop() {
+            stop_fn(stream);
+        }
+        if let Some(close_fn) = AAudioStream_close() {
+            close_fn(stream);
+        }
+    }
+}
+
+pub struct AAudioBackend {
+    shared: Arc<Shared>,
+    format: Option<StreamFormat>,
+}
 
-            self.stream = Some(stream);
+impl AAudioBackend {
+    pub fn new() -> Self {
+        log::info!("AAudioBackend::new()");
+        Self {
+            shared: Arc::new(Shared {
+                stream: Mutex::new(None),
+                sample_rate: AtomicU32::new(0),
+                channels: AtomicU32::new(2),
+                callback: Mutex::new(None),
+                started: AtomicBool::new(false),
+                state: SharedStreamState::new(),
+                events: Mutex::new(None),
+            }),
+            format: None,
         }
     }
+}
+
+impl AudioBackend for AAudioBackend {
+    fn open(&mut self) -> Result<StreamFormat, Error> {
+        let mut stream = lock(&self.shared.stream);
+        if let (Some(_), Some(format)) = (*stream, self.format) {
+            return Ok(format);
+        }
+
+        let (new, format) = open_stream(&self.shared)?;
+        self.shared.set_format(format);
+        self.format = Some(format);
+        *stream = Some(new);
+        Ok(format)
+    }
+
+    fn start(&mut self, f: RenderCallback) -> Result<(), Error> {
+        if self.shared.started.load(Ordering::Acquire) {
+            return Ok(()); // already running
+        }
+        let Some(stream) = *lock(&self.shared.stream) else {
+            return Err(Error::AudioOutput(
+                "AAudio stream has not been opened".to_string(),
+            ));
+        };
+
+        *lock(&self.shared.callback) = Some(f);
+
+        if let Err(e) = request_start(stream) {
+            self.stop();
+            return Err(e);
+        }
+        self.shared.started.store(true, Ordering::Release);
+        self.shared.state.set(StreamState::Playing);
+        Ok(())
+    }
 
     fn stop(&mut self) {
-        if let Some(stream) = self.stream.take() {
-            unsafe {
-                if let Some(stop_fn) = AAudioStream_requestStop() {
-                    stop_fn(stream);
-                }
-                if let Some(close_fn) = AAudioStream_close() {
-                    close_fn(stream);
-                }
-            }
+        self.shared.started.store(false, Ordering::Release);
+        if let Some(stream) = lock(&self.shared.stream).take() {
+            close_stream(stream);
         }
-        self.callback = None;
+        self.shared.state.set(StreamState::Stopped);
+        self.format = None;
+        *lock(&self.shared.callback) = None;
+    }
+
+    fn stream_state(&self) -> StreamState {
+        self.shared.state.get()
+    }
+
+    fn set_event_queue(&mut self, events: Arc<EventQueue>) {
+        *lock(&self.shared.events) = Some(events);
     }
 }
 
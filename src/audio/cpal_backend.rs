@@ -1,14 +1,273 @@
-use crate::audio::AudioBackend;
+use crate::audio::{
+    AudioBackend, OutputDevice, RenderCallback, SharedStreamState, StreamFormat, StreamState,
+};
+use crate::{Error, EventQueue, PlaybackEvent};
 use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
-use cpal::{Stream, StreamConfig};
+use cpal::{Device, SampleFormat, Stream, StreamConfig, StreamError, SupportedBufferSize};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+const PREFERRED_BUFFER_FRAMES: u32 = 2048;
+
+/// How long a stream may go without calling back before it is rebuilt. Also
+/// the interval between reconnect attempts.
+const WATCHDOG_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Rates reported for devices that support a continuous range of rates.
+const COMMON_SAMPLE_RATES: [u32; 6] = [44100, 48000, 88200, 96000, 176400, 192000];
+
+/// Builds ids from the host and device names. Devices with the same name get
+/// a suffix in enumeration order, e.g. `CoreAudio:USB Headset#2`.
+fn device_ids(host_name: &str, names: &[String]) -> Vec<String> {
+    names
+        .iter()
+        .enumerate()
+        .map(
+            |(i, name)| match names[..i].iter().filter(|n| *n == name).count() {
+                0 => format!("{host_name}:{name}"),
+                duplicates => format!("{host_name}:{name}#{}", duplicates + 1),
+            },
+        )
+        .collect()
+}
+
+enum Event {
+    Stop,
+    /// The render callback has finished the output.
+    Finished,
+    StreamError(StreamError),
+}
+
+/// Thread that owns the stream and rebuilds it when the device is lost or
+/// the stream stops calling back.
+struct Supervisor {
+    events: Sender<Event>,
+    thread: JoinHandle<()>,
+}
+
+/// Everything needed to (re)build the stream on the supervisor thread.
+struct StreamContext {
+    device_id: Option<String>,
+    callback: Arc<Mutex<RenderCallback>>,
+    callbacks: Arc<AtomicU64>,
+    events: Sender<Event>,
+    state: SharedStreamState,
+    playback_events: Option<Arc<EventQueue>>,
+}
+
+impl StreamContext {
+    fn report(&self, error: Error) {
+        log::warn!("{error}");
+        if let Some(events) = &self.playback_events {
+            events.push(PlaybackEvent::StreamError(error.code()));
+        }
+    }
+
+    fn build(&self, device: &Device, config: &StreamConfig) -> Result<Stream, Error> {
+        let format = StreamFormat {
+            sample_rate: config.sample_rate.0,
+            channels: config.channels,
+        };
+        let callback = self.callback.clone();
+        let callbacks = self.callbacks.clone();
+        let events = self.events.clone();
+        let finished_events = self.events.clone();
+        let mut finished = false;
+
+        let stream = device
+            .build_output_stream(
+                config,
+                move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
+                    callbacks.fetch_add(1, Ordering::Relaxed);
+                    // Only contended while a replaced stream is winding down
+                    if finished {
+                        data.fill(0.0);
+                        return;
+                    }
+                    let running = match callback.try_lock() {
+                        Ok(mut f) => f(data, format),
+                        Err(_) => {
+                            data.fill(0.0);
+                            true
+                        }
+                    };
+                    if !running {
+                        finished = true;
+                        let _ = finished_events.send(Event::Finished);
+                    }
+                },
+                move |err| {
+                    let _ = events.send(Event::StreamError(err));
+                },
+                None,
+            )
+            .map_err(|e| Error::AudioOutput(format!("Failed to build audio stream: {e}")))?;
+
+        stream
+            .play()
+            .map_err(|e| Error::AudioOutput(format!("Failed to play audio stream: {e}")))?;
+        Ok(stream)
+    }
+
+    /// Builds a stream on the selected device again, falling back to the
+    /// default device if it is gone.
+    fn reconnect(&self) -> Result<Stream, Error> {
+        let host = cpal::default_host();
+        let device = find_device(&host, self.device_id.as_deref())
+            .ok_or_else(|| Error::AudioOutput("No audio output device found".to_string()))?;
+        let config = negotiate_config(&device)?;
+        let stream = self.build(&device, &config)?;
+        log::info!(
+            "Audio output reconnected: {} Hz, {} channels",
+            config.sample_rate.0,
+            config.channels
+        );
+        Ok(stream)
+    }
+
+    fn run(self, initial: Stream, events: Receiver<Event>) {
+        let mut stream = Some(initial);
+        let mut last_callbacks = self.callbacks.load(Ordering::Relaxed);
+
+        loop {
+            match events.recv_timeout(WATCHDOG_INTERVAL) {
+                Ok(Event::Stop) | Err(RecvTimeoutError::Disconnected) => break,
+                Ok(Event::Finished) => {
+                    log::info!("Audio output finished");
+                    break;
+                }
+                Ok(Event::StreamError(StreamError::DeviceNotAvailable)) => {
+                    self.report(Error::AudioOutput(
+                        "Audio output device lost, reconnecting".to_string(),
+                    ));
+                    stream = None;
+                }
+                Ok(Event::StreamError(e)) => self.report(Error::AudioOutput(e.to_string())),
+                Err(RecvTimeoutError::Timeout) => {
+                    let callbacks = self.callbacks.load(Ordering::Relaxed);
+                    if stream.is_some() && callbacks == last_callbacks {
+                        self.report(Error::AudioOutput(
+                            "Audio stream stalled, reconnecting".to_string(),
+                        ));
+                        stream = None;
+                    }
+                    last_callbacks = callbacks;
+                }
+            }
+
+            if stream.is_none() {
+                self.state.set(StreamState::Reconnecting);
+                match self.reconnect() {
+                    Ok(s) => {
+                        stream = Some(s);
+                        last_callbacks = self.callbacks.load(Ordering::Relaxed);
+                        self.state.set(StreamState::Playing);
+                    }
+                    Err(e) => log::warn!("{e}, retrying"),
+                }
+            }
+        }
+
+        drop(stream);
+        self.state.set(StreamState::Stopped);
+    }
+}
+
+/// Finds the device with `id`, or by name for convenience, falling back to
+/// the system default.
+fn find_device(host: &cpal::Host, id: Option<&str>) -> Option<Device> {
+    if let Some(id) = id {
+        let device = CpalBackend::enumerate(host)
+            .into_iter()
+            .find(|(device_id, name, _)| device_id == id || name == id);
+        match device {
+            Some((_, _, d)) => return Some(d),
+            None => log::warn!("Output device '{id}' not found, using default"),
+        }
+    }
+    host.default_output_device()
+}
+
+fn negotiate_config(device: &Device) -> Result<StreamConfig, Error> {
+    let default_config = device
+        .default_output_config()
+        .map_err(|e| Error::AudioOutput(format!("Failed to query default output config: {e}")))?;
+
+    // The render callback produces f32 samples, so prefer an f32 config at
+    // the device's default rate.
+    let supported = if default_config.sample_format() == SampleFormat::F32 {
+        default_config
+    } else {
+        let sample_rate = default_config.sample_rate();
+        let f32_config = device
+            .supported_output_configs()
+            .ok()
+            .and_then(|mut configs| {
+                configs.find(|c| {
+                    c.sample_format() == SampleFormat::F32
+                        && c.min_sample_rate() <= sample_rate
+                        && sample_rate <= c.max_sample_rate()
+                })
+            });
+        match f32_config {
+            Some(c) => c.with_sample_rate(sample_rate),
+            None => {
+                return Err(Error::AudioOutput(
+                    "Audio output device does not support f32 samples".to_string(),
+                ));
+            }
+        }
+    };
+
+    let buffer_size = match supported.buffer_size() {
+        SupportedBufferSize::Range { min, max } => {
+            cpal::BufferSize::Fixed(PREFERRED_BUFFER_FRAMES.clamp(*min, *max))
+        }
+        SupportedBufferSize::Unknown => cpal::BufferSize::Default,
+    };
+
+    Ok(StreamConfig {
+        channels: supported.channels(),
+        sample_rate: supported.sample_rate(),
+        buffer_size,
+    })
+}
 
 pub struct CpalBackend {
-    stream: Option<Stream>,
+    supervisor: Option<Supervisor>,
+    device: Option<(Device, StreamConfig)>,
+    device_id: Option<String>,
+    state: SharedStreamState,
+    events: Option<Arc<EventQueue>>,
 }
 
 impl CpalBackend {
     pub fn new() -> Self {
-        Self { stream: None }
+        Self {
+            supervisor: None,
+            device: None,
+            device_id: None,
+            state: SharedStreamState::new(),
+            events: None,
+        }
+    }
+
+    /// Returns the output devices of `host` with their ids and names.
+    fn enumerate(host: &cpal::Host) -> Vec<(String, String, Device)> {
+        let Ok(devices) = host.output_devices() else {
+            return Vec::new();
+        };
+        let (names, devices): (Vec<_>, Vec<_>) =
+            devices.filter_map(|d| Some((d.name().ok()?, d))).unzip();
+        let ids = device_ids(host.id().name(), &names);
+        ids.into_iter()
+            .zip(names)
+            .zip(devices)
+            .map(|((id, name), device)| (id, name, device))
+            .collect()
     }
 }
 
@@ -17,52 +276,158 @@ unsafe impl Send for CpalBackend {}
 unsafe impl Sync for CpalBackend {}
 
 impl AudioBackend for CpalBackend {
-    fn start(&mut self, mut f: Box<dyn FnMut(&mut [f32]) + Send + 'static>) {
-        if self.stream.is_some() {
-            return; // already running
+    fn open(&mut self) -> Result<StreamFormat, Error> {
+        if self.supervisor.is_none() {
+            let host = cpal::default_host();
+
+            let device = find_device(&host, self.device_id.as_deref())
+                .ok_or_else(|| Error::AudioOutput("No audio output device found".to_string()))?;
+            let config = negotiate_config(&device)?;
+            self.device = Some((device, config));
         }
 
-        let host = cpal::default_host();
+        self.device
+            .as_ref()
+            .map(|(_, config)| StreamFormat {
+                sample_rate: config.sample_rate.0,
+                channels: config.channels,
+            })
+            .ok_or_else(|| Error::AudioOutput("Audio output has not been opened".to_string()))
+    }
 
-        let device = match host.default_output_device() {
-            Some(d) => d,
-            None => {
-                log::error!("No audio output device found");
-                return;
-            }
+    fn start(&mut self, f: RenderCallback) -> Result<(), Error> {
+        if self.supervisor.is_some() {
+            return Ok(()); // already running
+        }
+
+        let Some((device, config)) = self.device.clone() else {
+            return Err(Error::AudioOutput(
+                "Audio output has not been opened".to_string(),
+            ));
         };
-        let config = StreamConfig {
-            channels: 2,
-            sample_rate: cpal::SampleRate(44100),
-            buffer_size: cpal::BufferSize::Fixed(2048),
+
+        let (events_tx, events_rx) = mpsc::channel();
+        let context = StreamContext {
+            device_id: self.device_id.clone(),
+            callback: Arc::new(Mutex::new(f)),
+            callbacks: Arc::new(AtomicU64::new(0)),
+            events: events_tx.clone(),
+            state: self.state.clone(),
+            playback_events: self.events.clone(),
         };
 
-        let stream = match device.build_output_stream(
-            &config,
-            move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
-                f(data);
-            },
-            move |err| {
-                log::error!("audio error: {err}");
-            },
-            None,
-        ) {
-            Ok(s) => s,
+        // Streams are not Send on every host, so the supervisor builds the
+        // first one too and reports whether that worked.
+        let (ready_tx, ready_rx) = mpsc::channel();
+        let thread = thread::spawn(move || match context.build(&device, &config) {
+            Ok(stream) => {
+                context.state.set(StreamState::Playing);
+                let _ = ready_tx.send(Ok(()));
+                context.run(stream, events_rx);
+            }
             Err(e) => {
-                log::error!("Failed to build audio stream: {}", e);
-                return;
+                let _ = ready_tx.send(Err(e));
             }
-        };
+        });
 
-        if let Err(e) = stream.play() {
-            log::error!("Failed to play audio stream: {}", e);
-            return;
+        let result = ready_rx
+            .recv()
+            .unwrap_or_else(|_| Err(Error::AudioOutput("Audio stream thread exited".to_string())));
+        match result {
+            Ok(()) => {
+                self.supervisor = Some(Supervisor {
+                    events: events_tx,
+                    thread,
+                });
+                Ok(())
+            }
+            Err(e) => {
+                let _ = thread.join();
+                Err(e)
+            }
         }
-
-        self.stream = Some(stream);
     }
 
     fn stop(&mut self) {
-        self.stream.take(); // drops the stream
+        if let Some(supervisor) = self.supervisor.take() {
+            let _ = supervisor.events.send(Event::Stop);
+            let _ = supervisor.thread.join();
+        }
+        self.device = None;
+    }
+
+    fn stream_state(&self) -> StreamState {
+        self.state.get()
+    }
+
+    fn set_event_queue(&mut self, events: Arc<EventQueue>) {
+        self.events = Some(events);
+    }
+
+    fn set_output_device(&mut self, id: Option<String>) {
+        self.device_id = id;
+    }
+
+    fn output_devices(&self) -> Vec<OutputDevice> {
+        let host = cpal::default_host();
+        let default_name = host.default_output_device().and_then(|d| d.name().ok());
+        let mut found_default = false;
+
+        Self::enumerate(&host)
+            .into_iter()
+            .map(|(id, name, device)| {
+                let configs: Vec<_> = device
+                    .supported_output_configs()
+                    .map(Iterator::collect)
+                    .unwrap_or_default();
+
+                let mut sample_rates: Vec<u32> = configs
+                    .iter()
+                    .flat_map(|c| {
+                        let (min, max) = (c.min_sample_rate().0, c.max_sample_rate().0);
+                        COMMON_SAMPLE_RATES
+                            .into_iter()
+                            .filter(move |rate| (min..=max).contains(rate))
+                            .chain([min, max])
+                    })
+                    .collect();
+                sample_rates.sort_unstable();
+                sample_rates.dedup();
+
+                let mut channels: Vec<u16> = configs.iter().map(|c| c.channels()).collect();
+                channels.sort_unstable();
+                channels.dedup();
+
+                // Only the first of several devices with the default's name
+                let is_default = !found_default && default_name.as_ref() == Some(&name);
+                found_default |= is_default;
+                OutputDevice {
+                    id,
+                    name,
+                    is_default,
+                    sample_rates,
+                    channels,
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_device_ids() {
+        let names = ["Speakers", "USB Headset", "USB Headset", "Speakers"].map(String::from);
+        assert_eq!(
+            device_ids("CoreAudio", &names),
+            [
+                "CoreAudio:Speakers",
+                "CoreAudio:USB Headset",
+                "CoreAudio:USB Headset#2",
+                "CoreAudio:Speakers#2",
+            ]
+        );
     }
 }
@@ -0,0 +1,277 @@
+use crate::Error;
+use crate::audio::{AudioBackend, RenderCallback, SharedStreamState, StreamFormat, StreamState};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+const DEFAULT_BLOCK_FRAMES: usize = 512;
+
+struct Shared {
+    samples: Mutex<Vec<f32>>,
+    frames_rendered: AtomicU64,
+    finished: AtomicBool,
+    /// Format of a simulated reconnect, applied by the render thread.
+    reconnect: Mutex<Option<StreamFormat>>,
+    state: SharedStreamState,
+}
+
+/// Backend without an audio device. A thread pulls blocks from the render
+/// callback on a virtual clock and keeps the rendered samples in memory.
+pub struct NullBackend {
+    format: StreamFormat,
+    block_frames: usize,
+    realtime: bool,
+    capture: bool,
+    max_frames: Option<u64>,
+    shared: Arc<Shared>,
+    running: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+/// Gives access to the output of a `NullBackend` after it has been handed to
+/// an `AudioPlayer`.
+#[derive(Clone)]
+pub struct NullBackendHandle {
+    shared: Arc<Shared>,
+    sample_rate: u32,
+}
+
+impl NullBackend {
+    /// Creates a backend that renders as fast as possible and captures every
+    /// sample.
+    pub fn new(format: StreamFormat) -> Self {
+        Self {
+            format,
+            block_frames: DEFAULT_BLOCK_FRAMES,
+            realtime: false,
+            capture: true,
+            max_frames: None,
+            shared: Arc::new(Shared {
+                samples: Mutex::new(Vec::new()),
+                frames_rendered: AtomicU64::new(0),
+                finished: AtomicBool::new(false),
+                reconnect: Mutex::new(None),
+                state: SharedStreamState::new(),
+            }),
+            running: Arc::new(AtomicBool::new(false)),
+            thread: None,
+        }
+    }
+
+    /// Paces the render thread so that the virtual clock follows wall time.
+    pub fn with_realtime(mut self, realtime: bool) -> Self {
+        self.realtime = realtime;
+        self
+    }
+
+    /// Enables or disables keeping the rendered samples in memory.
+    pub fn with_capture(mut self, capture: bool) -> Self {
+        self.capture = capture;
+        self
+    }
+
+    /// Stops rendering after `frames` frames.
+    pub fn with_max_frames(mut self, frames: u64) -> Self {
+        self.max_frames = Some(frames);
+        self
+    }
+
+    pub fn with_block_frames(mut self, frames: usize) -> Self {
+        self.block_frames = frames.max(1);
+        self
+    }
+
+    pub fn handle(&self) -> NullBackendHandle {
+        NullBackendHandle {
+            shared: self.shared.clone(),
+            sample_rate: self.format.sample_rate,
+        }
+    }
+}
+
+impl NullBackendHandle {
+    pub fn frames_rendered(&self) -> u64 {
+        self.shared.frames_rendered.load(Ordering::Acquire)
+    }
+
+    /// Time elapsed on the virtual clock of the render thread.
+    pub fn elapsed(&self) -> Duration {
+        Duration::from_secs_f64(self.frames_rendered() as f64 / self.sample_rate as f64)
+    }
+
+    /// Returns a copy of the captured interleaved samples.
+    pub fn samples(&self) -> Vec<f32> {
+        self.shared.samples.lock().unwrap().clone()
+    }
+
+    /// Removes and returns the captured interleaved samples.
+    pub fn take_samples(&self) -> Vec<f32> {
+        std::mem::take(&mut *self.shared.samples.lock().unwrap())
+    }
+
+    /// Simulates losing the device and rebuilding the stream with `format`.
+    pub fn reconnect(&self, format: StreamFormat) {
+        *self.shared.reconnect.lock().unwrap() = Some(format);
+    }
+
+    /// Blocks until at least `frames` frames have been rendered or the render
+    /// thread has finished. Returns whether the frames were reached in time.
+    pub fn wait_for_frames(&self, frames: u64, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if self.frames_rendered() >= frames {
+                return true;
+            }
+            if self.shared.finished.load(Ordering::Acquire) || Instant::now() >= deadline {
+                return false;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+}
+
+impl AudioBackend for NullBackend {
+    fn open(&mut self) -> Result<StreamFormat, Error> {
+        Ok(self.format)
+    }
+
+    fn start(&mut self, mut f: RenderCallback) -> Result<(), Error> {
+        if self.thread.is_some() {
+            return Ok(()); // already running
+        }
+
+        let mut format = self.format;
+        let block_frames = self.block_frames;
+        let realtime = self.realtime;
+        let capture = self.capture;
+        let max_frames = self.max_frames;
+        let shared = self.shared.clone();
+        let running = self.running.clone();
+
+        shared.finished.store(false, Ordering::Release);
+        shared.state.set(StreamState::Playing);
+        running.store(true, Ordering::Release);
+        self.thread = Some(thread::spawn(move || {
+            let mut block = Vec::new();
+            let mut rendered: u64 = 0;
+            // Frames rendered in the current format, for pacing
+            let mut paced: u64 = 0;
+            let mut started = Instant::now();
+
+            while running.load(Ordering::Acquire) {
+                if let Some(new_format) = shared.reconnect.lock().unwrap().take() {
+                    format = new_format;
+                    paced = 0;
+                    started = Instant::now();
+                }
+                let channels = format.channels.max(1) as usize;
+                block.resize(block_frames * channels, 0.0);
+                let frames = match max_frames {
+                    Some(max) if rendered >= max => break,
+                    Some(max) => (max - rendered).min(block_frames as u64) as usize,
+                    None => block_frames,
+                };
+                let data = &mut block[..frames * channels];
+                let running = f(data, format);
+                if capture {
+                    shared.samples.lock().unwrap().extend_from_slice(data);
+                }
+                rendered += frames as u64;
+                paced += frames as u64;
+                shared
+                    .frames_rendered
+                    .fetch_add(frames as u64, Ordering::Release);
+                if !running {
+                    break;
+                }
+
+                if realtime {
+                    let virtual_time =
+                        Duration::from_secs_f64(paced as f64 / format.sample_rate as f64);
+                    if let Some(ahead) = virtual_time.checked_sub(started.elapsed()) {
+                        thread::sleep(ahead);
+                    }
+                }
+            }
+            shared.finished.store(true, Ordering::Release);
+            shared.state.set(StreamState::Stopped);
+        }));
+        Ok(())
+    }
+
+    fn stop(&mut self) {
+        self.running.store(false, Ordering::Release);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+
+    fn stream_state(&self) -> StreamState {
+        self.shared.state.get()
+    }
+}
+
+impl Drop for NullBackend {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FORMAT: StreamFormat = StreamFormat {
+        sample_rate: 48000,
+        channels: 2,
+    };
+
+    #[test]
+    fn test_captures_rendered_blocks() {
+        let mut backend = NullBackend::new(FORMAT)
+            .with_block_frames(100)
+            .with_max_frames(1050);
+        let handle = backend.handle();
+
+        let mut counter = 0.0;
+        backend
+            .start(Box::new(move |data, _| {
+                for sample in data {
+                    *sample = counter;
+                    counter += 1.0;
+                }
+                true
+            }))
+            .unwrap();
+        assert!(!handle.wait_for_frames(1051, Duration::from_secs(5)));
+        backend.stop();
+
+        let samples = handle.samples();
+        assert_eq!(handle.frames_rendered(), 1050);
+        assert_eq!(samples.len(), 2100);
+        assert!(samples.iter().enumerate().all(|(i, &s)| s == i as f32));
+        assert_eq!(handle.elapsed(), Duration::from_micros(21875));
+    }
+
+    #[test]
+    fn test_realtime_pacing() {
+        let mut backend = NullBackend::new(FORMAT)
+            .with_realtime(true)
+            .with_capture(false);
+        let handle = backend.handle();
+
+        let started = Instant::now();
+        backend
+            .start(Box::new(|data, _| {
+                data.fill(0.0);
+                true
+            }))
+            .unwrap();
+        assert!(handle.wait_for_frames(4800, Duration::from_secs(5)));
+        backend.stop();
+
+        assert!(started.elapsed() >= Duration::from_millis(90));
+        assert!(handle.samples().is_empty());
+    }
+}
@@ -0,0 +1,759 @@
+use crate::MAX_CALIBRATED_LEVEL_DB;
+use crate::json::{self, JsonValue};
+use crate::taus88::{SeedableRng, Taus88};
+use crate::test_tones::TonePattern;
+use crate::{Channel, Error, GainUnit};
+use rand::Rng;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::Path;
+
+/// Frequencies tested on each ear, in the order of ISO 8253-1: 1 kHz first,
+/// then upwards, then the low frequencies.
+pub const AUDIOMETRY_FREQUENCIES_HZ: [f32; 8] =
+    [1000.0, 2000.0, 3000.0, 4000.0, 6000.0, 8000.0, 500.0, 250.0];
+/// Softest level presented.
+pub const MIN_AUDIOMETRY_LEVEL_DB_HL: f32 = -10.0;
+
+/// Step up while no tone has been heard yet.
+const FAMILIARIZATION_STEP_DB: f32 = 20.0;
+/// Step down after a response.
+const DOWN_STEP_DB: f32 = 10.0;
+/// Step up after a missed tone.
+const UP_STEP_DB: f32 = 5.0;
+/// Ascending responses needed at the threshold level.
+const THRESHOLD_RESPONSES: u32 = 2;
+/// Presentations per frequency after which the procedure moves on, so that
+/// inconsistent answers cannot keep it running.
+const MAX_PRESENTATIONS: u32 = 40;
+
+/// Pulses of a presentation, which are easier to tell apart from tinnitus
+/// than a steady tone.
+const PULSES: usize = 3;
+const PULSE_MS: f32 = 225.0;
+const PULSE_FADE_MS: f32 = 20.0;
+/// Range of the random silence before a presentation, so that the patient
+/// cannot anticipate it.
+const MIN_DELAY_MS: f32 = 1000.0;
+const MAX_DELAY_MS: f32 = 2500.0;
+
+/// Header line of an audiogram CSV file. The `no_response` column may be
+/// left out in imported files.
+const CSV_HEADER: &str = "ear,frequency_hz,threshold_db_hl,no_response";
+
+/// Settings of a threshold test.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct AudiometrySettings {
+    /// Ear to test, `None` for the left and then the right ear.
+    pub channel: Option<Channel>,
+    /// Level of the first presentation at each frequency.
+    pub start_level_db_hl: f32,
+    /// Loudest level presented. It is lowered further where the device
+    /// cannot play it under the output ceiling.
+    pub max_level_db_hl: f32,
+}
+
+impl AudiometrySettings {
+    pub fn validate(&self) -> Result<(), Error> {
+        if !(MIN_AUDIOMETRY_LEVEL_DB_HL..=MAX_CALIBRATED_LEVEL_DB).contains(&self.max_level_db_hl) {
+            return Err(Error::InvalidArgument("audiometry maximum level"));
+        }
+        if !(MIN_AUDIOMETRY_LEVEL_DB_HL..=self.max_level_db_hl).contains(&self.start_level_db_hl) {
+            return Err(Error::InvalidArgument("audiometry start level"));
+        }
+        Ok(())
+    }
+}
+
+impl Default for AudiometrySettings {
+    fn default() -> Self {
+        Self {
+            channel: None,
+            start_level_db_hl: 30.0,
+            max_level_db_hl: 80.0,
+        }
+    }
+}
+
+/// Hearing threshold at one frequency.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Threshold {
+    pub frequency_hz: f32,
+    pub level_db_hl: f32,
+    /// The tone was not heard up to `level_db_hl`, the loudest level
+    /// presented.
+    pub no_response: bool,
+}
+
+/// Hearing thresholds of both ears, sorted by frequency.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Audiogram {
+    pub left: Vec<Threshold>,
+    pub right: Vec<Threshold>,
+}
+
+impl Audiogram {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn ear(&self, channel: Channel) -> &[Threshold] {
+        match channel {
+            Channel::Left => &self.left,
+            Channel::Right => &self.right,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.left.is_empty() && self.right.is_empty()
+    }
+
+    /// Adds a threshold, replacing one of the same ear and frequency.
+    pub fn set_threshold(&mut self, channel: Channel, threshold: Threshold) -> Result<(), Error> {
+        if !(threshold.frequency_hz.is_finite() && threshold.frequency_hz > 0.0) {
+            return Err(Error::InvalidArgument("threshold frequency"));
+        }
+        if !threshold.level_db_hl.is_finite() {
+            return Err(Error::InvalidArgument("threshold level"));
+        }
+        let thresholds = match channel {
+            Channel::Left => &mut self.left,
+            Channel::Right => &mut self.right,
+        };
+        thresholds.retain(|t| t.frequency_hz != threshold.frequency_hz);
+        let index = thresholds.partition_point(|t| t.frequency_hz < threshold.frequency_hz);
+        thresholds.insert(index, threshold);
+        Ok(())
+    }
+
+    /// Serializes the audiogram as CSV with one threshold per row.
+    pub fn to_csv(&self) -> String {
+        let mut csv = format!("{CSV_HEADER}\n");
+        for (ear, thresholds) in [("left", &self.left), ("right", &self.right)] {
+            for t in thresholds {
+                let _ = writeln!(
+                    csv,
+                    "{ear},{},{},{}",
+                    t.frequency_hz, t.level_db_hl, t.no_response
+                );
+            }
+        }
+        csv
+    }
+
+    pub fn from_csv(csv: &str) -> Result<Self, Error> {
+        let mut lines = csv.lines().map(str::trim);
+        let header = lines.next().unwrap_or_default();
+        let columns = match header {
+            CSV_HEADER => 4,
+            _ if CSV_HEADER.strip_suffix(",no_response") == Some(header) => 3,
+            _ => return Err(Error::Parse("not an audiogram file".to_string())),
+        };
+        let mut audiogram = Audiogram::new();
+        for (number, line) in lines.enumerate() {
+            let invalid = || Error::Parse(format!("invalid audiogram line {}", number + 2));
+            if line.is_empty() {
+                continue;
+            }
+            let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+            if fields.len() != columns {
+                return Err(invalid());
+            }
+            let [ear, frequency_hz, level_db_hl, ..] = fields[..] else {
+                return Err(invalid());
+            };
+            let no_response = fields.get(3).copied().unwrap_or("false");
+            let channel = match ear {
+                "left" => Channel::Left,
+                "right" => Channel::Right,
+                _ => return Err(invalid()),
+            };
+            let threshold = Threshold {
+                frequency_hz: frequency_hz.parse().map_err(|_| invalid())?,
+                level_db_hl: level_db_hl.parse().map_err(|_| invalid())?,
+                no_response: no_response.parse().map_err(|_| invalid())?,
+            };
+            audiogram
+                .set_threshold(channel, threshold)
+                .map_err(|_| invalid())?;
+        }
+        Ok(audiogram)
+    }
+
+    /// Serializes the audiogram as a JSON object with an array of
+    /// thresholds per ear.
+    pub fn to_json(&self) -> String {
+        let ear = |thresholds: &[Threshold]| {
+            thresholds
+                .iter()
+                .map(|t| {
+                    format!(
+                        "{{\"frequency_hz\": {}, \"threshold_db_hl\": {}, \"no_response\": {}}}",
+                        t.frequency_hz, t.level_db_hl, t.no_response
+                    )
+                })
+                .collect::<Vec<_>>()
+                .join(",\n    ")
+        };
+        format!(
+            "{{\n  \"left\": [\n    {}\n  ],\n  \"right\": [\n    {}\n  ]\n}}\n",
+            ear(&self.left),
+            ear(&self.right)
+        )
+    }
+
+    /// Reads the format of `to_json`. Missing ears are empty and a missing
+    /// `no_response` is false.
+    pub fn from_json(text: &str) -> Result<Self, Error> {
+        let root = json::parse(text)?;
+        if !matches!(root, JsonValue::Object(_)) {
+            return Err(Error::Parse("not an audiogram file".to_string()));
+        }
+        let mut audiogram = Audiogram::new();
+        for (key, channel) in [("left", Channel::Left), ("right", Channel::Right)] {
+            let Some(thresholds) = root.get(key) else {
+                continue;
+            };
+            let invalid = || Error::Parse(format!("invalid audiogram thresholds of {key}"));
+            for threshold in thresholds.as_array().ok_or_else(invalid)? {
+                let number = |name| threshold.get(name).and_then(JsonValue::as_f32);
+                let threshold = Threshold {
+                    frequency_hz: number("frequency_hz").ok_or_else(invalid)?,
+                    level_db_hl: number("threshold_db_hl").ok_or_else(invalid)?,
+                    no_response: match threshold.get("no_response") {
+                        Some(no_response) => no_response.as_bool().ok_or_else(invalid)?,
+                        None => false,
+                    },
+                };
+                audiogram
+                    .set_threshold(channel, threshold)
+                    .map_err(|_| invalid())?;
+            }
+        }
+        Ok(audiogram)
+    }
+
+    /// Reads an audiogram in either the CSV or the JSON format.
+    pub fn parse(text: &str) -> Result<Self, Error> {
+        if text.trim_start().starts_with('{') {
+            Self::from_json(text)
+        } else {
+            Self::from_csv(text)
+        }
+    }
+
+    /// Writes the audiogram as CSV, or as JSON if `path` ends in `.json`.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), Error> {
+        let path = path.as_ref();
+        let text = if path
+            .extension()
+            .is_some_and(|e| e.eq_ignore_ascii_case("json"))
+        {
+            self.to_json()
+        } else {
+            self.to_csv()
+        };
+        fs::write(path, text).map_err(|e| Error::Io(e.to_string()))
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let text = fs::read_to_string(path).map_err(|e| Error::Io(e.to_string()))?;
+        Self::parse(&text)
+    }
+}
+
+/// A pulsed tone the patient is asked about.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct AudiometryPresentation {
+    pub channel: Channel,
+    pub frequency_hz: f32,
+    pub level_db_hl: f32,
+    /// Silence before the first pulse.
+    pub delay_ms: f32,
+}
+
+/// Responses at one level, counted on ascending presentations only.
+#[derive(Copy, Clone, Debug)]
+struct AscendingCount {
+    level_db_hl: f32,
+    presentations: u32,
+    responses: u32,
+}
+
+impl AscendingCount {
+    fn heard_half(&self) -> bool {
+        self.responses * 2 >= self.presentations
+    }
+}
+
+/// Pure-tone threshold test with the modified Hughson-Westlake procedure of
+/// ISO 8253-1. The level rises by 20 dB until the first response, then falls
+/// by 10 dB after each response and rises by 5 dB after each miss. The
+/// threshold is the lowest level heard on at least half of the ascending
+/// presentations, with at least two responses.
+pub struct Audiometer {
+    settings: AudiometrySettings,
+    rng: Taus88,
+    // Index into `ears()` and `AUDIOMETRY_FREQUENCIES_HZ`, `None` once
+    // finished
+    position: Option<(usize, usize)>,
+    level_db_hl: f32,
+    // Loudest level the device can play at the current frequency
+    output_limit_db_hl: f32,
+    // Whether the last tone was heard, `None` before the first response
+    last_heard: Option<bool>,
+    ascending: Vec<AscendingCount>,
+    presentations: u32,
+    delay_ms: f32,
+    audiogram: Audiogram,
+}
+
+impl Audiometer {
+    /// Creates a test whose delays before the presentations are drawn from
+    /// `seed`.
+    pub fn new(settings: AudiometrySettings, seed: u64) -> Result<Self, Error> {
+        settings.validate()?;
+        let mut audiometer = Self {
+            settings,
+            rng: Taus88::seed_from_u64(seed),
+            position: Some((0, 0)),
+            level_db_hl: settings.start_level_db_hl,
+            output_limit_db_hl: MAX_CALIBRATED_LEVEL_DB,
+            last_heard: None,
+            ascending: Vec::new(),
+            presentations: 0,
+            delay_ms: 0.0,
+            audiogram: Audiogram::new(),
+        };
+        audiometer.draw_delay();
+        Ok(audiometer)
+    }
+
+    pub fn settings(&self) -> &AudiometrySettings {
+        &self.settings
+    }
+
+    fn ears(&self) -> &'static [Channel] {
+        match self.settings.channel {
+            None => &Channel::ALL,
+            Some(Channel::Left) => &[Channel::Left],
+            Some(Channel::Right) => &[Channel::Right],
+        }
+    }
+
+    fn max_level_db_hl(&self) -> f32 {
+        self.settings
+            .max_level_db_hl
+            .min(self.output_limit_db_hl)
+            .max(MIN_AUDIOMETRY_LEVEL_DB_HL)
+    }
+
+    /// Ear and frequency being tested, `None` once finished.
+    pub fn current_test(&self) -> Option<(Channel, f32)> {
+        let (ear, frequency) = self.position?;
+        Some((self.ears()[ear], AUDIOMETRY_FREQUENCIES_HZ[frequency]))
+    }
+
+    /// Caps the levels at the current frequency, e.g. at the loudest level
+    /// the device can play under the output ceiling.
+    pub fn set_output_limit(&mut self, max_level_db_hl: f32) {
+        self.output_limit_db_hl = max_level_db_hl;
+        self.level_db_hl = self.level_db_hl.min(self.max_level_db_hl());
+    }
+
+    /// The tone to present next, `None` once finished.
+    pub fn presentation(&self) -> Option<AudiometryPresentation> {
+        let (channel, frequency_hz) = self.current_test()?;
+        Some(AudiometryPresentation {
+            channel,
+            frequency_hz,
+            level_db_hl: self.level_db_hl,
+            delay_ms: self.delay_ms,
+        })
+    }
+
+    /// The pulses of `presentation` as played to the patient.
+    pub(crate) fn pattern(presentation: &AudiometryPresentation) -> TonePattern {
+        TonePattern {
+            frequencies: [presentation.frequency_hz; crate::test_tones::MAX_PATTERN_TONES],
+            tones: PULSES,
+            tone_ms: PULSE_MS,
+            gap_ms: PULSE_MS,
+            delay_ms: presentation.delay_ms,
+            fade_ms: PULSE_FADE_MS,
+            channel: Some(presentation.channel),
+            level_db: presentation.level_db_hl,
+            unit: GainUnit::DbHl,
+        }
+    }
+
+    fn draw_delay(&mut self) {
+        self.delay_ms = self.rng.random_range(MIN_DELAY_MS..=MAX_DELAY_MS);
+    }
+
+    /// Takes whether the patient heard the current presentation and moves
+    /// to the next one.
+    pub fn respond(&mut self, heard: bool) -> Result<(), Error> {
+        if self.position.is_none() {
+            return Err(Error::InvalidArgument("finished audiometry"));
+        }
+        self.presentations += 1;
+        let level = self.level_db_hl;
+        let max_level = self.max_level_db_hl();
+        // The softest level cannot be approached from below, so every
+        // presentation there counts as ascending
+        let ascending = self.last_heard == Some(false)
+            || (self.last_heard.is_some() && level <= MIN_AUDIOMETRY_LEVEL_DB_HL);
+        if ascending {
+            let index = match self
+                .ascending
+                .iter()
+                .position(|count| count.level_db_hl == level)
+            {
+                Some(index) => index,
+                None => {
+                    self.ascending.push(AscendingCount {
+                        level_db_hl: level,
+                        presentations: 0,
+                        responses: 0,
+                    });
+                    self.ascending.len() - 1
+                }
+            };
+            let count = &mut self.ascending[index];
+            count.presentations += 1;
+            count.responses += u32::from(heard);
+            if count.responses >= THRESHOLD_RESPONSES && count.heard_half() {
+                self.finish_frequency(level, false);
+                return Ok(());
+            }
+        }
+
+        if heard {
+            self.level_db_hl = (level - DOWN_STEP_DB).max(MIN_AUDIOMETRY_LEVEL_DB_HL);
+        } else if level >= max_level {
+            self.finish_frequency(max_level, true);
+            return Ok(());
+        } else {
+            let step = match self.last_heard {
+                None => FAMILIARIZATION_STEP_DB,
+                Some(_) => UP_STEP_DB,
+            };
+            self.level_db_hl = (level + step).min(max_level);
+        }
+        if heard || self.last_heard.is_some() {
+            self.last_heard = Some(heard);
+        }
+
+        if self.presentations >= MAX_PRESENTATIONS {
+            let heard = self
+                .ascending
+                .iter()
+                .filter(|count| count.responses > 0 && count.heard_half())
+                .map(|count| count.level_db_hl)
+                .reduce(f32::min);
+            match heard {
+                Some(level) => self.finish_frequency(level, false),
+                None => self.finish_frequency(max_level, true),
+            }
+            return Ok(());
+        }
+        self.draw_delay();
+        Ok(())
+    }
+
+    fn finish_frequency(&mut self, level_db_hl: f32, no_response: bool) {
+        let Some((channel, frequency_hz)) = self.current_test() else {
+            return;
+        };
+        let threshold = Threshold {
+            frequency_hz,
+            level_db_hl,
+            no_response,
+        };
+        // Levels and frequencies are always finite
+        let _ = self.audiogram.set_threshold(channel, threshold);
+
+        self.position = match self.position {
+            Some((ear, frequency)) if frequency + 1 < AUDIOMETRY_FREQUENCIES_HZ.len() => {
+                Some((ear, frequency + 1))
+            }
+            Some((ear, _)) if ear + 1 < self.ears().len() => Some((ear + 1, 0)),
+            _ => None,
+        };
+        self.level_db_hl = self.settings.start_level_db_hl;
+        self.output_limit_db_hl = MAX_CALIBRATED_LEVEL_DB;
+        self.last_heard = None;
+        self.ascending.clear();
+        self.presentations = 0;
+        self.draw_delay();
+    }
+
+    /// Ends the test early, keeping the thresholds found so far.
+    pub fn stop(&mut self) {
+        self.position = None;
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.position.is_none()
+    }
+
+    /// Thresholds found so far.
+    pub fn audiogram(&self) -> &Audiogram {
+        &self.audiogram
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::STEREO_48K;
+    use crate::{AudioPlayer, NullBackend, PitchMatchSettings};
+
+    /// Runs a test with a patient who hears every tone at or above
+    /// `threshold(ear, frequency)`, and returns the levels presented.
+    fn run(
+        audiometer: &mut Audiometer,
+        threshold: impl Fn(Channel, f32) -> f32,
+    ) -> Vec<(Channel, f32, f32)> {
+        let mut presented = Vec::new();
+        while let Some(p) = audiometer.presentation() {
+            assert!((MIN_DELAY_MS..=MAX_DELAY_MS).contains(&p.delay_ms));
+            presented.push((p.channel, p.frequency_hz, p.level_db_hl));
+            let heard = p.level_db_hl >= threshold(p.channel, p.frequency_hz);
+            audiometer.respond(heard).unwrap();
+            assert!(presented.len() < 1000, "did not finish");
+        }
+        presented
+    }
+
+    #[test]
+    fn test_hughson_westlake() {
+        let mut audiometer = Audiometer::new(AudiometrySettings::default(), 1).unwrap();
+        let threshold = |ear, hz| match (ear, hz) {
+            (Channel::Left, 4000.0) => 55.0,
+            (Channel::Right, 250.0) => 100.0,
+            (_, 8000.0) => -20.0,
+            _ => 12.0,
+        };
+        let presented = run(&mut audiometer, threshold);
+
+        let left_1k: Vec<f32> = presented
+            .iter()
+            .filter(|(ear, hz, _)| *ear == Channel::Left && *hz == 1000.0)
+            .map(|&(_, _, level)| level)
+            .collect();
+        assert_eq!(left_1k, [30.0, 20.0, 10.0, 15.0, 5.0, 10.0, 15.0]);
+        let left_4k: Vec<f32> = presented
+            .iter()
+            .filter(|(ear, hz, _)| *ear == Channel::Left && *hz == 4000.0)
+            .map(|&(_, _, level)| level)
+            .collect();
+        assert_eq!(left_4k[..3], [30.0, 50.0, 70.0]);
+
+        let audiogram = audiometer.audiogram();
+        assert_eq!(audiogram.left.len(), AUDIOMETRY_FREQUENCIES_HZ.len());
+        assert_eq!(audiogram.right.len(), AUDIOMETRY_FREQUENCIES_HZ.len());
+        for ear in Channel::ALL {
+            assert!(
+                audiogram
+                    .ear(ear)
+                    .windows(2)
+                    .all(|w| w[0].frequency_hz < w[1].frequency_hz)
+            );
+            for t in audiogram.ear(ear) {
+                let expected = match (ear, t.frequency_hz) {
+                    (Channel::Left, 4000.0) => (55.0, false),
+                    (Channel::Right, 250.0) => (80.0, true),
+                    (_, 8000.0) => (MIN_AUDIOMETRY_LEVEL_DB_HL, false),
+                    _ => (15.0, false),
+                };
+                assert_eq!((t.level_db_hl, t.no_response), expected, "{ear:?} {t:?}");
+            }
+        }
+        assert!(audiometer.is_finished());
+        assert_eq!(
+            audiometer.respond(true),
+            Err(Error::InvalidArgument("finished audiometry"))
+        );
+    }
+
+    #[test]
+    fn test_output_limit_and_inconsistent_answers() {
+        let settings = AudiometrySettings {
+            channel: Some(Channel::Right),
+            ..Default::default()
+        };
+        let mut audiometer = Audiometer::new(settings, 2).unwrap();
+        audiometer.set_output_limit(42.0);
+        let presentation = audiometer.presentation().unwrap();
+        assert_eq!(presentation.channel, Channel::Right);
+        assert_eq!(presentation.level_db_hl, 30.0);
+        audiometer.respond(false).unwrap();
+        assert_eq!(audiometer.presentation().unwrap().level_db_hl, 42.0);
+        audiometer.respond(false).unwrap();
+        assert_eq!(
+            audiometer.audiogram().right,
+            [Threshold {
+                frequency_hz: 1000.0,
+                level_db_hl: 42.0,
+                no_response: true
+            }]
+        );
+
+        // Answering at random still ends every frequency
+        let mut answers = Taus88::seed_from_u64(3);
+        while audiometer.presentation().is_some() {
+            audiometer.respond(answers.random()).unwrap();
+        }
+        assert!(audiometer.audiogram().left.is_empty());
+        assert_eq!(
+            audiometer.audiogram().right.len(),
+            AUDIOMETRY_FREQUENCIES_HZ.len()
+        );
+
+        assert!(
+            Audiometer::new(
+                AudiometrySettings {
+                    start_level_db_hl: 90.0,
+                    ..Default::default()
+                },
+                0
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn test_audiogram_csv() {
+        let mut audiogram = Audiogram::new();
+        for (channel, frequency_hz, level_db_hl) in [
+            (Channel::Left, 4000.0, 35.0),
+            (Channel::Left, 1000.0, 10.0),
+            (Channel::Right, 8000.0, 70.0),
+            (Channel::Left, 1000.0, 15.0),
+        ] {
+            let threshold = Threshold {
+                frequency_hz,
+                level_db_hl,
+                no_response: frequency_hz == 8000.0,
+            };
+            audiogram.set_threshold(channel, threshold).unwrap();
+        }
+        let csv = audiogram.to_csv();
+        assert_eq!(
+            csv,
+            "ear,frequency_hz,threshold_db_hl,no_response\n\
+             left,1000,15,false\n\
+             left,4000,35,false\n\
+             right,8000,70,true\n"
+        );
+        assert_eq!(Audiogram::from_csv(&csv).unwrap(), audiogram);
+        assert_eq!(
+            Audiogram::from_csv("ear,frequency_hz,threshold_db_hl,no_response\nleft,1000,x,false"),
+            Err(Error::Parse("invalid audiogram line 2".to_string()))
+        );
+        assert!(Audiogram::from_csv("left,1000,15,false").is_err());
+        // Imported files may leave out `no_response`
+        assert_eq!(
+            Audiogram::parse("ear,frequency_hz,threshold_db_hl\nright,500,25\n")
+                .unwrap()
+                .right,
+            [Threshold {
+                frequency_hz: 500.0,
+                level_db_hl: 25.0,
+                no_response: false
+            }]
+        );
+        assert!(Audiogram::parse("ear,frequency_hz,threshold_db_hl\nright,500,25,true").is_err());
+    }
+
+    #[test]
+    fn test_audiogram_json() {
+        let mut audiogram = Audiogram::new();
+        for (channel, frequency_hz, level_db_hl) in [
+            (Channel::Left, 1000.0, 15.0),
+            (Channel::Left, 8000.0, 62.5),
+            (Channel::Right, 4000.0, -5.0),
+        ] {
+            let threshold = Threshold {
+                frequency_hz,
+                level_db_hl,
+                no_response: false,
+            };
+            audiogram.set_threshold(channel, threshold).unwrap();
+        }
+        assert_eq!(Audiogram::parse(&audiogram.to_json()), Ok(audiogram));
+        assert_eq!(
+            Audiogram::parse(r#"{"left": []}"#),
+            Ok(Audiogram::default())
+        );
+
+        let imported = Audiogram::parse(
+            r#"{"right": [{"frequency_hz": 6000, "threshold_db_hl": 40, "no_response": true}]}"#,
+        )
+        .unwrap();
+        assert!(imported.right[0].no_response);
+        assert_eq!(
+            Audiogram::parse(r#"{"left": [{"frequency_hz": 1000}]}"#),
+            Err(Error::Parse(
+                "invalid audiogram thresholds of left".to_string()
+            ))
+        );
+        assert!(Audiogram::parse("[]").is_err());
+    }
+
+    #[test]
+    fn test_audiometry_procedure() {
+        let mut player = AudioPlayer::with_backend(Box::new(NullBackend::new(STEREO_48K)));
+        assert_eq!(
+            player.respond_audiometry(true),
+            Err(Error::InvalidArgument("response without audiometry"))
+        );
+        player
+            .play_reference_tone(Channel::Left, 1000.0, -20.0)
+            .unwrap();
+        player.add_calibration_measurement(75.0).unwrap();
+        player.set_output_ceiling_db(-30.0).unwrap();
+
+        player
+            .start_audiometry(AudiometrySettings {
+                channel: Some(Channel::Left),
+                ..Default::default()
+            })
+            .unwrap();
+        // 95 dB SPL at full scale and a ceiling of -30 dBFS leave 59.5 dB HL
+        // at 1 kHz
+        let mut levels = Vec::new();
+        while let Some(presentation) = player.audiometry_presentation() {
+            if presentation.frequency_hz != 1000.0 {
+                break;
+            }
+            levels.push(presentation.level_db_hl);
+            player.respond_audiometry(false).unwrap();
+        }
+        assert_eq!(levels, [30.0, 50.0, 59.5]);
+        assert_eq!(
+            player.audiogram().unwrap().left,
+            [Threshold {
+                frequency_hz: 1000.0,
+                level_db_hl: 59.5,
+                no_response: true,
+            }]
+        );
+
+        // Pitch matching takes over the test tones and ends the test
+        player
+            .start_pitch_match(PitchMatchSettings::default())
+            .unwrap();
+        assert_eq!(player.audiometry_presentation(), None);
+        assert!(player.respond_audiometry(true).is_err());
+        assert!(player.pitch_trial().is_some());
+
+        let path = std::env::temp_dir().join(format!("audiogram-{}.csv", std::process::id()));
+        player.save_audiogram(&path).unwrap();
+        let audiogram = Audiogram::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(Some(&audiogram), player.audiogram());
+    }
+}
@@ -0,0 +1,629 @@
+use crate::ear_curves::{EarCurves, interpolate};
+use crate::error::lock;
+use crate::{Channel, Error, FadeCurve, oscillator};
+use std::fmt::Write as _;
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+/// Loudest accepted gain in dB SPL or dB HL. The output ceiling still
+/// applies.
+pub const MAX_CALIBRATED_LEVEL_DB: f32 = 120.0;
+/// Loudest accepted reference tone.
+pub const MAX_REFERENCE_LEVEL_DBFS: f32 = 0.0;
+
+/// First line of a calibration file.
+const FILE_HEADER: &str = "sinewave-calibration 1";
+
+/// Fade of the reference tone when it starts, stops or changes.
+const REFERENCE_FADE_MS: f32 = 50.0;
+
+/// Reference equivalent threshold SPL (0 dB HL) of circumaural audiometric
+/// headphones, from ISO 389-8 up to 8 kHz and ISO 389-5 above (Sennheiser
+/// HDA 200). Consumer headphones differ, so dB HL values are approximate
+/// unless the device was calibrated on a coupler.
+const RETSPL: [(f32, f32); 17] = [
+    (125.0, 30.5),
+    (250.0, 18.0),
+    (500.0, 11.0),
+    (750.0, 6.0),
+    (1000.0, 5.5),
+    (1500.0, 5.5),
+    (2000.0, 4.5),
+    (3000.0, 2.5),
+    (4000.0, 9.5),
+    (6000.0, 17.0),
+    (8000.0, 17.5),
+    (9000.0, 18.5),
+    (10000.0, 22.0),
+    (11200.0, 23.0),
+    (12500.0, 28.0),
+    (14000.0, 36.0),
+    (16000.0, 56.0),
+];
+
+/// Unit of a channel gain.
+#[derive(PartialEq, Eq, Copy, Clone, Debug, Default)]
+pub enum GainUnit {
+    /// Relative to digital full scale.
+    #[default]
+    Dbfs = 0,
+    /// Sound pressure level at the ear, using the calibration table.
+    DbSpl = 1,
+    /// Hearing level, i.e. dB SPL above the normal hearing threshold at the
+    /// tone's frequency.
+    DbHl = 2,
+}
+
+impl GainUnit {
+    pub fn from_index(index: i32) -> Option<Self> {
+        match index {
+            0 => Some(GainUnit::Dbfs),
+            1 => Some(GainUnit::DbSpl),
+            2 => Some(GainUnit::DbHl),
+            _ => None,
+        }
+    }
+}
+
+/// Hearing threshold in dB SPL at `frequency_hz`, the 0 dB HL reference.
+pub fn reference_threshold_spl_db(frequency_hz: f32) -> f32 {
+    interpolate(&RETSPL, frequency_hz).unwrap_or_default()
+}
+
+/// Level that a full-scale sine reaches on one ear at one frequency.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct CalibrationPoint {
+    pub channel: Channel,
+    pub frequency_hz: f32,
+    pub full_scale_spl_db: f32,
+}
+
+/// Per-ear, per-frequency calibration of an output device. Between the
+/// measured frequencies the level is interpolated on log frequency.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CalibrationTable {
+    /// Output device the table was measured on, `None` for the default.
+    pub device_id: Option<String>,
+    points: Vec<CalibrationPoint>,
+}
+
+impl CalibrationTable {
+    pub fn new(device_id: Option<String>) -> Self {
+        Self {
+            device_id,
+            points: Vec::new(),
+        }
+    }
+
+    pub fn points(&self) -> &[CalibrationPoint] {
+        &self.points
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.points.is_empty()
+    }
+
+    /// Adds a measurement, replacing an earlier one of the same ear and
+    /// frequency.
+    pub fn set_point(&mut self, point: CalibrationPoint) -> Result<(), Error> {
+        let valid = point.frequency_hz.is_finite()
+            && point.frequency_hz > 0.0
+            && (0.0..=crate::MAX_FULL_SCALE_SPL_DB).contains(&point.full_scale_spl_db);
+        if !valid {
+            return Err(Error::InvalidArgument("calibration point"));
+        }
+        self.points
+            .retain(|p| !(p.channel == point.channel && p.frequency_hz == point.frequency_hz));
+        self.points.push(point);
+        self.points.sort_by(|a, b| {
+            (a.channel as i32, a.frequency_hz)
+                .partial_cmp(&(b.channel as i32, b.frequency_hz))
+                .unwrap_or(std::cmp::Ordering::Equal)
+        });
+        Ok(())
+    }
+
+    fn channel_points(&self, channel: Channel) -> Vec<(f32, f32)> {
+        self.points
+            .iter()
+            .filter(|p| p.channel == channel)
+            .map(|p| (p.frequency_hz, p.full_scale_spl_db))
+            .collect()
+    }
+
+    /// Level of a full-scale sine at `frequency_hz`, `None` if the ear has
+    /// not been calibrated.
+    pub fn full_scale_spl_db(&self, channel: Channel, frequency_hz: f32) -> Option<f32> {
+        interpolate(&self.channel_points(channel), frequency_hz)
+    }
+
+    /// Loudest calibrated level, used as the conservative estimate for the
+    /// noise dose.
+    pub fn max_full_scale_spl_db(&self) -> Option<f32> {
+        self.points
+            .iter()
+            .map(|p| p.full_scale_spl_db)
+            .reduce(f32::max)
+    }
+
+    /// Serializes the table as text, one measurement per line.
+    pub fn to_text(&self) -> String {
+        let mut text = format!("{FILE_HEADER}\n");
+        if let Some(device_id) = &self.device_id {
+            let _ = writeln!(text, "device {device_id}");
+        }
+        for point in &self.points {
+            let channel = match point.channel {
+                Channel::Left => "left",
+                Channel::Right => "right",
+            };
+            let _ = writeln!(
+                text,
+                "{channel} {} {}",
+                point.frequency_hz, point.full_scale_spl_db
+            );
+        }
+        text
+    }
+
+    pub fn from_text(text: &str) -> Result<Self, Error> {
+        let mut lines = text.lines().map(str::trim);
+        if lines.next() != Some(FILE_HEADER) {
+            return Err(Error::Parse("not a calibration file".to_string()));
+        }
+        let mut table = CalibrationTable::default();
+        for (number, line) in lines.enumerate() {
+            let invalid = || Error::Parse(format!("invalid calibration line {}", number + 2));
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if let Some(device_id) = line.strip_prefix("device ") {
+                table.device_id = Some(device_id.to_string());
+                continue;
+            }
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let [channel, frequency_hz, full_scale_spl_db] = fields[..] else {
+                return Err(invalid());
+            };
+            let channel = match channel {
+                "left" => Channel::Left,
+                "right" => Channel::Right,
+                _ => return Err(invalid()),
+            };
+            let point = CalibrationPoint {
+                channel,
+                frequency_hz: frequency_hz.parse().map_err(|_| invalid())?,
+                full_scale_spl_db: full_scale_spl_db.parse().map_err(|_| invalid())?,
+            };
+            table.set_point(point).map_err(|_| invalid())?;
+        }
+        Ok(table)
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), Error> {
+        fs::write(path, self.to_text()).map_err(|e| Error::Io(e.to_string()))
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let text = fs::read_to_string(path).map_err(|e| Error::Io(e.to_string()))?;
+        Self::from_text(&text)
+    }
+}
+
+/// Calibration shared with the audio thread: the table sampled on a MIDI
+/// note grid, and the reference tone played during calibration.
+pub struct CalibrationParams {
+    // Full-scale level per ear
+    curves: EarCurves,
+    tone_active: AtomicBool,
+    tone_channel: AtomicU32,
+    tone_frequency_hz: AtomicU32,
+    tone_level_dbfs: AtomicU32,
+    writer: Mutex<()>,
+}
+
+impl CalibrationParams {
+    pub fn new() -> Self {
+        Self {
+            curves: EarCurves::new(),
+            tone_active: AtomicBool::new(false),
+            tone_channel: AtomicU32::new(0),
+            tone_frequency_hz: AtomicU32::new(1000.0_f32.to_bits()),
+            tone_level_dbfs: AtomicU32::new(f32::NEG_INFINITY.to_bits()),
+            writer: Mutex::new(()),
+        }
+    }
+
+    pub(crate) fn set_table(&self, table: &CalibrationTable) {
+        let _guard = lock(&self.writer);
+        for channel in Channel::ALL {
+            self.curves.set(channel, &table.channel_points(channel));
+        }
+    }
+
+    /// Calibrated full-scale level at `frequency_hz`, `None` if the ear has
+    /// not been calibrated.
+    fn full_scale_spl_db(&self, channel: Channel, frequency_hz: f32) -> Option<f32> {
+        self.curves.get(channel, frequency_hz)
+    }
+
+    /// Linear factor that turns a gain in `unit` into a gain relative to full
+    /// scale for a tone at `frequency_hz`. Uncalibrated ears assume
+    /// `fallback_spl_db` for a full-scale sine.
+    pub(crate) fn tone_gain(
+        &self,
+        unit: GainUnit,
+        channel: Channel,
+        frequency_hz: f32,
+        fallback_spl_db: f32,
+    ) -> f32 {
+        let threshold_db = match unit {
+            GainUnit::Dbfs => return 1.0,
+            GainUnit::DbSpl => 0.0,
+            GainUnit::DbHl => reference_threshold_spl_db(frequency_hz),
+        };
+        let full_scale_db = self
+            .full_scale_spl_db(channel, frequency_hz)
+            .unwrap_or(fallback_spl_db);
+        10.0_f32.powf((threshold_db - full_scale_db) / 20.0)
+    }
+
+    /// Plays a steady tone on one ear instead of the sequence, for measuring
+    /// with a sound level meter.
+    pub(crate) fn play_reference_tone(&self, channel: Channel, frequency_hz: f32, level_dbfs: f32) {
+        let _guard = lock(&self.writer);
+        self.tone_channel.store(channel as u32, Ordering::Relaxed);
+        self.tone_frequency_hz
+            .store(frequency_hz.to_bits(), Ordering::Relaxed);
+        self.tone_level_dbfs
+            .store(level_dbfs.to_bits(), Ordering::Relaxed);
+        self.tone_active.store(true, Ordering::Release);
+    }
+
+    pub(crate) fn stop_reference_tone(&self) {
+        self.tone_active.store(false, Ordering::Release);
+    }
+
+    pub fn is_reference_tone_active(&self) -> bool {
+        self.tone_active.load(Ordering::Acquire)
+    }
+
+    /// The ear, frequency and level of the reference tone while it plays.
+    pub fn reference_tone(&self) -> Option<(Channel, f32, f32)> {
+        if !self.is_reference_tone_active() {
+            return None;
+        }
+        let channel = Channel::from_index(self.tone_channel.load(Ordering::Relaxed) as i32)?;
+        let frequency_hz = f32::from_bits(self.tone_frequency_hz.load(Ordering::Relaxed));
+        let level_dbfs = f32::from_bits(self.tone_level_dbfs.load(Ordering::Relaxed));
+        Some((channel, frequency_hz, level_dbfs))
+    }
+}
+
+impl Default for CalibrationParams {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Renders the reference tone on the audio thread. Changes of ear or
+/// frequency fade out first, so the tone never jumps.
+pub(crate) struct ReferenceTone {
+    oscillator: oscillator::Oscillator,
+    channel: Channel,
+    frequency_hz: f32,
+    // Position on the fade, from 0 (silent) to `fade_samples` (full)
+    fade_position: u64,
+    fade_samples: u64,
+}
+
+impl ReferenceTone {
+    pub(crate) fn new(sample_rate: u32) -> Self {
+        Self {
+            oscillator: oscillator::Oscillator::new(sample_rate as f32),
+            channel: Channel::Left,
+            frequency_hz: 0.0,
+            fade_position: 0,
+            fade_samples: Self::fade_samples(sample_rate),
+        }
+    }
+
+    fn fade_samples(sample_rate: u32) -> u64 {
+        ((REFERENCE_FADE_MS * sample_rate as f32 / 1000.0) as u64).max(1)
+    }
+
+    pub(crate) fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.oscillator.set_sample_rate(sample_rate as f32);
+        let fade_samples = Self::fade_samples(sample_rate);
+        self.fade_position = self.fade_position * fade_samples / self.fade_samples;
+        self.fade_samples = fade_samples;
+    }
+
+    /// Adds the tone to the interleaved `data`, at most at `ceiling`. Fades
+    /// out unless `enabled`, e.g. while the player pauses.
+    pub(crate) fn render(
+        &mut self,
+        params: &CalibrationParams,
+        enabled: bool,
+        data: &mut [f32],
+        num_channels: usize,
+        ceiling: f32,
+    ) {
+        let tone = params.reference_tone().filter(|_| enabled);
+        if tone.is_none() && self.fade_position == 0 {
+            return;
+        }
+        let gain = tone.map_or(0.0, |(_, _, level_dbfs)| {
+            10.0_f32.powf(level_dbfs / 20.0).max(0.0).min(ceiling)
+        });
+        for frame in data.chunks_mut(num_channels) {
+            let target_reached = tone.is_some_and(|(channel, frequency_hz, _)| {
+                channel == self.channel && frequency_hz == self.frequency_hz
+            });
+            if target_reached {
+                self.fade_position = (self.fade_position + 1).min(self.fade_samples);
+            } else if self.fade_position > 0 {
+                self.fade_position -= 1;
+            } else if let Some((channel, frequency_hz, _)) = tone {
+                // Silent, so the new ear and frequency can be taken over
+                self.channel = channel;
+                self.frequency_hz = frequency_hz;
+                self.oscillator.set_freq(frequency_hz, 0);
+                continue;
+            } else {
+                break;
+            }
+            let fade =
+                FadeCurve::RaisedCosine.gain(self.fade_position as f32 / self.fade_samples as f32);
+            let output_channel = (self.channel as usize).min(num_channels - 1);
+            frame[output_channel] += self.oscillator.next_sample() * gain * fade;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ear_curves::hz_to_midi;
+    use crate::test_util::{STEREO_48K, hold_tones, params, peak, peak_db};
+    use crate::{AudioPlayer, AudioState, GainUnit, NullBackend};
+
+    fn table() -> CalibrationTable {
+        let mut table = CalibrationTable::new(Some("usb:Headphones 2".to_string()));
+        for (channel, frequency_hz, full_scale_spl_db) in [
+            (Channel::Left, 1000.0, 100.0),
+            (Channel::Left, 4000.0, 90.0),
+            (Channel::Right, 1000.0, 95.0),
+        ] {
+            table
+                .set_point(CalibrationPoint {
+                    channel,
+                    frequency_hz,
+                    full_scale_spl_db,
+                })
+                .unwrap();
+        }
+        table
+    }
+
+    #[test]
+    fn test_interpolation() {
+        let table = table();
+        // Halfway between 1 and 4 kHz on a log scale
+        assert_eq!(table.full_scale_spl_db(Channel::Left, 2000.0), Some(95.0));
+        assert_eq!(table.full_scale_spl_db(Channel::Left, 100.0), Some(100.0));
+        assert_eq!(table.full_scale_spl_db(Channel::Left, 16000.0), Some(90.0));
+        assert_eq!(table.full_scale_spl_db(Channel::Right, 8000.0), Some(95.0));
+        assert_eq!(table.max_full_scale_spl_db(), Some(100.0));
+        assert_eq!(reference_threshold_spl_db(1000.0), 5.5);
+        assert_eq!(reference_threshold_spl_db(20.0), 30.5);
+
+        let params = CalibrationParams::new();
+        params.set_table(&table);
+        let level = params.full_scale_spl_db(Channel::Left, 2000.0).unwrap();
+        assert!((level - 95.0).abs() < 0.01);
+        // 0 dB SPL on a device reaching 95 dB SPL at full scale is -95 dBFS
+        let gain = params.tone_gain(GainUnit::DbSpl, Channel::Left, 2000.0, 0.0);
+        assert!((20.0 * gain.log10() + 95.0).abs() < 0.01);
+        let gain = params.tone_gain(GainUnit::DbHl, Channel::Right, 1000.0, 0.0);
+        assert!((20.0 * gain.log10() + 89.5).abs() < 0.01);
+
+        params.set_table(&CalibrationTable::default());
+        assert_eq!(params.full_scale_spl_db(Channel::Left, 2000.0), None);
+        let gain = params.tone_gain(GainUnit::DbSpl, Channel::Left, 2000.0, 100.0);
+        assert!((gain - 1e-5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_text_round_trip() {
+        let table = table();
+        let text = table.to_text();
+        assert_eq!(CalibrationTable::from_text(&text), Ok(table));
+
+        assert!(CalibrationTable::from_text("left 1000 90").is_err());
+        let text = format!("{FILE_HEADER}\n# comment\n\nleft 1000\n");
+        assert_eq!(
+            CalibrationTable::from_text(&text),
+            Err(Error::Parse("invalid calibration line 4".to_string()))
+        );
+        let text = format!("{FILE_HEADER}\ncenter 1000 90\n");
+        assert!(CalibrationTable::from_text(&text).is_err());
+    }
+
+    #[test]
+    fn test_reference_tone() {
+        let params = CalibrationParams::new();
+        let mut tone = ReferenceTone::new(48000);
+        let mut data = vec![0.0; 48000 * 2];
+        tone.render(&params, true, &mut data, 2, 1.0);
+        assert!(data.iter().all(|&s| s == 0.0));
+
+        params.play_reference_tone(Channel::Right, 1000.0, -20.0);
+        tone.render(&params, true, &mut data, 2, 1.0);
+        assert_eq!(peak(&data, 0), 0.0);
+        assert!((peak(&data[4800 * 2..], 1) - 0.1).abs() < 1e-3);
+
+        // Capped at the ceiling
+        params.play_reference_tone(Channel::Right, 1000.0, 0.0);
+        data.fill(0.0);
+        tone.render(&params, true, &mut data, 2, 0.5);
+        assert!((peak(&data, 1) - 0.5).abs() < 1e-3);
+
+        // Moves to the other ear through silence
+        params.play_reference_tone(Channel::Left, 1000.0, -20.0);
+        data.fill(0.0);
+        tone.render(&params, true, &mut data, 2, 1.0);
+        assert!(peak(&data[4800 * 2..], 1) == 0.0);
+        assert!((peak(&data[4800 * 2..], 0) - 0.1).abs() < 1e-3);
+
+        params.stop_reference_tone();
+        data.fill(0.0);
+        tone.render(&params, true, &mut data, 2, 1.0);
+        assert!(data[4800 * 2..].iter().all(|&s| s == 0.0));
+    }
+
+    #[test]
+    fn test_calibrated_gain_units() {
+        // Every tone at 1 kHz
+        let note = hz_to_midi(1000.0);
+        let params = params(0.0, note, note);
+        hold_tones(&params);
+        params
+            .channel(Channel::Left)
+            .set_gain(70.0, GainUnit::DbSpl);
+        params
+            .channel(Channel::Right)
+            .set_gain(30.0, GainUnit::DbHl);
+        let mut table = CalibrationTable::new(None);
+        table
+            .set_point(CalibrationPoint {
+                channel: Channel::Left,
+                frequency_hz: 1000.0,
+                full_scale_spl_db: 100.0,
+            })
+            .unwrap();
+        params.calibration().set_table(&table);
+        let format = STEREO_48K;
+        let mut state = AudioState::new(format, params.clone(), 0);
+
+        let mut data = vec![0.0; 48000 * 2];
+        state.render(&mut data, format);
+        // 70 dB SPL on the calibrated ear, and 30 dB HL (35.5 dB SPL) on the
+        // other, which falls back to the dose calibration of 100 dB SPL
+        assert!((peak_db(&data[24000 * 2..], 0) + 30.0).abs() < 0.05);
+        assert!((peak_db(&data[24000 * 2..], 1) + 64.5).abs() < 0.05);
+
+        // The calibration tone replaces the sequence on one ear only
+        params
+            .calibration()
+            .play_reference_tone(Channel::Right, 500.0, -10.0);
+        let elapsed_ms = params.session_elapsed_ms();
+        state.render(&mut data, format);
+        assert_eq!(peak_db(&data[24000 * 2..], 0), f32::NEG_INFINITY);
+        assert!((peak_db(&data[24000 * 2..], 1) + 10.0).abs() < 0.05);
+        assert!(params.session_elapsed_ms() - elapsed_ms < 200);
+
+        params.calibration().stop_reference_tone();
+        state.render(&mut data, format);
+        assert!((peak_db(&data[24000 * 2..], 0) + 30.0).abs() < 0.05);
+        assert!((peak_db(&data[24000 * 2..], 1) + 64.5).abs() < 0.05);
+    }
+
+    #[test]
+    fn test_calibration_changes_are_smoothed() {
+        let note = hz_to_midi(1000.0);
+        let params = params(0.0, note, note);
+        hold_tones(&params);
+        params
+            .channel(Channel::Left)
+            .set_gain(70.0, GainUnit::DbSpl);
+        let format = STEREO_48K;
+        let mut state = AudioState::new(format, params.clone(), 0);
+        let mut data = vec![0.0; 48000 * 2];
+        state.render(&mut data, format);
+        // The dose calibration of 100 dB SPL applies without a table
+        assert!((peak_db(&data[24000 * 2..], 0) + 30.0).abs() < 0.05);
+
+        // A table for a louder headphone ramps up from the old level
+        let mut table = CalibrationTable::new(None);
+        table
+            .set_point(CalibrationPoint {
+                channel: Channel::Left,
+                frequency_hz: 1000.0,
+                full_scale_spl_db: 90.0,
+            })
+            .unwrap();
+        params.calibration().set_table(&table);
+        let mut block = vec![0.0; 9600 * 2];
+        state.render(&mut block, format);
+        assert!(peak_db(&block[..48 * 2], 0) < -29.0);
+        assert!(peak_db(&block, 0) < -20.0 + 0.05);
+        assert!((peak_db(&block[4800 * 2..], 0) + 20.0).abs() < 0.05);
+
+        // Switching the unit ramps down without going above the old level
+        params
+            .channel(Channel::Left)
+            .set_gain(-40.0, GainUnit::Dbfs);
+        state.render(&mut block, format);
+        assert!(peak(&block, 0) <= 0.1 * 1.001);
+        assert!((peak_db(&block[4800 * 2..], 0) + 40.0).abs() < 0.05);
+    }
+
+    #[test]
+    fn test_calibration_procedure() {
+        let mut player = AudioPlayer::with_backend(Box::new(NullBackend::new(STEREO_48K)));
+        assert_eq!(
+            player.add_calibration_measurement(80.0),
+            Err(Error::InvalidArgument("measurement without reference tone"))
+        );
+        assert!(
+            player
+                .play_reference_tone(Channel::Left, 0.0, -20.0)
+                .is_err()
+        );
+        assert!(
+            player
+                .play_reference_tone(Channel::Left, 1000.0, 3.0)
+                .is_err()
+        );
+
+        for (channel, frequency_hz, measured_spl_db) in [
+            (Channel::Left, 1000.0, 75.0),
+            (Channel::Left, 4000.0, 85.0),
+            (Channel::Right, 1000.0, 80.0),
+            (Channel::Right, 1000.0, 78.0),
+        ] {
+            player
+                .play_reference_tone(channel, frequency_hz, -20.0)
+                .unwrap();
+            let point = player.add_calibration_measurement(measured_spl_db).unwrap();
+            assert_eq!(point.full_scale_spl_db, measured_spl_db + 20.0);
+        }
+        player.stop_reference_tone();
+        let table = player.calibration_table().clone();
+        assert_eq!(table.points().len(), 3);
+        assert_eq!(table.full_scale_spl_db(Channel::Right, 1000.0), Some(98.0));
+        assert_eq!(player.full_scale_spl_db(), 105.0);
+
+        assert!(player.set_gain(120.0, GainUnit::DbSpl).is_ok());
+        assert_eq!(
+            player.set_gain(121.0, GainUnit::DbHl),
+            Err(Error::InvalidGain(121.0))
+        );
+        assert_eq!(
+            player.set_gain_channel(Channel::Left, 1.0, GainUnit::Dbfs),
+            Err(Error::InvalidGain(1.0))
+        );
+
+        let path = std::env::temp_dir().join(format!("calibration-{}.txt", std::process::id()));
+        player.save_calibration(&path).unwrap();
+        player.clear_calibration();
+        assert!(player.calibration_table().is_empty());
+        player.load_calibration(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(player.calibration_table(), &table);
+        assert!(matches!(player.load_calibration(&path), Err(Error::Io(_))));
+    }
+}
@@ -0,0 +1,381 @@
+use crate::ear_curves::{EarCurves, curve_frequencies, interpolate};
+use crate::error::lock;
+use crate::{Audiogram, Channel, Error, HeadphoneProfile};
+use std::sync::Mutex;
+
+include!(concat!(env!("OUT_DIR"), "/equal_loudness.rs"));
+
+/// Largest gain a compensation curve may add to a tone.
+pub const MAX_COMPENSATION_GAIN_DB: f32 = 60.0;
+/// Loudest contour of ISO 226:2003.
+pub const MAX_EQUAL_LOUDNESS_PHON: f32 = 90.0;
+
+/// Level in dB SPL at which a tone at `frequency_hz` is as loud as a 1 kHz
+/// tone at `phon` dB SPL, from the ISO 226:2003 contours. Between the 10 phon
+/// contours it is interpolated linearly, and outside 20 Hz to 12.5 kHz the
+/// outermost value is held.
+pub fn equal_loudness_spl_db(frequency_hz: f32, phon: f32) -> f32 {
+    let position = phon.clamp(0.0, MAX_EQUAL_LOUDNESS_PHON) / EQUAL_LOUDNESS_PHON_STEP;
+    let lower = (position as usize).min(EQUAL_LOUDNESS_SPL_DB.len() - 2);
+    let frac = position - lower as f32;
+    let level = |contour: &[f32; 29]| {
+        let points: Vec<(f32, f32)> = EQUAL_LOUDNESS_FREQUENCIES_HZ
+            .iter()
+            .copied()
+            .zip(contour.iter().copied())
+            .collect();
+        interpolate(&points, frequency_hz).unwrap_or_default()
+    };
+    let (low, high) = (
+        level(&EQUAL_LOUDNESS_SPL_DB[lower]),
+        level(&EQUAL_LOUDNESS_SPL_DB[lower + 1]),
+    );
+    low + frac * (high - low)
+}
+
+/// How the hearing thresholds of an audiogram are turned into tone gains.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct HearingCompensation {
+    /// Share of the hearing loss made up for, e.g. 0.5 for the half-gain
+    /// rule. Full compensation is rarely comfortable.
+    pub fraction: f32,
+    /// Most gain added at any frequency.
+    pub max_gain_db: f32,
+}
+
+impl HearingCompensation {
+    pub fn validate(&self) -> Result<(), Error> {
+        if !(0.0..=1.0).contains(&self.fraction) {
+            return Err(Error::InvalidArgument("compensation fraction"));
+        }
+        if !(0.0..=MAX_COMPENSATION_GAIN_DB).contains(&self.max_gain_db) {
+            return Err(Error::InvalidArgument("compensation maximum gain"));
+        }
+        Ok(())
+    }
+}
+
+impl Default for HearingCompensation {
+    fn default() -> Self {
+        Self {
+            fraction: 0.5,
+            max_gain_db: 30.0,
+        }
+    }
+}
+
+/// Extra gain per ear over frequency, interpolated on log frequency and held
+/// beyond the outermost points.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct GainCurve {
+    left: Vec<(f32, f32)>,
+    right: Vec<(f32, f32)>,
+}
+
+impl GainCurve {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Gains that make up for `compensation.fraction` of the hearing loss in
+    /// `audiogram`. Thresholds at or below 0 dB HL get no gain.
+    pub fn from_audiogram(
+        audiogram: &Audiogram,
+        compensation: HearingCompensation,
+    ) -> Result<Self, Error> {
+        compensation.validate()?;
+        let ear = |channel| {
+            audiogram
+                .ear(channel)
+                .iter()
+                .map(|t| {
+                    let gain_db = t.level_db_hl.max(0.0) * compensation.fraction;
+                    (t.frequency_hz, gain_db.min(compensation.max_gain_db))
+                })
+                .collect()
+        };
+        Ok(Self {
+            left: ear(Channel::Left),
+            right: ear(Channel::Right),
+        })
+    }
+
+    /// Gains that make tones of every frequency as loud as a 1 kHz tone of
+    /// the same level, following the contour of `target_phon`. Both ears get
+    /// the same curve.
+    pub fn equal_loudness(target_phon: f32) -> Result<Self, Error> {
+        if !(0.0..=MAX_EQUAL_LOUDNESS_PHON).contains(&target_phon) {
+            return Err(Error::InvalidArgument("equal-loudness level"));
+        }
+        let reference_db = equal_loudness_spl_db(1000.0, target_phon);
+        let points: Vec<(f32, f32)> = EQUAL_LOUDNESS_FREQUENCIES_HZ
+            .iter()
+            .map(|&frequency_hz| {
+                let gain_db = equal_loudness_spl_db(frequency_hz, target_phon) - reference_db;
+                let gain_db = gain_db.clamp(-MAX_COMPENSATION_GAIN_DB, MAX_COMPENSATION_GAIN_DB);
+                (frequency_hz, gain_db)
+            })
+            .collect();
+        Ok(Self {
+            left: points.clone(),
+            right: points,
+        })
+    }
+
+    /// Gains of the equalization in `profile`, which flatten the frequency
+    /// response of the headphone.
+    pub fn from_headphone_profile(profile: &HeadphoneProfile) -> Self {
+        let ear = |channel| {
+            let eq = profile.ear(channel);
+            if eq.is_empty() {
+                return Vec::new();
+            }
+            curve_frequencies()
+                .map(|frequency_hz| {
+                    let gain_db = eq.response_db(frequency_hz);
+                    let gain_db =
+                        gain_db.clamp(-MAX_COMPENSATION_GAIN_DB, MAX_COMPENSATION_GAIN_DB);
+                    (frequency_hz, gain_db)
+                })
+                .collect()
+        };
+        Self {
+            left: ear(Channel::Left),
+            right: ear(Channel::Right),
+        }
+    }
+
+    /// `(frequency_hz, gain_db)` points of one ear, sorted by frequency.
+    pub fn points(&self, channel: Channel) -> &[(f32, f32)] {
+        match channel {
+            Channel::Left => &self.left,
+            Channel::Right => &self.right,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.left.is_empty() && self.right.is_empty()
+    }
+
+    /// Gain at `frequency_hz`, 0 where the ear has no points.
+    pub fn gain_db(&self, channel: Channel, frequency_hz: f32) -> f32 {
+        interpolate(self.points(channel), frequency_hz).unwrap_or_default()
+    }
+}
+
+/// Frequency-dependent tone gains shared with the audio thread.
+pub struct CompensationParams {
+    // Gains in dB per ear from the audiogram, the equal-loudness contour and
+    // the headphone correction
+    audiogram: EarCurves,
+    equal_loudness: EarCurves,
+    headphone: EarCurves,
+    writer: Mutex<()>,
+}
+
+impl CompensationParams {
+    pub fn new() -> Self {
+        Self {
+            audiogram: EarCurves::new(),
+            equal_loudness: EarCurves::new(),
+            headphone: EarCurves::new(),
+            writer: Mutex::new(()),
+        }
+    }
+
+    pub(crate) fn set_audiogram_curve(&self, curve: &GainCurve) {
+        let _guard = lock(&self.writer);
+        for channel in Channel::ALL {
+            self.audiogram.set(channel, curve.points(channel));
+        }
+    }
+
+    pub(crate) fn set_equal_loudness_curve(&self, curve: &GainCurve) {
+        let _guard = lock(&self.writer);
+        for channel in Channel::ALL {
+            self.equal_loudness.set(channel, curve.points(channel));
+        }
+    }
+
+    pub(crate) fn set_headphone_curve(&self, curve: &GainCurve) {
+        let _guard = lock(&self.writer);
+        for channel in Channel::ALL {
+            self.headphone.set(channel, curve.points(channel));
+        }
+    }
+
+    /// Linear gain added to a tone at `frequency_hz` on `channel`.
+    pub(crate) fn tone_gain(&self, channel: Channel, frequency_hz: f32) -> f32 {
+        let gain_db = [&self.audiogram, &self.equal_loudness, &self.headphone]
+            .iter()
+            .filter_map(|curves| curves.get(channel, frequency_hz))
+            .sum::<f32>();
+        10.0_f32.powf(gain_db / 20.0)
+    }
+}
+
+impl Default for CompensationParams {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ear_curves::hz_to_midi;
+    use crate::test_util::{STEREO_48K, hold_tones, level_db, params, peak_db};
+    use crate::{AudioState, Channel, Threshold};
+
+    #[test]
+    fn test_curve_from_audiogram() {
+        let mut audiogram = Audiogram::new();
+        for (frequency_hz, level_db_hl) in [(1000.0, -5.0), (4000.0, 40.0), (8000.0, 90.0)] {
+            let threshold = Threshold {
+                frequency_hz,
+                level_db_hl,
+                no_response: false,
+            };
+            audiogram.set_threshold(Channel::Right, threshold).unwrap();
+        }
+        let curve = GainCurve::from_audiogram(&audiogram, HearingCompensation::default()).unwrap();
+        assert_eq!(
+            curve.points(Channel::Right),
+            [(1000.0, 0.0), (4000.0, 20.0), (8000.0, 30.0)]
+        );
+        assert!(curve.points(Channel::Left).is_empty());
+        // Halfway between 1 and 4 kHz on a log scale
+        assert_eq!(curve.gain_db(Channel::Right, 2000.0), 10.0);
+        assert_eq!(curve.gain_db(Channel::Right, 12000.0), 30.0);
+        assert_eq!(curve.gain_db(Channel::Left, 2000.0), 0.0);
+
+        let params = CompensationParams::new();
+        assert_eq!(params.tone_gain(Channel::Right, 2000.0), 1.0);
+        params.set_audiogram_curve(&curve);
+        let gain_db = 20.0 * params.tone_gain(Channel::Right, 2000.0).log10();
+        assert!((gain_db - 10.0).abs() < 0.05);
+        assert_eq!(params.tone_gain(Channel::Left, 2000.0), 1.0);
+        params.set_audiogram_curve(&GainCurve::new());
+        assert_eq!(params.tone_gain(Channel::Right, 2000.0), 1.0);
+
+        let invalid = HearingCompensation {
+            fraction: 1.5,
+            ..Default::default()
+        };
+        assert_eq!(
+            GainCurve::from_audiogram(&audiogram, invalid),
+            Err(Error::InvalidArgument("compensation fraction"))
+        );
+    }
+
+    #[test]
+    fn test_equal_loudness() {
+        assert_eq!(EQUAL_LOUDNESS_SPL_DB.len(), 10);
+        // Values of ISO 226:2003
+        assert!((equal_loudness_spl_db(1000.0, 40.0) - 40.0).abs() < 0.05);
+        assert!((equal_loudness_spl_db(20.0, 40.0) - 99.85).abs() < 0.05);
+        assert!((equal_loudness_spl_db(4000.0, 90.0) - 88.66).abs() < 0.05);
+        // Between contours and beyond 12.5 kHz
+        let between = equal_loudness_spl_db(100.0, 45.0);
+        assert!(equal_loudness_spl_db(100.0, 40.0) < between);
+        assert!(between < equal_loudness_spl_db(100.0, 50.0));
+        assert_eq!(
+            equal_loudness_spl_db(16000.0, 60.0),
+            equal_loudness_spl_db(12500.0, 60.0)
+        );
+
+        let curve = GainCurve::equal_loudness(40.0).unwrap();
+        assert_eq!(curve.points(Channel::Left), curve.points(Channel::Right));
+        assert!(curve.gain_db(Channel::Left, 1000.0).abs() < 0.05);
+        // Low tones are raised, and the most sensitive region lowered
+        assert!((curve.gain_db(Channel::Left, 100.0) - 24.36).abs() < 0.05);
+        assert!(curve.gain_db(Channel::Left, 3150.0) < -3.0);
+        assert!(GainCurve::equal_loudness(95.0).is_err());
+
+        // Adds to the audiogram compensation
+        let params = CompensationParams::new();
+        params.set_equal_loudness_curve(&curve);
+        let mut audiogram = Audiogram::new();
+        let threshold = Threshold {
+            frequency_hz: 1000.0,
+            level_db_hl: 20.0,
+            no_response: false,
+        };
+        audiogram.set_threshold(Channel::Left, threshold).unwrap();
+        let hearing =
+            GainCurve::from_audiogram(&audiogram, HearingCompensation::default()).unwrap();
+        params.set_audiogram_curve(&hearing);
+        let gain_db = 20.0 * params.tone_gain(Channel::Left, 100.0).log10();
+        assert!((gain_db - 34.36).abs() < 0.1, "{gain_db}");
+    }
+
+    #[test]
+    fn test_hearing_compensation() {
+        // Every tone at 4 kHz
+        let note = hz_to_midi(4000.0);
+        let params = params(-40.0, note, note);
+        hold_tones(&params);
+        let audiogram = Audiogram::parse(
+            "ear,frequency_hz,threshold_db_hl\n\
+             left,1000,10\n\
+             left,8000,40\n\
+             right,4000,60\n",
+        )
+        .unwrap();
+        let curve = GainCurve::from_audiogram(&audiogram, HearingCompensation::default()).unwrap();
+        params.compensation().set_audiogram_curve(&curve);
+        let format = STEREO_48K;
+        let mut state = AudioState::new(format, params.clone(), 0);
+
+        let mut data = vec![0.0; 48000 * 2];
+        state.render(&mut data, format);
+        // Half of the loss: two thirds of the way from 5 to 20 dB between 1
+        // and 8 kHz on a log scale, and 30 dB of 60 dB HL
+        assert!((level_db(&data[24000 * 2..], 0) + 25.0).abs() < 0.05);
+        assert!((level_db(&data[24000 * 2..], 1) + 10.0).abs() < 0.05);
+
+        // The compensated tones stay under the ceiling
+        params.set_output_ceiling_db(-20.0);
+        state.render(&mut data, format);
+        assert!((level_db(&data[24000 * 2..], 0) + 25.0).abs() < 0.05);
+        assert!((level_db(&data[24000 * 2..], 1) + 20.0).abs() < 0.05);
+
+        // The equal-loudness weighting adds to it; at 40 phon a 4 kHz tone
+        // sounds as loud as 1 kHz with 3.4 dB less
+        let contour = GainCurve::equal_loudness(40.0).unwrap();
+        params.compensation().set_equal_loudness_curve(&contour);
+        state.render(&mut data, format);
+        let weight_db = contour.gain_db(Channel::Left, 4000.0);
+        assert!((weight_db + 3.36).abs() < 0.05, "{weight_db}");
+        assert!((level_db(&data[24000 * 2..], 0) + 25.0 - weight_db).abs() < 0.05);
+        assert!((level_db(&data[24000 * 2..], 1) + 20.0).abs() < 0.05);
+    }
+
+    #[test]
+    fn test_compensation_changes_are_smoothed() {
+        let note = hz_to_midi(4000.0);
+        let params = params(-40.0, note, note);
+        hold_tones(&params);
+        let format = STEREO_48K;
+        let mut state = AudioState::new(format, params.clone(), 0);
+        let mut data = vec![0.0; 48000 * 2];
+        state.render(&mut data, format);
+
+        // 30 dB of compensation on the right ear, loaded during a tone, ramps
+        // up from the old level instead of jumping
+        let audiogram =
+            Audiogram::parse("ear,frequency_hz,threshold_db_hl\nright,4000,60\n").unwrap();
+        let curve = GainCurve::from_audiogram(&audiogram, HearingCompensation::default()).unwrap();
+        params.compensation().set_audiogram_curve(&curve);
+        let mut block = vec![0.0; 9600 * 2];
+        state.render(&mut block, format);
+        assert!(peak_db(&block[..48 * 2], 1) < -35.0);
+        assert!((level_db(&block[4800 * 2..], 1) + 10.0).abs() < 0.05);
+
+        // And so does removing it
+        params.compensation().set_audiogram_curve(&GainCurve::new());
+        state.render(&mut block, format);
+        assert!(peak_db(&block[..48 * 2], 1) > -11.0);
+        assert!((level_db(&block[4800 * 2..], 1) + 40.0).abs() < 0.05);
+    }
+}
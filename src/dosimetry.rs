@@ -0,0 +1,391 @@
+use crate::Error;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+
+/// Assumed level of a full-scale sine until the device is calibrated. Errs on
+/// the loud side so that an uncalibrated dose is overestimated.
+pub const DEFAULT_FULL_SCALE_SPL_DB: f32 = 100.0;
+/// Highest accepted calibration level.
+pub const MAX_FULL_SCALE_SPL_DB: f32 = 160.0;
+
+/// Mean square of a full-scale sine, the 0 dB reference of output levels.
+const FULL_SCALE_SINE_POWER: f64 = 0.5;
+
+/// Daily noise exposure budget. The default is the NIOSH recommendation of
+/// 85 dBA for 8 hours with a 3 dB exchange rate.
+///
+/// Levels are estimated from the unweighted output. For the tones played here
+/// the A-weighting differs by a few dB at most, and above 1 kHz mostly lowers
+/// the level, so the estimate stays on the safe side.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct DoseLimit {
+    /// Level that may be played for `criterion_hours` a day, in dB SPL.
+    pub criterion_db: f32,
+    pub criterion_hours: f32,
+    /// Level increase that halves the allowed time.
+    pub exchange_rate_db: f32,
+    /// Share of the daily dose at which `PlaybackEvent::DoseWarning` is
+    /// emitted.
+    pub warning_fraction: f32,
+    /// Whether the output fades out and stops once the daily dose is used
+    /// up.
+    pub auto_stop: bool,
+}
+
+impl DoseLimit {
+    pub fn validate(&self) -> Result<(), Error> {
+        let valid = (0.0..=MAX_FULL_SCALE_SPL_DB).contains(&self.criterion_db)
+            && self.criterion_hours > 0.0
+            && self.criterion_hours <= 24.0
+            && (1.0..=10.0).contains(&self.exchange_rate_db)
+            && (0.0..=1.0).contains(&self.warning_fraction);
+        if !valid {
+            return Err(Error::InvalidArgument("dose limit"));
+        }
+        Ok(())
+    }
+}
+
+impl Default for DoseLimit {
+    fn default() -> Self {
+        Self {
+            criterion_db: 85.0,
+            criterion_hours: 8.0,
+            exchange_rate_db: 3.0,
+            warning_fraction: 0.8,
+            auto_stop: true,
+        }
+    }
+}
+
+/// Noise dose as a share of the daily budget, 1.0 when it is used up.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct NoiseDose {
+    pub session: f64,
+    /// Includes the current session. The host app decides when a day starts
+    /// and restores the dose across restarts with `set_daily_dose`.
+    pub daily: f64,
+}
+
+/// Dose settings and totals, shared between the player and the audio thread.
+pub struct DoseParams {
+    full_scale_spl_db: AtomicU32,
+    criterion_db: AtomicU32,
+    criterion_hours: AtomicU32,
+    exchange_rate_db: AtomicU32,
+    warning_fraction: AtomicU32,
+    auto_stop: AtomicBool,
+    // f64 bits
+    session_dose: AtomicU64,
+    daily_dose: AtomicU64,
+    writer: Mutex<()>,
+}
+
+impl DoseParams {
+    pub fn new() -> Self {
+        let params = Self {
+            full_scale_spl_db: AtomicU32::new(DEFAULT_FULL_SCALE_SPL_DB.to_bits()),
+            criterion_db: AtomicU32::new(0),
+            criterion_hours: AtomicU32::new(0),
+            exchange_rate_db: AtomicU32::new(0),
+            warning_fraction: AtomicU32::new(0),
+            auto_stop: AtomicBool::new(false),
+            session_dose: AtomicU64::new(0.0_f64.to_bits()),
+            daily_dose: AtomicU64::new(0.0_f64.to_bits()),
+            writer: Mutex::new(()),
+        };
+        params.store_limit(&DoseLimit::default());
+        params
+    }
+
+    pub fn full_scale_spl_db(&self) -> f32 {
+        f32::from_bits(self.full_scale_spl_db.load(Ordering::Relaxed))
+    }
+
+    /// Sets the level in dB SPL that a full-scale sine reaches on the output
+    /// device.
+    pub fn set_full_scale_spl_db(&self, spl_db: f32) -> Result<(), Error> {
+        if !(0.0..=MAX_FULL_SCALE_SPL_DB).contains(&spl_db) {
+            return Err(Error::InvalidArgument("full scale level"));
+        }
+        self.full_scale_spl_db
+            .store(spl_db.to_bits(), Ordering::Relaxed);
+        Ok(())
+    }
+
+    pub fn limit(&self) -> DoseLimit {
+        let load = |value: &AtomicU32| f32::from_bits(value.load(Ordering::Relaxed));
+        DoseLimit {
+            criterion_db: load(&self.criterion_db),
+            criterion_hours: load(&self.criterion_hours),
+            exchange_rate_db: load(&self.exchange_rate_db),
+            warning_fraction: load(&self.warning_fraction),
+            auto_stop: self.auto_stop.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn set_limit(&self, limit: DoseLimit) -> Result<(), Error> {
+        limit.validate()?;
+        let _guard = crate::error::lock(&self.writer);
+        self.store_limit(&limit);
+        Ok(())
+    }
+
+    fn store_limit(&self, limit: &DoseLimit) {
+        let store = |value: &AtomicU32, x: f32| value.store(x.to_bits(), Ordering::Relaxed);
+        store(&self.criterion_db, limit.criterion_db);
+        store(&self.criterion_hours, limit.criterion_hours);
+        store(&self.exchange_rate_db, limit.exchange_rate_db);
+        store(&self.warning_fraction, limit.warning_fraction);
+        self.auto_stop.store(limit.auto_stop, Ordering::Relaxed);
+    }
+
+    pub fn dose(&self) -> NoiseDose {
+        NoiseDose {
+            session: f64::from_bits(self.session_dose.load(Ordering::Relaxed)),
+            daily: f64::from_bits(self.daily_dose.load(Ordering::Relaxed)),
+        }
+    }
+
+    /// Replaces the daily dose, e.g. with 0 at the start of a day or with
+    /// the value saved before the app was closed.
+    pub fn set_daily_dose(&self, dose: f64) -> Result<(), Error> {
+        if !(dose.is_finite() && dose >= 0.0) {
+            return Err(Error::InvalidArgument("daily dose"));
+        }
+        self.daily_dose.store(dose.to_bits(), Ordering::Relaxed);
+        Ok(())
+    }
+
+    pub(crate) fn reset_session(&self) {
+        self.session_dose
+            .store(0.0_f64.to_bits(), Ordering::Relaxed);
+    }
+
+    fn add(&self, dose: f64) {
+        let add = |total: &AtomicU64| {
+            // The player may reset the daily dose concurrently
+            let _ = total.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+                Some((f64::from_bits(bits) + dose).to_bits())
+            });
+        };
+        add(&self.session_dose);
+        add(&self.daily_dose);
+    }
+}
+
+impl Default for DoseParams {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Budget thresholds crossed by a measured buffer.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub(crate) struct DoseStatus {
+    pub warning: bool,
+    pub limit_reached: bool,
+}
+
+/// Adds the output of the audio thread to the dose.
+pub(crate) struct DoseMeter {
+    warned: bool,
+    limit_reached: bool,
+}
+
+impl DoseMeter {
+    pub(crate) fn new() -> Self {
+        Self {
+            warned: false,
+            limit_reached: false,
+        }
+    }
+
+    /// Measures an interleaved buffer as heard by the louder ear and returns
+    /// the thresholds crossed for the first time since the meter was created.
+    pub(crate) fn measure(
+        &mut self,
+        params: &DoseParams,
+        data: &[f32],
+        num_channels: usize,
+        sample_rate: u32,
+    ) -> DoseStatus {
+        let frames = data.len() / num_channels.max(1);
+        if frames > 0 && sample_rate > 0 {
+            let power = (0..num_channels.min(2))
+                .map(|channel| {
+                    data.iter()
+                        .skip(channel)
+                        .step_by(num_channels)
+                        .map(|&s| s as f64 * s as f64)
+                        .sum::<f64>()
+                        / frames as f64
+                })
+                .fold(0.0, f64::max);
+            let limit = params.limit();
+            let spl_db =
+                10.0 * (power / FULL_SCALE_SINE_POWER).log10() + params.full_scale_spl_db() as f64;
+            let seconds = frames as f64 / sample_rate as f64;
+            let allowed_seconds = limit.criterion_hours as f64 * 3600.0
+                / 2.0_f64
+                    .powf((spl_db - limit.criterion_db as f64) / limit.exchange_rate_db as f64);
+            // Silence gives an infinite allowance, i.e. no dose
+            params.add(seconds / allowed_seconds);
+        }
+
+        let limit = params.limit();
+        let daily = params.dose().daily;
+        let mut status = DoseStatus::default();
+        if !self.warned && daily >= limit.warning_fraction as f64 {
+            self.warned = true;
+            status.warning = true;
+        }
+        if !self.limit_reached && daily >= 1.0 {
+            self.limit_reached = true;
+            status.limit_reached = true;
+        }
+        status
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{STEREO_48K, hold_tones, params};
+    use crate::{AudioState, EventQueue, PlaybackEvent};
+    use std::sync::Arc;
+
+    fn sine(amplitude: f32, frames: usize) -> Vec<f32> {
+        (0..frames)
+            .flat_map(|i| {
+                let s = amplitude * (i as f32 * 0.1).sin();
+                [s, s * 0.5]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_dose_follows_exchange_rate() {
+        let params = DoseParams::new();
+        params.set_full_scale_spl_db(100.0).unwrap();
+        let mut meter = DoseMeter::new();
+
+        // 85 dB SPL for 1s is 1/28800 of the NIOSH budget
+        let at_85_db = sine(10.0_f32.powf(-15.0 / 20.0), 48000);
+        meter.measure(&params, &at_85_db, 2, 48000);
+        let dose = params.dose();
+        assert!((dose.session * 28800.0 - 1.0).abs() < 0.01, "{dose:?}");
+        assert_eq!(dose.session, dose.daily);
+
+        // 3 dB more doubles the dose rate
+        params.reset_session();
+        let at_88_db = sine(10.0_f32.powf(-12.0 / 20.0), 48000);
+        meter.measure(&params, &at_88_db, 2, 48000);
+        assert!((params.dose().session * 28800.0 - 2.0).abs() < 0.02);
+
+        // Silence adds nothing
+        params.reset_session();
+        meter.measure(&params, &[0.0; 9600], 2, 48000);
+        assert_eq!(params.dose().session, 0.0);
+    }
+
+    #[test]
+    fn test_thresholds_are_reported_once() {
+        let params = DoseParams::new();
+        params.set_full_scale_spl_db(100.0).unwrap();
+        let mut meter = DoseMeter::new();
+        let buffer = sine(1.0, 4800);
+
+        params.set_daily_dose(0.79).unwrap();
+        assert_eq!(meter.measure(&params, &[], 2, 48000), DoseStatus::default());
+        params.set_daily_dose(0.9).unwrap();
+        let status = meter.measure(&params, &buffer, 2, 48000);
+        assert!(status.warning && !status.limit_reached);
+
+        params.set_daily_dose(1.0).unwrap();
+        let status = meter.measure(&params, &buffer, 2, 48000);
+        assert!(!status.warning && status.limit_reached);
+        assert_eq!(
+            meter.measure(&params, &buffer, 2, 48000),
+            DoseStatus::default()
+        );
+    }
+
+    #[test]
+    fn test_validation() {
+        let params = DoseParams::new();
+        assert!(params.set_full_scale_spl_db(f32::NAN).is_err());
+        assert!(params.set_daily_dose(-1.0).is_err());
+        let limit = DoseLimit {
+            exchange_rate_db: 0.0,
+            ..Default::default()
+        };
+        assert_eq!(
+            params.set_limit(limit),
+            Err(Error::InvalidArgument("dose limit"))
+        );
+        assert_eq!(params.limit(), DoseLimit::default());
+    }
+
+    #[test]
+    fn test_used_up_dose_stops_output() {
+        let params = params(0.0, 60.0, 72.0);
+        hold_tones(&params);
+        params.set_tail_fade_ms(10.0);
+        params.dose().set_full_scale_spl_db(120.0).unwrap();
+        params.dose().set_daily_dose(0.5).unwrap();
+        let format = STEREO_48K;
+        let events = Arc::new(EventQueue::new());
+        let dose_events = || {
+            std::iter::from_fn(|| events.pop())
+                .filter(|event| {
+                    matches!(
+                        event,
+                        PlaybackEvent::DoseWarning | PlaybackEvent::DoseLimitReached
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // At 120 dB SPL the NIOSH budget lasts 9s, so half of it takes 4.4s
+        let mut state = AudioState::new(format, params.clone(), 0).with_events(events.clone());
+        let mut data = vec![0.0; 4800 * 2];
+        let mut buffers = 0;
+        while state.render(&mut data, format) {
+            buffers += 1;
+            assert!(buffers < 100, "did not stop");
+        }
+        assert_eq!(
+            dose_events(),
+            [PlaybackEvent::DoseWarning, PlaybackEvent::DoseLimitReached]
+        );
+        let dose = params.dose().dose();
+        assert!(dose.daily >= 1.0 && dose.daily < 1.01, "{dose:?}");
+        assert!((dose.daily - dose.session - 0.5).abs() < 1e-9);
+        // The last buffer fades out over the tail fade
+        assert!(data[480 * 2..].iter().all(|&s| s == 0.0));
+
+        // A new session does not play at all once the dose is used up
+        let mut state = AudioState::new(format, params.clone(), 0).with_events(events.clone());
+        assert!(!state.render(&mut data, format));
+        assert_eq!(params.dose().dose().session, 0.0);
+        assert_eq!(
+            dose_events(),
+            [PlaybackEvent::DoseWarning, PlaybackEvent::DoseLimitReached]
+        );
+
+        // Without auto stop it only warns
+        params
+            .dose()
+            .set_limit(DoseLimit {
+                auto_stop: false,
+                ..Default::default()
+            })
+            .unwrap();
+        let mut state = AudioState::new(format, params.clone(), 0);
+        for _ in 0..20 {
+            assert!(state.render(&mut data, format));
+        }
+        assert!(data.iter().any(|&s| s != 0.0));
+    }
+}
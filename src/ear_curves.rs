@@ -0,0 +1,80 @@
+use crate::{Channel, MAX_MIDI_NOTE, MIN_MIDI_NOTE};
+use std::sync::atomic::{AtomicU32, Ordering};
+
+/// Resolution of the curves read by the audio thread.
+const CURVE_STEPS_PER_NOTE: usize = 4;
+const CURVE_POINTS: usize = (MAX_MIDI_NOTE - MIN_MIDI_NOTE) as usize * CURVE_STEPS_PER_NOTE + 1;
+
+pub(crate) fn midi_to_hz(midi: f32) -> f32 {
+    440.0 * 2.0_f32.powf((midi - 69.0) / 12.0)
+}
+
+pub(crate) fn hz_to_midi(hz: f32) -> f32 {
+    69.0 + 12.0 * (hz / 440.0).log2()
+}
+
+/// Frequencies of the curve points, for sampling a response that cannot be
+/// interpolated from a few points.
+pub(crate) fn curve_frequencies() -> impl Iterator<Item = f32> {
+    (0..CURVE_POINTS).map(|i| midi_to_hz(MIN_MIDI_NOTE + i as f32 / CURVE_STEPS_PER_NOTE as f32))
+}
+
+/// Interpolates `(frequency, value)` points sorted by frequency linearly on
+/// log frequency, holding the end values outside.
+pub(crate) fn interpolate(points: &[(f32, f32)], frequency_hz: f32) -> Option<f32> {
+    let (first, last) = (points.first()?, points.last()?);
+    if frequency_hz <= first.0 {
+        return Some(first.1);
+    }
+    if frequency_hz >= last.0 {
+        return Some(last.1);
+    }
+    let upper = points.iter().position(|&(f, _)| f >= frequency_hz)?;
+    let (f0, v0) = points[upper - 1];
+    let (f1, v1) = points[upper];
+    let t = (frequency_hz / f0).ln() / (f1 / f0).ln();
+    Some(v0 + t * (v1 - v0))
+}
+
+/// A value per ear over the tone range, sampled on a MIDI note grid so that
+/// the audio thread can look it up without locking. Writers must not run
+/// concurrently; a reader may see a curve that is partly updated.
+pub(crate) struct EarCurves {
+    // NaN where the ear has no curve
+    curves: [[AtomicU32; CURVE_POINTS]; 2],
+}
+
+impl EarCurves {
+    pub(crate) fn new() -> Self {
+        Self {
+            curves: std::array::from_fn(|_| {
+                std::array::from_fn(|_| AtomicU32::new(f32::NAN.to_bits()))
+            }),
+        }
+    }
+
+    /// Samples `points`, sorted by frequency, into the curve of `channel`.
+    /// No points remove the curve.
+    pub(crate) fn set(&self, channel: Channel, points: &[(f32, f32)]) {
+        for (value, frequency_hz) in self.curves[channel as usize]
+            .iter()
+            .zip(curve_frequencies())
+        {
+            let level = interpolate(points, frequency_hz).unwrap_or(f32::NAN);
+            value.store(level.to_bits(), Ordering::Relaxed);
+        }
+    }
+
+    /// Value at `frequency_hz`, `None` if the ear has no curve.
+    pub(crate) fn get(&self, channel: Channel, frequency_hz: f32) -> Option<f32> {
+        let position = ((hz_to_midi(frequency_hz) - MIN_MIDI_NOTE) * CURVE_STEPS_PER_NOTE as f32)
+            .max(0.0)
+            .min((CURVE_POINTS - 1) as f32);
+        let index = (position as usize).min(CURVE_POINTS - 2);
+        let frac = position - index as f32;
+        let curve = &self.curves[channel as usize];
+        let load = |i: usize| f32::from_bits(curve[i].load(Ordering::Relaxed));
+        let value = load(index) + frac * (load(index + 1) - load(index));
+        value.is_finite().then_some(value)
+    }
+}
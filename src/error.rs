@@ -0,0 +1,299 @@
+use crate::{
+    EventQueue, GainUnit, MAX_CALIBRATED_LEVEL_DB, PlaybackEvent, ScheduleError, StreamFormat,
+};
+use std::any::Any;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+/// Lowest accepted MIDI note (8.18 Hz).
+pub const MIN_MIDI_NOTE: f32 = 0.0;
+/// Highest accepted MIDI note (about 19.9 kHz).
+pub const MAX_MIDI_NOTE: f32 = 135.0;
+/// Highest accepted gain. Louder output would clip.
+pub const MAX_GAIN_DB: f32 = 0.0;
+
+/// Errors reported by `AudioPlayer` and the audio backends.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Error {
+    /// The C API received a null player, or the JNI player was not created.
+    NoPlayer,
+    InvalidGain(f32),
+    InvalidNoteRange {
+        min_midi_note: f32,
+        max_midi_note: f32,
+    },
+    InvalidChannel(i32),
+    /// An enum value passed over the FFI is out of range.
+    InvalidArgument(&'static str),
+    InvalidSchedule(ScheduleError),
+    /// The audio device could not be opened or started.
+    AudioOutput(String),
+    /// Writing a render failed.
+    Io(String),
+    /// A panic was caught at the FFI boundary or in the render callback.
+    Panic(String),
+    UnknownDevice(String),
+    /// The limiter settings were locked and the PIN did not match.
+    LimiterLocked,
+    /// A file read by the core, e.g. a calibration table, is malformed.
+    Parse(String),
+}
+
+/// Status codes returned by the C and JNI exports.
+#[repr(i32)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ErrorCode {
+    Ok = 0,
+    NoPlayer = 1,
+    InvalidGain = 2,
+    InvalidNoteRange = 3,
+    InvalidChannel = 4,
+    InvalidArgument = 5,
+    InvalidSchedule = 6,
+    AudioOutput = 7,
+    Io = 8,
+    Panic = 9,
+    UnknownDevice = 10,
+    LimiterLocked = 11,
+    Parse = 12,
+}
+
+impl Error {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Error::NoPlayer => ErrorCode::NoPlayer,
+            Error::InvalidGain(_) => ErrorCode::InvalidGain,
+            Error::InvalidNoteRange { .. } => ErrorCode::InvalidNoteRange,
+            Error::InvalidChannel(_) => ErrorCode::InvalidChannel,
+            Error::InvalidArgument(_) => ErrorCode::InvalidArgument,
+            Error::InvalidSchedule(_) => ErrorCode::InvalidSchedule,
+            Error::AudioOutput(_) => ErrorCode::AudioOutput,
+            Error::Io(_) => ErrorCode::Io,
+            Error::Panic(_) => ErrorCode::Panic,
+            Error::UnknownDevice(_) => ErrorCode::UnknownDevice,
+            Error::LimiterLocked => ErrorCode::LimiterLocked,
+            Error::Parse(_) => ErrorCode::Parse,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::NoPlayer => write!(f, "no audio player"),
+            Error::InvalidGain(gain_db) => {
+                write!(
+                    f,
+                    "invalid gain {gain_db} dB, must be at most {MAX_GAIN_DB} dBFS or \
+                     {MAX_CALIBRATED_LEVEL_DB} dB SPL or HL"
+                )
+            }
+            Error::InvalidNoteRange {
+                min_midi_note,
+                max_midi_note,
+            } => write!(
+                f,
+                "invalid note range {min_midi_note}..{max_midi_note}, must be ordered and \
+                 within {MIN_MIDI_NOTE}..{MAX_MIDI_NOTE}"
+            ),
+            Error::InvalidChannel(channel) => write!(f, "invalid channel {channel}"),
+            Error::InvalidArgument(what) => write!(f, "invalid {what}"),
+            Error::InvalidSchedule(e) => write!(f, "invalid schedule: {e}"),
+            Error::AudioOutput(message) => write!(f, "audio output error: {message}"),
+            Error::Io(message) => write!(f, "I/O error: {message}"),
+            Error::Panic(message) => write!(f, "internal error: {message}"),
+            Error::UnknownDevice(id) => write!(f, "unknown output device '{id}'"),
+            Error::LimiterLocked => write!(f, "the limiter settings are locked"),
+            Error::Parse(message) => write!(f, "parse error: {message}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<ScheduleError> for Error {
+    fn from(e: ScheduleError) -> Self {
+        Error::InvalidSchedule(e)
+    }
+}
+
+static LAST_ERROR: Mutex<Option<Error>> = Mutex::new(None);
+
+/// Locks `mutex`, ignoring poisoning by a panic that has been caught.
+pub(crate) fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Returns the most recent error reported through the FFI or by the render
+/// callback.
+pub fn last_error() -> Option<Error> {
+    lock(&LAST_ERROR).clone()
+}
+
+pub fn clear_last_error() {
+    *lock(&LAST_ERROR) = None;
+}
+
+pub(crate) fn record_error(error: &Error) {
+    log::error!("{error}");
+    *lock(&LAST_ERROR) = Some(error.clone());
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+/// Runs `f`, turning a panic into `Error::Panic` so that it cannot unwind
+/// into C, Dart or the JVM.
+pub(crate) fn catch_panic<T>(f: impl FnOnce() -> Result<T, Error>) -> Result<T, Error> {
+    panic::catch_unwind(AssertUnwindSafe(f))
+        .unwrap_or_else(|payload| Err(Error::Panic(panic_message(payload.as_ref()))))
+}
+
+/// Converts a result into the status code returned over the FFI, recording
+/// the error.
+pub(crate) fn status(result: Result<(), Error>) -> i32 {
+    match result {
+        Ok(()) => ErrorCode::Ok as i32,
+        Err(e) => {
+            record_error(&e);
+            e.code() as i32
+        }
+    }
+}
+
+/// Runs `f` with panics caught and returns its status code.
+pub(crate) fn guarded(f: impl FnOnce() -> Result<(), Error>) -> i32 {
+    status(catch_panic(f))
+}
+
+/// Wraps a render callback so that a panic outputs silence instead of
+/// unwinding into the audio driver, and is reported to `events`. The callback
+/// stays silent afterwards, since its state may be inconsistent.
+pub(crate) fn guard_render(
+    events: Arc<EventQueue>,
+    mut f: impl FnMut(&mut [f32], StreamFormat) -> bool + Send + 'static,
+) -> impl FnMut(&mut [f32], StreamFormat) -> bool + Send + 'static {
+    let mut failed = false;
+    move |data, format| {
+        if !failed {
+            match catch_panic(|| Ok(f(data, format))) {
+                Ok(running) => return running,
+                Err(e) => {
+                    record_error(&e);
+                    events.push(PlaybackEvent::StreamError(e.code()));
+                    failed = true;
+                }
+            }
+        }
+        data.fill(0.0);
+        true
+    }
+}
+
+/// Serializes tests that inspect the global last error.
+#[cfg(test)]
+pub(crate) static TEST_LOCK: Mutex<()> = Mutex::new(());
+
+pub(crate) fn validate_gain_db(gain_db: f32) -> Result<(), Error> {
+    validate_gain(gain_db, GainUnit::Dbfs)
+}
+
+/// Validates a gain in `unit`. Calibrated levels may exceed full scale in
+/// number, they are capped by the output ceiling when played.
+pub(crate) fn validate_gain(gain_db: f32, unit: GainUnit) -> Result<(), Error> {
+    // -inf is accepted and mutes the channel
+    let max_db = match unit {
+        GainUnit::Dbfs => MAX_GAIN_DB,
+        GainUnit::DbSpl | GainUnit::DbHl => MAX_CALIBRATED_LEVEL_DB,
+    };
+    if gain_db.is_nan() || gain_db > max_db {
+        return Err(Error::InvalidGain(gain_db));
+    }
+    Ok(())
+}
+
+pub(crate) fn validate_note_range(min_midi_note: f32, max_midi_note: f32) -> Result<(), Error> {
+    let in_range = |note: f32| (MIN_MIDI_NOTE..=MAX_MIDI_NOTE).contains(&note);
+    if !(in_range(min_midi_note) && in_range(max_midi_note) && min_midi_note <= max_midi_note) {
+        return Err(Error::InvalidNoteRange {
+            min_midi_note,
+            max_midi_note,
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validation() {
+        assert!(validate_gain_db(-12.0).is_ok());
+        assert!(validate_gain_db(f32::NEG_INFINITY).is_ok());
+        assert_eq!(validate_gain_db(3.0), Err(Error::InvalidGain(3.0)));
+        assert!(validate_gain_db(f32::NAN).is_err());
+
+        assert!(validate_note_range(107.9, 119.4).is_ok());
+        assert!(validate_note_range(60.0, 60.0).is_ok());
+        assert!(validate_note_range(100.0, 90.0).is_err());
+        assert!(validate_note_range(-1.0, 90.0).is_err());
+        assert!(validate_note_range(60.0, f32::NAN).is_err());
+        assert!(validate_note_range(60.0, 200.0).is_err());
+    }
+
+    #[test]
+    fn test_status_codes() {
+        let _guard = lock(&TEST_LOCK);
+        assert_eq!(status(Ok(())), 0);
+        assert_eq!(status(Err(Error::NoPlayer)), 1);
+        assert_eq!(
+            status(Err(ScheduleError::InvalidProbability.into())),
+            ErrorCode::InvalidSchedule as i32
+        );
+    }
+
+    #[test]
+    fn test_panics_are_contained() {
+        let _guard = lock(&TEST_LOCK);
+        clear_last_error();
+        assert_eq!(guarded(|| panic!("boom")), ErrorCode::Panic as i32);
+        assert_eq!(last_error(), Some(Error::Panic("boom".to_string())));
+        clear_last_error();
+
+        let mut calls = 0;
+        let format = StreamFormat {
+            sample_rate: 48000,
+            channels: 2,
+        };
+        let events = Arc::new(EventQueue::new());
+        let mut render = guard_render(events.clone(), move |data, _| {
+            calls += 1;
+            data.fill(0.5);
+            assert!(calls < 2, "render failed");
+            true
+        });
+        let mut data = [1.0; 8];
+        assert!(render(&mut data, format));
+        assert_eq!(data, [0.5; 8]);
+        render(&mut data, format);
+        assert_eq!(data, [0.0; 8]);
+        assert_eq!(last_error().map(|e| e.code()), Some(ErrorCode::Panic));
+        assert_eq!(
+            events.pop(),
+            Some(PlaybackEvent::StreamError(ErrorCode::Panic))
+        );
+        // Stays silent after the panic
+        data.fill(1.0);
+        render(&mut data, format);
+        assert_eq!(data, [0.0; 8]);
+    }
+}
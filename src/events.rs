@@ -0,0 +1,370 @@
+use crate::error::{catch_panic, record_error};
+use crate::{Channel, ErrorCode};
+use std::cell::UnsafeCell;
+use std::mem::MaybeUninit;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// Number of events kept until the host app polls them. Further events are
+/// dropped.
+const EVENT_QUEUE_CAPACITY: usize = 256;
+
+/// How often registered callbacks are fed from the queue.
+const DISPATCH_INTERVAL: Duration = Duration::from_millis(10);
+
+/// What the engine is doing, as reported to the host app. Tone and silence
+/// events are emitted when the segment is rendered, i.e. up to one buffer
+/// before it is heard.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum PlaybackEvent {
+    ToneStarted {
+        channel: Channel,
+        frequency_hz: f32,
+        duration_ms: f32,
+    },
+    SilenceStarted {
+        channel: Channel,
+        duration_ms: f32,
+    },
+    StreamStopped,
+    /// The stream failed or was lost. The message is available from
+    /// `last_error`.
+    StreamError(ErrorCode),
+    /// A timed session has faded out. The stream stops after this.
+    SessionCompleted,
+    /// The daily noise dose has reached the warning share of the budget.
+    DoseWarning,
+    /// The daily noise dose is used up. With auto stop enabled the output
+    /// fades out and the stream stops.
+    DoseLimitReached,
+    /// The tones of a pitch matching trial or an audiometry presentation have
+    /// been played, so the patient can answer.
+    TestTonesPlayed,
+}
+
+/// Kind of a `RawPlaybackEvent`.
+#[repr(i32)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PlaybackEventKind {
+    ToneStarted = 0,
+    SilenceStarted = 1,
+    StreamStopped = 2,
+    StreamError = 3,
+    SessionCompleted = 4,
+    DoseWarning = 5,
+    DoseLimitReached = 6,
+    TestTonesPlayed = 7,
+}
+
+/// `PlaybackEvent` as passed over the C API. Fields that do not apply to the
+/// kind are zero.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct RawPlaybackEvent {
+    pub kind: i32,
+    pub channel: i32,
+    pub frequency_hz: f32,
+    pub duration_ms: f32,
+    pub error_code: i32,
+}
+
+impl PlaybackEvent {
+    pub fn kind(&self) -> PlaybackEventKind {
+        match self {
+            PlaybackEvent::ToneStarted { .. } => PlaybackEventKind::ToneStarted,
+            PlaybackEvent::SilenceStarted { .. } => PlaybackEventKind::SilenceStarted,
+            PlaybackEvent::StreamStopped => PlaybackEventKind::StreamStopped,
+            PlaybackEvent::StreamError(_) => PlaybackEventKind::StreamError,
+            PlaybackEvent::SessionCompleted => PlaybackEventKind::SessionCompleted,
+            PlaybackEvent::DoseWarning => PlaybackEventKind::DoseWarning,
+            PlaybackEvent::DoseLimitReached => PlaybackEventKind::DoseLimitReached,
+            PlaybackEvent::TestTonesPlayed => PlaybackEventKind::TestTonesPlayed,
+        }
+    }
+}
+
+impl From<PlaybackEvent> for RawPlaybackEvent {
+    fn from(event: PlaybackEvent) -> Self {
+        let raw = RawPlaybackEvent {
+            kind: event.kind() as i32,
+            ..Default::default()
+        };
+        match event {
+            PlaybackEvent::ToneStarted {
+                channel,
+                frequency_hz,
+                duration_ms,
+            } => RawPlaybackEvent {
+                channel: channel as i32,
+                frequency_hz,
+                duration_ms,
+                ..raw
+            },
+            PlaybackEvent::SilenceStarted {
+                channel,
+                duration_ms,
+            } => RawPlaybackEvent {
+                channel: channel as i32,
+                duration_ms,
+                ..raw
+            },
+            PlaybackEvent::StreamStopped
+            | PlaybackEvent::SessionCompleted
+            | PlaybackEvent::DoseWarning
+            | PlaybackEvent::DoseLimitReached
+            | PlaybackEvent::TestTonesPlayed => raw,
+            PlaybackEvent::StreamError(code) => RawPlaybackEvent {
+                error_code: code as i32,
+                ..raw
+            },
+        }
+    }
+}
+
+struct Slot {
+    /// Position the slot is ready for: equal to the enqueue position when
+    /// free, one past it when holding an event.
+    sequence: AtomicUsize,
+    event: UnsafeCell<MaybeUninit<PlaybackEvent>>,
+}
+
+/// Bounded lock-free queue of playback events. Any thread may push or pop,
+/// including the audio thread, which never blocks on it.
+pub struct EventQueue {
+    slots: Box<[Slot]>,
+    enqueue_pos: AtomicUsize,
+    dequeue_pos: AtomicUsize,
+}
+
+// Slots are only accessed by the thread that claimed them through the
+// sequence numbers
+unsafe impl Sync for EventQueue {}
+
+impl EventQueue {
+    pub fn new() -> Self {
+        Self::with_capacity(EVENT_QUEUE_CAPACITY)
+    }
+
+    /// Creates a queue holding `capacity` events, rounded up to a power of
+    /// two.
+    pub fn with_capacity(capacity: usize) -> Self {
+        let capacity = capacity.max(2).next_power_of_two();
+        Self {
+            slots: (0..capacity)
+                .map(|i| Slot {
+                    sequence: AtomicUsize::new(i),
+                    event: UnsafeCell::new(MaybeUninit::uninit()),
+                })
+                .collect(),
+            enqueue_pos: AtomicUsize::new(0),
+            dequeue_pos: AtomicUsize::new(0),
+        }
+    }
+
+    /// Appends an event. Returns false and drops it if the queue is full.
+    pub fn push(&self, event: PlaybackEvent) -> bool {
+        let mask = self.slots.len() - 1;
+        let mut pos = self.enqueue_pos.load(Ordering::Relaxed);
+        loop {
+            let slot = &self.slots[pos & mask];
+            let sequence = slot.sequence.load(Ordering::Acquire);
+            match sequence.wrapping_sub(pos) as isize {
+                0 => match self.enqueue_pos.compare_exchange_weak(
+                    pos,
+                    pos.wrapping_add(1),
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        unsafe { (*slot.event.get()).write(event) };
+                        slot.sequence.store(pos.wrapping_add(1), Ordering::Release);
+                        return true;
+                    }
+                    Err(current) => pos = current,
+                },
+                // The slot still holds an event from the previous lap
+                diff if diff < 0 => return false,
+                _ => pos = self.enqueue_pos.load(Ordering::Relaxed),
+            }
+        }
+    }
+
+    /// Removes the oldest event.
+    pub fn pop(&self) -> Option<PlaybackEvent> {
+        let mask = self.slots.len() - 1;
+        let mut pos = self.dequeue_pos.load(Ordering::Relaxed);
+        loop {
+            let slot = &self.slots[pos & mask];
+            let sequence = slot.sequence.load(Ordering::Acquire);
+            match sequence.wrapping_sub(pos.wrapping_add(1)) as isize {
+                0 => match self.dequeue_pos.compare_exchange_weak(
+                    pos,
+                    pos.wrapping_add(1),
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        let event = unsafe { (*slot.event.get()).assume_init_read() };
+                        slot.sequence
+                            .store(pos.wrapping_add(mask + 1), Ordering::Release);
+                        return Some(event);
+                    }
+                    Err(current) => pos = current,
+                },
+                // Nothing has been pushed to the slot yet
+                diff if diff < 0 => return None,
+                _ => pos = self.dequeue_pos.load(Ordering::Relaxed),
+            }
+        }
+    }
+}
+
+impl Default for EventQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub type EventCallback = Box<dyn FnMut(PlaybackEvent) + Send + 'static>;
+
+/// Thread that drains the queue into a registered callback, so that the
+/// callback never runs on the audio thread.
+pub(crate) struct EventDispatcher {
+    running: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl EventDispatcher {
+    pub(crate) fn spawn(events: Arc<EventQueue>, mut callback: EventCallback) -> Self {
+        let running = Arc::new(AtomicBool::new(true));
+        let thread = {
+            let running = running.clone();
+            thread::spawn(move || {
+                // Returns false once the callback has panicked
+                let mut dispatch = || {
+                    while let Some(event) = events.pop() {
+                        let result = catch_panic(|| {
+                            callback(event);
+                            Ok(())
+                        });
+                        if let Err(e) = result {
+                            record_error(&e);
+                            return false;
+                        }
+                    }
+                    true
+                };
+                loop {
+                    // Events pushed before the dispatcher is dropped are
+                    // still delivered by the last round
+                    let stopping = !running.load(Ordering::Acquire);
+                    if !dispatch() || stopping {
+                        break;
+                    }
+                    thread::sleep(DISPATCH_INTERVAL);
+                }
+            })
+        };
+        Self {
+            running,
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for EventDispatcher {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Release);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    fn tone(frequency_hz: f32) -> PlaybackEvent {
+        PlaybackEvent::ToneStarted {
+            channel: Channel::Left,
+            frequency_hz,
+            duration_ms: 200.0,
+        }
+    }
+
+    #[test]
+    fn test_queue_is_bounded_and_ordered() {
+        let queue = EventQueue::with_capacity(4);
+        for lap in 0..3 {
+            for i in 0..4 {
+                assert!(queue.push(tone((lap * 4 + i) as f32)));
+            }
+            assert!(!queue.push(PlaybackEvent::StreamStopped));
+            for i in 0..4 {
+                assert_eq!(queue.pop(), Some(tone((lap * 4 + i) as f32)));
+            }
+            assert_eq!(queue.pop(), None);
+        }
+    }
+
+    #[test]
+    fn test_concurrent_producers() {
+        let queue = Arc::new(EventQueue::with_capacity(4096));
+        let producers: Vec<_> = (0..4)
+            .map(|p| {
+                let queue = queue.clone();
+                thread::spawn(move || {
+                    for i in 0..1000 {
+                        assert!(queue.push(tone((p * 1000 + i) as f32)));
+                    }
+                })
+            })
+            .collect();
+        for producer in producers {
+            producer.join().unwrap();
+        }
+
+        let mut frequencies = Vec::new();
+        while let Some(PlaybackEvent::ToneStarted { frequency_hz, .. }) = queue.pop() {
+            frequencies.push(frequency_hz as usize);
+        }
+        frequencies.sort_unstable();
+        assert_eq!(frequencies, (0..4000).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_dispatcher_calls_back() {
+        let queue = Arc::new(EventQueue::new());
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let dispatcher = {
+            let received = received.clone();
+            EventDispatcher::spawn(
+                queue.clone(),
+                Box::new(move |event| received.lock().unwrap().push(event)),
+            )
+        };
+        queue.push(PlaybackEvent::StreamError(ErrorCode::AudioOutput));
+        queue.push(PlaybackEvent::StreamStopped);
+        for _ in 0..100 {
+            if received.lock().unwrap().len() == 2 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        // Pushed right before the drop, within one dispatch interval
+        queue.push(PlaybackEvent::SessionCompleted);
+        drop(dispatcher);
+        assert_eq!(
+            *received.lock().unwrap(),
+            [
+                PlaybackEvent::StreamError(ErrorCode::AudioOutput),
+                PlaybackEvent::StreamStopped,
+                PlaybackEvent::SessionCompleted
+            ]
+        );
+    }
+}
@@ -0,0 +1,440 @@
+use crate::ear_curves::interpolate;
+use crate::{Channel, Error};
+use std::f64::consts::PI;
+use std::fs;
+use std::path::Path;
+
+/// Sample rate the biquads of a parametric EQ are designed for. AutoEq and
+/// Equalizer APO use the coefficients of the Audio EQ Cookbook, whose
+/// response differs slightly between sample rates near Nyquist.
+const DESIGN_SAMPLE_RATE: f64 = 48000.0;
+/// Q of shelf and pass filters that give none, as in Equalizer APO.
+const DEFAULT_Q: f32 = std::f32::consts::FRAC_1_SQRT_2;
+
+/// Shape of a parametric EQ band.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FilterType {
+    Peak,
+    LowShelf,
+    HighShelf,
+    LowPass,
+    HighPass,
+}
+
+impl FilterType {
+    /// Parses the filter names of Equalizer APO, e.g. `PK` or `LSC`.
+    fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_uppercase().as_str() {
+            "PK" | "PEQ" => Some(FilterType::Peak),
+            "LS" | "LSC" => Some(FilterType::LowShelf),
+            "HS" | "HSC" => Some(FilterType::HighShelf),
+            "LP" | "LPQ" => Some(FilterType::LowPass),
+            "HP" | "HPQ" => Some(FilterType::HighPass),
+            _ => None,
+        }
+    }
+}
+
+/// One band of a parametric EQ.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct EqFilter {
+    pub filter_type: FilterType,
+    pub frequency_hz: f32,
+    /// Ignored by the pass filters.
+    pub gain_db: f32,
+    pub q: f32,
+}
+
+impl EqFilter {
+    pub fn validate(&self) -> Result<(), Error> {
+        if !(self.frequency_hz > 0.0 && (self.frequency_hz as f64) < DESIGN_SAMPLE_RATE / 2.0) {
+            return Err(Error::InvalidArgument("filter frequency"));
+        }
+        if !self.gain_db.is_finite() {
+            return Err(Error::InvalidArgument("filter gain"));
+        }
+        if !(self.q > 0.0 && self.q.is_finite()) {
+            return Err(Error::InvalidArgument("filter Q"));
+        }
+        Ok(())
+    }
+
+    /// Gain of the filter at `frequency_hz`.
+    pub fn response_db(&self, frequency_hz: f32) -> f32 {
+        let a = 10.0_f64.powf(self.gain_db as f64 / 40.0);
+        let w0 = 2.0 * PI * self.frequency_hz as f64 / DESIGN_SAMPLE_RATE;
+        let (sin, cos) = w0.sin_cos();
+        let alpha = sin / (2.0 * self.q as f64);
+        let shelf = 2.0 * a.sqrt() * alpha;
+        // Audio EQ Cookbook coefficients, b and then a
+        let ([b0, b1, b2], [a0, a1, a2]) = match self.filter_type {
+            FilterType::Peak => (
+                [1.0 + alpha * a, -2.0 * cos, 1.0 - alpha * a],
+                [1.0 + alpha / a, -2.0 * cos, 1.0 - alpha / a],
+            ),
+            FilterType::LowShelf => (
+                [
+                    a * ((a + 1.0) - (a - 1.0) * cos + shelf),
+                    2.0 * a * ((a - 1.0) - (a + 1.0) * cos),
+                    a * ((a + 1.0) - (a - 1.0) * cos - shelf),
+                ],
+                [
+                    (a + 1.0) + (a - 1.0) * cos + shelf,
+                    -2.0 * ((a - 1.0) + (a + 1.0) * cos),
+                    (a + 1.0) + (a - 1.0) * cos - shelf,
+                ],
+            ),
+            FilterType::HighShelf => (
+                [
+                    a * ((a + 1.0) + (a - 1.0) * cos + shelf),
+                    -2.0 * a * ((a - 1.0) + (a + 1.0) * cos),
+                    a * ((a + 1.0) + (a - 1.0) * cos - shelf),
+                ],
+                [
+                    (a + 1.0) - (a - 1.0) * cos + shelf,
+                    2.0 * ((a - 1.0) - (a + 1.0) * cos),
+                    (a + 1.0) - (a - 1.0) * cos - shelf,
+                ],
+            ),
+            FilterType::LowPass => (
+                [(1.0 - cos) / 2.0, 1.0 - cos, (1.0 - cos) / 2.0],
+                [1.0 + alpha, -2.0 * cos, 1.0 - alpha],
+            ),
+            FilterType::HighPass => (
+                [(1.0 + cos) / 2.0, -(1.0 + cos), (1.0 + cos) / 2.0],
+                [1.0 + alpha, -2.0 * cos, 1.0 - alpha],
+            ),
+        };
+        // Squared magnitude of a second order polynomial in e^-jw
+        let w = 2.0 * PI * frequency_hz as f64 / DESIGN_SAMPLE_RATE;
+        let power = |c0: f64, c1: f64, c2: f64| {
+            c0 * c0
+                + c1 * c1
+                + c2 * c2
+                + 2.0 * (c0 * c1 + c1 * c2) * w.cos()
+                + 2.0 * c0 * c2 * (2.0 * w).cos()
+        };
+        (10.0 * (power(b0, b1, b2) / power(a0, a1, a2)).log10()) as f32
+    }
+}
+
+/// Equalization of one ear: parametric bands and a graphic EQ, which add up.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct HeadphoneEq {
+    pub filters: Vec<EqFilter>,
+    /// `(frequency_hz, gain_db)` points sorted by frequency, interpolated on
+    /// log frequency and held beyond the outermost points.
+    pub graphic: Vec<(f32, f32)>,
+}
+
+impl HeadphoneEq {
+    pub fn is_empty(&self) -> bool {
+        self.filters.is_empty() && self.graphic.is_empty()
+    }
+
+    /// Gain of the equalization at `frequency_hz`.
+    pub fn response_db(&self, frequency_hz: f32) -> f32 {
+        let filters: f32 = self
+            .filters
+            .iter()
+            .map(|filter| filter.response_db(frequency_hz))
+            .sum();
+        filters + interpolate(&self.graphic, frequency_hz).unwrap_or_default()
+    }
+}
+
+/// The equalization that flattens the frequency response of a headphone,
+/// as published by AutoEq for Equalizer APO.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct HeadphoneProfile {
+    pub left: HeadphoneEq,
+    pub right: HeadphoneEq,
+}
+
+impl HeadphoneProfile {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn ear(&self, channel: Channel) -> &HeadphoneEq {
+        match channel {
+            Channel::Left => &self.left,
+            Channel::Right => &self.right,
+        }
+    }
+
+    pub fn ear_mut(&mut self, channel: Channel) -> &mut HeadphoneEq {
+        match channel {
+            Channel::Left => &mut self.left,
+            Channel::Right => &mut self.right,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.left.is_empty() && self.right.is_empty()
+    }
+
+    /// Parses an Equalizer APO configuration as written by AutoEq, with
+    /// `Filter` lines of a parametric EQ or a `GraphicEQ` line. Both apply to
+    /// the two ears unless a `Channel: L` or `Channel: R` line selects one.
+    /// The `Preamp` line is ignored, as the output ceiling already keeps the
+    /// boosted tones from clipping.
+    pub fn parse(text: &str) -> Result<Self, Error> {
+        let mut profile = HeadphoneProfile::new();
+        let mut ears = Channel::ALL.to_vec();
+        for (number, line) in text.lines().enumerate() {
+            let invalid = || Error::Parse(format!("invalid headphone profile line {}", number + 1));
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (directive, value) = line.split_once(':').ok_or_else(invalid)?;
+            let directive = directive.trim();
+            if directive.eq_ignore_ascii_case("preamp") {
+                continue;
+            } else if directive.eq_ignore_ascii_case("channel") {
+                ears = parse_channels(value);
+            } else if directive.eq_ignore_ascii_case("graphiceq") {
+                let points = parse_graphic(value).ok_or_else(invalid)?;
+                for &channel in &ears {
+                    let eq = profile.ear_mut(channel);
+                    if !eq.graphic.is_empty() {
+                        return Err(invalid());
+                    }
+                    eq.graphic = points.clone();
+                }
+            } else if directive
+                .split_whitespace()
+                .next()
+                .is_some_and(|d| d.eq_ignore_ascii_case("filter"))
+            {
+                if let Some(filter) = parse_filter(value).ok_or_else(invalid)? {
+                    for &channel in &ears {
+                        profile.ear_mut(channel).filters.push(filter);
+                    }
+                }
+            } else {
+                return Err(invalid());
+            }
+        }
+        if profile.is_empty() {
+            return Err(Error::Parse("not a headphone profile".to_string()));
+        }
+        Ok(profile)
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let text = fs::read_to_string(path).map_err(|e| Error::Io(e.to_string()))?;
+        Self::parse(&text)
+    }
+}
+
+/// Ears named by a `Channel` line. Speaker channels other than the front
+/// pair select neither ear.
+fn parse_channels(value: &str) -> Vec<Channel> {
+    let names: Vec<String> = value
+        .split_whitespace()
+        .map(str::to_ascii_uppercase)
+        .collect();
+    if names.iter().any(|name| name == "ALL") {
+        return Channel::ALL.to_vec();
+    }
+    Channel::ALL
+        .into_iter()
+        .filter(|channel| {
+            let name = match channel {
+                Channel::Left => "L",
+                Channel::Right => "R",
+            };
+            names.iter().any(|n| n == name)
+        })
+        .collect()
+}
+
+/// Parses `20 -1.5; 21 -1.6; ...`, with increasing frequencies.
+fn parse_graphic(value: &str) -> Option<Vec<(f32, f32)>> {
+    let mut points: Vec<(f32, f32)> = Vec::new();
+    for point in value.split(';').map(str::trim).filter(|p| !p.is_empty()) {
+        let mut fields = point.split_whitespace().map(str::parse::<f32>);
+        let (Some(Ok(frequency_hz)), Some(Ok(gain_db)), None) =
+            (fields.next(), fields.next(), fields.next())
+        else {
+            return None;
+        };
+        let increasing = points.last().is_none_or(|&(last, _)| frequency_hz > last);
+        if !(increasing && frequency_hz > 0.0 && gain_db.is_finite()) {
+            return None;
+        }
+        points.push((frequency_hz, gain_db));
+    }
+    (!points.is_empty()).then_some(points)
+}
+
+/// Parses `ON PK Fc 1000 Hz Gain -3.0 dB Q 1.41`, `None` for a filter that
+/// is off.
+fn parse_filter(value: &str) -> Option<Option<EqFilter>> {
+    let mut tokens = value.split_whitespace();
+    let on = match tokens.next()?.to_ascii_uppercase().as_str() {
+        "ON" => true,
+        "OFF" => false,
+        _ => return None,
+    };
+    let filter_type = FilterType::from_name(tokens.next()?)?;
+    let (mut frequency_hz, mut gain_db, mut q) = (None, 0.0, DEFAULT_Q);
+    while let Some(key) = tokens.next() {
+        match key.to_ascii_uppercase().as_str() {
+            "HZ" | "DB" => continue,
+            "FC" => frequency_hz = Some(tokens.next()?.parse().ok()?),
+            "GAIN" => gain_db = tokens.next()?.parse().ok()?,
+            "Q" => q = tokens.next()?.parse().ok()?,
+            _ => return None,
+        }
+    }
+    let filter = EqFilter {
+        filter_type,
+        frequency_hz: frequency_hz?,
+        gain_db,
+        q,
+    };
+    filter.validate().ok()?;
+    Some(on.then_some(filter))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ear_curves::hz_to_midi;
+    use crate::test_util::{STEREO_48K, hold_tones, level_db};
+    use crate::{AudioState, ChannelParams, GainCurve, SharedParams};
+
+    #[test]
+    fn test_filter_response() {
+        let peak = EqFilter {
+            filter_type: FilterType::Peak,
+            frequency_hz: 1000.0,
+            gain_db: -6.0,
+            q: 1.41,
+        };
+        assert!((peak.response_db(1000.0) + 6.0).abs() < 0.01);
+        assert!(peak.response_db(100.0).abs() < 0.1);
+        assert!(peak.response_db(10000.0).abs() < 0.1);
+
+        let shelf = EqFilter {
+            filter_type: FilterType::HighShelf,
+            frequency_hz: 4000.0,
+            gain_db: 8.0,
+            q: 0.7,
+        };
+        assert!(shelf.response_db(200.0).abs() < 0.1);
+        assert!((shelf.response_db(4000.0) - 4.0).abs() < 0.05);
+        assert!((shelf.response_db(16000.0) - 8.0).abs() < 0.5);
+
+        let low_pass = EqFilter {
+            filter_type: FilterType::LowPass,
+            frequency_hz: 2000.0,
+            gain_db: 0.0,
+            q: DEFAULT_Q,
+        };
+        assert!((low_pass.response_db(2000.0) + 3.01).abs() < 0.01);
+        assert!(low_pass.response_db(8000.0) < -20.0);
+    }
+
+    #[test]
+    fn test_parse_parametric() {
+        let profile = HeadphoneProfile::parse(
+            "Preamp: -6.2 dB\n\
+             Filter 1: ON LSC Fc 105 Hz Gain 5.5 dB Q 0.70\n\
+             Filter 2: ON PK Fc 2660 Hz Gain -3.1 dB Q 1.41\n\
+             Filter 3: OFF PK Fc 5000 Hz Gain 9.0 dB Q 2.00\n\
+             \n\
+             # Right ear only\n\
+             Channel: R\n\
+             Filter: ON HSC Fc 10000 Hz Gain -2.0 dB Q 0.70\n",
+        )
+        .unwrap();
+        assert_eq!(profile.left.filters.len(), 2);
+        assert_eq!(profile.right.filters.len(), 3);
+        assert_eq!(
+            profile.left.filters[1],
+            EqFilter {
+                filter_type: FilterType::Peak,
+                frequency_hz: 2660.0,
+                gain_db: -3.1,
+                q: 1.41,
+            }
+        );
+        assert_eq!(profile.right.filters[2].filter_type, FilterType::HighShelf);
+        assert!((profile.left.response_db(2660.0) + 3.1).abs() < 0.1);
+
+        for invalid in [
+            "",
+            "Preamp: -6.2 dB\n",
+            "Filter 1: ON XX Fc 100 Hz Gain 1 dB Q 1\n",
+            "Filter 1: ON PK Gain 1 dB Q 1\n",
+            "Filter 1: ON PK Fc 100 Hz Gain 1 dB Q 0\n",
+            "Filter 1: ON PK Fc 30000 Hz Gain 1 dB Q 1\n",
+            "Convolution: impulse.wav\n",
+        ] {
+            assert!(HeadphoneProfile::parse(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_parse_graphic() {
+        let profile =
+            HeadphoneProfile::parse("GraphicEQ: 20 -1.5; 1000 0.0; 8000 -6.0; 16000 -9.5\n")
+                .unwrap();
+        assert_eq!(profile.left, profile.right);
+        assert_eq!(profile.left.graphic.len(), 4);
+        assert_eq!(profile.left.response_db(1000.0), 0.0);
+        // Halfway between 1 and 8 kHz on a log scale
+        assert!((profile.left.response_db(2828.4) + 3.0).abs() < 0.01);
+        assert_eq!(profile.left.response_db(20000.0), -9.5);
+
+        for invalid in [
+            "GraphicEQ: 20 -1.5; 10 0.0\n",
+            "GraphicEQ: 20\n",
+            "GraphicEQ: 20 -1.5\nGraphicEQ: 30 -1.5\n",
+        ] {
+            assert!(HeadphoneProfile::parse(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_headphone_correction() {
+        // Left tones at 1 kHz, right ones at 8 kHz
+        let (left, right) = (hz_to_midi(1000.0), hz_to_midi(8000.0));
+        let params = SharedParams::new([
+            ChannelParams::new(-40.0, left, left),
+            ChannelParams::new(-40.0, right, right),
+        ]);
+        hold_tones(&params);
+        let profile = HeadphoneProfile::parse(
+            "Preamp: -6.0 dB\n\
+             Channel: L\n\
+             Filter 1: ON PK Fc 1000 Hz Gain -4.0 dB Q 1.00\n\
+             Channel: R\n\
+             GraphicEQ: 20 0.0; 8000 6.0; 20000 6.0\n",
+        )
+        .unwrap();
+        params
+            .compensation()
+            .set_headphone_curve(&GainCurve::from_headphone_profile(&profile));
+        let format = STEREO_48K;
+        let mut state = AudioState::new(format, params.clone(), 0);
+
+        let mut data = vec![0.0; 48000 * 2];
+        state.render(&mut data, format);
+        assert!((level_db(&data[24000 * 2..], 0) + 44.0).abs() < 0.05);
+        assert!((level_db(&data[24000 * 2..], 1) + 34.0).abs() < 0.05);
+
+        params
+            .compensation()
+            .set_headphone_curve(&GainCurve::from_headphone_profile(&HeadphoneProfile::new()));
+        state.render(&mut data, format);
+        // The correction fades out with the gain ramp instead of jumping
+        assert!(level_db(&data[..480 * 2], 1) > -35.5);
+        assert!((level_db(&data[24000 * 2..], 0) + 40.0).abs() < 0.05);
+        assert!((level_db(&data[24000 * 2..], 1) + 40.0).abs() < 0.05);
+    }
+}
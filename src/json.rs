@@ -0,0 +1,242 @@
+use crate::Error;
+
+/// A parsed JSON value. Only what the import formats need is kept: numbers
+/// are `f64` and objects keep their members in file order.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum JsonValue {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<JsonValue>),
+    Object(Vec<(String, JsonValue)>),
+}
+
+impl JsonValue {
+    /// Member `key` of an object.
+    pub(crate) fn get(&self, key: &str) -> Option<&JsonValue> {
+        match self {
+            JsonValue::Object(members) => members.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_f32(&self) -> Option<f32> {
+        match self {
+            JsonValue::Number(n) => Some(*n as f32),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_bool(&self) -> Option<bool> {
+        match self {
+            JsonValue::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_array(&self) -> Option<&[JsonValue]> {
+        match self {
+            JsonValue::Array(items) => Some(items),
+            _ => None,
+        }
+    }
+}
+
+/// Parses a complete JSON document.
+pub(crate) fn parse(text: &str) -> Result<JsonValue, Error> {
+    let mut parser = Parser {
+        bytes: text.as_bytes(),
+        pos: 0,
+    };
+    let value = parser.value(0)?;
+    parser.skip_whitespace();
+    if parser.pos != parser.bytes.len() {
+        return Err(parser.error());
+    }
+    Ok(value)
+}
+
+/// Nesting accepted before giving up, so that malformed files cannot
+/// overflow the stack.
+const MAX_DEPTH: usize = 32;
+
+struct Parser<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn error(&self) -> Error {
+        Error::Parse(format!("invalid JSON at byte {}", self.pos))
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self
+            .bytes
+            .get(self.pos)
+            .is_some_and(u8::is_ascii_whitespace)
+        {
+            self.pos += 1;
+        }
+    }
+
+    fn peek(&mut self) -> Option<u8> {
+        self.skip_whitespace();
+        self.bytes.get(self.pos).copied()
+    }
+
+    fn expect(&mut self, byte: u8) -> Result<(), Error> {
+        if self.peek() != Some(byte) {
+            return Err(self.error());
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn literal(&mut self, literal: &str, value: JsonValue) -> Result<JsonValue, Error> {
+        if !self.bytes[self.pos..].starts_with(literal.as_bytes()) {
+            return Err(self.error());
+        }
+        self.pos += literal.len();
+        Ok(value)
+    }
+
+    fn value(&mut self, depth: usize) -> Result<JsonValue, Error> {
+        if depth > MAX_DEPTH {
+            return Err(self.error());
+        }
+        match self.peek().ok_or_else(|| self.error())? {
+            b'{' => {
+                self.pos += 1;
+                let mut members = Vec::new();
+                if self.peek() == Some(b'}') {
+                    self.pos += 1;
+                    return Ok(JsonValue::Object(members));
+                }
+                loop {
+                    let key = self.string()?;
+                    self.expect(b':')?;
+                    members.push((key, self.value(depth + 1)?));
+                    match self.peek() {
+                        Some(b',') => self.pos += 1,
+                        Some(b'}') => {
+                            self.pos += 1;
+                            return Ok(JsonValue::Object(members));
+                        }
+                        _ => return Err(self.error()),
+                    }
+                }
+            }
+            b'[' => {
+                self.pos += 1;
+                let mut items = Vec::new();
+                if self.peek() == Some(b']') {
+                    self.pos += 1;
+                    return Ok(JsonValue::Array(items));
+                }
+                loop {
+                    items.push(self.value(depth + 1)?);
+                    match self.peek() {
+                        Some(b',') => self.pos += 1,
+                        Some(b']') => {
+                            self.pos += 1;
+                            return Ok(JsonValue::Array(items));
+                        }
+                        _ => return Err(self.error()),
+                    }
+                }
+            }
+            b'"' => Ok(JsonValue::String(self.string()?)),
+            b't' => self.literal("true", JsonValue::Bool(true)),
+            b'f' => self.literal("false", JsonValue::Bool(false)),
+            b'n' => self.literal("null", JsonValue::Null),
+            _ => self.number(),
+        }
+    }
+
+    fn number(&mut self) -> Result<JsonValue, Error> {
+        let start = self.pos;
+        while self
+            .bytes
+            .get(self.pos)
+            .is_some_and(|b| b.is_ascii_digit() || b"+-.eE".contains(b))
+        {
+            self.pos += 1;
+        }
+        std::str::from_utf8(&self.bytes[start..self.pos])
+            .ok()
+            .and_then(|number| number.parse().ok())
+            .filter(|n: &f64| n.is_finite())
+            .map(JsonValue::Number)
+            .ok_or_else(|| self.error())
+    }
+
+    fn string(&mut self) -> Result<String, Error> {
+        self.expect(b'"')?;
+        let mut bytes = Vec::new();
+        loop {
+            let byte = *self.bytes.get(self.pos).ok_or_else(|| self.error())?;
+            self.pos += 1;
+            match byte {
+                b'"' => break,
+                b'\\' => {
+                    let escape = *self.bytes.get(self.pos).ok_or_else(|| self.error())?;
+                    self.pos += 1;
+                    let unescaped = match escape {
+                        b'"' | b'\\' | b'/' => escape as char,
+                        b'b' => '\u{8}',
+                        b'f' => '\u{c}',
+                        b'n' => '\n',
+                        b'r' => '\r',
+                        b't' => '\t',
+                        b'u' => {
+                            let hex = self.bytes.get(self.pos..self.pos + 4);
+                            let code = hex
+                                .and_then(|hex| std::str::from_utf8(hex).ok())
+                                .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                                .ok_or_else(|| self.error())?;
+                            self.pos += 4;
+                            // Surrogate pairs are not needed by the formats
+                            char::from_u32(code).unwrap_or(char::REPLACEMENT_CHARACTER)
+                        }
+                        _ => return Err(self.error()),
+                    };
+                    let mut buffer = [0; 4];
+                    bytes.extend_from_slice(unescaped.encode_utf8(&mut buffer).as_bytes());
+                }
+                _ => bytes.push(byte),
+            }
+        }
+        String::from_utf8(bytes).map_err(|_| self.error())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let value = parse(
+            r#" {"left": [{"f": 1000, "t": -5.5e0, "x": true}], "name": "a\"bé", "n": null} "#,
+        )
+        .unwrap();
+        let left = value.get("left").and_then(JsonValue::as_array).unwrap();
+        assert_eq!(left[0].get("f").and_then(JsonValue::as_f32), Some(1000.0));
+        assert_eq!(left[0].get("t").and_then(JsonValue::as_f32), Some(-5.5));
+        assert_eq!(left[0].get("x").and_then(JsonValue::as_bool), Some(true));
+        assert_eq!(
+            value.get("name"),
+            Some(&JsonValue::String("a\"bé".to_string()))
+        );
+        assert_eq!(value.get("n"), Some(&JsonValue::Null));
+        assert_eq!(value.get("right"), None);
+
+        assert_eq!(parse("[]"), Ok(JsonValue::Array(Vec::new())));
+        for invalid in ["", "{", "[1,]", "{\"a\" 1}", "tru", "1 2", "\"abc", "1e999"] {
+            assert!(parse(invalid).is_err(), "{invalid}");
+        }
+        assert!(parse(&"[".repeat(100)).is_err());
+    }
+}
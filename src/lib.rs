@@ -1,22 +1,118 @@
+// The exported functions receive raw player pointers from C / Dart and
+// null-check them before use.
+#![allow(clippy::not_unsafe_ptr_arg_deref)]
+
 use rand::Rng;
+use std::ffi::{CStr, CString, c_char};
 use std::slice::ChunksMut;
 use std::sync::Arc;
 use std::sync::Mutex;
-use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::time::Duration;
+
 mod audio;
 
+mod audiometry;
+pub use audiometry::{
+    AUDIOMETRY_FREQUENCIES_HZ, Audiogram, Audiometer, AudiometryPresentation, AudiometrySettings,
+    MIN_AUDIOMETRY_LEVEL_DB_HL, Threshold,
+};
+
+mod calibration;
+use calibration::{CalibrationParams, ReferenceTone};
+pub use calibration::{
+    CalibrationPoint, CalibrationTable, GainUnit, MAX_CALIBRATED_LEVEL_DB,
+    MAX_REFERENCE_LEVEL_DBFS, reference_threshold_spl_db,
+};
+
+mod compensation;
+pub use compensation::{
+    CompensationParams, GainCurve, HearingCompensation, MAX_COMPENSATION_GAIN_DB,
+    MAX_EQUAL_LOUDNESS_PHON, equal_loudness_spl_db,
+};
+
+mod dosimetry;
+use dosimetry::DoseMeter;
+pub use dosimetry::{
+    DEFAULT_FULL_SCALE_SPL_DB, DoseLimit, DoseParams, MAX_FULL_SCALE_SPL_DB, NoiseDose,
+};
+
+mod ear_curves;
+
+mod error;
+pub use error::{
+    Error, ErrorCode, MAX_GAIN_DB, MAX_MIDI_NOTE, MIN_MIDI_NOTE, clear_last_error, last_error,
+};
+
+mod events;
+pub use events::{EventCallback, EventQueue, PlaybackEvent, PlaybackEventKind, RawPlaybackEvent};
+
+mod headphone;
+pub use headphone::{EqFilter, FilterType, HeadphoneEq, HeadphoneProfile};
+
+mod json;
+
+mod limiter;
+use limiter::Limiter;
+pub use limiter::{MAX_OUTPUT_CEILING_DB, MIN_OUTPUT_CEILING_DB};
+
 mod oscillator;
 
+mod pitch_match;
+pub use pitch_match::{
+    PitchMatchResult, PitchMatchSettings, PitchMatcher, PitchResponse, PitchTrial,
+};
+
+mod render;
+mod schedule;
+use schedule::ScheduleParams;
+pub use schedule::{DurationDistribution, Schedule, ScheduleError};
+
+pub use render::{RenderOptions, WavFormat, render_to_wav};
+
 mod taus88;
+mod test_tones;
+pub use test_tones::TestToneParams;
+use test_tones::TestTonePlayer;
+
 use crate::taus88::SeedableRng;
 use crate::taus88::Taus88;
 
-use audio::AudioPlayer;
+pub use audio::null_backend::{NullBackend, NullBackendHandle};
+pub use audio::{
+    AudioBackend, AudioPlayer, OutputDevice, RenderCallback, StreamFormat, StreamState,
+    entropy_seed,
+};
 
 // Global audio player instance for JNI
 static AUDIO_PLAYER: Mutex<Option<Box<AudioPlayer>>> = Mutex::new(None);
 
-const FADE_SAMPLES: u64 = 64;
+/// Default attack and release of each tone.
+pub const DEFAULT_TONE_FADE_MS: f32 = 5.0;
+/// Longest accepted tone fade.
+pub const MAX_TONE_FADE_MS: f32 = 100.0;
+/// Range covered by `FadeCurve::Exponential`.
+const EXPONENTIAL_FADE_RANGE_DB: f32 = 60.0;
+/// Default time over which gain changes are ramped.
+pub const DEFAULT_GAIN_RAMP_MS: f32 = 50.0;
+/// Longest accepted gain ramp.
+pub const MAX_GAIN_RAMP_MS: f32 = 5000.0;
+/// Default fade applied when pausing, resuming or stopping.
+pub const DEFAULT_TAIL_FADE_MS: f32 = 100.0;
+/// Longest accepted tail fade.
+pub const MAX_TAIL_FADE_MS: f32 = 5000.0;
+/// Fade at the end of a timed session.
+pub const SESSION_END_FADE_MS: u64 = 5000;
+/// Highest linear channel gain, reached at `MAX_CALIBRATED_LEVEL_DB`.
+const MAX_LINEAR_GAIN: f32 = 1e6;
+
+/// Reads a float parameter on the audio thread, which must not be driven by
+/// NaN or infinite values. NaN reads as `min`.
+fn load_clamped(value: &AtomicU32, min: f32, max: f32) -> f32 {
+    f32::from_bits(value.load(Ordering::Relaxed))
+        .max(min)
+        .min(max)
+}
 
 #[derive(PartialEq, Copy, Clone)]
 enum AudioPhase {
@@ -40,95 +136,564 @@ enum SegmentParams {
     Silence(SilenceParams),
 }
 
-pub struct AudioState {
+/// Lock-free parameters of one output channel, shared between the player and
+/// the audio thread.
+#[derive(Clone)]
+pub struct ChannelParams {
+    // Linear gain in the low 32 bits and its `GainUnit` in the high bits, so
+    // that both change together
+    gain: Arc<AtomicU64>,
+    min_midi_note: Arc<AtomicU32>,
+    max_midi_note: Arc<AtomicU32>,
+}
+
+impl ChannelParams {
+    pub fn new(gain_db: f32, min_midi_note: f32, max_midi_note: f32) -> Self {
+        let params = Self {
+            gain: Arc::new(AtomicU64::new(0)),
+            min_midi_note: Arc::new(AtomicU32::new(0)),
+            max_midi_note: Arc::new(AtomicU32::new(0)),
+        };
+        params.set_gain_db(gain_db);
+        params.set_frequency_range(min_midi_note, max_midi_note);
+        params
+    }
+
+    pub fn set_gain_db(&self, gain_db: f32) {
+        self.set_gain(gain_db, GainUnit::Dbfs);
+    }
+
+    /// Sets the gain in dBFS, or the level in dB SPL or dB HL.
+    pub fn set_gain(&self, gain_db: f32, unit: GainUnit) {
+        let linear_gain = 10.0_f32.powf(gain_db / 20.0);
+        self.gain.store(
+            (unit as u64) << 32 | linear_gain.to_bits() as u64,
+            Ordering::Relaxed,
+        );
+    }
+
+    /// Returns the linear gain and its unit.
+    fn gain(&self) -> (f32, GainUnit) {
+        let bits = self.gain.load(Ordering::Relaxed);
+        let unit = GainUnit::from_index((bits >> 32) as i32).unwrap_or_default();
+        (f32::from_bits(bits as u32), unit)
+    }
+
+    pub fn set_frequency_range(&self, min_midi_note: f32, max_midi_note: f32) {
+        self.min_midi_note
+            .store(min_midi_note.to_bits(), Ordering::Relaxed);
+        self.max_midi_note
+            .store(max_midi_note.to_bits(), Ordering::Relaxed);
+    }
+}
+
+/// How tone frequencies are drawn from the configured note range.
+#[derive(PartialEq, Eq, Copy, Clone, Debug, Default)]
+pub enum FrequencyMode {
+    /// Any frequency within the range, uniformly distributed on a log scale.
+    #[default]
+    Continuous = 0,
+    /// Only the semitones within the range.
+    Semitone = 1,
+}
+
+impl FrequencyMode {
+    pub fn from_index(index: i32) -> Option<Self> {
+        match index {
+            0 => Some(FrequencyMode::Continuous),
+            1 => Some(FrequencyMode::Semitone),
+            _ => None,
+        }
+    }
+
+    /// Draws a MIDI note number within `min_midi..=max_midi`.
+    fn sample_midi<R: Rng>(self, rng: &mut R, min_midi: f32, max_midi: f32) -> f32 {
+        if self == FrequencyMode::Semitone {
+            let lowest = min_midi.ceil() as i32;
+            let highest = max_midi.floor() as i32;
+            if lowest <= highest {
+                return rng.random_range(lowest..=highest) as f32;
+            }
+            // No semitone inside the range, fall back to a continuous draw
+        }
+        // MIDI notes are linear in log frequency. `min`/`max` instead of
+        // `clamp` so that an unordered range cannot panic on the audio thread.
+        (min_midi + rng.random::<f32>() * (max_midi - min_midi))
+            .max(min_midi)
+            .min(max_midi)
+    }
+}
+
+/// Shape of the attack and release of each tone.
+#[derive(PartialEq, Eq, Copy, Clone, Debug, Default)]
+pub enum FadeCurve {
+    /// Straight ramp in amplitude. Its corners spread energy far from the
+    /// tone frequency.
+    Linear = 0,
+    /// Half a cosine period, starting and ending with zero slope.
+    #[default]
+    RaisedCosine = 1,
+    /// Straight ramp in dB over `EXPONENTIAL_FADE_RANGE_DB`, then a jump to
+    /// silence.
+    Exponential = 2,
+}
+
+impl FadeCurve {
+    pub fn from_index(index: i32) -> Option<Self> {
+        match index {
+            0 => Some(FadeCurve::Linear),
+            1 => Some(FadeCurve::RaisedCosine),
+            2 => Some(FadeCurve::Exponential),
+            _ => None,
+        }
+    }
+
+    /// Gain at `progress` (0 to 1) through an attack. Releases run the curve
+    /// backwards.
+    fn gain(self, progress: f32) -> f32 {
+        match self {
+            FadeCurve::Linear => progress,
+            FadeCurve::RaisedCosine => 0.5 - 0.5 * (std::f32::consts::PI * progress).cos(),
+            FadeCurve::Exponential => {
+                10.0_f32.powf((progress - 1.0) * EXPONENTIAL_FADE_RANGE_DB / 20.0)
+            }
+        }
+    }
+}
+
+/// Pause requests from the player, and their completion on the audio thread.
+#[derive(Clone, Default)]
+struct PauseControl {
+    requested: Arc<AtomicBool>,
+    /// Set once the output has faded out after a request.
+    faded_out: Arc<AtomicBool>,
+    // Set by `AudioPlayer::stop`, cleared by whoever reports the stopped stream
+    stop_requested: Arc<AtomicBool>,
+}
+
+/// All parameters shared between the player and the audio thread.
+#[derive(Clone)]
+pub struct SharedParams {
+    channels: [ChannelParams; 2],
+    schedule: Arc<ScheduleParams>,
+    frequency_mode: Arc<AtomicU32>,
+    gain_ramp_ms: Arc<AtomicU32>,
+    tone_fade_ms: Arc<AtomicU32>,
+    fade_curve: Arc<AtomicU32>,
+    tail_fade_ms: Arc<AtomicU32>,
+    // Linear, applied by the limiter and as a cap on the channel gains
+    output_ceiling: Arc<AtomicU32>,
+    dose: Arc<DoseParams>,
+    calibration: Arc<CalibrationParams>,
+    compensation: Arc<CompensationParams>,
+    test_tones: Arc<TestToneParams>,
+    pause: PauseControl,
+    // 0 plays until stopped
+    session_duration_ms: Arc<AtomicU64>,
+    session_elapsed_ms: Arc<AtomicU64>,
+}
+
+impl SharedParams {
+    pub fn new(channels: [ChannelParams; 2]) -> Self {
+        Self {
+            channels,
+            schedule: Arc::new(ScheduleParams::new(Schedule::default())),
+            frequency_mode: Arc::new(AtomicU32::new(FrequencyMode::default() as u32)),
+            gain_ramp_ms: Arc::new(AtomicU32::new(DEFAULT_GAIN_RAMP_MS.to_bits())),
+            tone_fade_ms: Arc::new(AtomicU32::new(DEFAULT_TONE_FADE_MS.to_bits())),
+            fade_curve: Arc::new(AtomicU32::new(FadeCurve::default() as u32)),
+            tail_fade_ms: Arc::new(AtomicU32::new(DEFAULT_TAIL_FADE_MS.to_bits())),
+            output_ceiling: Arc::new(AtomicU32::new(1.0_f32.to_bits())),
+            dose: Arc::new(DoseParams::new()),
+            calibration: Arc::new(CalibrationParams::new()),
+            compensation: Arc::new(CompensationParams::new()),
+            test_tones: Arc::new(TestToneParams::new()),
+            pause: PauseControl::default(),
+            session_duration_ms: Arc::new(AtomicU64::new(0)),
+            session_elapsed_ms: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    pub fn channel(&self, channel: Channel) -> &ChannelParams {
+        &self.channels[channel as usize]
+    }
+
+    pub fn schedule(&self) -> &ScheduleParams {
+        &self.schedule
+    }
+
+    pub fn dose(&self) -> &DoseParams {
+        &self.dose
+    }
+
+    pub fn calibration(&self) -> &CalibrationParams {
+        &self.calibration
+    }
+
+    pub fn compensation(&self) -> &CompensationParams {
+        &self.compensation
+    }
+
+    pub fn test_tones(&self) -> &TestToneParams {
+        &self.test_tones
+    }
+
+    pub fn frequency_mode(&self) -> FrequencyMode {
+        FrequencyMode::from_index(self.frequency_mode.load(Ordering::Relaxed) as i32)
+            .unwrap_or_default()
+    }
+
+    pub fn set_frequency_mode(&self, mode: FrequencyMode) {
+        self.frequency_mode.store(mode as u32, Ordering::Relaxed);
+    }
+
+    pub fn gain_ramp_ms(&self) -> f32 {
+        f32::from_bits(self.gain_ramp_ms.load(Ordering::Relaxed))
+    }
+
+    /// Sets the time over which gain changes are ramped. 0 applies them at
+    /// the next sample.
+    pub fn set_gain_ramp_ms(&self, ramp_ms: f32) {
+        self.gain_ramp_ms
+            .store(ramp_ms.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn tone_fade_ms(&self) -> f32 {
+        f32::from_bits(self.tone_fade_ms.load(Ordering::Relaxed))
+    }
+
+    /// Sets the attack and release of each tone. Applies from the next fade.
+    pub fn set_tone_fade_ms(&self, fade_ms: f32) {
+        self.tone_fade_ms
+            .store(fade_ms.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn fade_curve(&self) -> FadeCurve {
+        FadeCurve::from_index(self.fade_curve.load(Ordering::Relaxed) as i32).unwrap_or_default()
+    }
+
+    pub fn set_fade_curve(&self, curve: FadeCurve) {
+        self.fade_curve.store(curve as u32, Ordering::Relaxed);
+    }
+
+    pub fn tail_fade_ms(&self) -> f32 {
+        f32::from_bits(self.tail_fade_ms.load(Ordering::Relaxed))
+    }
+
+    /// Sets the fade applied when pausing, resuming or stopping.
+    pub fn set_tail_fade_ms(&self, fade_ms: f32) {
+        self.tail_fade_ms
+            .store(fade_ms.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn output_ceiling_db(&self) -> f32 {
+        20.0 * f32::from_bits(self.output_ceiling.load(Ordering::Relaxed)).log10()
+    }
+
+    /// Sets the highest level the output may reach in dBFS. Louder channel
+    /// gains are capped and peaks are limited to it.
+    pub fn set_output_ceiling_db(&self, ceiling_db: f32) {
+        let ceiling = 10.0_f32.powf(ceiling_db / 20.0);
+        self.output_ceiling
+            .store(ceiling.to_bits(), Ordering::Relaxed);
+    }
+
+    /// Fades the output out and holds the sequence until `resume`.
+    pub fn pause(&self) {
+        self.pause.requested.store(true, Ordering::Release);
+    }
+
+    /// Fades the output back in, continuing the sequence where it paused.
+    pub fn resume(&self) {
+        self.pause.requested.store(false, Ordering::Release);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.pause.requested.load(Ordering::Acquire)
+    }
+
+    /// Whether the output has faded out since `pause`.
+    pub fn is_faded_out(&self) -> bool {
+        self.pause.faded_out.load(Ordering::Acquire)
+    }
+
+    /// Fades out, after which the render callback ends the stream.
+    pub(crate) fn request_stop(&self) {
+        self.pause.stop_requested.store(true, Ordering::Release);
+    }
+
+    /// Clears a pending stop request, returning whether there was one.
+    pub(crate) fn take_stop_request(&self) -> bool {
+        self.pause.stop_requested.swap(false, Ordering::AcqRel)
+    }
+
+    /// Length of the session in ms, 0 if it plays until stopped.
+    pub fn session_duration_ms(&self) -> u64 {
+        self.session_duration_ms.load(Ordering::Relaxed)
+    }
+
+    /// Sets the length of the session, after which the output fades out
+    /// and stops. 0 plays until stopped.
+    pub fn set_session_duration_ms(&self, duration_ms: u64) {
+        self.session_duration_ms
+            .store(duration_ms, Ordering::Relaxed);
+    }
+
+    /// Time played in the current session, not counting pauses.
+    pub fn session_elapsed_ms(&self) -> u64 {
+        self.session_elapsed_ms.load(Ordering::Relaxed)
+    }
+}
+
+/// Limits a channel gain read on the audio thread. NaN reads as silence.
+fn clamp_gain(linear_gain: f32) -> f32 {
+    if linear_gain.is_nan() {
+        return 0.0;
+    }
+    linear_gain.clamp(0.0, MAX_LINEAR_GAIN)
+}
+
+/// Output channel of a voice.
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub enum Channel {
+    Left = 0,
+    Right = 1,
+}
+
+impl Channel {
+    pub const ALL: [Channel; 2] = [Channel::Left, Channel::Right];
+
+    pub fn from_index(index: i32) -> Option<Self> {
+        match index {
+            0 => Some(Channel::Left),
+            1 => Some(Channel::Right),
+            _ => None,
+        }
+    }
+}
+
+/// A single tone generator with its own segment state machine, feeding one
+/// output channel.
+struct Voice {
     oscillator: oscillator::Oscillator,
     tone_samples_left: u64,
     pause_samples_left: u64,
-    rng: Taus88,
     sample_rate: f32,
     state: AudioPhase,
+    // Length of the running tone fade, and of the next one
+    fade_samples: u64,
+    next_fade_samples: u64,
     fade_samples_left: u64,
-    linear_gain: Arc<AtomicU32>,
-    min_midi_note: Arc<AtomicU32>,
-    max_midi_note: Arc<AtomicU32>,
+    fade_curve: FadeCurve,
+    params: ChannelParams,
+    // Smoothed full-scale gain, ramping linearly towards `full_scale_gain`,
+    // so that changes of the unit, calibration or compensation are smoothed
+    // like changes of the gain itself
+    gain: f32,
+    gain_target: f32,
+    gain_step: f32,
+    gain_ramp_left: u64,
+    gain_ramp_samples: u64,
+    max_gain: f32,
+    freq: f32,
+    calibration: Arc<CalibrationParams>,
+    compensation: Arc<CompensationParams>,
+    // Full-scale level assumed where the ear is not calibrated
+    fallback_spl_db: f32,
+    ear: Channel,
+    events: Option<Arc<EventQueue>>,
 }
 
-impl AudioState {
-    pub fn new(
+impl Voice {
+    fn new(
         sample_rate: f32,
-        linear_gain: Arc<AtomicU32>,
-        min_midi_note: Arc<AtomicU32>,
-        max_midi_note: Arc<AtomicU32>,
+        params: ChannelParams,
+        ear: Channel,
+        calibration: Arc<CalibrationParams>,
+        compensation: Arc<CompensationParams>,
     ) -> Self {
+        let gain = clamp_gain(params.gain().0);
         Self {
             oscillator: oscillator::Oscillator::new(sample_rate),
             tone_samples_left: 0,
             pause_samples_left: (sample_rate * 0.5) as u64, // Start with 500ms silence
-            rng: Taus88::from_seed([0; 12]),
             sample_rate,
             state: AudioPhase::Paused,
+            fade_samples: 1,
+            next_fade_samples: 1,
             fade_samples_left: 0,
-            linear_gain,
-            min_midi_note,
-            max_midi_note,
+            fade_curve: FadeCurve::default(),
+            params,
+            gain,
+            gain_target: gain,
+            gain_step: 0.0,
+            gain_ramp_left: 0,
+            gain_ramp_samples: 1,
+            max_gain: 1.0,
+            freq: 0.0,
+            calibration,
+            compensation,
+            fallback_spl_db: DEFAULT_FULL_SCALE_SPL_DB,
+            ear,
+            events: None,
         }
     }
 
-    fn fill(&mut self, data: &mut [f32]) {
-        let frames = data.chunks_mut(2);
+    /// Starts a ramp if the target gain has changed since the last buffer.
+    fn update_gain_target(&mut self) {
+        let target = self.full_scale_gain();
+        if target != self.gain_target {
+            self.gain_target = target;
+            self.gain_ramp_left = self.gain_ramp_samples.max(1);
+            self.gain_step = (target - self.gain) / self.gain_ramp_left as f32;
+        }
+    }
+
+    fn advance_gain(&mut self, samples: u64) {
+        if self.gain_ramp_left > samples {
+            self.gain += self.gain_step * samples as f32;
+            self.gain_ramp_left -= samples;
+        } else {
+            // Land exactly on the target
+            self.gain = self.gain_target;
+            self.gain_ramp_left = 0;
+        }
+    }
+
+    /// Gain in `params` converted from its unit to full scale at the current
+    /// frequency, including the compensation for the listener.
+    fn full_scale_gain(&self) -> f32 {
+        let (linear_gain, unit) = self.params.gain();
+        let tone_gain = self
+            .calibration
+            .tone_gain(unit, self.ear, self.freq, self.fallback_spl_db)
+            * self.compensation.tone_gain(self.ear, self.freq);
+        (clamp_gain(linear_gain) * tone_gain).min(self.max_gain)
+    }
+
+    fn next_gain(&mut self) -> f32 {
+        self.advance_gain(1);
+        // The ceiling is applied at once, without waiting for the ramp
+        self.gain.min(self.max_gain)
+    }
+
+    fn emit(&self, event: PlaybackEvent) {
+        if let Some(events) = &self.events {
+            events.push(event);
+        }
+    }
+
+    fn duration_ms(&self, samples: u64) -> f32 {
+        samples as f32 * 1000.0 / self.sample_rate
+    }
+
+    fn set_sample_rate(&mut self, sample_rate: f32) {
+        // Keep the remaining durations in time rather than in samples
+        let ratio = sample_rate / self.sample_rate;
+        self.tone_samples_left = (self.tone_samples_left as f32 * ratio) as u64;
+        self.pause_samples_left = (self.pause_samples_left as f32 * ratio) as u64;
+        self.sample_rate = sample_rate;
+        self.oscillator.set_sample_rate(sample_rate);
+    }
+
+    /// Adds the voice's output to `channel` of the interleaved buffer.
+    fn fill(
+        &mut self,
+        data: &mut [f32],
+        num_channels: usize,
+        channel: usize,
+        rng: &mut Taus88,
+        schedule: &Schedule,
+        frequency_mode: FrequencyMode,
+    ) {
+        let frames = data.chunks_mut(num_channels);
+        self.update_gain_target();
 
         let no_new_segment_needed = (self.state == AudioPhase::Paused
             && self.pause_samples_left >= frames.len() as u64)
             || (self.state == AudioPhase::Playing && self.tone_samples_left >= frames.len() as u64);
         if no_new_segment_needed {
-            self.fill_without_segment_change(frames)
+            self.fill_without_segment_change(frames, channel)
         } else {
-            self.fill_with_segment_change(frames)
+            self.fill_with_segment_change(frames, channel, rng, schedule, frequency_mode)
         }
     }
 
-    fn fill_with_segment_change(&mut self, data: ChunksMut<f32>) {
-        let linear_gain = f32::from_bits(self.linear_gain.load(Ordering::Relaxed));
-
+    fn fill_with_segment_change(
+        &mut self,
+        data: ChunksMut<f32>,
+        channel: usize,
+        rng: &mut Taus88,
+        schedule: &Schedule,
+        frequency_mode: FrequencyMode,
+    ) {
         for frame in data {
             let needs_new_segment = (self.state == AudioPhase::Paused
                 && self.pause_samples_left == 0)
                 || (self.state == AudioPhase::FadingOut && self.fade_samples_left == 0);
 
             if needs_new_segment {
-                let min_midi = f32::from_bits(self.min_midi_note.load(Ordering::Relaxed));
-                let max_midi = f32::from_bits(self.max_midi_note.load(Ordering::Relaxed));
-                let params =
-                    Self::randomize_params(&mut self.rng, self.sample_rate, min_midi, max_midi);
+                let min_midi =
+                    load_clamped(&self.params.min_midi_note, MIN_MIDI_NOTE, MAX_MIDI_NOTE);
+                let max_midi =
+                    load_clamped(&self.params.max_midi_note, MIN_MIDI_NOTE, MAX_MIDI_NOTE);
+                let params = Self::randomize_params(
+                    rng,
+                    schedule,
+                    frequency_mode,
+                    self.sample_rate,
+                    min_midi,
+                    max_midi,
+                );
                 match params {
                     SegmentParams::Sound(p) => {
-                        self.oscillator.set_freq(p.freq, FADE_SAMPLES as u32);
+                        self.fade_samples = self.next_fade_samples;
+                        self.oscillator.set_freq(p.freq, self.fade_samples as u32);
+                        self.freq = p.freq;
+                        // The voice is silent between tones, so the gain of
+                        // the new frequency applies without a ramp
+                        self.gain = self.full_scale_gain();
+                        self.gain_target = self.gain;
+                        self.gain_ramp_left = 0;
                         self.tone_samples_left = p.duration_samples;
                         self.state = AudioPhase::FadingIn;
-                        self.fade_samples_left = FADE_SAMPLES;
+                        self.fade_samples_left = self.fade_samples;
+                        self.emit(PlaybackEvent::ToneStarted {
+                            channel: self.ear,
+                            frequency_hz: p.freq,
+                            duration_ms: self.duration_ms(p.duration_samples),
+                        });
                     }
                     SegmentParams::Silence(p) => {
                         self.pause_samples_left = p.duration_samples;
                         self.state = AudioPhase::Paused;
+                        self.emit(PlaybackEvent::SilenceStarted {
+                            channel: self.ear,
+                            duration_ms: self.duration_ms(p.duration_samples),
+                        });
                     }
                 }
             } else if self.state == AudioPhase::FadingIn && self.fade_samples_left == 0 {
                 self.state = AudioPhase::Playing;
             } else if self.state == AudioPhase::Playing && self.tone_samples_left == 0 {
                 self.state = AudioPhase::FadingOut;
-                self.fade_samples_left = FADE_SAMPLES;
+                self.fade_samples = self.next_fade_samples;
+                self.fade_samples_left = self.fade_samples;
             }
 
+            // The envelope multiplies the smoothed gain, so both ramps combine
+            let linear_gain = self.next_gain();
             let value = match self.state {
                 AudioPhase::Paused => {
                     self.pause_samples_left -= 1;
                     0.0
                 }
                 AudioPhase::FadingIn => {
-                    // Linear ramp from 0 to 1
-                    let fade_progress =
-                        (FADE_SAMPLES - self.fade_samples_left) as f32 / (FADE_SAMPLES - 1) as f32;
-                    let current_gain = linear_gain * fade_progress;
+                    // Both ends of the curve are left out, so that even a
+                    // one sample fade lies between silence and full level
+                    let fade_progress = (self.fade_samples - self.fade_samples_left + 1) as f32
+                        / (self.fade_samples + 1) as f32;
+                    let current_gain = linear_gain * self.fade_curve.gain(fade_progress);
                     self.fade_samples_left -= 1;
                     self.oscillator.next_sample() * current_gain
                 }
@@ -137,67 +702,56 @@ impl AudioState {
                     self.oscillator.next_sample() * linear_gain
                 }
                 AudioPhase::FadingOut => {
-                    // Linear ramp from 1 to 0
                     let fade_progress =
-                        (self.fade_samples_left - 1) as f32 / (FADE_SAMPLES - 1) as f32;
-                    let current_gain = linear_gain * fade_progress;
+                        self.fade_samples_left as f32 / (self.fade_samples + 1) as f32;
+                    let current_gain = linear_gain * self.fade_curve.gain(fade_progress);
                     self.fade_samples_left -= 1;
                     self.oscillator.next_sample() * current_gain
                 }
             };
 
-            if frame.len() == 2 {
-                frame[0] = 0.0; // Left channel
-                frame[1] = value; // Right channel
-            } else {
-                frame[0] = value;
+            if let Some(sample) = frame.get_mut(channel) {
+                *sample += value;
             }
         }
     }
 
-    fn fill_without_segment_change(&mut self, data: ChunksMut<f32>) {
-        let linear_gain = f32::from_bits(self.linear_gain.load(Ordering::Relaxed));
-
+    fn fill_without_segment_change(&mut self, data: ChunksMut<f32>, channel: usize) {
         match self.state {
             AudioPhase::Paused => {
+                // The buffer has been cleared by `AudioState::fill`
                 self.pause_samples_left -= data.len() as u64;
-                for frame in data {
-                    assert_eq!(frame.len(), 2);
-
-                    frame[0] = 0.0; // Left channel
-                    frame[1] = 0.0; // Right channel
-                }
+                self.advance_gain(data.len() as u64);
             }
             AudioPhase::Playing => {
                 let to_consume = data.len().min(self.tone_samples_left as usize);
                 self.tone_samples_left -= to_consume as u64;
                 for frame in data {
-                    assert_eq!(frame.len(), 2);
-
-                    let value = self.oscillator.next_sample() * linear_gain;
-                    frame[0] = 0.0; // Left channel
-                    frame[1] = value; // Right channel
+                    let value = self.oscillator.next_sample() * self.next_gain();
+                    if let Some(sample) = frame.get_mut(channel) {
+                        *sample += value;
+                    }
                 }
             }
             _ => panic!("Invalid state in fill_without_segment_change"),
         }
     }
+
     fn randomize_params(
         rng: &mut Taus88,
+        schedule: &Schedule,
+        frequency_mode: FrequencyMode,
         sample_rate: f32,
         min_midi: f32,
         max_midi: f32,
     ) -> SegmentParams {
-        if rng.random::<f32>() < 0.1 {
-            // 10% chance for silence
-            let pause_ms = rng.random_range(150..=400);
-            let duration_samples = (pause_ms as f32 / 1000.0 * sample_rate) as u64;
+        if rng.random::<f32>() < schedule.silence_probability {
+            let duration_samples = schedule.pause_duration.sample_samples(rng, sample_rate);
             SegmentParams::Silence(SilenceParams { duration_samples })
         } else {
-            let midi = rng.random_range(min_midi as i32..=max_midi as i32);
-            let freq = 440.0 * 2.0_f32.powf((midi as f32 - 69.0) / 12.0);
-            let duration_ms = rng.random_range(150..=400);
-            let duration_samples = (duration_ms as f32 / 1000.0 * sample_rate) as u64;
+            let midi = frequency_mode.sample_midi(rng, min_midi, max_midi);
+            let freq = 440.0 * 2.0_f32.powf((midi - 69.0) / 12.0);
+            let duration_samples = schedule.tone_duration.sample_samples(rng, sample_rate);
             SegmentParams::Sound(SoundParams {
                 freq,
                 duration_samples,
@@ -206,98 +760,1987 @@ impl AudioState {
     }
 }
 
-#[unsafe(no_mangle)]
-pub extern "C" fn set_gain_db(player: *mut AudioPlayer, gain_db: f32) {
-    if !player.is_null() {
-        unsafe { (*player).set_gain_db(gain_db) };
+pub struct AudioState {
+    voices: [Voice; 2],
+    schedule: Arc<ScheduleParams>,
+    // Last consistent copy of `schedule`
+    schedule_cache: Schedule,
+    frequency_mode: Arc<AtomicU32>,
+    gain_ramp_ms: Arc<AtomicU32>,
+    tone_fade_ms: Arc<AtomicU32>,
+    fade_curve: Arc<AtomicU32>,
+    tail_fade_ms: Arc<AtomicU32>,
+    output_ceiling: Arc<AtomicU32>,
+    limiter: Limiter,
+    dose: Arc<DoseParams>,
+    dose_meter: DoseMeter,
+    // Set once the daily dose is used up with auto stop enabled
+    dose_exceeded: bool,
+    calibration: Arc<CalibrationParams>,
+    reference_tone: ReferenceTone,
+    test_tones: Arc<TestToneParams>,
+    test_tone_player: TestTonePlayer,
+    pause: PauseControl,
+    // Position on the pause fade, from 0 (silent) to `fade_samples` (full)
+    fade_position: u64,
+    fade_samples: u64,
+    session_duration_ms: Arc<AtomicU64>,
+    session_elapsed_ms: Arc<AtomicU64>,
+    // Frames played at the current sample rate, not counting pauses
+    elapsed_frames: u64,
+    session_completed: bool,
+    events: Option<Arc<EventQueue>>,
+    // One generator per voice, so the sequence does not depend on the buffer size
+    rngs: [Taus88; 2],
+    format: StreamFormat,
+    num_channels: usize,
+}
+
+impl AudioState {
+    pub fn new(format: StreamFormat, params: SharedParams, seed: u64) -> Self {
+        let sample_rate = format.sample_rate as f32;
+        let [left, right] = params.channels;
+        let mut seeder = Taus88::seed_from_u64(seed);
+        params.session_elapsed_ms.store(0, Ordering::Relaxed);
+        params.dose.reset_session();
+        // Do not start playing if the dose is already used up
+        let dose_exceeded = params.dose.dose().daily >= 1.0 && params.dose.limit().auto_stop;
+        Self {
+            voices: [(left, Channel::Left), (right, Channel::Right)].map(|(channel, ear)| {
+                Voice::new(
+                    sample_rate,
+                    channel,
+                    ear,
+                    params.calibration.clone(),
+                    params.compensation.clone(),
+                )
+            }),
+            schedule_cache: params.schedule.snapshot(),
+            schedule: params.schedule,
+            frequency_mode: params.frequency_mode,
+            gain_ramp_ms: params.gain_ramp_ms,
+            tone_fade_ms: params.tone_fade_ms,
+            fade_curve: params.fade_curve,
+            tail_fade_ms: params.tail_fade_ms,
+            output_ceiling: params.output_ceiling,
+            limiter: Limiter::new(format.sample_rate),
+            dose: params.dose,
+            dose_meter: DoseMeter::new(),
+            dose_exceeded,
+            calibration: params.calibration,
+            reference_tone: ReferenceTone::new(format.sample_rate),
+            test_tones: params.test_tones,
+            test_tone_player: TestTonePlayer::new(format.sample_rate),
+            pause: params.pause,
+            fade_position: (!dose_exceeded).into(),
+            fade_samples: 1,
+            session_duration_ms: params.session_duration_ms,
+            session_elapsed_ms: params.session_elapsed_ms,
+            elapsed_frames: 0,
+            session_completed: false,
+            events: None,
+            rngs: [0; 2].map(|_| Taus88::seed_from_u64(seeder.random())),
+            format,
+            num_channels: format.channels.max(1) as usize,
+        }
+    }
+
+    /// Reports the started tones and silences to `events`.
+    pub fn with_events(mut self, events: Arc<EventQueue>) -> Self {
+        for voice in &mut self.voices {
+            voice.events = Some(events.clone());
+        }
+        self.events = Some(events);
+        self
+    }
+
+    /// Adapts to a stream that has been reopened with another format, keeping
+    /// the position in the sequence.
+    fn set_format(&mut self, format: StreamFormat) {
+        if format == self.format {
+            return;
+        }
+        for voice in &mut self.voices {
+            voice.set_sample_rate(format.sample_rate as f32);
+        }
+        self.limiter.set_sample_rate(format.sample_rate);
+        self.reference_tone.set_sample_rate(format.sample_rate);
+        self.test_tone_player.set_sample_rate(format.sample_rate);
+        self.elapsed_frames =
+            self.elapsed_frames * format.sample_rate as u64 / self.format.sample_rate.max(1) as u64;
+        self.format = format;
+        self.num_channels = format.channels.max(1) as usize;
+    }
+
+    fn emit(&self, event: PlaybackEvent) {
+        if let Some(events) = &self.events {
+            events.push(event);
+        }
+    }
+
+    fn ms_to_frames(&self, ms: u64) -> u64 {
+        ms * self.format.sample_rate as u64 / 1000
+    }
+
+    /// Renders into a buffer of the given format, fading out and holding
+    /// the sequence while paused. Returns false once a timed session has
+    /// ended or the output has faded out after a stop or after the daily
+    /// noise dose was used up.
+    fn render(&mut self, data: &mut [f32], format: StreamFormat) -> bool {
+        self.set_format(format);
+
+        let fade_ms = load_clamped(&self.tail_fade_ms, 0.0, MAX_TAIL_FADE_MS);
+        let fade_samples = ((fade_ms * format.sample_rate as f32 / 1000.0) as u64).max(1);
+        if fade_samples != self.fade_samples {
+            // Keep the relative position if the fade time changes
+            self.fade_position = self.fade_position * fade_samples / self.fade_samples;
+            self.fade_samples = fade_samples;
+        }
+
+        // The voices only advance while they can be heard, i.e. until the
+        // end of a pause fade or of the session. A used up dose fades out
+        // like a pause, and the voices also pause for the calibration tone
+        // and the test tones of pitch matching and audiometry.
+        let pause_requested = self.pause.requested.load(Ordering::Acquire);
+        let stop_requested = self.pause.stop_requested.load(Ordering::Acquire);
+        let stopping = pause_requested || stop_requested || self.dose_exceeded;
+        let paused =
+            stopping || self.calibration.is_reference_tone_active() || self.test_tones.is_active();
+        let mut audible_frames = data.len() / self.num_channels;
+        if paused {
+            audible_frames = audible_frames.min(self.fade_position as usize);
+        }
+        let session_ms = self.session_duration_ms.load(Ordering::Relaxed);
+        let session_frames_left = (session_ms > 0).then(|| {
+            self.ms_to_frames(session_ms)
+                .saturating_sub(self.elapsed_frames)
+        });
+        if let Some(frames_left) = session_frames_left {
+            audible_frames = audible_frames.min(frames_left as usize);
+        }
+        let (audible, silent) = data.split_at_mut(audible_frames * self.num_channels);
+        silent.fill(0.0);
+
+        if !audible.is_empty() {
+            self.fill_voices(audible);
+        }
+        self.apply_pause_fade(audible, paused);
+        if let Some(frames_left) = session_frames_left {
+            let fade_frames = self
+                .ms_to_frames(SESSION_END_FADE_MS)
+                .min(self.ms_to_frames(session_ms))
+                .max(1);
+            for (i, frame) in audible.chunks_mut(self.num_channels).enumerate() {
+                let frames_after = frames_left - i as u64 - 1;
+                if frames_after < fade_frames {
+                    let gain = frames_after as f32 / fade_frames as f32;
+                    for sample in frame {
+                        *sample *= gain;
+                    }
+                }
+            }
+        }
+
+        let ceiling = load_clamped(&self.output_ceiling, 0.0, 1.0);
+        self.reference_tone.render(
+            &self.calibration,
+            !stopping,
+            data,
+            self.num_channels,
+            ceiling,
+        );
+        self.test_tone_player.fallback_spl_db = self.dose.full_scale_spl_db();
+        self.test_tone_player.ceiling = ceiling;
+        let tones_played = self.test_tone_player.render(
+            &self.test_tones,
+            &self.calibration,
+            !stopping,
+            data,
+            self.num_channels,
+        );
+        if tones_played {
+            self.emit(PlaybackEvent::TestTonesPlayed);
+        }
+        self.limiter.process(data, self.num_channels, ceiling);
+
+        self.elapsed_frames += audible_frames as u64;
+        self.session_elapsed_ms.store(
+            self.elapsed_frames * 1000 / format.sample_rate.max(1) as u64,
+            Ordering::Relaxed,
+        );
+        self.pause.faded_out.store(
+            pause_requested && self.fade_position == 0,
+            Ordering::Release,
+        );
+
+        let dose = self
+            .dose_meter
+            .measure(&self.dose, data, self.num_channels, format.sample_rate);
+        if dose.warning {
+            self.emit(PlaybackEvent::DoseWarning);
+        }
+        if dose.limit_reached {
+            self.emit(PlaybackEvent::DoseLimitReached);
+            self.dose_exceeded = self.dose.limit().auto_stop;
+        }
+
+        if stop_requested && self.fade_position == 0 {
+            // `AudioPlayer::start` reports it instead if it cut the fade short
+            if self.pause.stop_requested.swap(false, Ordering::AcqRel) {
+                self.emit(PlaybackEvent::StreamStopped);
+            }
+            return false;
+        }
+        if session_frames_left == Some(audible_frames as u64) {
+            if !self.session_completed {
+                self.session_completed = true;
+                self.emit(PlaybackEvent::SessionCompleted);
+            }
+            return false;
+        }
+        !(self.dose_exceeded && self.fade_position == 0)
+    }
+
+    fn apply_pause_fade(&mut self, data: &mut [f32], paused: bool) {
+        let target = if paused { 0 } else { self.fade_samples };
+        if self.fade_position == target {
+            return;
+        }
+        for frame in data.chunks_mut(self.num_channels) {
+            if paused {
+                self.fade_position = self.fade_position.saturating_sub(1);
+            } else {
+                self.fade_position = (self.fade_position + 1).min(self.fade_samples);
+            }
+            let gain = self.fade_position as f32 / self.fade_samples as f32;
+            for sample in frame {
+                *sample *= gain;
+            }
+        }
+    }
+
+    /// Renders the voices alone, without the pause fade, session timer,
+    /// calibration and test tones or dose meter of `render`.
+    #[cfg(test)]
+    fn fill(&mut self, data: &mut [f32]) {
+        self.fill_voices(data);
+        // Catches what the gain cap cannot, e.g. both voices mixed on mono
+        let ceiling = load_clamped(&self.output_ceiling, 0.0, 1.0);
+        self.limiter.process(data, self.num_channels, ceiling);
+    }
+
+    fn fill_voices(&mut self, data: &mut [f32]) {
+        // Keep the previous schedule rather than wait for a write
+        if let Some(schedule) = self.schedule.try_snapshot() {
+            self.schedule_cache = schedule;
+        }
+        let schedule = self.schedule_cache;
+        let frequency_mode =
+            FrequencyMode::from_index(self.frequency_mode.load(Ordering::Relaxed) as i32)
+                .unwrap_or_default();
+        let gain_ramp_ms = load_clamped(&self.gain_ramp_ms, 0.0, MAX_GAIN_RAMP_MS);
+        let gain_ramp_samples = (gain_ramp_ms * self.format.sample_rate as f32 / 1000.0) as u64;
+        let tone_fade_ms = load_clamped(&self.tone_fade_ms, 0.0, MAX_TONE_FADE_MS);
+        let tone_fade_samples =
+            ((tone_fade_ms * self.format.sample_rate as f32 / 1000.0) as u64).max(1);
+        let fade_curve = FadeCurve::from_index(self.fade_curve.load(Ordering::Relaxed) as i32)
+            .unwrap_or_default();
+        let ceiling = load_clamped(&self.output_ceiling, 0.0, 1.0);
+        let fallback_spl_db = self.dose.full_scale_spl_db();
+        data.fill(0.0);
+        for (channel, (voice, rng)) in self.voices.iter_mut().zip(&mut self.rngs).enumerate() {
+            voice.gain_ramp_samples = gain_ramp_samples;
+            voice.max_gain = ceiling;
+            voice.fallback_spl_db = fallback_spl_db;
+            voice.next_fade_samples = tone_fade_samples;
+            voice.fade_curve = fade_curve;
+            // On mono outputs both voices are mixed into the single channel
+            let output_channel = channel.min(self.num_channels - 1);
+            voice.fill(
+                data,
+                self.num_channels,
+                output_channel,
+                rng,
+                &schedule,
+                frequency_mode,
+            );
+        }
     }
 }
 
-#[unsafe(no_mangle)]
-pub extern "C" fn set_frequency_range(
+/// Runs `f` on the player behind a raw pointer from C, catching panics.
+fn with_player(
     player: *mut AudioPlayer,
-    min_midi_note: f32,
-    max_midi_note: f32,
-) {
-    if !player.is_null() {
-        unsafe { (*player).set_frequency_range(min_midi_note, max_midi_note) };
-    }
+    f: impl FnOnce(&mut AudioPlayer) -> Result<(), Error>,
+) -> i32 {
+    error::guarded(|| {
+        if player.is_null() {
+            return Err(Error::NoPlayer);
+        }
+        f(unsafe { &mut *player })
+    })
 }
 
-#[unsafe(no_mangle)]
-pub extern "C" fn create_audio_player() -> *mut AudioPlayer {
-    let player = Box::new(AudioPlayer::new());
-    let ptr = Box::into_raw(player);
-    ptr
+/// Runs `f` on the global JNI player, catching panics.
+fn with_global_player(f: impl FnOnce(&mut AudioPlayer) -> Result<(), Error>) -> i32 {
+    error::guarded(|| match *error::lock(&AUDIO_PLAYER) {
+        Some(ref mut player) => f(player),
+        None => Err(Error::NoPlayer),
+    })
 }
 
-#[unsafe(no_mangle)]
-pub extern "C" fn start_audio_player(player: *mut AudioPlayer) {
-    if !player.is_null() {
-        unsafe { (*player).start() }
-    } else {
-        log::error!("player is null");
-    }
+fn channel_from_index(channel: i32) -> Result<Channel, Error> {
+    Channel::from_index(channel).ok_or(Error::InvalidChannel(channel))
 }
 
-#[unsafe(no_mangle)]
-pub extern "C" fn stop_audio_player(player: *mut AudioPlayer) {
-    if !player.is_null() {
-        unsafe { (*player).stop() }
-    }
+fn frequency_mode_from_index(mode: i32) -> Result<FrequencyMode, Error> {
+    FrequencyMode::from_index(mode).ok_or(Error::InvalidArgument("frequency mode"))
 }
 
-#[unsafe(no_mangle)]
-pub extern "C" fn destroy_audio_player(player: *mut AudioPlayer) {
-    if !player.is_null() {
-        unsafe { drop(Box::from_raw(player)) }
-    }
+fn fade_curve_from_index(curve: i32) -> Result<FadeCurve, Error> {
+    FadeCurve::from_index(curve).ok_or(Error::InvalidArgument("fade curve"))
 }
 
-// JNI-compatible exports for Android service using global static
-#[unsafe(no_mangle)]
-pub extern "C" fn Java_org_klingt_tim_sinewaveTinnitusRetraining_service_AudioPlaybackService_create_1audio_1player()
--> i32 {
-    let mut player_guard = AUDIO_PLAYER.lock().unwrap();
-    if player_guard.is_some() {
-        // Already created
-        return 1;
+/// Reads a channel index that may be -1 for both ears.
+fn ears_from_index(channel: i32) -> Result<Option<Channel>, Error> {
+    match channel {
+        -1 => Ok(None),
+        _ => channel_from_index(channel).map(Some),
     }
+}
 
-    *player_guard = Some(Box::new(AudioPlayer::new()));
-    1 // Success
+fn pitch_response_from_index(response: i32) -> Result<PitchResponse, Error> {
+    PitchResponse::from_index(response).ok_or(Error::InvalidArgument("pitch response"))
 }
 
-#[unsafe(no_mangle)]
-pub extern "C" fn Java_org_klingt_tim_sinewaveTinnitusRetraining_service_AudioPlaybackService_start_1audio_1player()
--> i32 {
-    let mut player_guard = AUDIO_PLAYER.lock().unwrap();
-    if let Some(ref mut player) = *player_guard {
-        player.start();
-        1 // Success
-    } else {
-        0 // No player
+fn gain_unit_from_index(unit: i32) -> Result<GainUnit, Error> {
+    GainUnit::from_index(unit).ok_or(Error::InvalidArgument("gain unit"))
+}
+
+/// Reads a path passed from C.
+fn path_from_c<'a>(path: *const c_char) -> Result<&'a str, Error> {
+    if path.is_null() {
+        return Err(Error::InvalidArgument("path"));
     }
+    unsafe { CStr::from_ptr(path) }
+        .to_str()
+        .map_err(|_| Error::InvalidArgument("path"))
 }
 
+// All C and JNI functions returning `i32` return an `ErrorCode`, 0 on success.
+
 #[unsafe(no_mangle)]
-pub extern "C" fn Java_org_klingt_tim_sinewaveTinnitusRetraining_service_AudioPlaybackService_stop_1audio_1player()
--> i32 {
-    let mut player_guard = AUDIO_PLAYER.lock().unwrap();
-    if let Some(ref mut player) = *player_guard {
-        player.stop();
-        1 // Success
-    } else {
-        0 // No player
-    }
+pub extern "C" fn set_gain_db(player: *mut AudioPlayer, gain_db: f32) -> i32 {
+    with_player(player, |p| p.set_gain_db(gain_db))
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn set_frequency_range(
+    player: *mut AudioPlayer,
+    min_midi_note: f32,
+    max_midi_note: f32,
+) -> i32 {
+    with_player(player, |p| {
+        p.set_frequency_range(min_midi_note, max_midi_note)
+    })
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn set_gain_db_channel(player: *mut AudioPlayer, channel: i32, gain_db: f32) -> i32 {
+    with_player(player, |p| {
+        p.set_gain_db_channel(channel_from_index(channel)?, gain_db)
+    })
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn set_frequency_range_channel(
+    player: *mut AudioPlayer,
+    channel: i32,
+    min_midi_note: f32,
+    max_midi_note: f32,
+) -> i32 {
+    with_player(player, |p| {
+        p.set_frequency_range_channel(channel_from_index(channel)?, min_midi_note, max_midi_note)
+    })
+}
+
+/// Sets the time over which gain changes are ramped, 0 to apply them
+/// immediately.
+#[unsafe(no_mangle)]
+pub extern "C" fn set_gain_ramp_ms(player: *mut AudioPlayer, ramp_ms: f32) -> i32 {
+    with_player(player, |p| p.set_gain_ramp_ms(ramp_ms))
+}
+
+/// Selects continuous (0) or semitone (1) frequencies.
+#[unsafe(no_mangle)]
+pub extern "C" fn set_frequency_mode(player: *mut AudioPlayer, mode: i32) -> i32 {
+    with_player(player, |p| {
+        p.set_frequency_mode(frequency_mode_from_index(mode)?);
+        Ok(())
+    })
+}
+
+/// Sets the attack and release of each tone.
+#[unsafe(no_mangle)]
+pub extern "C" fn set_tone_fade_ms(player: *mut AudioPlayer, fade_ms: f32) -> i32 {
+    with_player(player, |p| p.set_tone_fade_ms(fade_ms))
+}
+
+/// Selects linear (0), raised cosine (1) or exponential (2) tone fades.
+#[unsafe(no_mangle)]
+pub extern "C" fn set_fade_curve(player: *mut AudioPlayer, curve: i32) -> i32 {
+    with_player(player, |p| {
+        p.set_fade_curve(fade_curve_from_index(curve)?);
+        Ok(())
+    })
+}
+
+/// Sets the probability of a pause between tones.
+#[unsafe(no_mangle)]
+pub extern "C" fn set_silence_probability(player: *mut AudioPlayer, probability: f32) -> i32 {
+    with_player(player, |p| p.set_silence_probability(probability))
+}
+
+/// Sets the tone duration distribution: `kind` 0 is fixed (`a` ms), 1 is
+/// uniform (`a` to `b` ms) and 2 is exponential (mean `a` ms, at most `b` ms).
+#[unsafe(no_mangle)]
+pub extern "C" fn set_tone_duration_distribution(
+    player: *mut AudioPlayer,
+    kind: i32,
+    a: f32,
+    b: f32,
+) -> i32 {
+    with_player(player, |p| {
+        p.set_tone_duration(DurationDistribution::from_raw(kind, a, b)?)
+    })
+}
+
+/// Sets the pause duration distribution, see `set_tone_duration_distribution`.
+#[unsafe(no_mangle)]
+pub extern "C" fn set_pause_duration_distribution(
+    player: *mut AudioPlayer,
+    kind: i32,
+    a: f32,
+    b: f32,
+) -> i32 {
+    with_player(player, |p| {
+        p.set_pause_duration(DurationDistribution::from_raw(kind, a, b)?)
+    })
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn create_audio_player() -> *mut AudioPlayer {
+    match error::catch_panic(|| Ok(AudioPlayer::new())) {
+        Ok(player) => Box::into_raw(Box::new(player)),
+        Err(e) => {
+            error::record_error(&e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn start_audio_player(player: *mut AudioPlayer) -> i32 {
+    with_player(player, AudioPlayer::start)
+}
+
+/// Starts the tail fade and returns. A `StreamStopped` event follows once
+/// the stream has closed.
+#[unsafe(no_mangle)]
+pub extern "C" fn stop_audio_player(player: *mut AudioPlayer) -> i32 {
+    with_player(player, |p| {
+        p.stop();
+        Ok(())
+    })
+}
+
+/// Fades out and holds the sequence, keeping the stream open.
+#[unsafe(no_mangle)]
+pub extern "C" fn pause_audio_player(player: *mut AudioPlayer) -> i32 {
+    with_player(player, |p| {
+        p.pause();
+        Ok(())
+    })
+}
+
+/// Fades back in, continuing the sequence where it paused.
+#[unsafe(no_mangle)]
+pub extern "C" fn resume_audio_player(player: *mut AudioPlayer) -> i32 {
+    with_player(player, |p| {
+        p.resume();
+        Ok(())
+    })
+}
+
+/// Sets the fade applied by pause, resume and stop, 0 to cut immediately.
+#[unsafe(no_mangle)]
+pub extern "C" fn set_tail_fade_ms(player: *mut AudioPlayer, fade_ms: f32) -> i32 {
+    with_player(player, |p| p.set_tail_fade_ms(fade_ms))
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn destroy_audio_player(player: *mut AudioPlayer) {
+    if !player.is_null() {
+        error::guarded(|| {
+            unsafe { drop(Box::from_raw(player)) };
+            Ok(())
+        });
+    }
+}
+
+/// Sets the seed of the tone sequence played by the next start.
+#[unsafe(no_mangle)]
+pub extern "C" fn set_seed(player: *mut AudioPlayer, seed: u64) -> i32 {
+    with_player(player, |p| {
+        p.set_seed(seed);
+        Ok(())
+    })
+}
+
+/// Writes the seed of the running session, or of the last one once stopped.
+/// Returns false before the first start.
+#[unsafe(no_mangle)]
+pub extern "C" fn get_seed(player: *mut AudioPlayer, seed: *mut u64) -> bool {
+    if player.is_null() || seed.is_null() {
+        return false;
+    }
+    match error::catch_panic(|| Ok(unsafe { (*player).last_session_seed() })) {
+        Ok(Some(value)) => {
+            unsafe { *seed = value };
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Returns the `StreamState` of the output: 0 stopped, 1 playing, 2
+/// reconnecting after the device was lost.
+#[unsafe(no_mangle)]
+pub extern "C" fn get_stream_state(player: *mut AudioPlayer) -> i32 {
+    if player.is_null() {
+        return StreamState::Stopped as i32;
+    }
+    error::catch_panic(|| Ok(unsafe { (*player).stream_state() } as i32))
+        .unwrap_or(StreamState::Stopped as i32)
+}
+
+/// Sets the highest output level in dBFS, from -60 to 0.
+#[unsafe(no_mangle)]
+pub extern "C" fn set_output_ceiling_db(player: *mut AudioPlayer, ceiling_db: f32) -> i32 {
+    with_player(player, |p| p.set_output_ceiling_db(ceiling_db))
+}
+
+/// Locks the output ceiling until `unlock_limiter` is called with `pin`.
+#[unsafe(no_mangle)]
+pub extern "C" fn lock_limiter(player: *mut AudioPlayer, pin: u32) -> i32 {
+    with_player(player, |p| p.lock_limiter(pin))
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn unlock_limiter(player: *mut AudioPlayer, pin: u32) -> i32 {
+    with_player(player, |p| p.unlock_limiter(pin))
+}
+
+/// Returns 1 if the limiter settings are locked, 0 otherwise.
+#[unsafe(no_mangle)]
+pub extern "C" fn is_limiter_locked(player: *mut AudioPlayer) -> i32 {
+    if player.is_null() {
+        return 0;
+    }
+    error::catch_panic(|| Ok(unsafe { (*player).is_limiter_locked() } as i32)).unwrap_or_default()
+}
+
+/// Sets the level in dB SPL of a full-scale sine on the current output
+/// device, used to estimate the noise dose.
+#[unsafe(no_mangle)]
+pub extern "C" fn set_full_scale_spl_db(player: *mut AudioPlayer, spl_db: f32) -> i32 {
+    with_player(player, |p| p.set_full_scale_spl_db(spl_db))
+}
+
+/// Sets the daily noise budget: `criterion_db` may be played for
+/// `criterion_hours`, and each `exchange_rate_db` more halves the time. A
+/// warning event is sent at `warning_fraction` of the budget, and playback
+/// stops when it is used up if `auto_stop` is non-zero.
+#[unsafe(no_mangle)]
+pub extern "C" fn set_dose_limit(
+    player: *mut AudioPlayer,
+    criterion_db: f32,
+    criterion_hours: f32,
+    exchange_rate_db: f32,
+    warning_fraction: f32,
+    auto_stop: i32,
+) -> i32 {
+    with_player(player, |p| {
+        p.set_dose_limit(DoseLimit {
+            criterion_db,
+            criterion_hours,
+            exchange_rate_db,
+            warning_fraction,
+            auto_stop: auto_stop != 0,
+        })
+    })
+}
+
+/// Returns the noise dose of the current or last session as a share of the
+/// daily budget.
+#[unsafe(no_mangle)]
+pub extern "C" fn get_session_dose(player: *mut AudioPlayer) -> f64 {
+    if player.is_null() {
+        return 0.0;
+    }
+    error::catch_panic(|| Ok(unsafe { (*player).noise_dose() }.session)).unwrap_or_default()
+}
+
+/// Returns the noise dose of the day as a share of the daily budget.
+#[unsafe(no_mangle)]
+pub extern "C" fn get_daily_dose(player: *mut AudioPlayer) -> f64 {
+    if player.is_null() {
+        return 0.0;
+    }
+    error::catch_panic(|| Ok(unsafe { (*player).noise_dose() }.daily)).unwrap_or_default()
+}
+
+/// Replaces the daily dose, 0 at the start of a day.
+#[unsafe(no_mangle)]
+pub extern "C" fn set_daily_dose(player: *mut AudioPlayer, dose: f64) -> i32 {
+    with_player(player, |p| p.set_daily_dose(dose))
+}
+
+/// Sets the gain of both channels in dBFS (`unit` 0), dB SPL (1) or dB HL (2).
+#[unsafe(no_mangle)]
+pub extern "C" fn set_gain_with_unit(player: *mut AudioPlayer, gain_db: f32, unit: i32) -> i32 {
+    with_player(player, |p| p.set_gain(gain_db, gain_unit_from_index(unit)?))
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn set_gain_channel_with_unit(
+    player: *mut AudioPlayer,
+    channel: i32,
+    gain_db: f32,
+    unit: i32,
+) -> i32 {
+    with_player(player, |p| {
+        p.set_gain_channel(
+            channel_from_index(channel)?,
+            gain_db,
+            gain_unit_from_index(unit)?,
+        )
+    })
+}
+
+/// Plays a steady calibration tone on one channel instead of the sequence.
+#[unsafe(no_mangle)]
+pub extern "C" fn play_reference_tone(
+    player: *mut AudioPlayer,
+    channel: i32,
+    frequency_hz: f32,
+    level_dbfs: f32,
+) -> i32 {
+    with_player(player, |p| {
+        p.play_reference_tone(channel_from_index(channel)?, frequency_hz, level_dbfs)
+    })
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn stop_reference_tone(player: *mut AudioPlayer) -> i32 {
+    with_player(player, |p| {
+        p.stop_reference_tone();
+        Ok(())
+    })
+}
+
+/// Stores the level in dB SPL measured while the reference tone plays.
+#[unsafe(no_mangle)]
+pub extern "C" fn add_calibration_measurement(
+    player: *mut AudioPlayer,
+    measured_spl_db: f32,
+) -> i32 {
+    with_player(player, |p| {
+        p.add_calibration_measurement(measured_spl_db).map(drop)
+    })
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn clear_calibration(player: *mut AudioPlayer) -> i32 {
+    with_player(player, |p| {
+        p.clear_calibration();
+        Ok(())
+    })
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn save_calibration(player: *mut AudioPlayer, path: *const c_char) -> i32 {
+    with_player(player, |p| p.save_calibration(path_from_c(path)?))
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn load_calibration(player: *mut AudioPlayer, path: *const c_char) -> i32 {
+    with_player(player, |p| p.load_calibration(path_from_c(path)?))
+}
+
+/// Starts matching the tinnitus pitch on `channel` (-1 for both ears) within
+/// the given note range. `unit` is as for `set_gain_with_unit`.
+#[unsafe(no_mangle)]
+pub extern "C" fn start_pitch_match(
+    player: *mut AudioPlayer,
+    channel: i32,
+    level_db: f32,
+    unit: i32,
+    min_midi_note: f32,
+    max_midi_note: f32,
+    resolution_semitones: f32,
+) -> i32 {
+    with_player(player, |p| {
+        p.start_pitch_match(PitchMatchSettings {
+            channel: ears_from_index(channel)?,
+            level_db,
+            unit: gain_unit_from_index(unit)?,
+            min_midi_note,
+            max_midi_note,
+            resolution_semitones,
+        })
+    })
+}
+
+/// Answers the current pair: first closer (0), second closer (1), tinnitus
+/// lower (2) or higher (3) than both.
+#[unsafe(no_mangle)]
+pub extern "C" fn respond_pitch_match(player: *mut AudioPlayer, response: i32) -> i32 {
+    with_player(player, |p| {
+        p.respond_pitch_match(pitch_response_from_index(response)?)
+    })
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn replay_pitch_pair(player: *mut AudioPlayer) -> i32 {
+    with_player(player, |p| p.replay_pitch_pair())
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn stop_pitch_match(player: *mut AudioPlayer) -> i32 {
+    with_player(player, |p| {
+        p.stop_pitch_match();
+        Ok(())
+    })
+}
+
+/// Writes the frequencies of the current pair. Returns false if no match is
+/// running.
+#[unsafe(no_mangle)]
+pub extern "C" fn get_pitch_trial(
+    player: *mut AudioPlayer,
+    first_hz: *mut f32,
+    second_hz: *mut f32,
+) -> bool {
+    if player.is_null() || first_hz.is_null() || second_hz.is_null() {
+        return false;
+    }
+    match error::catch_panic(|| Ok(unsafe { (*player).pitch_trial() })) {
+        Ok(Some(trial)) => {
+            unsafe {
+                *first_hz = trial.first_hz;
+                *second_hz = trial.second_hz;
+            }
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Writes the matched frequency and its confidence range. Returns false if
+/// no match has finished.
+#[unsafe(no_mangle)]
+pub extern "C" fn get_pitch_match_result(
+    player: *mut AudioPlayer,
+    frequency_hz: *mut f32,
+    min_hz: *mut f32,
+    max_hz: *mut f32,
+) -> bool {
+    if player.is_null() || frequency_hz.is_null() || min_hz.is_null() || max_hz.is_null() {
+        return false;
+    }
+    match error::catch_panic(|| Ok(unsafe { (*player).pitch_match_result() })) {
+        Ok(Some(result)) => {
+            unsafe {
+                *frequency_hz = result.frequency_hz;
+                *min_hz = result.min_hz;
+                *max_hz = result.max_hz;
+            }
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Starts a threshold test on `channel` (-1 for the left and then the right
+/// ear), with levels in dB HL.
+#[unsafe(no_mangle)]
+pub extern "C" fn start_audiometry(
+    player: *mut AudioPlayer,
+    channel: i32,
+    start_level_db_hl: f32,
+    max_level_db_hl: f32,
+) -> i32 {
+    with_player(player, |p| {
+        p.start_audiometry(AudiometrySettings {
+            channel: ears_from_index(channel)?,
+            start_level_db_hl,
+            max_level_db_hl,
+        })
+    })
+}
+
+/// Answers the current tone: `heard` is nonzero if the patient heard it.
+#[unsafe(no_mangle)]
+pub extern "C" fn respond_audiometry(player: *mut AudioPlayer, heard: i32) -> i32 {
+    with_player(player, |p| p.respond_audiometry(heard != 0))
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn replay_audiometry_tone(player: *mut AudioPlayer) -> i32 {
+    with_player(player, |p| p.replay_audiometry_tone())
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn stop_audiometry(player: *mut AudioPlayer) -> i32 {
+    with_player(player, |p| {
+        p.stop_audiometry();
+        Ok(())
+    })
+}
+
+/// Writes the ear, frequency and level of the current tone. Returns false if
+/// no test is running.
+#[unsafe(no_mangle)]
+pub extern "C" fn get_audiometry_presentation(
+    player: *mut AudioPlayer,
+    channel: *mut i32,
+    frequency_hz: *mut f32,
+    level_db_hl: *mut f32,
+) -> bool {
+    if player.is_null() || channel.is_null() || frequency_hz.is_null() || level_db_hl.is_null() {
+        return false;
+    }
+    match error::catch_panic(|| Ok(unsafe { (*player).audiometry_presentation() })) {
+        Ok(Some(presentation)) => {
+            unsafe {
+                *channel = presentation.channel as i32;
+                *frequency_hz = presentation.frequency_hz;
+                *level_db_hl = presentation.level_db_hl;
+            }
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Number of thresholds of `channel` in the audiogram of the last test.
+#[unsafe(no_mangle)]
+pub extern "C" fn audiogram_threshold_count(player: *mut AudioPlayer, channel: i32) -> usize {
+    if player.is_null() {
+        return 0;
+    }
+    error::catch_panic(|| {
+        let channel = channel_from_index(channel)?;
+        Ok(unsafe { (*player).audiogram() }.map_or(0, |a| a.ear(channel).len()))
+    })
+    .unwrap_or_default()
+}
+
+/// Writes a threshold of the audiogram of the last test, `no_response` being
+/// 1 if the tone was not heard at `level_db_hl`. Returns false if `index` is
+/// out of range.
+#[unsafe(no_mangle)]
+pub extern "C" fn get_audiogram_threshold(
+    player: *mut AudioPlayer,
+    channel: i32,
+    index: usize,
+    frequency_hz: *mut f32,
+    level_db_hl: *mut f32,
+    no_response: *mut i32,
+) -> bool {
+    if player.is_null() || frequency_hz.is_null() || level_db_hl.is_null() || no_response.is_null()
+    {
+        return false;
+    }
+    let threshold = error::catch_panic(|| {
+        let channel = channel_from_index(channel)?;
+        Ok(unsafe { (*player).audiogram() }.and_then(|a| a.ear(channel).get(index).copied()))
+    });
+    match threshold {
+        Ok(Some(threshold)) => {
+            unsafe {
+                *frequency_hz = threshold.frequency_hz;
+                *level_db_hl = threshold.level_db_hl;
+                *no_response = threshold.no_response as i32;
+            }
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Writes the audiogram of the last test to a CSV file.
+#[unsafe(no_mangle)]
+pub extern "C" fn save_audiogram(player: *mut AudioPlayer, path: *const c_char) -> i32 {
+    with_player(player, |p| p.save_audiogram(path_from_c(path)?))
+}
+
+/// Raises the tones by `fraction` of the hearing loss in an audiogram file in
+/// CSV or JSON, by at most `max_gain_db`.
+#[unsafe(no_mangle)]
+pub extern "C" fn load_hearing_compensation(
+    player: *mut AudioPlayer,
+    path: *const c_char,
+    fraction: f32,
+    max_gain_db: f32,
+) -> i32 {
+    with_player(player, |p| {
+        p.load_hearing_compensation(
+            path_from_c(path)?,
+            HearingCompensation {
+                fraction,
+                max_gain_db,
+            },
+        )
+    })
+}
+
+/// As `load_hearing_compensation`, with the audiogram of the last threshold
+/// test.
+#[unsafe(no_mangle)]
+pub extern "C" fn use_audiometry_for_compensation(
+    player: *mut AudioPlayer,
+    fraction: f32,
+    max_gain_db: f32,
+) -> i32 {
+    with_player(player, |p| {
+        p.use_audiometry_for_compensation(HearingCompensation {
+            fraction,
+            max_gain_db,
+        })
+    })
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn clear_hearing_compensation(player: *mut AudioPlayer) -> i32 {
+    with_player(player, |p| {
+        p.clear_hearing_compensation();
+        Ok(())
+    })
+}
+
+/// Weights the tones to the ISO 226 equal-loudness contour of `target_phon`
+/// if `enabled` is non-zero, otherwise turns the weighting off.
+#[unsafe(no_mangle)]
+pub extern "C" fn set_equal_loudness(
+    player: *mut AudioPlayer,
+    enabled: i32,
+    target_phon: f32,
+) -> i32 {
+    with_player(player, |p| {
+        p.set_equal_loudness((enabled != 0).then_some(target_phon))
+    })
+}
+
+/// Corrects the headphone on `channel`, -1 for both ears, with an AutoEq
+/// profile for Equalizer APO.
+#[unsafe(no_mangle)]
+pub extern "C" fn load_headphone_profile(
+    player: *mut AudioPlayer,
+    channel: i32,
+    path: *const c_char,
+) -> i32 {
+    with_player(player, |p| {
+        p.load_headphone_profile(ears_from_index(channel)?, path_from_c(path)?)
+    })
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn clear_headphone_profile(player: *mut AudioPlayer) -> i32 {
+    with_player(player, |p| {
+        p.clear_headphone_profile();
+        Ok(())
+    })
+}
+
+/// Limits the session to `duration_ms`, after which the output fades out and
+/// stops with a `SessionCompleted` event. 0 plays until stopped.
+#[unsafe(no_mangle)]
+pub extern "C" fn set_session_duration_ms(player: *mut AudioPlayer, duration_ms: u64) -> i32 {
+    with_player(player, |p| {
+        p.set_session_duration((duration_ms > 0).then(|| Duration::from_millis(duration_ms)));
+        Ok(())
+    })
+}
+
+/// Returns the time played in the current or last session in ms, not
+/// counting pauses.
+#[unsafe(no_mangle)]
+pub extern "C" fn get_session_elapsed_ms(player: *mut AudioPlayer) -> u64 {
+    if player.is_null() {
+        return 0;
+    }
+    error::catch_panic(|| Ok(unsafe { (*player).session_elapsed() }.as_millis() as u64))
+        .unwrap_or_default()
+}
+
+/// Returns the time left in the session in ms, or -1 if it plays until
+/// stopped.
+#[unsafe(no_mangle)]
+pub extern "C" fn get_session_remaining_ms(player: *mut AudioPlayer) -> i64 {
+    if player.is_null() {
+        return -1;
+    }
+    error::catch_panic(|| Ok(session_remaining_ms(unsafe { &*player }))).unwrap_or(-1)
+}
+
+fn session_remaining_ms(player: &AudioPlayer) -> i64 {
+    player
+        .session_remaining()
+        .map_or(-1, |remaining| remaining.as_millis() as i64)
+}
+
+/// Removes the oldest pending playback event and writes it to `event`.
+/// Returns false if there is none, or while a callback is registered.
+#[unsafe(no_mangle)]
+pub extern "C" fn poll_playback_event(
+    player: *mut AudioPlayer,
+    event: *mut RawPlaybackEvent,
+) -> bool {
+    if player.is_null() || event.is_null() {
+        return false;
+    }
+    match error::catch_panic(|| Ok(unsafe { (*player).poll_event() })) {
+        Ok(Some(e)) => {
+            unsafe { *event = e.into() };
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Receives a playback event and the `user_data` given at registration. The
+/// event is only valid during the call.
+pub type PlaybackEventCallback =
+    extern "C" fn(event: *const RawPlaybackEvent, user_data: *mut std::ffi::c_void);
+
+/// Pointer passed back to a C callback on the dispatch thread. The caller
+/// is responsible for its thread safety.
+struct UserData(*mut std::ffi::c_void);
+
+unsafe impl Send for UserData {}
+
+impl UserData {
+    fn get(&self) -> *mut std::ffi::c_void {
+        self.0
+    }
+}
+
+/// Registers `callback` to receive all playback events on a separate thread.
+/// A null callback unregisters it, after which events can be polled again.
+#[unsafe(no_mangle)]
+pub extern "C" fn set_playback_event_callback(
+    player: *mut AudioPlayer,
+    callback: Option<PlaybackEventCallback>,
+    user_data: *mut std::ffi::c_void,
+) -> i32 {
+    let user_data = UserData(user_data);
+    with_player(player, |p| {
+        p.set_event_callback(callback.map(|callback| -> EventCallback {
+            Box::new(move |event| {
+                let raw = RawPlaybackEvent::from(event);
+                callback(&raw, user_data.get());
+            })
+        }));
+        Ok(())
+    })
+}
+
+/// Returns the `ErrorCode` of the most recent error, 0 if there was none.
+#[unsafe(no_mangle)]
+pub extern "C" fn get_last_error_code() -> i32 {
+    error::catch_panic(|| Ok(last_error().map_or(ErrorCode::Ok, |e| e.code()) as i32))
+        .unwrap_or(ErrorCode::Panic as i32)
+}
+
+/// Copies the message of the most recent error into `buffer` as a
+/// NUL-terminated string, truncated to `capacity` bytes. Returns the length of
+/// the full message, without terminator.
+#[unsafe(no_mangle)]
+pub extern "C" fn get_last_error_message(buffer: *mut c_char, capacity: usize) -> usize {
+    error::catch_panic(|| {
+        let message = last_error().map(|e| e.to_string()).unwrap_or_default();
+        if !buffer.is_null() && capacity > 0 {
+            let len = message.len().min(capacity - 1);
+            unsafe {
+                std::ptr::copy_nonoverlapping(message.as_ptr(), buffer as *mut u8, len);
+                *buffer.add(len) = 0;
+            }
+        }
+        Ok(message.len())
+    })
+    .unwrap_or_default()
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn reset_last_error() {
+    let _ = error::catch_panic(|| {
+        clear_last_error();
+        Ok(())
+    });
+}
+
+/// Snapshot of the output devices handed to C. Strings stay valid until the
+/// list is freed.
+pub struct OutputDeviceList {
+    devices: Vec<OutputDevice>,
+    ids: Vec<CString>,
+    names: Vec<CString>,
+}
+
+impl OutputDeviceList {
+    fn new(devices: Vec<OutputDevice>) -> Self {
+        // Interior NULs cannot occur in device names on any supported host
+        let c_string = |s: &str| CString::new(s.replace('\0', " ")).unwrap_or_default();
+        Self {
+            ids: devices.iter().map(|d| c_string(&d.id)).collect(),
+            names: devices.iter().map(|d| c_string(&d.name)).collect(),
+            devices,
+        }
+    }
+
+    fn from_ptr<'a>(list: *const OutputDeviceList) -> Option<&'a OutputDeviceList> {
+        unsafe { list.as_ref() }
+    }
+
+    fn get<'a>(list: *const OutputDeviceList, index: usize) -> Option<&'a OutputDevice> {
+        Self::from_ptr(list).and_then(|l| l.devices.get(index))
+    }
+}
+
+/// Lists the output devices. Free the result with `free_output_device_list`.
+#[unsafe(no_mangle)]
+pub extern "C" fn list_output_devices(player: *mut AudioPlayer) -> *mut OutputDeviceList {
+    let result = error::catch_panic(|| {
+        if player.is_null() {
+            return Err(Error::NoPlayer);
+        }
+        let devices = unsafe { (*player).output_devices() };
+        Ok(Box::into_raw(Box::new(OutputDeviceList::new(devices))))
+    });
+    result.unwrap_or_else(|e| {
+        error::record_error(&e);
+        std::ptr::null_mut()
+    })
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn free_output_device_list(list: *mut OutputDeviceList) {
+    if !list.is_null() {
+        unsafe { drop(Box::from_raw(list)) }
+    }
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn output_device_count(list: *const OutputDeviceList) -> usize {
+    OutputDeviceList::from_ptr(list).map_or(0, |l| l.devices.len())
+}
+
+/// Returns the id to pass to `set_output_device`, or null if `index` is out
+/// of range.
+#[unsafe(no_mangle)]
+pub extern "C" fn output_device_id(list: *const OutputDeviceList, index: usize) -> *const c_char {
+    OutputDeviceList::from_ptr(list)
+        .and_then(|l| l.ids.get(index))
+        .map_or(std::ptr::null(), |s| s.as_ptr())
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn output_device_name(list: *const OutputDeviceList, index: usize) -> *const c_char {
+    OutputDeviceList::from_ptr(list)
+        .and_then(|l| l.names.get(index))
+        .map_or(std::ptr::null(), |s| s.as_ptr())
+}
+
+/// Returns 1 if the device is the system default, 0 otherwise.
+#[unsafe(no_mangle)]
+pub extern "C" fn output_device_is_default(list: *const OutputDeviceList, index: usize) -> i32 {
+    OutputDeviceList::get(list, index).is_some_and(|d| d.is_default) as i32
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn output_device_sample_rate_count(
+    list: *const OutputDeviceList,
+    index: usize,
+) -> usize {
+    OutputDeviceList::get(list, index).map_or(0, |d| d.sample_rates.len())
+}
+
+/// Returns a supported sample rate of the device, or 0 if out of range.
+#[unsafe(no_mangle)]
+pub extern "C" fn output_device_sample_rate(
+    list: *const OutputDeviceList,
+    index: usize,
+    rate_index: usize,
+) -> u32 {
+    OutputDeviceList::get(list, index)
+        .and_then(|d| d.sample_rates.get(rate_index).copied())
+        .unwrap_or(0)
+}
+
+/// Returns the highest channel count the device supports, or 0.
+#[unsafe(no_mangle)]
+pub extern "C" fn output_device_max_channels(list: *const OutputDeviceList, index: usize) -> u16 {
+    OutputDeviceList::get(list, index)
+        .and_then(|d| d.channels.iter().max().copied())
+        .unwrap_or(0)
+}
+
+/// Selects the output device by id for the next start. Null selects the
+/// system default.
+#[unsafe(no_mangle)]
+pub extern "C" fn set_output_device(player: *mut AudioPlayer, id: *const c_char) -> i32 {
+    with_player(player, |p| {
+        if id.is_null() {
+            return p.set_output_device(None);
+        }
+        let id = unsafe { CStr::from_ptr(id) }
+            .to_str()
+            .map_err(|_| Error::InvalidArgument("device id"))?;
+        p.set_output_device(Some(id))
+    })
+}
+
+/// Renders a session to a WAV file. `wav_format` is 0 for 16 bit PCM, 1 for
+/// 24 bit PCM and 2 for 32 bit float.
+#[unsafe(no_mangle)]
+pub extern "C" fn render_to_wav_file(
+    path: *const c_char,
+    duration_secs: f32,
+    seed: u64,
+    gain_db: f32,
+    min_midi_note: f32,
+    max_midi_note: f32,
+    sample_rate: u32,
+    wav_format: i32,
+) -> i32 {
+    let render = || {
+        if path.is_null() {
+            return Err(Error::InvalidArgument("render path"));
+        }
+        let path = unsafe { CStr::from_ptr(path) }
+            .to_str()
+            .map_err(|_| Error::InvalidArgument("render path"))?;
+        let wav_format =
+            WavFormat::from_index(wav_format).ok_or(Error::InvalidArgument("WAV format"))?;
+        error::validate_gain_db(gain_db)?;
+        error::validate_note_range(min_midi_note, max_midi_note)?;
+
+        let options = RenderOptions {
+            duration_secs,
+            seed,
+            gain_db,
+            min_midi_note,
+            max_midi_note,
+            sample_rate,
+            wav_format,
+            ..Default::default()
+        };
+        render_to_wav(path, &options)
+            .map_err(|e| Error::Io(format!("Failed to render {path}: {e}")))
+    };
+    error::guarded(render)
+}
+
+// JNI-compatible exports for Android service using global static
+#[unsafe(no_mangle)]
+pub extern "C" fn Java_org_klingt_tim_sinewaveTinnitusRetraining_service_AudioPlaybackService_create_1audio_1player()
+-> i32 {
+    error::guarded(|| {
+        let mut player_guard = error::lock(&AUDIO_PLAYER);
+        if player_guard.is_none() {
+            *player_guard = Some(Box::new(AudioPlayer::new()));
+        }
+        Ok(())
+    })
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn Java_org_klingt_tim_sinewaveTinnitusRetraining_service_AudioPlaybackService_start_1audio_1player()
+-> i32 {
+    with_global_player(AudioPlayer::start)
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn Java_org_klingt_tim_sinewaveTinnitusRetraining_service_AudioPlaybackService_stop_1audio_1player()
+-> i32 {
+    with_global_player(|player| {
+        player.stop();
+        Ok(())
+    })
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn Java_org_klingt_tim_sinewaveTinnitusRetraining_service_AudioPlaybackService_pause_1audio_1player()
+-> i32 {
+    with_global_player(|player| {
+        player.pause();
+        Ok(())
+    })
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn Java_org_klingt_tim_sinewaveTinnitusRetraining_service_AudioPlaybackService_resume_1audio_1player()
+-> i32 {
+    with_global_player(|player| {
+        player.resume();
+        Ok(())
+    })
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn Java_org_klingt_tim_sinewaveTinnitusRetraining_service_AudioPlaybackService_setTailFadeMs(
+    _env: *const (),
+    _class: *const (),
+    fade_ms: f32,
+) -> i32 {
+    with_global_player(|player| player.set_tail_fade_ms(fade_ms))
 }
 
 #[unsafe(no_mangle)]
 pub extern "C" fn Java_org_klingt_tim_sinewaveTinnitusRetraining_service_AudioPlaybackService_destroy_1audio_1player()
 -> i32 {
-    let mut player_guard = AUDIO_PLAYER.lock().unwrap();
-    *player_guard = None; // Drop the player
-    1 // Success
+    error::guarded(|| {
+        *error::lock(&AUDIO_PLAYER) = None; // Drop the player
+        Ok(())
+    })
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn Java_org_klingt_tim_sinewaveTinnitusRetraining_service_AudioPlaybackService_setSeed(
+    _env: *const (),
+    _class: *const (),
+    seed: i64,
+) -> i32 {
+    with_global_player(|player| {
+        player.set_seed(seed as u64);
+        Ok(())
+    })
+}
+
+/// Seed of the running or last session, 0 before the first start.
+#[unsafe(no_mangle)]
+pub extern "C" fn Java_org_klingt_tim_sinewaveTinnitusRetraining_service_AudioPlaybackService_getSeed(
+    _env: *const (),
+    _class: *const (),
+) -> i64 {
+    error::catch_panic(|| {
+        Ok(error::lock(&AUDIO_PLAYER)
+            .as_ref()
+            .and_then(|player| player.last_session_seed())
+            .map_or(0, |seed| seed as i64))
+    })
+    .unwrap_or_default()
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn Java_org_klingt_tim_sinewaveTinnitusRetraining_service_AudioPlaybackService_getStreamState(
+    _env: *const (),
+    _class: *const (),
+) -> i32 {
+    error::catch_panic(|| {
+        Ok(error::lock(&AUDIO_PLAYER)
+            .as_ref()
+            .map_or(StreamState::Stopped, |player| player.stream_state()) as i32)
+    })
+    .unwrap_or(StreamState::Stopped as i32)
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn Java_org_klingt_tim_sinewaveTinnitusRetraining_service_AudioPlaybackService_setOutputCeilingDb(
+    _env: *const (),
+    _class: *const (),
+    ceiling_db: f32,
+) -> i32 {
+    with_global_player(|player| player.set_output_ceiling_db(ceiling_db))
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn Java_org_klingt_tim_sinewaveTinnitusRetraining_service_AudioPlaybackService_lockLimiter(
+    _env: *const (),
+    _class: *const (),
+    pin: i32,
+) -> i32 {
+    with_global_player(|player| player.lock_limiter(pin as u32))
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn Java_org_klingt_tim_sinewaveTinnitusRetraining_service_AudioPlaybackService_unlockLimiter(
+    _env: *const (),
+    _class: *const (),
+    pin: i32,
+) -> i32 {
+    with_global_player(|player| player.unlock_limiter(pin as u32))
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn Java_org_klingt_tim_sinewaveTinnitusRetraining_service_AudioPlaybackService_isLimiterLocked(
+    _env: *const (),
+    _class: *const (),
+) -> i32 {
+    error::catch_panic(|| {
+        Ok(error::lock(&AUDIO_PLAYER)
+            .as_ref()
+            .is_some_and(|player| player.is_limiter_locked()) as i32)
+    })
+    .unwrap_or_default()
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn Java_org_klingt_tim_sinewaveTinnitusRetraining_service_AudioPlaybackService_setFullScaleSplDb(
+    _env: *const (),
+    _class: *const (),
+    spl_db: f32,
+) -> i32 {
+    with_global_player(|player| player.set_full_scale_spl_db(spl_db))
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn Java_org_klingt_tim_sinewaveTinnitusRetraining_service_AudioPlaybackService_setDoseLimit(
+    _env: *const (),
+    _class: *const (),
+    criterion_db: f32,
+    criterion_hours: f32,
+    exchange_rate_db: f32,
+    warning_fraction: f32,
+    auto_stop: u8,
+) -> i32 {
+    with_global_player(|player| {
+        player.set_dose_limit(DoseLimit {
+            criterion_db,
+            criterion_hours,
+            exchange_rate_db,
+            warning_fraction,
+            auto_stop: auto_stop != 0,
+        })
+    })
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn Java_org_klingt_tim_sinewaveTinnitusRetraining_service_AudioPlaybackService_getSessionDose(
+    _env: *const (),
+    _class: *const (),
+) -> f64 {
+    error::catch_panic(|| {
+        Ok(error::lock(&AUDIO_PLAYER)
+            .as_ref()
+            .map_or(0.0, |player| player.noise_dose().session))
+    })
+    .unwrap_or_default()
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn Java_org_klingt_tim_sinewaveTinnitusRetraining_service_AudioPlaybackService_getDailyDose(
+    _env: *const (),
+    _class: *const (),
+) -> f64 {
+    error::catch_panic(|| {
+        Ok(error::lock(&AUDIO_PLAYER)
+            .as_ref()
+            .map_or(0.0, |player| player.noise_dose().daily))
+    })
+    .unwrap_or_default()
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn Java_org_klingt_tim_sinewaveTinnitusRetraining_service_AudioPlaybackService_setDailyDose(
+    _env: *const (),
+    _class: *const (),
+    dose: f64,
+) -> i32 {
+    with_global_player(|player| player.set_daily_dose(dose))
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn Java_org_klingt_tim_sinewaveTinnitusRetraining_service_AudioPlaybackService_setGainWithUnit(
+    _env: *const (),
+    _class: *const (),
+    gain_db: f32,
+    unit: i32,
+) -> i32 {
+    with_global_player(|player| player.set_gain(gain_db, gain_unit_from_index(unit)?))
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn Java_org_klingt_tim_sinewaveTinnitusRetraining_service_AudioPlaybackService_setGainChannelWithUnit(
+    _env: *const (),
+    _class: *const (),
+    channel: i32,
+    gain_db: f32,
+    unit: i32,
+) -> i32 {
+    with_global_player(|player| {
+        player.set_gain_channel(
+            channel_from_index(channel)?,
+            gain_db,
+            gain_unit_from_index(unit)?,
+        )
+    })
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn Java_org_klingt_tim_sinewaveTinnitusRetraining_service_AudioPlaybackService_playReferenceTone(
+    _env: *const (),
+    _class: *const (),
+    channel: i32,
+    frequency_hz: f32,
+    level_dbfs: f32,
+) -> i32 {
+    with_global_player(|player| {
+        player.play_reference_tone(channel_from_index(channel)?, frequency_hz, level_dbfs)
+    })
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn Java_org_klingt_tim_sinewaveTinnitusRetraining_service_AudioPlaybackService_stopReferenceTone(
+    _env: *const (),
+    _class: *const (),
+) -> i32 {
+    with_global_player(|player| {
+        player.stop_reference_tone();
+        Ok(())
+    })
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn Java_org_klingt_tim_sinewaveTinnitusRetraining_service_AudioPlaybackService_addCalibrationMeasurement(
+    _env: *const (),
+    _class: *const (),
+    measured_spl_db: f32,
+) -> i32 {
+    with_global_player(|player| {
+        player
+            .add_calibration_measurement(measured_spl_db)
+            .map(drop)
+    })
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn Java_org_klingt_tim_sinewaveTinnitusRetraining_service_AudioPlaybackService_clearCalibration(
+    _env: *const (),
+    _class: *const (),
+) -> i32 {
+    with_global_player(|player| {
+        player.clear_calibration();
+        Ok(())
+    })
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn Java_org_klingt_tim_sinewaveTinnitusRetraining_service_AudioPlaybackService_saveCalibration(
+    env: *mut JniEnv,
+    _class: *const (),
+    path: *mut std::ffi::c_void,
+) -> i32 {
+    with_global_player(|player| {
+        let path = unsafe { jni_string(env, path) }.ok_or(Error::InvalidArgument("path"))?;
+        player.save_calibration(path)
+    })
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn Java_org_klingt_tim_sinewaveTinnitusRetraining_service_AudioPlaybackService_loadCalibration(
+    env: *mut JniEnv,
+    _class: *const (),
+    path: *mut std::ffi::c_void,
+) -> i32 {
+    with_global_player(|player| {
+        let path = unsafe { jni_string(env, path) }.ok_or(Error::InvalidArgument("path"))?;
+        player.load_calibration(path)
+    })
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn Java_org_klingt_tim_sinewaveTinnitusRetraining_service_AudioPlaybackService_startPitchMatch(
+    _env: *const (),
+    _class: *const (),
+    channel: i32,
+    level_db: f32,
+    unit: i32,
+    min_midi_note: f32,
+    max_midi_note: f32,
+    resolution_semitones: f32,
+) -> i32 {
+    with_global_player(|player| {
+        player.start_pitch_match(PitchMatchSettings {
+            channel: ears_from_index(channel)?,
+            level_db,
+            unit: gain_unit_from_index(unit)?,
+            min_midi_note,
+            max_midi_note,
+            resolution_semitones,
+        })
+    })
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn Java_org_klingt_tim_sinewaveTinnitusRetraining_service_AudioPlaybackService_respondPitchMatch(
+    _env: *const (),
+    _class: *const (),
+    response: i32,
+) -> i32 {
+    with_global_player(|player| player.respond_pitch_match(pitch_response_from_index(response)?))
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn Java_org_klingt_tim_sinewaveTinnitusRetraining_service_AudioPlaybackService_replayPitchPair(
+    _env: *const (),
+    _class: *const (),
+) -> i32 {
+    with_global_player(|player| player.replay_pitch_pair())
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn Java_org_klingt_tim_sinewaveTinnitusRetraining_service_AudioPlaybackService_stopPitchMatch(
+    _env: *const (),
+    _class: *const (),
+) -> i32 {
+    with_global_player(|player| {
+        player.stop_pitch_match();
+        Ok(())
+    })
+}
+
+/// Returns the first frequency of the current pair, NaN if no match is
+/// running.
+#[unsafe(no_mangle)]
+pub extern "C" fn Java_org_klingt_tim_sinewaveTinnitusRetraining_service_AudioPlaybackService_getPitchTrialFirstHz(
+    _env: *const (),
+    _class: *const (),
+) -> f32 {
+    global_test_value(|player| Some(player.pitch_trial()?.first_hz))
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn Java_org_klingt_tim_sinewaveTinnitusRetraining_service_AudioPlaybackService_getPitchTrialSecondHz(
+    _env: *const (),
+    _class: *const (),
+) -> f32 {
+    global_test_value(|player| Some(player.pitch_trial()?.second_hz))
+}
+
+/// Returns the matched frequency, NaN if no match has finished.
+#[unsafe(no_mangle)]
+pub extern "C" fn Java_org_klingt_tim_sinewaveTinnitusRetraining_service_AudioPlaybackService_getPitchMatchFrequencyHz(
+    _env: *const (),
+    _class: *const (),
+) -> f32 {
+    global_test_value(|player| Some(player.pitch_match_result()?.frequency_hz))
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn Java_org_klingt_tim_sinewaveTinnitusRetraining_service_AudioPlaybackService_getPitchMatchMinHz(
+    _env: *const (),
+    _class: *const (),
+) -> f32 {
+    global_test_value(|player| Some(player.pitch_match_result()?.min_hz))
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn Java_org_klingt_tim_sinewaveTinnitusRetraining_service_AudioPlaybackService_getPitchMatchMaxHz(
+    _env: *const (),
+    _class: *const (),
+) -> f32 {
+    global_test_value(|player| Some(player.pitch_match_result()?.max_hz))
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn Java_org_klingt_tim_sinewaveTinnitusRetraining_service_AudioPlaybackService_startAudiometry(
+    _env: *const (),
+    _class: *const (),
+    channel: i32,
+    start_level_db_hl: f32,
+    max_level_db_hl: f32,
+) -> i32 {
+    with_global_player(|player| {
+        player.start_audiometry(AudiometrySettings {
+            channel: ears_from_index(channel)?,
+            start_level_db_hl,
+            max_level_db_hl,
+        })
+    })
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn Java_org_klingt_tim_sinewaveTinnitusRetraining_service_AudioPlaybackService_respondAudiometry(
+    _env: *const (),
+    _class: *const (),
+    heard: u8,
+) -> i32 {
+    with_global_player(|player| player.respond_audiometry(heard != 0))
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn Java_org_klingt_tim_sinewaveTinnitusRetraining_service_AudioPlaybackService_replayAudiometryTone(
+    _env: *const (),
+    _class: *const (),
+) -> i32 {
+    with_global_player(|player| player.replay_audiometry_tone())
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn Java_org_klingt_tim_sinewaveTinnitusRetraining_service_AudioPlaybackService_stopAudiometry(
+    _env: *const (),
+    _class: *const (),
+) -> i32 {
+    with_global_player(|player| {
+        player.stop_audiometry();
+        Ok(())
+    })
+}
+
+/// Returns the ear of the current tone, -1 if no test is running.
+#[unsafe(no_mangle)]
+pub extern "C" fn Java_org_klingt_tim_sinewaveTinnitusRetraining_service_AudioPlaybackService_getAudiometryChannel(
+    _env: *const (),
+    _class: *const (),
+) -> i32 {
+    error::catch_panic(|| {
+        Ok(error::lock(&AUDIO_PLAYER)
+            .as_deref()
+            .and_then(AudioPlayer::audiometry_presentation)
+            .map_or(-1, |presentation| presentation.channel as i32))
+    })
+    .unwrap_or(-1)
+}
+
+/// Returns the frequency of the current tone, NaN if no test is running.
+#[unsafe(no_mangle)]
+pub extern "C" fn Java_org_klingt_tim_sinewaveTinnitusRetraining_service_AudioPlaybackService_getAudiometryFrequencyHz(
+    _env: *const (),
+    _class: *const (),
+) -> f32 {
+    global_test_value(|player| Some(player.audiometry_presentation()?.frequency_hz))
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn Java_org_klingt_tim_sinewaveTinnitusRetraining_service_AudioPlaybackService_getAudiometryLevelDbHl(
+    _env: *const (),
+    _class: *const (),
+) -> f32 {
+    global_test_value(|player| Some(player.audiometry_presentation()?.level_db_hl))
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn Java_org_klingt_tim_sinewaveTinnitusRetraining_service_AudioPlaybackService_saveAudiogram(
+    env: *mut JniEnv,
+    _class: *const (),
+    path: *mut std::ffi::c_void,
+) -> i32 {
+    with_global_player(|player| {
+        let path = unsafe { jni_string(env, path) }.ok_or(Error::InvalidArgument("path"))?;
+        player.save_audiogram(path)
+    })
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn Java_org_klingt_tim_sinewaveTinnitusRetraining_service_AudioPlaybackService_loadHearingCompensation(
+    env: *mut JniEnv,
+    _class: *const (),
+    path: *mut std::ffi::c_void,
+    fraction: f32,
+    max_gain_db: f32,
+) -> i32 {
+    with_global_player(|player| {
+        let path = unsafe { jni_string(env, path) }.ok_or(Error::InvalidArgument("path"))?;
+        player.load_hearing_compensation(
+            path,
+            HearingCompensation {
+                fraction,
+                max_gain_db,
+            },
+        )
+    })
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn Java_org_klingt_tim_sinewaveTinnitusRetraining_service_AudioPlaybackService_useAudiometryForCompensation(
+    _env: *const (),
+    _class: *const (),
+    fraction: f32,
+    max_gain_db: f32,
+) -> i32 {
+    with_global_player(|player| {
+        player.use_audiometry_for_compensation(HearingCompensation {
+            fraction,
+            max_gain_db,
+        })
+    })
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn Java_org_klingt_tim_sinewaveTinnitusRetraining_service_AudioPlaybackService_clearHearingCompensation(
+    _env: *const (),
+    _class: *const (),
+) -> i32 {
+    with_global_player(|player| {
+        player.clear_hearing_compensation();
+        Ok(())
+    })
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn Java_org_klingt_tim_sinewaveTinnitusRetraining_service_AudioPlaybackService_setEqualLoudness(
+    _env: *const (),
+    _class: *const (),
+    enabled: u8,
+    target_phon: f32,
+) -> i32 {
+    with_global_player(|player| player.set_equal_loudness((enabled != 0).then_some(target_phon)))
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn Java_org_klingt_tim_sinewaveTinnitusRetraining_service_AudioPlaybackService_loadHeadphoneProfile(
+    env: *mut JniEnv,
+    _class: *const (),
+    channel: i32,
+    path: *mut std::ffi::c_void,
+) -> i32 {
+    with_global_player(|player| {
+        let path = unsafe { jni_string(env, path) }.ok_or(Error::InvalidArgument("path"))?;
+        player.load_headphone_profile(ears_from_index(channel)?, path)
+    })
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn Java_org_klingt_tim_sinewaveTinnitusRetraining_service_AudioPlaybackService_clearHeadphoneProfile(
+    _env: *const (),
+    _class: *const (),
+) -> i32 {
+    with_global_player(|player| {
+        player.clear_headphone_profile();
+        Ok(())
+    })
+}
+
+/// Reads a pitch matching or audiometry value from the global JNI player,
+/// NaN if absent.
+fn global_test_value(f: impl FnOnce(&AudioPlayer) -> Option<f32>) -> f32 {
+    error::catch_panic(|| Ok(error::lock(&AUDIO_PLAYER).as_deref().and_then(f)))
+        .ok()
+        .flatten()
+        .unwrap_or(f32::NAN)
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn Java_org_klingt_tim_sinewaveTinnitusRetraining_service_AudioPlaybackService_setSessionDurationMs(
+    _env: *const (),
+    _class: *const (),
+    duration_ms: i64,
+) -> i32 {
+    with_global_player(|player| {
+        let duration_ms =
+            u64::try_from(duration_ms).map_err(|_| Error::InvalidArgument("session duration"))?;
+        player.set_session_duration((duration_ms > 0).then(|| Duration::from_millis(duration_ms)));
+        Ok(())
+    })
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn Java_org_klingt_tim_sinewaveTinnitusRetraining_service_AudioPlaybackService_getSessionElapsedMs(
+    _env: *const (),
+    _class: *const (),
+) -> i64 {
+    error::catch_panic(|| {
+        Ok(error::lock(&AUDIO_PLAYER)
+            .as_ref()
+            .map_or(0, |player| player.session_elapsed().as_millis() as i64))
+    })
+    .unwrap_or_default()
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn Java_org_klingt_tim_sinewaveTinnitusRetraining_service_AudioPlaybackService_getSessionRemainingMs(
+    _env: *const (),
+    _class: *const (),
+) -> i64 {
+    error::catch_panic(|| {
+        Ok(error::lock(&AUDIO_PLAYER)
+            .as_ref()
+            .map_or(-1, |player| session_remaining_ms(player)))
+    })
+    .unwrap_or(-1)
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn Java_org_klingt_tim_sinewaveTinnitusRetraining_service_AudioPlaybackService_getLastErrorCode(
+    _env: *const (),
+    _class: *const (),
+) -> i32 {
+    get_last_error_code()
+}
+
+/// Returns the message of the most recent error as a Java string, or null if
+/// there was none.
+#[unsafe(no_mangle)]
+pub extern "C" fn Java_org_klingt_tim_sinewaveTinnitusRetraining_service_AudioPlaybackService_getLastErrorMessage(
+    env: *mut JniEnv,
+    _class: *const (),
+) -> *mut std::ffi::c_void {
+    error::catch_panic(|| {
+        let Some(error) = last_error() else {
+            return Ok(std::ptr::null_mut());
+        };
+        // Interior NULs cannot be passed to NewStringUTF
+        let message =
+            std::ffi::CString::new(error.to_string().replace('\0', " ")).unwrap_or_default();
+        Ok(unsafe { jni_new_string_utf(env, message.as_ptr()) })
+    })
+    .unwrap_or(std::ptr::null_mut())
+}
+
+/// `JNIEnv`, a pointer to the JNI function table.
+type JniEnv = *const *const std::ffi::c_void;
+
+/// Indices in the JNI function table.
+const JNI_NEW_STRING_UTF: usize = 167;
+const JNI_GET_STRING_UTF_CHARS: usize = 169;
+const JNI_RELEASE_STRING_UTF_CHARS: usize = 170;
+
+unsafe fn jni_new_string_utf(env: *mut JniEnv, utf: *const c_char) -> *mut std::ffi::c_void {
+    type NewStringUtf = unsafe extern "C" fn(*mut JniEnv, *const c_char) -> *mut std::ffi::c_void;
+    if env.is_null() {
+        return std::ptr::null_mut();
+    }
+    unsafe {
+        let new_string_utf: NewStringUtf = std::mem::transmute(*(*env).add(JNI_NEW_STRING_UTF));
+        new_string_utf(env, utf)
+    }
+}
+
+/// Copies a Java string, `None` if it is null or not valid UTF-8.
+unsafe fn jni_string(env: *mut JniEnv, string: *mut std::ffi::c_void) -> Option<String> {
+    type GetStringUtfChars =
+        unsafe extern "C" fn(*mut JniEnv, *mut std::ffi::c_void, *mut u8) -> *const c_char;
+    type ReleaseStringUtfChars =
+        unsafe extern "C" fn(*mut JniEnv, *mut std::ffi::c_void, *const c_char);
+    if env.is_null() || string.is_null() {
+        return None;
+    }
+    unsafe {
+        let get_chars: GetStringUtfChars =
+            std::mem::transmute(*(*env).add(JNI_GET_STRING_UTF_CHARS));
+        let release_chars: ReleaseStringUtfChars =
+            std::mem::transmute(*(*env).add(JNI_RELEASE_STRING_UTF_CHARS));
+        let chars = get_chars(env, string, std::ptr::null_mut());
+        if chars.is_null() {
+            return None;
+        }
+        let copy = CStr::from_ptr(chars).to_str().ok().map(str::to_owned);
+        release_chars(env, string, chars);
+        copy
+    }
 }
 
 #[unsafe(no_mangle)]
@@ -305,11 +2748,8 @@ pub extern "C" fn Java_org_klingt_tim_sinewaveTinnitusRetraining_service_AudioPl
     _env: *const (),
     _class: *const (),
     gain_db: f32,
-) {
-    let player_guard = AUDIO_PLAYER.lock().unwrap();
-    if let Some(ref player) = *player_guard {
-        player.set_gain_db(gain_db);
-    }
+) -> i32 {
+    with_global_player(|player| player.set_gain_db(gain_db))
 }
 
 #[unsafe(no_mangle)]
@@ -318,16 +2758,184 @@ pub extern "C" fn Java_org_klingt_tim_sinewaveTinnitusRetraining_service_AudioPl
     _class: *const (),
     min_midi_note: f32,
     max_midi_note: f32,
-) {
-    let player_guard = AUDIO_PLAYER.lock().unwrap();
-    if let Some(ref player) = *player_guard {
-        player.set_frequency_range(min_midi_note, max_midi_note);
+) -> i32 {
+    with_global_player(|player| player.set_frequency_range(min_midi_note, max_midi_note))
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn Java_org_klingt_tim_sinewaveTinnitusRetraining_service_AudioPlaybackService_setGainChannel(
+    _env: *const (),
+    _class: *const (),
+    channel: i32,
+    gain_db: f32,
+) -> i32 {
+    with_global_player(|player| player.set_gain_db_channel(channel_from_index(channel)?, gain_db))
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn Java_org_klingt_tim_sinewaveTinnitusRetraining_service_AudioPlaybackService_setFrequencyRangeChannel(
+    _env: *const (),
+    _class: *const (),
+    channel: i32,
+    min_midi_note: f32,
+    max_midi_note: f32,
+) -> i32 {
+    with_global_player(|player| {
+        player.set_frequency_range_channel(
+            channel_from_index(channel)?,
+            min_midi_note,
+            max_midi_note,
+        )
+    })
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn Java_org_klingt_tim_sinewaveTinnitusRetraining_service_AudioPlaybackService_setGainRampMs(
+    _env: *const (),
+    _class: *const (),
+    ramp_ms: f32,
+) -> i32 {
+    with_global_player(|player| player.set_gain_ramp_ms(ramp_ms))
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn Java_org_klingt_tim_sinewaveTinnitusRetraining_service_AudioPlaybackService_setFrequencyMode(
+    _env: *const (),
+    _class: *const (),
+    mode: i32,
+) -> i32 {
+    with_global_player(|player| {
+        player.set_frequency_mode(frequency_mode_from_index(mode)?);
+        Ok(())
+    })
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn Java_org_klingt_tim_sinewaveTinnitusRetraining_service_AudioPlaybackService_setToneFadeMs(
+    _env: *const (),
+    _class: *const (),
+    fade_ms: f32,
+) -> i32 {
+    with_global_player(|player| player.set_tone_fade_ms(fade_ms))
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn Java_org_klingt_tim_sinewaveTinnitusRetraining_service_AudioPlaybackService_setFadeCurve(
+    _env: *const (),
+    _class: *const (),
+    curve: i32,
+) -> i32 {
+    with_global_player(|player| {
+        player.set_fade_curve(fade_curve_from_index(curve)?);
+        Ok(())
+    })
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn Java_org_klingt_tim_sinewaveTinnitusRetraining_service_AudioPlaybackService_setSilenceProbability(
+    _env: *const (),
+    _class: *const (),
+    probability: f32,
+) -> i32 {
+    with_global_player(|player| player.set_silence_probability(probability))
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn Java_org_klingt_tim_sinewaveTinnitusRetraining_service_AudioPlaybackService_setToneDurationDistribution(
+    _env: *const (),
+    _class: *const (),
+    kind: i32,
+    a: f32,
+    b: f32,
+) -> i32 {
+    with_global_player(|player| {
+        player.set_tone_duration(DurationDistribution::from_raw(kind, a, b)?)
+    })
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn Java_org_klingt_tim_sinewaveTinnitusRetraining_service_AudioPlaybackService_setPauseDurationDistribution(
+    _env: *const (),
+    _class: *const (),
+    kind: i32,
+    a: f32,
+    b: f32,
+) -> i32 {
+    with_global_player(|player| {
+        player.set_pause_duration(DurationDistribution::from_raw(kind, a, b)?)
+    })
+}
+
+/// Fixtures shared by the tests that render through `AudioState` or an
+/// `AudioPlayer`.
+#[cfg(test)]
+pub(crate) mod test_util {
+    use super::*;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    pub const STEREO_48K: StreamFormat = StreamFormat {
+        sample_rate: 48000,
+        channels: 2,
+    };
+
+    /// Parameters with the same gain and note range on both ears.
+    pub fn params(gain_db: f32, min_midi_note: f32, max_midi_note: f32) -> SharedParams {
+        SharedParams::new(
+            Channel::ALL.map(|_| ChannelParams::new(gain_db, min_midi_note, max_midi_note)),
+        )
+    }
+
+    /// Plays 10 s tones back to back, so that each ear holds a single tone
+    /// after the initial pause.
+    pub fn hold_tones(params: &SharedParams) {
+        params
+            .schedule()
+            .update(|s| {
+                s.silence_probability = 0.0;
+                s.tone_duration = DurationDistribution::Fixed { ms: 10000.0 };
+            })
+            .unwrap();
+    }
+
+    /// Peak of one channel of interleaved stereo samples.
+    pub fn peak(data: &[f32], channel: usize) -> f32 {
+        data.iter()
+            .skip(channel)
+            .step_by(2)
+            .fold(0.0_f32, |peak, sample| peak.max(sample.abs()))
+    }
+
+    pub fn peak_db(data: &[f32], channel: usize) -> f32 {
+        20.0 * peak(data, channel).log10()
+    }
+
+    /// Level of a tone on one channel of interleaved stereo samples in dBFS.
+    /// Taken from the RMS, as the samples may miss the peaks of high tones.
+    pub fn level_db(data: &[f32], channel: usize) -> f32 {
+        let samples = data.iter().skip(channel).step_by(2);
+        let power = samples.map(|s| s * s).sum::<f32>() / (data.len() / 2) as f32;
+        10.0 * (2.0 * power).log10()
+    }
+
+    /// Waits for the stream to close after `stop` has faded it out.
+    pub fn wait_until_stopped(player: &AudioPlayer) -> bool {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while player.stream_state() != StreamState::Stopped {
+            if Instant::now() >= deadline {
+                return false;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        true
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_util::{STEREO_48K, hold_tones, params, peak, wait_until_stopped};
+    use std::f64::consts::TAU;
     use std::thread;
     use std::time::Duration;
 
@@ -335,20 +2943,567 @@ mod tests {
     #[ignore] // Run manually with: cargo test manual_test_play_sound -- --ignored
     fn manual_test_play_sound() {
         let mut player = AudioPlayer::new();
-        player.start();
+        player.start().unwrap();
         thread::sleep(Duration::from_secs(10)); // Play for 10 seconds
         player.stop();
         // Manually verify sound output
-        assert!(true);
+    }
+
+    #[test]
+    fn test_voices_render_both_channels() {
+        let left = ChannelParams::new(-6.0, 60.0, 72.0);
+        let right = ChannelParams::new(-20.0, 100.0, 110.0);
+        let format = StreamFormat {
+            sample_rate: 44100,
+            channels: 2,
+        };
+        let mut state = AudioState::new(format, SharedParams::new([left, right]), 0);
+
+        let mut data = vec![0.0; 44100 * 2];
+        state.fill(&mut data);
+
+        let left_peak = peak(&data, Channel::Left as usize);
+        let right_peak = peak(&data, Channel::Right as usize);
+        assert!(left_peak > 0.4 && left_peak <= 0.51);
+        assert!(right_peak > 0.05 && right_peak <= 0.11);
+    }
+
+    #[test]
+    fn test_mono_output_mixes_voices() {
+        let format = StreamFormat {
+            sample_rate: 48000,
+            channels: 1,
+        };
+        let mut state = AudioState::new(format, params(-6.0, 60.0, 72.0), 0);
+
+        let mut data = vec![0.0; 48000];
+        state.fill(&mut data);
+
+        let peak = data
+            .iter()
+            .fold(0.0_f32, |peak, sample| peak.max(sample.abs()));
+        assert!(peak > 0.5 && peak <= 1.01);
+    }
+
+    #[test]
+    fn test_reconnect_keeps_sequence() {
+        // Quiet enough that the mono mix stays below the limiter
+        let params = || params(-7.0, 60.0, 72.0);
+        let stereo = STEREO_48K;
+        let mono = StreamFormat {
+            sample_rate: 48000,
+            channels: 1,
+        };
+        let mut reference = AudioState::new(stereo, params(), 5);
+        let mut expected = vec![0.0; 2 * 48000 * 2];
+        reference.render(&mut expected, stereo);
+
+        // The device is replaced by a mono one halfway through
+        let mut state = AudioState::new(stereo, params(), 5);
+        let mut first = vec![0.0; 48000 * 2];
+        state.render(&mut first, stereo);
+        let mut second = vec![0.0; 48000];
+        state.render(&mut second, mono);
+
+        assert_eq!(first, expected[..48000 * 2]);
+        let mixed: Vec<f32> = expected[48000 * 2..]
+            .chunks(2)
+            .map(|frame| frame[0] + frame[1])
+            .collect();
+        assert_eq!(second, mixed);
+
+        // A player keeps rendering through a reconnect at another rate
+        let backend = NullBackend::new(stereo).with_capture(false);
+        let handle = backend.handle();
+        let mut player = AudioPlayer::with_backend(Box::new(backend));
+        assert_eq!(player.stream_state(), StreamState::Stopped);
+        player.start().unwrap();
+        handle.wait_for_frames(4800, Duration::from_secs(10));
+        handle.reconnect(StreamFormat {
+            sample_rate: 44100,
+            channels: 1,
+        });
+        let frames = handle.frames_rendered();
+        assert!(handle.wait_for_frames(frames + 44100, Duration::from_secs(10)));
+        assert_eq!(player.stream_state(), StreamState::Playing);
+        player.stop();
+        assert!(wait_until_stopped(&player));
+    }
+
+    /// Largest difference between consecutive samples of the left channel
+    /// while the gain is raised from -40 dB to 0 dB.
+    fn max_step_while_raising_gain(gain_ramp_ms: f32, tone_ms: f32) -> f32 {
+        let params = params(-40.0, 60.0, 60.0);
+        params.set_gain_ramp_ms(gain_ramp_ms);
+        params
+            .schedule()
+            .update(|s| {
+                s.silence_probability = 0.0;
+                s.tone_duration = DurationDistribution::Fixed { ms: tone_ms };
+            })
+            .unwrap();
+        let format = STEREO_48K;
+        let mut state = AudioState::new(format, params.clone(), 0);
+        let mut output = vec![0.0; 48000 * 2];
+        state.render(&mut output, format);
+
+        // A coarse slider, in steps of 5 dB every 125 ms
+        let mut block = vec![0.0; 6000 * 2];
+        for i in 0..=8 {
+            let gain_db = -40.0 + 5.0 * i as f32;
+            params.channel(Channel::Left).set_gain_db(gain_db);
+            state.render(&mut block, format);
+            output.extend_from_slice(&block);
+        }
+        let left: Vec<f32> = output.iter().step_by(2).copied().collect();
+        assert!(left[left.len() - 1000..].iter().any(|s| s.abs() > 0.9));
+        left.windows(2)
+            .map(|pair| (pair[1] - pair[0]).abs())
+            .fold(0.0, f32::max)
+    }
+
+    #[test]
+    fn test_gain_changes_are_smoothed() {
+        // A 261.6 Hz sine at full scale moves by at most 2 pi f / fs = 0.0342
+        // per sample. The 10 ms ramp adds at most 1 / 480 per sample.
+        let sine_step = 2.0 * std::f32::consts::PI * 261.63 / 48000.0;
+        let max_step = max_step_while_raising_gain(10.0, 10000.0);
+        assert!(max_step < sine_step + 1.0 / 480.0, "{max_step}");
+        assert!(max_step_while_raising_gain(0.0, 10000.0) > 2.0 * sine_step);
+
+        // With fades every 100 ms, the envelope adds at most 1 / 63 per sample
+        let max_step = max_step_while_raising_gain(10.0, 100.0);
+        assert!(
+            max_step < sine_step + 1.0 / 480.0 + 1.0 / 63.0,
+            "{max_step}"
+        );
+    }
+
+    #[test]
+    fn test_pause_keeps_sequence() {
+        let params = || params(-6.0, 60.0, 72.0);
+        let format = STEREO_48K;
+        let mut reference = AudioState::new(format, params(), 3);
+        let mut expected = vec![0.0; (48000 + 4800 + 48000) * 2];
+        reference.render(&mut expected, format);
+
+        let params = params();
+        params.set_tail_fade_ms(100.0);
+        let mut state = AudioState::new(format, params.clone(), 3);
+        let mut before = vec![0.0; 48000 * 2];
+        state.render(&mut before, format);
+        assert_eq!(before, expected[..48000 * 2]);
+
+        // Fades out over 4800 frames, then holds
+        params.pause();
+        let mut paused = vec![0.0; 24000 * 2];
+        state.render(&mut paused, format);
+        assert!(params.is_faded_out());
+        for (i, frame) in paused[..4800 * 2].chunks(2).enumerate() {
+            let gain = (4800 - 1 - i) as f32 / 4800.0;
+            assert_eq!(frame[0], expected[(48000 + i) * 2] * gain);
+        }
+        assert!(paused[4800 * 2..].iter().all(|&s| s == 0.0));
+
+        // Fades back in and continues where it paused
+        params.resume();
+        let mut resumed = vec![0.0; 48000 * 2];
+        state.render(&mut resumed, format);
+        assert!(!params.is_faded_out());
+        assert_eq!(resumed[4800 * 2..], expected[(48000 + 4800 + 4800) * 2..]);
+    }
+
+    #[test]
+    fn test_session_fades_out_and_completes() {
+        let params = params(0.0, 60.0, 72.0);
+        hold_tones(&params);
+        params.set_session_duration_ms(2000);
+        params.set_tail_fade_ms(10.0);
+        let format = STEREO_48K;
+        let events = Arc::new(EventQueue::new());
+        let mut state = AudioState::new(format, params.clone(), 0).with_events(events.clone());
+
+        // Pauses do not count towards the session
+        let mut data = vec![0.0; 48000 * 2];
+        assert!(state.render(&mut data, format));
+        params.pause();
+        assert!(state.render(&mut data, format));
+        assert_eq!(params.session_elapsed_ms(), 1010);
+        params.resume();
+
+        // The whole 2s session is the end fade, so the rest fades from the
+        // level it had at the pause
+        let mut rest = vec![0.0; 48000 * 2];
+        assert!(!state.render(&mut rest, format));
+        assert_eq!(params.session_elapsed_ms(), 2000);
+        let start = peak(&rest[..4800 * 2], 0);
+        assert!(start > 0.4 && start < 0.55);
+        assert!(peak(&rest[(47520 - 480) * 2..47520 * 2], 0) < 0.011);
+        assert!(rest[47520 * 2..].iter().step_by(2).all(|&s| s == 0.0));
+        let completed = || {
+            std::iter::from_fn(|| events.pop())
+                .filter(|&event| event == PlaybackEvent::SessionCompleted)
+                .count()
+        };
+        assert_eq!(completed(), 1);
+
+        // Only completes once
+        assert!(!state.render(&mut rest, format));
+        assert!(rest.iter().all(|&s| s == 0.0));
+        assert_eq!(completed(), 0);
+    }
+
+    /// Renders back-to-back 20ms tones at a fixed frequency and returns the
+    /// left channel after the initial pause.
+    fn render_tone_bursts(sample_rate: u32, fade_ms: f32, curve: FadeCurve) -> Vec<f32> {
+        let params = params(0.0, 115.0, 115.0);
+        params
+            .schedule()
+            .update(|s| {
+                s.silence_probability = 0.0;
+                s.tone_duration = DurationDistribution::Fixed { ms: 20.0 };
+            })
+            .unwrap();
+        params.set_tone_fade_ms(fade_ms);
+        params.set_fade_curve(curve);
+        let format = StreamFormat {
+            sample_rate,
+            channels: 2,
+        };
+        let mut state = AudioState::new(format, params, 0);
+        let mut data = vec![0.0; sample_rate as usize * 2];
+        state.fill(&mut data);
+        data[sample_rate as usize..]
+            .iter()
+            .step_by(2)
+            .copied()
+            .collect()
+    }
+
+    #[test]
+    fn test_tone_fade_is_sample_rate_independent() {
+        for sample_rate in [44100, 96000] {
+            // With a linear 10ms fade the tone first reaches 0.95 after 9.5ms
+            let left = render_tone_bursts(sample_rate, 10.0, FadeCurve::Linear);
+            let start = left.iter().position(|&s| s != 0.0).unwrap();
+            let loud = left.iter().position(|s| s.abs() >= 0.95).unwrap();
+            let attack_ms = (loud - start) as f32 * 1000.0 / sample_rate as f32;
+            assert!((attack_ms - 9.5).abs() < 0.2, "{attack_ms} ms");
+        }
+    }
+
+    #[test]
+    fn test_shaped_fades_reduce_side_lobes() {
+        // Share of the energy more than 2 kHz away from the 6.3 kHz tone, in
+        // a Hann windowed DFT
+        let side_lobe_energy = |curve: FadeCurve| {
+            let signal = &render_tone_bursts(48000, 64.0 / 48.0, curve)[..8192];
+            let n = signal.len();
+            let windowed: Vec<f64> = signal
+                .iter()
+                .enumerate()
+                .map(|(i, &s)| {
+                    let hann = 0.5 - 0.5 * (TAU * i as f64 / n as f64).cos();
+                    s as f64 * hann
+                })
+                .collect();
+            let tone_hz = 440.0 * 2.0_f64.powf((115.0 - 69.0) / 12.0);
+            let (mut total, mut side) = (0.0, 0.0);
+            for bin in (1..n / 2).step_by(2) {
+                let omega = TAU * bin as f64 / n as f64;
+                let (re, im) = windowed
+                    .iter()
+                    .enumerate()
+                    .fold((0.0, 0.0), |(re, im), (i, s)| {
+                        let phase = omega * i as f64;
+                        (re + s * phase.cos(), im - s * phase.sin())
+                    });
+                let power = re * re + im * im;
+                total += power;
+                if (bin as f64 * 48000.0 / n as f64 - tone_hz).abs() > 2000.0 {
+                    side += power;
+                }
+            }
+            side / total
+        };
+
+        // The same 64 samples as the fixed linear ramp used before
+        let linear = side_lobe_energy(FadeCurve::Linear);
+        let raised_cosine = side_lobe_energy(FadeCurve::RaisedCosine);
+        assert!(
+            raised_cosine < linear * 0.1,
+            "linear {linear}, raised cosine {raised_cosine}"
+        );
+    }
+
+    #[test]
+    fn test_fade_curves() {
+        for curve in [
+            FadeCurve::Linear,
+            FadeCurve::RaisedCosine,
+            FadeCurve::Exponential,
+        ] {
+            assert_eq!(curve.gain(1.0), 1.0);
+            assert!(curve.gain(0.0) <= 0.001);
+            let steps: Vec<f32> = (0..=100).map(|i| curve.gain(i as f32 / 100.0)).collect();
+            assert!(steps.windows(2).all(|w| w[0] < w[1]));
+        }
+        assert!((FadeCurve::RaisedCosine.gain(0.5) - 0.5).abs() < 1e-6);
+        // Halfway through the exponential fade is -30 dB
+        assert!((FadeCurve::Exponential.gain(0.5) - 0.0316).abs() < 1e-3);
+        assert_eq!(FadeCurve::from_index(2), Some(FadeCurve::Exponential));
+        assert_eq!(FadeCurve::from_index(3), None);
+    }
+
+    #[test]
+    fn test_frequency_bounds() {
+        let mut rng = Taus88::seed_from_u64(3);
+        let (min_midi, max_midi) = (107.9, 119.4);
+
+        let mut lowest = f32::MAX;
+        let mut fractional = false;
+        for _ in 0..10000 {
+            let midi = FrequencyMode::Continuous.sample_midi(&mut rng, min_midi, max_midi);
+            assert!((min_midi..=max_midi).contains(&midi));
+            lowest = lowest.min(midi);
+            fractional |= midi.fract() != 0.0;
+        }
+        assert!(lowest < 108.0);
+        assert!(fractional);
+
+        for _ in 0..10000 {
+            let midi = FrequencyMode::Semitone.sample_midi(&mut rng, min_midi, max_midi);
+            assert!((108.0..=119.0).contains(&midi));
+            assert_eq!(midi.fract(), 0.0);
+        }
+
+        // A range without a semitone still stays within its bounds
+        let midi = FrequencyMode::Semitone.sample_midi(&mut rng, 60.2, 60.4);
+        assert!((60.2..=60.4).contains(&midi));
+        assert_eq!(
+            FrequencyMode::Continuous.sample_midi(&mut rng, 72.0, 72.0),
+            72.0
+        );
+    }
+
+    #[test]
+    fn test_schedule_controls_pauses() {
+        let params = params(-6.0, 60.0, 72.0);
+        params
+            .schedule()
+            .set(Schedule {
+                silence_probability: 0.0,
+                tone_duration: DurationDistribution::Fixed { ms: 100.0 },
+                pause_duration: DurationDistribution::Fixed { ms: 100.0 },
+            })
+            .unwrap();
+        let mut state = AudioState::new(STEREO_48K, params.clone(), 0);
+
+        // After the initial 500ms pause tones follow each other directly
+        let mut data = vec![0.0; 48000 * 2];
+        state.fill(&mut data);
+        let longest_silence = |data: &[f32]| {
+            let mut longest = 0;
+            let mut current = 0;
+            for frame in data[24000 * 2..].chunks(2) {
+                current = if frame[0] == 0.0 { current + 1 } else { 0 };
+                longest = longest.max(current);
+            }
+            longest
+        };
+        assert!(longest_silence(&data) < 10);
+
+        params
+            .schedule()
+            .update(|s| s.silence_probability = 1.0)
+            .unwrap();
+        state.fill(&mut data);
+        assert!(data.iter().skip(4800 * 2).all(|&s| s == 0.0));
     }
 
     #[test]
     fn test_audio_player() {
-        let mut player = AudioPlayer::new();
-        player.start();
-        thread::sleep(Duration::from_millis(100)); // Brief play
+        let format = StreamFormat {
+            sample_rate: 44100,
+            channels: 2,
+        };
+        let backend = NullBackend::new(format).with_max_frames(44100);
+        let handle = backend.handle();
+        let mut player = AudioPlayer::with_backend(Box::new(backend));
+        player.set_gain_db_channel(Channel::Left, -6.0).unwrap();
+        player.set_gain_db_channel(Channel::Right, -20.0).unwrap();
+        // A sequence that plays a tone on both ears in the second half
+        player.set_seed(0);
+
+        player.start().unwrap();
+        handle.wait_for_frames(44100, Duration::from_secs(10));
+        player.stop();
+
+        let samples = handle.samples();
+        assert_eq!(samples.len(), 44100 * 2);
+        // The first 500ms are silent on both channels
+        assert!(samples[..22050 * 2].iter().all(|&s| s == 0.0));
+        let (left, right) = (peak(&samples, 0), peak(&samples, 1));
+        assert!(left > 0.4 && left <= 0.51);
+        assert!(right > 0.05 && right <= 0.11);
+    }
+
+    #[test]
+    fn test_invalid_parameters() {
+        let _guard = error::lock(&error::TEST_LOCK);
+        let mut player = AudioPlayer::with_backend(Box::new(NullBackend::new(STEREO_48K)));
+        assert_eq!(player.set_gain_db(6.0), Err(Error::InvalidGain(6.0)));
+        assert!(player.set_frequency_range(100.0, 90.0).is_err());
+        assert!(player.set_silence_probability(2.0).is_err());
+
+        assert_eq!(set_gain_db(&mut player, -12.0), ErrorCode::Ok as i32);
+        assert_eq!(
+            set_frequency_range(&mut player, f32::NAN, 90.0),
+            ErrorCode::InvalidNoteRange as i32
+        );
+        assert_eq!(
+            set_gain_db_channel(&mut player, 2, -12.0),
+            ErrorCode::InvalidChannel as i32
+        );
+        assert_eq!(get_last_error_code(), ErrorCode::InvalidChannel as i32);
+        let mut buffer = [1 as c_char; 8];
+        assert_eq!(get_last_error_message(buffer.as_mut_ptr(), 8), 17);
+        let message = unsafe { CStr::from_ptr(buffer.as_ptr()) };
+        assert_eq!(message.to_str(), Ok("invalid"));
+        assert_eq!(
+            set_tone_duration_distribution(&mut player, 7, 100.0, 200.0),
+            ErrorCode::InvalidSchedule as i32
+        );
+        assert_eq!(
+            start_audio_player(std::ptr::null_mut()),
+            ErrorCode::NoPlayer as i32
+        );
+    }
+
+    #[test]
+    fn test_playback_events() {
+        let params = params(-6.0, 60.0, 72.0);
+        params
+            .schedule()
+            .update(|s| s.silence_probability = 0.5)
+            .unwrap();
+        let format = STEREO_48K;
+        let events = Arc::new(EventQueue::new());
+        let mut state = AudioState::new(format, params, 1).with_events(events.clone());
+        let mut data = vec![0.0; 48000 * 2 * 4];
+        state.render(&mut data, format);
+
+        let (mut tones, mut silences) = ([0; 2], [0; 2]);
+        while let Some(event) = events.pop() {
+            match event {
+                PlaybackEvent::ToneStarted {
+                    channel,
+                    frequency_hz,
+                    duration_ms,
+                } => {
+                    assert!((261.0..524.0).contains(&frequency_hz));
+                    assert!((150.0..=400.0).contains(&duration_ms));
+                    tones[channel as usize] += 1;
+                }
+                PlaybackEvent::SilenceStarted {
+                    channel,
+                    duration_ms,
+                } => {
+                    assert!((150.0..=400.0).contains(&duration_ms));
+                    silences[channel as usize] += 1;
+                }
+                _ => panic!("unexpected event {event:?}"),
+            }
+        }
+        assert!(tones.iter().all(|&n| n > 0));
+        assert!(silences.iter().all(|&n| n > 0));
+
+        // Over the C API
+        let backend = NullBackend::new(format).with_max_frames(48000);
+        let handle = backend.handle();
+        let mut player = AudioPlayer::with_backend(Box::new(backend));
+        assert_eq!(start_audio_player(&mut player), ErrorCode::Ok as i32);
+        handle.wait_for_frames(48000, Duration::from_secs(10));
+        let mut event = RawPlaybackEvent::default();
+        assert!(poll_playback_event(&mut player, &mut event));
+        assert!(event.kind <= PlaybackEventKind::SilenceStarted as i32);
+
+        extern "C" fn count_stops(
+            event: *const RawPlaybackEvent,
+            user_data: *mut std::ffi::c_void,
+        ) {
+            let counter = unsafe { &*(user_data as *const AtomicU32) };
+            if unsafe { (*event).kind } == PlaybackEventKind::StreamStopped as i32 {
+                counter.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        let stops = AtomicU32::new(0);
+        let user_data = &stops as *const AtomicU32 as *mut std::ffi::c_void;
+        assert_eq!(
+            set_playback_event_callback(&mut player, Some(count_stops), user_data),
+            ErrorCode::Ok as i32
+        );
+        assert_eq!(stop_audio_player(&mut player), ErrorCode::Ok as i32);
+        for _ in 0..100 {
+            if stops.load(Ordering::Relaxed) > 0 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        set_playback_event_callback(&mut player, None, std::ptr::null_mut());
+        assert_eq!(stops.load(Ordering::Relaxed), 1);
+        assert!(!poll_playback_event(&mut player, &mut event));
+    }
+
+    #[test]
+    fn test_unordered_range_does_not_panic() {
+        // `ChannelParams` itself is not validated
+        let voices = Channel::ALL.map(|_| ChannelParams::new(-6.0, 90.0, 80.0));
+        let format = StreamFormat {
+            sample_rate: 44100,
+            channels: 2,
+        };
+        for mode in [FrequencyMode::Continuous, FrequencyMode::Semitone] {
+            let params = SharedParams::new(voices.clone());
+            params.set_frequency_mode(mode);
+            let mut state = AudioState::new(format, params, 0);
+            let mut data = vec![0.0; 44100 * 2];
+            state.fill(&mut data);
+            assert!(data.iter().all(|s| s.is_finite()));
+        }
+    }
+
+    #[test]
+    fn test_seed_replays_session() {
+        let format = StreamFormat {
+            sample_rate: 44100,
+            channels: 2,
+        };
+        let backend = NullBackend::new(format).with_max_frames(44100);
+        let handle = backend.handle();
+        let mut player = AudioPlayer::with_backend(Box::new(backend));
+        assert_eq!(player.last_session_seed(), None);
+
+        player.start().unwrap();
+        handle.wait_for_frames(44100, Duration::from_secs(10));
+        let seed = player.last_session_seed().unwrap();
+        // A seed set while playing is kept for the next session
+        player.set_seed(seed);
+        player.stop();
+        assert_eq!(player.last_session_seed(), Some(seed));
+        let first = handle.take_samples();
+
+        player.start().unwrap();
+        handle.wait_for_frames(88200, Duration::from_secs(10));
+        player.stop();
+        assert_eq!(handle.take_samples(), first);
+        assert_eq!(player.last_session_seed(), Some(seed));
+
+        // Without a seed every session plays a new sequence
+        player.start().unwrap();
+        handle.wait_for_frames(132300, Duration::from_secs(10));
         player.stop();
-        // Should not panic
-        assert!(true);
+        assert_ne!(player.last_session_seed(), Some(seed));
+        assert_ne!(handle.take_samples(), first);
     }
 }
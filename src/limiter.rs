@@ -0,0 +1,155 @@
+/// Highest accepted output ceiling, i.e. digital full scale.
+pub const MAX_OUTPUT_CEILING_DB: f32 = 0.0;
+/// Lowest accepted output ceiling.
+pub const MIN_OUTPUT_CEILING_DB: f32 = -60.0;
+
+/// Time for the limiter to recover by 1/e after a peak.
+const RELEASE_MS: f32 = 50.0;
+
+/// Channels limited independently. Further output channels are never
+/// written to by the voices.
+const LIMITED_CHANNELS: usize = 2;
+
+/// Peak limiter without look-ahead: the gain drops at once to keep each
+/// sample at or below the ceiling and recovers exponentially afterwards.
+/// Each ear is limited on its own.
+pub(crate) struct Limiter {
+    gains: [f32; LIMITED_CHANNELS],
+    release_coeff: f32,
+}
+
+impl Limiter {
+    pub(crate) fn new(sample_rate: u32) -> Self {
+        let mut limiter = Self {
+            gains: [1.0; LIMITED_CHANNELS],
+            release_coeff: 0.0,
+        };
+        limiter.set_sample_rate(sample_rate);
+        limiter
+    }
+
+    pub(crate) fn set_sample_rate(&mut self, sample_rate: u32) {
+        let release_samples = (RELEASE_MS * sample_rate as f32 / 1000.0).max(1.0);
+        self.release_coeff = (-1.0 / release_samples).exp();
+    }
+
+    /// Limits the interleaved `data` to `ceiling` (linear). Samples that are
+    /// not finite are replaced by silence.
+    pub(crate) fn process(&mut self, data: &mut [f32], num_channels: usize, ceiling: f32) {
+        for frame in data.chunks_mut(num_channels) {
+            for (sample, gain) in frame.iter_mut().zip(&mut self.gains) {
+                if !sample.is_finite() {
+                    *sample = 0.0;
+                }
+                *gain = 1.0 - (1.0 - *gain) * self.release_coeff;
+                let peak = sample.abs() * *gain;
+                if peak > ceiling {
+                    *gain *= ceiling / peak;
+                }
+                // Guards against rounding in the gain computation
+                *sample = (*sample * *gain).clamp(-ceiling, ceiling);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{STEREO_48K, peak};
+    use crate::{
+        AudioPlayer, AudioState, ChannelParams, Error, NullBackend, SharedParams, StreamFormat,
+    };
+
+    #[test]
+    fn test_limiter_holds_ceiling() {
+        let mut limiter = Limiter::new(48000);
+        let ceiling = 0.5;
+        let mut data: Vec<f32> = (0..48000)
+            .flat_map(|i| {
+                let s = (i as f32 * 0.05).sin();
+                [s * 4.0, s * 0.25]
+            })
+            .collect();
+        data[1000] = f32::NAN;
+        data[1002] = f32::INFINITY;
+        limiter.process(&mut data, 2, ceiling);
+
+        assert!(data.iter().all(|s| s.abs() <= ceiling));
+        assert_eq!(data[1000], 0.0);
+        assert_eq!(data[1002], 0.0);
+        // The loud channel is held at the ceiling, the quiet one untouched
+        assert!(peak(&data, 0) > 0.49);
+        assert!((peak(&data, 1) - 0.25).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_limiter_releases() {
+        let mut limiter = Limiter::new(48000);
+        let mut peak = [2.0, 0.0];
+        limiter.process(&mut peak, 2, 1.0);
+        assert_eq!(peak[0], 1.0);
+
+        // Recovers to within 1% of unity after 5 release times
+        let mut quiet = vec![0.1; 2 * 48000 * 5 * RELEASE_MS as usize / 1000];
+        limiter.process(&mut quiet, 2, 1.0);
+        assert!(quiet[0] < 0.06);
+        assert!(quiet[quiet.len() - 2] > 0.099);
+    }
+
+    #[test]
+    fn test_output_stays_below_ceiling() {
+        // Values the player would reject, written straight to the atomics
+        let left = ChannelParams::new(40.0, 100.0, 110.0);
+        let right = ChannelParams::new(0.0, f32::NAN, f32::INFINITY);
+        right.set_gain_db(f32::NAN);
+        let params = SharedParams::new([left, right]);
+        params.set_gain_ramp_ms(f32::INFINITY);
+        params.set_tone_fade_ms(f32::NAN);
+        params.set_tail_fade_ms(f32::NEG_INFINITY);
+        params.set_output_ceiling_db(-20.0);
+        assert!((params.output_ceiling_db() + 20.0).abs() < 1e-4);
+        let mut state = AudioState::new(STEREO_48K, params.clone(), 0);
+
+        let mut data = vec![0.0; 48000 * 2];
+        state.render(&mut data, STEREO_48K);
+        assert!(data.iter().all(|s| s.is_finite()));
+        assert!(peak(&data, 0) > 0.09 && peak(&data, 0) <= 0.1);
+        assert_eq!(peak(&data, 1), 0.0);
+
+        // Both voices at the ceiling mixed on a mono output are limited too
+        let voices = crate::test_util::params(0.0, 60.0, 72.0);
+        voices.set_output_ceiling_db(-6.0);
+        let mono = StreamFormat {
+            sample_rate: 48000,
+            channels: 1,
+        };
+        let mut state = AudioState::new(mono, voices, 0);
+        let mut data = vec![0.0; 48000];
+        state.render(&mut data, mono);
+        assert!(data.iter().all(|s| s.abs() <= 0.502));
+    }
+
+    #[test]
+    fn test_limiter_lock() {
+        let mut player = AudioPlayer::with_backend(Box::new(NullBackend::new(STEREO_48K)));
+        assert_eq!(player.output_ceiling_db(), 0.0);
+        assert_eq!(
+            player.set_output_ceiling_db(3.0),
+            Err(Error::InvalidArgument("output ceiling"))
+        );
+        assert!(player.set_output_ceiling_db(f32::NAN).is_err());
+        player.set_output_ceiling_db(-10.0).unwrap();
+
+        player.lock_limiter(1234).unwrap();
+        assert!(player.is_limiter_locked());
+        assert_eq!(player.lock_limiter(0), Err(Error::LimiterLocked));
+        assert_eq!(player.set_output_ceiling_db(0.0), Err(Error::LimiterLocked));
+        assert_eq!(player.unlock_limiter(4321), Err(Error::LimiterLocked));
+        assert!((player.output_ceiling_db() + 10.0).abs() < 1e-4);
+
+        player.unlock_limiter(1234).unwrap();
+        assert!(!player.is_limiter_locked());
+        player.set_output_ceiling_db(0.0).unwrap();
+    }
+}
@@ -0,0 +1,335 @@
+//! Command-line player for the retraining sound, for desktops and headless
+//! machines without the Flutter app.
+
+use sinewave_tinnitus_retraining_audio_core::{
+    AudioPlayer, FrequencyMode, NullBackend, RenderOptions, StreamFormat, StreamState, WavFormat,
+    entropy_seed, render_to_wav,
+};
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const USAGE: &str = "\
+Usage: sinewave-tinnitus-retraining [OPTIONS]
+
+Plays the tinnitus retraining sound until interrupted with Ctrl-C.
+
+Options:
+  --gain <DB>          Output gain in dBFS (default: -12)
+  --min-note <MIDI>    Lowest MIDI note (default: 69)
+  --max-note <MIDI>    Highest MIDI note (default: 115)
+  --min-hz <HZ>        Lowest frequency in Hz
+  --max-hz <HZ>        Highest frequency in Hz
+  --semitones          Only play semitones within the frequency range
+  --duration <SECS>    Stop after this many seconds
+  --seed <N>           Seed of the tone sequence (default: random)
+  --device <ID>        Output device id or name (default: system default)
+  --list-devices       List the output devices and exit
+  --dry-run            Render in real time without an audio device
+  --output <FILE>      Render to a WAV file instead of playing
+  --format <FORMAT>    WAV sample format: pcm16, pcm24 or float (default: pcm16)
+  --sample-rate <HZ>   Sample rate of WAV renders and dry runs (default: 44100)
+  -h, --help           Print this help";
+
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+#[derive(Debug, PartialEq)]
+struct Options {
+    gain_db: f32,
+    min_midi_note: f32,
+    max_midi_note: f32,
+    frequency_mode: FrequencyMode,
+    duration_secs: Option<f32>,
+    seed: Option<u64>,
+    device: Option<String>,
+    list_devices: bool,
+    dry_run: bool,
+    output: Option<PathBuf>,
+    wav_format: WavFormat,
+    sample_rate: u32,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            gain_db: -12.0,
+            min_midi_note: 69.0,
+            max_midi_note: 115.0,
+            frequency_mode: FrequencyMode::Continuous,
+            duration_secs: None,
+            seed: None,
+            device: None,
+            list_devices: false,
+            dry_run: false,
+            output: None,
+            wav_format: WavFormat::Pcm16,
+            sample_rate: 44100,
+        }
+    }
+}
+
+fn hz_to_midi(hz: f32) -> f32 {
+    69.0 + 12.0 * (hz / 440.0).log2()
+}
+
+fn parse_value<T: std::str::FromStr>(option: &str, value: Option<String>) -> Result<T, String> {
+    let value = value.ok_or_else(|| format!("{option} requires a value"))?;
+    value
+        .parse()
+        .map_err(|_| format!("invalid value for {option}: {value}"))
+}
+
+fn parse_hz(option: &str, value: Option<String>) -> Result<f32, String> {
+    let hz: f32 = parse_value(option, value)?;
+    if !(hz.is_finite() && hz > 0.0) {
+        return Err(format!("{option} must be a positive frequency"));
+    }
+    Ok(hz_to_midi(hz))
+}
+
+/// Parses the command line. Returns `None` if help was requested.
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Option<Options>, String> {
+    let mut options = Options::default();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--gain" => options.gain_db = parse_value(&arg, args.next())?,
+            "--min-note" => options.min_midi_note = parse_value(&arg, args.next())?,
+            "--max-note" => options.max_midi_note = parse_value(&arg, args.next())?,
+            "--min-hz" => options.min_midi_note = parse_hz(&arg, args.next())?,
+            "--max-hz" => options.max_midi_note = parse_hz(&arg, args.next())?,
+            "--semitones" => options.frequency_mode = FrequencyMode::Semitone,
+            "--duration" => options.duration_secs = Some(parse_value(&arg, args.next())?),
+            "--seed" => options.seed = Some(parse_value(&arg, args.next())?),
+            "--device" => options.device = Some(parse_value(&arg, args.next())?),
+            "--list-devices" => options.list_devices = true,
+            "--dry-run" => options.dry_run = true,
+            "--output" => options.output = Some(parse_value(&arg, args.next())?),
+            "--sample-rate" => options.sample_rate = parse_value(&arg, args.next())?,
+            "--format" => {
+                let format: String = parse_value(&arg, args.next())?;
+                options.wav_format = match format.as_str() {
+                    "pcm16" => WavFormat::Pcm16,
+                    "pcm24" => WavFormat::Pcm24,
+                    "float" => WavFormat::Float32,
+                    _ => return Err(format!("unknown WAV format: {format}")),
+                };
+            }
+            "-h" | "--help" => return Ok(None),
+            _ => return Err(format!("unknown option: {arg}")),
+        }
+    }
+
+    if options.min_midi_note > options.max_midi_note {
+        return Err("the minimum frequency is above the maximum frequency".to_string());
+    }
+    if let Some(duration) = options.duration_secs
+        && !(duration.is_finite() && duration > 0.0)
+    {
+        return Err("--duration must be positive".to_string());
+    }
+    if options.output.is_some() && options.duration_secs.is_none() {
+        return Err("--output requires --duration".to_string());
+    }
+    Ok(Some(options))
+}
+
+fn render(options: &Options, path: &PathBuf) -> ExitCode {
+    let seed = options.seed.unwrap_or_else(entropy_seed);
+    log::info!("Rendering tone sequence with seed {seed}");
+    let render_options = RenderOptions {
+        duration_secs: options.duration_secs.unwrap_or_default(),
+        seed,
+        gain_db: options.gain_db,
+        min_midi_note: options.min_midi_note,
+        max_midi_note: options.max_midi_note,
+        frequency_mode: options.frequency_mode,
+        sample_rate: options.sample_rate,
+        wav_format: options.wav_format,
+        ..Default::default()
+    };
+    match render_to_wav(path, &render_options) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Failed to render {}: {e}", path.display());
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn play(options: &Options) -> ExitCode {
+    let interrupted = Arc::new(AtomicBool::new(false));
+    let handler_flag = interrupted.clone();
+    if let Err(e) = ctrlc::set_handler(move || handler_flag.store(true, Ordering::Relaxed)) {
+        eprintln!("Failed to install Ctrl-C handler: {e}");
+        return ExitCode::FAILURE;
+    }
+
+    let mut player = if options.dry_run {
+        let format = StreamFormat {
+            sample_rate: options.sample_rate,
+            channels: 2,
+        };
+        let backend = NullBackend::new(format)
+            .with_realtime(true)
+            .with_capture(false);
+        AudioPlayer::with_backend(Box::new(backend))
+    } else {
+        AudioPlayer::new()
+    };
+    let configured = player
+        .set_gain_db(options.gain_db)
+        .and_then(|()| player.set_frequency_range(options.min_midi_note, options.max_midi_note));
+    if let Err(e) = configured {
+        eprintln!("{e}");
+        return ExitCode::FAILURE;
+    }
+    player.set_frequency_mode(options.frequency_mode);
+    if let Some(seed) = options.seed {
+        player.set_seed(seed);
+    }
+    if let Some(device) = &options.device
+        && let Err(e) = player.set_output_device(Some(device))
+    {
+        eprintln!("{e}");
+        return ExitCode::FAILURE;
+    }
+    if let Err(e) = player.start() {
+        eprintln!("Failed to start playback: {e}");
+        return ExitCode::FAILURE;
+    }
+
+    let deadline = options
+        .duration_secs
+        .map(|secs| Instant::now() + Duration::from_secs_f32(secs));
+    while !interrupted.load(Ordering::Relaxed) && deadline.is_none_or(|d| Instant::now() < d) {
+        thread::sleep(POLL_INTERVAL);
+    }
+
+    // The player fades out the current tone before the stream closes
+    player.stop();
+    let fade = Duration::from_secs_f32(player.tail_fade_ms() / 1000.0);
+    let fade_deadline = Instant::now() + fade + 2 * POLL_INTERVAL;
+    while player.stream_state() == StreamState::Playing && Instant::now() < fade_deadline {
+        thread::sleep(POLL_INTERVAL);
+    }
+    ExitCode::SUCCESS
+}
+
+fn list_devices() -> ExitCode {
+    let devices = AudioPlayer::new().output_devices();
+    if devices.is_empty() {
+        eprintln!("No output devices found");
+        return ExitCode::FAILURE;
+    }
+    for device in devices {
+        let default = if device.is_default { " (default)" } else { "" };
+        println!("{}{default}", device.id);
+        println!("  name: {}", device.name);
+        println!("  sample rates: {:?}", device.sample_rates);
+        println!("  channels: {:?}", device.channels);
+    }
+    ExitCode::SUCCESS
+}
+
+struct StderrLogger;
+
+impl log::Log for StderrLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::Level::Info
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!("{}: {}", record.level(), record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: StderrLogger = StderrLogger;
+
+fn main() -> ExitCode {
+    if log::set_logger(&LOGGER).is_ok() {
+        log::set_max_level(log::LevelFilter::Info);
+    }
+
+    let options = match parse_args(std::env::args().skip(1)) {
+        Ok(Some(options)) => options,
+        Ok(None) => {
+            println!("{USAGE}");
+            return ExitCode::SUCCESS;
+        }
+        Err(e) => {
+            eprintln!("{e}\n\n{USAGE}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    if options.list_devices {
+        return list_devices();
+    }
+    match &options.output {
+        Some(path) => render(&options, path),
+        None => play(&options),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Option<Options>, String> {
+        parse_args(args.iter().map(|s| s.to_string()))
+    }
+
+    #[test]
+    fn test_defaults() {
+        assert_eq!(parse(&[]), Ok(Some(Options::default())));
+        assert_eq!(parse(&["--help"]), Ok(None));
+    }
+
+    #[test]
+    fn test_frequency_options() {
+        let options = parse(&["--min-hz", "440", "--max-note", "100.5"])
+            .unwrap()
+            .unwrap();
+        assert!((options.min_midi_note - 69.0).abs() < 1e-4);
+        assert_eq!(options.max_midi_note, 100.5);
+
+        assert!(parse(&["--min-note", "100", "--max-note", "90"]).is_err());
+        assert!(parse(&["--min-hz", "-3"]).is_err());
+        assert_eq!(
+            parse(&["--semitones"]).unwrap().unwrap().frequency_mode,
+            FrequencyMode::Semitone
+        );
+    }
+
+    #[test]
+    fn test_render_options() {
+        let options = parse(&[
+            "--output",
+            "session.wav",
+            "--duration",
+            "60",
+            "--format",
+            "pcm24",
+            "--seed",
+            "42",
+        ])
+        .unwrap()
+        .unwrap();
+        assert_eq!(options.output, Some(PathBuf::from("session.wav")));
+        assert_eq!(options.wav_format, WavFormat::Pcm24);
+        assert_eq!(options.seed, Some(42));
+
+        assert!(parse(&["--output", "session.wav"]).is_err());
+        assert!(parse(&["--dry-run"]).unwrap().unwrap().dry_run);
+        assert!(parse(&["--format", "mp3"]).is_err());
+        assert!(parse(&["--gain"]).is_err());
+    }
+}
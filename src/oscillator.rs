@@ -29,6 +29,11 @@ impl Oscillator {
         x
     }
 
+    pub fn set_sample_rate(&mut self, sample_rate: f32) {
+        self.sample_rate = sample_rate;
+        self.phase_inc = TWO_PI * self.freq / self.sample_rate;
+    }
+
     pub fn set_freq(&mut self, freq: f32, interp_samples: u32) {
         if interp_samples == 0 {
             self.freq = freq;
@@ -98,7 +103,27 @@ mod tests {
     fn test_oscillator_produces_samples() {
         let mut osc = Oscillator::new(44100.0);
         let sample = osc.next_sample();
-        assert!(sample >= -1.0 && sample <= 1.0);
+        assert!((-1.0..=1.0).contains(&sample));
+    }
+
+    #[test]
+    fn test_frequency_at_sample_rate() {
+        for sample_rate in [44100.0, 48000.0, 96000.0] {
+            let mut osc = Oscillator::new(sample_rate);
+            osc.set_freq(1000.0, 0);
+
+            // One second of output contains one rising zero crossing per period
+            let mut previous = osc.next_sample();
+            let mut crossings = 0;
+            for _ in 1..sample_rate as usize {
+                let sample = osc.next_sample();
+                if previous < 0.0 && sample >= 0.0 {
+                    crossings += 1;
+                }
+                previous = sample;
+            }
+            assert!((999..=1001).contains(&crossings));
+        }
     }
 
     #[test]
@@ -0,0 +1,422 @@
+use crate::ear_curves::{hz_to_midi, midi_to_hz};
+use crate::error::{validate_gain, validate_note_range};
+use crate::test_tones::TonePattern;
+use crate::{Channel, Error, GainUnit};
+
+/// Length of each tone of a pair.
+const PAIR_TONE_MS: f32 = 1000.0;
+/// Silence between the two tones of a pair.
+const PAIR_GAP_MS: f32 = 500.0;
+/// Attack and release of the pair tones.
+const PAIR_FADE_MS: f32 = 50.0;
+
+/// Half width of the bracket searched again after an octave correction.
+const OCTAVE_BRACKET_SEMITONES: f32 = 6.0;
+/// Octave corrections made at most, so that an inconsistent patient cannot
+/// keep the procedure running.
+const MAX_OCTAVE_SHIFTS: i32 = 2;
+
+/// Settings of a pitch match.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct PitchMatchSettings {
+    /// Ear the tones are played on, `None` for both.
+    pub channel: Option<Channel>,
+    /// Level of the tones, capped by the output ceiling.
+    pub level_db: f32,
+    pub unit: GainUnit,
+    /// Range searched for the tinnitus pitch.
+    pub min_midi_note: f32,
+    pub max_midi_note: f32,
+    /// Width of the bracket at which the search ends, in semitones.
+    pub resolution_semitones: f32,
+}
+
+impl PitchMatchSettings {
+    pub fn validate(&self) -> Result<(), Error> {
+        validate_gain(self.level_db, self.unit)?;
+        validate_note_range(self.min_midi_note, self.max_midi_note)?;
+        if !(0.1..=12.0).contains(&self.resolution_semitones) {
+            return Err(Error::InvalidArgument("pitch match resolution"));
+        }
+        Ok(())
+    }
+}
+
+impl Default for PitchMatchSettings {
+    fn default() -> Self {
+        Self {
+            channel: None,
+            level_db: -30.0,
+            unit: GainUnit::Dbfs,
+            // About 250 Hz to 12.5 kHz
+            min_midi_note: 59.0,
+            max_midi_note: 127.0,
+            resolution_semitones: 1.0,
+        }
+    }
+}
+
+/// The patient's answer to a pair of tones.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PitchResponse {
+    FirstCloser = 0,
+    SecondCloser = 1,
+    /// The tinnitus is lower than both tones.
+    TinnitusLower = 2,
+    /// The tinnitus is higher than both tones.
+    TinnitusHigher = 3,
+}
+
+impl PitchResponse {
+    pub fn from_index(index: i32) -> Option<Self> {
+        match index {
+            0 => Some(PitchResponse::FirstCloser),
+            1 => Some(PitchResponse::SecondCloser),
+            2 => Some(PitchResponse::TinnitusLower),
+            3 => Some(PitchResponse::TinnitusHigher),
+            _ => None,
+        }
+    }
+
+    /// Whether the higher tone of the pair was chosen.
+    fn chose_higher(self) -> bool {
+        matches!(
+            self,
+            PitchResponse::SecondCloser | PitchResponse::TinnitusHigher
+        )
+    }
+}
+
+/// Two tones to compare with the tinnitus, the lower one first.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct PitchTrial {
+    pub first_hz: f32,
+    pub second_hz: f32,
+}
+
+/// Estimated tinnitus pitch.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct PitchMatchResult {
+    pub frequency_hz: f32,
+    /// Range the tinnitus pitch was narrowed down to.
+    pub min_hz: f32,
+    pub max_hz: f32,
+    pub trials: u32,
+    /// Octaves the first estimate was moved by after the octave confusion
+    /// check, negative if down.
+    pub octave_shifts: i32,
+}
+
+impl PitchMatchResult {
+    /// The confidence range as MIDI notes, e.g. for
+    /// `AudioPlayer::set_frequency_range`.
+    pub fn note_range(&self) -> (f32, f32) {
+        (hz_to_midi(self.min_hz), hz_to_midi(self.max_hz))
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Phase {
+    Bracketing,
+    /// Compares the estimate with the octave above.
+    OctaveUp,
+    /// Compares the estimate with the octave below.
+    OctaveDown,
+    Finished,
+}
+
+/// Bracketing procedure for matching the tinnitus pitch. Each trial presents
+/// two tones at a quarter and three quarters of the bracket on a log
+/// frequency scale, and each answer halves the bracket or moves it past the
+/// tones. Once the bracket is narrow enough, the estimate is compared with
+/// the octaves above and below, which patients often confuse with it, and
+/// moved and bracketed again if one of them is chosen.
+#[derive(Clone, Debug)]
+pub struct PitchMatcher {
+    settings: PitchMatchSettings,
+    // Bracket on the MIDI note scale
+    low: f32,
+    high: f32,
+    phase: Phase,
+    octave_shifts: i32,
+    trials: u32,
+}
+
+impl PitchMatcher {
+    pub fn new(settings: PitchMatchSettings) -> Result<Self, Error> {
+        settings.validate()?;
+        let mut matcher = Self {
+            settings,
+            low: settings.min_midi_note,
+            high: settings.max_midi_note,
+            phase: Phase::Bracketing,
+            octave_shifts: 0,
+            trials: 0,
+        };
+        if matcher.high - matcher.low <= settings.resolution_semitones {
+            matcher.check_octave();
+        }
+        Ok(matcher)
+    }
+
+    pub fn settings(&self) -> &PitchMatchSettings {
+        &self.settings
+    }
+
+    fn estimate(&self) -> f32 {
+        (self.low + self.high) / 2.0
+    }
+
+    /// The pair to present next, `None` once finished.
+    pub fn trial(&self) -> Option<PitchTrial> {
+        let (first, second) = match self.phase {
+            Phase::Bracketing => {
+                let quarter = (self.high - self.low) / 4.0;
+                (self.low + quarter, self.high - quarter)
+            }
+            Phase::OctaveUp => (self.estimate(), self.estimate() + 12.0),
+            Phase::OctaveDown => (self.estimate() - 12.0, self.estimate()),
+            Phase::Finished => return None,
+        };
+        Some(PitchTrial {
+            first_hz: midi_to_hz(first),
+            second_hz: midi_to_hz(second),
+        })
+    }
+
+    pub fn respond(&mut self, response: PitchResponse) -> Result<(), Error> {
+        let quarter = (self.high - self.low) / 4.0;
+        match self.phase {
+            Phase::Bracketing => {
+                match response {
+                    PitchResponse::FirstCloser => self.high = self.estimate(),
+                    PitchResponse::SecondCloser => self.low = self.estimate(),
+                    PitchResponse::TinnitusLower => self.high = self.low + quarter,
+                    PitchResponse::TinnitusHigher => self.low = self.high - quarter,
+                }
+                if self.high - self.low <= self.settings.resolution_semitones {
+                    self.check_octave();
+                }
+            }
+            Phase::OctaveUp if response.chose_higher() => self.shift_octave(1),
+            // Only checked down if the estimate has not been moved up
+            Phase::OctaveUp if self.octave_shifts == 0 && self.can_shift(-1) => {
+                self.phase = Phase::OctaveDown;
+            }
+            Phase::OctaveDown if !response.chose_higher() => self.shift_octave(-1),
+            Phase::OctaveUp | Phase::OctaveDown => self.phase = Phase::Finished,
+            Phase::Finished => return Err(Error::InvalidArgument("finished pitch match")),
+        }
+        self.trials += 1;
+        Ok(())
+    }
+
+    fn can_shift(&self, octaves: i32) -> bool {
+        let shifted = self.estimate() + 12.0 * octaves as f32;
+        (self.octave_shifts + octaves).abs() <= MAX_OCTAVE_SHIFTS
+            && (self.settings.min_midi_note..=self.settings.max_midi_note).contains(&shifted)
+    }
+
+    /// Starts the octave check after bracketing, continuing in the direction
+    /// of an earlier correction.
+    fn check_octave(&mut self) {
+        self.phase = match self.octave_shifts.signum() {
+            1 if self.can_shift(1) => Phase::OctaveUp,
+            -1 if self.can_shift(-1) => Phase::OctaveDown,
+            0 if self.can_shift(1) => Phase::OctaveUp,
+            0 if self.can_shift(-1) => Phase::OctaveDown,
+            _ => Phase::Finished,
+        };
+    }
+
+    fn shift_octave(&mut self, octaves: i32) {
+        let center = self.estimate() + 12.0 * octaves as f32;
+        self.octave_shifts += octaves;
+        self.low = (center - OCTAVE_BRACKET_SEMITONES).max(self.settings.min_midi_note);
+        self.high = (center + OCTAVE_BRACKET_SEMITONES).min(self.settings.max_midi_note);
+        self.phase = Phase::Bracketing;
+    }
+
+    /// The tones of `trial` as played to the patient.
+    pub(crate) fn pattern(&self, trial: PitchTrial) -> TonePattern {
+        TonePattern {
+            frequencies: [trial.first_hz, trial.second_hz, 0.0, 0.0],
+            tones: 2,
+            tone_ms: PAIR_TONE_MS,
+            gap_ms: PAIR_GAP_MS,
+            delay_ms: 0.0,
+            fade_ms: PAIR_FADE_MS,
+            channel: self.settings.channel,
+            level_db: self.settings.level_db,
+            unit: self.settings.unit,
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.phase == Phase::Finished
+    }
+
+    pub fn result(&self) -> Option<PitchMatchResult> {
+        self.is_finished().then(|| PitchMatchResult {
+            frequency_hz: midi_to_hz(self.estimate()),
+            min_hz: midi_to_hz(self.low),
+            max_hz: midi_to_hz(self.high),
+            trials: self.trials,
+            octave_shifts: self.octave_shifts,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{STEREO_48K, params};
+    use crate::{AudioState, EventQueue, PlaybackEvent};
+    use std::sync::Arc;
+
+    /// Runs a match with a patient who answers by the distance on the MIDI
+    /// scale to `perceived(trials so far)`.
+    fn run(
+        settings: PitchMatchSettings,
+        perceived: impl Fn(&PitchMatcher) -> f32,
+        use_higher_lower: bool,
+    ) -> PitchMatchResult {
+        let mut matcher = PitchMatcher::new(settings).unwrap();
+        while let Some(trial) = matcher.trial() {
+            let tinnitus = perceived(&matcher);
+            let (first, second) = (hz_to_midi(trial.first_hz), hz_to_midi(trial.second_hz));
+            let response = if use_higher_lower && tinnitus < first {
+                PitchResponse::TinnitusLower
+            } else if use_higher_lower && tinnitus > second {
+                PitchResponse::TinnitusHigher
+            } else if (tinnitus - first).abs() < (tinnitus - second).abs() {
+                PitchResponse::FirstCloser
+            } else {
+                PitchResponse::SecondCloser
+            };
+            matcher.respond(response).unwrap();
+            assert!(matcher.trials < 100, "did not finish");
+        }
+        assert_eq!(
+            matcher.respond(PitchResponse::FirstCloser),
+            Err(Error::InvalidArgument("finished pitch match"))
+        );
+        matcher.result().unwrap()
+    }
+
+    #[test]
+    fn test_bracketing_converges() {
+        let settings = PitchMatchSettings::default();
+        for tinnitus_hz in [300.0, 1000.0, 4000.0, 6300.0, 12000.0] {
+            for use_higher_lower in [false, true] {
+                let result = run(settings, |_| hz_to_midi(tinnitus_hz), use_higher_lower);
+                assert!(
+                    result.min_hz <= tinnitus_hz && tinnitus_hz <= result.max_hz,
+                    "{tinnitus_hz} Hz: {result:?}"
+                );
+                let (min_note, max_note) = result.note_range();
+                assert!(max_note - min_note <= settings.resolution_semitones + 1e-3);
+                assert_eq!(result.octave_shifts, 0);
+                assert!(result.trials <= 12, "{result:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_octave_confusion_is_corrected() {
+        // Matches an octave too low at first, but then recognises the octave
+        let tinnitus_hz = 6000.0;
+        let result = run(
+            PitchMatchSettings::default(),
+            |matcher| match matcher.phase {
+                Phase::Bracketing if matcher.octave_shifts == 0 => hz_to_midi(tinnitus_hz) - 12.0,
+                _ => hz_to_midi(tinnitus_hz),
+            },
+            false,
+        );
+        assert_eq!(result.octave_shifts, 1);
+        assert!(
+            result.min_hz <= tinnitus_hz && tinnitus_hz <= result.max_hz,
+            "{result:?}"
+        );
+    }
+
+    #[test]
+    fn test_validation() {
+        let invalid = |settings| PitchMatcher::new(settings).map(|_| ());
+        let settings = PitchMatchSettings::default();
+        assert_eq!(
+            invalid(PitchMatchSettings {
+                resolution_semitones: 0.0,
+                ..settings
+            }),
+            Err(Error::InvalidArgument("pitch match resolution"))
+        );
+        assert!(
+            invalid(PitchMatchSettings {
+                level_db: 3.0,
+                ..settings
+            })
+            .is_err()
+        );
+        assert!(
+            invalid(PitchMatchSettings {
+                min_midi_note: 100.0,
+                max_midi_note: 60.0,
+                ..settings
+            })
+            .is_err()
+        );
+        // A range within the resolution leaves no room for octave checks
+        let matcher = PitchMatcher::new(PitchMatchSettings {
+            min_midi_note: 80.0,
+            max_midi_note: 80.5,
+            ..settings
+        })
+        .unwrap();
+        assert_eq!(matcher.result().map(|r| r.trials), Some(0));
+        assert_eq!(
+            PitchResponse::from_index(3),
+            Some(PitchResponse::TinnitusHigher)
+        );
+        assert_eq!(PitchResponse::from_index(4), None);
+    }
+
+    #[test]
+    fn test_pitch_pair_replaces_sequence() {
+        let params = params(-6.0, 60.0, 72.0);
+        params
+            .schedule()
+            .update(|s| s.silence_probability = 0.0)
+            .unwrap();
+        let format = STEREO_48K;
+        let events = Arc::new(EventQueue::new());
+        let mut state = AudioState::new(format, params.clone(), 0).with_events(events.clone());
+        let mut data = vec![0.0; 48000 * 2];
+        state.render(&mut data, format);
+
+        let settings = PitchMatchSettings {
+            channel: Some(Channel::Left),
+            ..Default::default()
+        };
+        let matcher = PitchMatcher::new(settings).unwrap();
+        params
+            .test_tones()
+            .play(&matcher.pattern(matcher.trial().unwrap()));
+        let mut pair = vec![0.0; 48000 * 3 * 2];
+        state.render(&mut pair, format);
+        let played = std::iter::from_fn(|| events.pop())
+            .filter(|event| *event == PlaybackEvent::TestTonesPlayed)
+            .count();
+        assert_eq!(played, 1);
+        // The voices fade out, leaving the pair on the left ear only
+        assert!(
+            pair[9600 * 2..]
+                .iter()
+                .skip(1)
+                .step_by(2)
+                .all(|&s| s == 0.0)
+        );
+        assert!(pair[9600 * 2..].iter().step_by(2).any(|&s| s != 0.0));
+    }
+}
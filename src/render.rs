@@ -0,0 +1,335 @@
+use crate::audio::StreamFormat;
+use crate::{
+    AudioState, Channel, ChannelParams, DEFAULT_TONE_FADE_MS, Error, FadeCurve, FrequencyMode,
+    MAX_TONE_FADE_MS, Schedule, SharedParams,
+};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+const RENDER_CHANNELS: u16 = 2;
+const BLOCK_FRAMES: usize = 512;
+
+const WAVE_FORMAT_PCM: u16 = 1;
+const WAVE_FORMAT_IEEE_FLOAT: u16 = 3;
+
+/// Sample encoding of a rendered WAV file.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum WavFormat {
+    Pcm16,
+    Pcm24,
+    Float32,
+}
+
+impl WavFormat {
+    pub fn from_index(index: i32) -> Option<Self> {
+        match index {
+            0 => Some(WavFormat::Pcm16),
+            1 => Some(WavFormat::Pcm24),
+            2 => Some(WavFormat::Float32),
+            _ => None,
+        }
+    }
+
+    fn bytes_per_sample(self) -> u16 {
+        match self {
+            WavFormat::Pcm16 => 2,
+            WavFormat::Pcm24 => 3,
+            WavFormat::Float32 => 4,
+        }
+    }
+
+    fn format_tag(self) -> u16 {
+        match self {
+            WavFormat::Pcm16 | WavFormat::Pcm24 => WAVE_FORMAT_PCM,
+            WavFormat::Float32 => WAVE_FORMAT_IEEE_FLOAT,
+        }
+    }
+}
+
+/// Parameters of an offline session render.
+#[derive(Clone, Debug)]
+pub struct RenderOptions {
+    pub duration_secs: f32,
+    pub seed: u64,
+    pub gain_db: f32,
+    pub min_midi_note: f32,
+    pub max_midi_note: f32,
+    pub sample_rate: u32,
+    pub wav_format: WavFormat,
+    pub schedule: Schedule,
+    pub frequency_mode: FrequencyMode,
+    pub tone_fade_ms: f32,
+    pub fade_curve: FadeCurve,
+}
+
+impl Default for RenderOptions {
+    fn default() -> Self {
+        Self {
+            duration_secs: 60.0,
+            seed: 0,
+            gain_db: -12.0,
+            min_midi_note: 69.0,
+            max_midi_note: 115.0,
+            sample_rate: 44100,
+            wav_format: WavFormat::Pcm16,
+            schedule: Schedule::default(),
+            frequency_mode: FrequencyMode::default(),
+            tone_fade_ms: DEFAULT_TONE_FADE_MS,
+            fade_curve: FadeCurve::default(),
+        }
+    }
+}
+
+impl RenderOptions {
+    pub fn validate(&self) -> io::Result<()> {
+        if self.sample_rate == 0 || !self.duration_secs.is_finite() || self.duration_secs < 0.0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid sample rate or duration",
+            ));
+        }
+        if !(0.0..=MAX_TONE_FADE_MS).contains(&self.tone_fade_ms) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid tone fade",
+            ));
+        }
+        crate::error::validate_gain_db(self.gain_db)
+            .and_then(|()| {
+                crate::error::validate_note_range(self.min_midi_note, self.max_midi_note)
+            })
+            .and_then(|()| self.schedule.validate().map_err(Error::from))
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+    }
+}
+
+/// Renders a session to a WAV file at `path`. Invalid options leave an
+/// existing file untouched.
+pub fn render_to_wav(path: impl AsRef<Path>, options: &RenderOptions) -> io::Result<()> {
+    options.validate()?;
+    let mut writer = BufWriter::new(File::create(path)?);
+    render_wav(&mut writer, options)?;
+    writer.flush()
+}
+
+/// Renders a session as a WAV stream into `writer`.
+///
+/// The samples are rendered like the buffers of live playback, so a render
+/// with the same seed sounds identical. There is no session timer, dose
+/// limit, calibration tone or test tone in a render.
+pub fn render_wav<W: Write>(writer: &mut W, options: &RenderOptions) -> io::Result<()> {
+    options.validate()?;
+
+    let format = StreamFormat {
+        sample_rate: options.sample_rate,
+        channels: RENDER_CHANNELS,
+    };
+    let voices = Channel::ALL.map(|_| {
+        ChannelParams::new(
+            options.gain_db,
+            options.min_midi_note,
+            options.max_midi_note,
+        )
+    });
+    let params = SharedParams::new(voices);
+    params
+        .schedule()
+        .set(options.schedule)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    params.set_frequency_mode(options.frequency_mode);
+    params.set_tone_fade_ms(options.tone_fade_ms);
+    params.set_fade_curve(options.fade_curve);
+    let mut audio_state = AudioState::new(format, params, options.seed);
+
+    let total_frames = (options.duration_secs as f64 * options.sample_rate as f64).round() as u64;
+    write_header(writer, options.wav_format, format, total_frames)?;
+
+    let mut block = vec![0.0; BLOCK_FRAMES * RENDER_CHANNELS as usize];
+    let mut bytes = Vec::with_capacity(block.len() * 4);
+    let mut frames_left = total_frames;
+    while frames_left > 0 {
+        let frames = frames_left.min(BLOCK_FRAMES as u64) as usize;
+        let data = &mut block[..frames * RENDER_CHANNELS as usize];
+        audio_state.render(data, format);
+
+        bytes.clear();
+        encode_samples(data, options.wav_format, &mut bytes);
+        writer.write_all(&bytes)?;
+        frames_left -= frames as u64;
+    }
+    Ok(())
+}
+
+fn write_header<W: Write>(
+    writer: &mut W,
+    wav_format: WavFormat,
+    format: StreamFormat,
+    total_frames: u64,
+) -> io::Result<()> {
+    let block_align = wav_format.bytes_per_sample() * format.channels;
+    let data_size = total_frames * block_align as u64;
+    // Non-PCM formats carry an extension size field and a fact chunk
+    let is_pcm = wav_format.format_tag() == WAVE_FORMAT_PCM;
+    let fmt_size: u32 = if is_pcm { 16 } else { 18 };
+    let fact_size: u32 = if is_pcm { 0 } else { 12 };
+    let riff_size = (4 + (8 + fmt_size) + fact_size + 8) as u64 + data_size;
+    let (Ok(riff_size), Ok(data_size)) = (u32::try_from(riff_size), u32::try_from(data_size))
+    else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "render too long for a WAV file",
+        ));
+    };
+
+    writer.write_all(b"RIFF")?;
+    writer.write_all(&riff_size.to_le_bytes())?;
+    writer.write_all(b"WAVE")?;
+
+    writer.write_all(b"fmt ")?;
+    writer.write_all(&fmt_size.to_le_bytes())?;
+    writer.write_all(&wav_format.format_tag().to_le_bytes())?;
+    writer.write_all(&format.channels.to_le_bytes())?;
+    writer.write_all(&format.sample_rate.to_le_bytes())?;
+    writer.write_all(&(format.sample_rate * block_align as u32).to_le_bytes())?;
+    writer.write_all(&block_align.to_le_bytes())?;
+    writer.write_all(&(wav_format.bytes_per_sample() * 8).to_le_bytes())?;
+    if !is_pcm {
+        writer.write_all(&0u16.to_le_bytes())?;
+        writer.write_all(b"fact")?;
+        writer.write_all(&4u32.to_le_bytes())?;
+        writer.write_all(&(total_frames as u32).to_le_bytes())?;
+    }
+
+    writer.write_all(b"data")?;
+    writer.write_all(&data_size.to_le_bytes())
+}
+
+fn encode_samples(data: &[f32], wav_format: WavFormat, bytes: &mut Vec<u8>) {
+    for &sample in data {
+        let sample = sample.clamp(-1.0, 1.0);
+        match wav_format {
+            WavFormat::Pcm16 => {
+                let value = (sample * i16::MAX as f32).round() as i16;
+                bytes.extend_from_slice(&value.to_le_bytes());
+            }
+            WavFormat::Pcm24 => {
+                let value = (sample * 8_388_607.0).round() as i32;
+                bytes.extend_from_slice(&value.to_le_bytes()[..3]);
+            }
+            WavFormat::Float32 => bytes.extend_from_slice(&sample.to_le_bytes()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AudioPlayer, NullBackend};
+    use std::fs;
+    use std::time::Duration;
+
+    fn render(options: &RenderOptions) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        render_wav(&mut bytes, options).unwrap();
+        bytes
+    }
+
+    fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn test_pcm16_header() {
+        let options = RenderOptions {
+            duration_secs: 0.5,
+            ..Default::default()
+        };
+        let bytes = render(&options);
+
+        assert_eq!(&bytes[0..4], b"RIFF");
+        assert_eq!(read_u32(&bytes, 4) as usize, bytes.len() - 8);
+        assert_eq!(&bytes[8..16], b"WAVEfmt ");
+        assert_eq!(read_u32(&bytes, 24), 44100);
+        assert_eq!(&bytes[36..40], b"data");
+        assert_eq!(read_u32(&bytes, 40), 22050 * 2 * 2);
+        assert_eq!(bytes.len(), 44 + 22050 * 2 * 2);
+    }
+
+    #[test]
+    fn test_sample_sizes() {
+        for (wav_format, header, bytes_per_sample) in
+            [(WavFormat::Pcm24, 44, 3), (WavFormat::Float32, 58, 4)]
+        {
+            let options = RenderOptions {
+                duration_secs: 0.1,
+                sample_rate: 48000,
+                wav_format,
+                ..Default::default()
+            };
+            let bytes = render(&options);
+            assert_eq!(bytes.len(), header + 4800 * 2 * bytes_per_sample);
+            assert_eq!(read_u32(&bytes, 4) as usize, bytes.len() - 8);
+        }
+    }
+
+    #[test]
+    fn test_render_matches_live_playback() {
+        let options = RenderOptions {
+            duration_secs: 2.0,
+            seed: 1234,
+            wav_format: WavFormat::Float32,
+            ..Default::default()
+        };
+        let bytes = render(&options);
+        let rendered: Vec<f32> = bytes[58..]
+            .chunks_exact(4)
+            .map(|b| f32::from_le_bytes(b.try_into().unwrap()))
+            .collect();
+
+        // Live playback uses differently sized buffers
+        let format = StreamFormat {
+            sample_rate: 44100,
+            channels: 2,
+        };
+        let backend = NullBackend::new(format)
+            .with_block_frames(1000)
+            .with_max_frames(88200);
+        let handle = backend.handle();
+        let mut player = AudioPlayer::with_backend(Box::new(backend));
+        player.set_seed(1234);
+        player.start().unwrap();
+        handle.wait_for_frames(88200, Duration::from_secs(10));
+        player.stop();
+
+        assert_eq!(rendered, handle.samples());
+        assert!(rendered.iter().any(|&s| s != 0.0));
+    }
+
+    #[test]
+    fn test_invalid_options_keep_file() {
+        let path = std::env::temp_dir().join(format!("render-{}.wav", std::process::id()));
+        fs::write(&path, b"previous").unwrap();
+        let options = RenderOptions {
+            max_midi_note: 10.0,
+            ..Default::default()
+        };
+        assert!(render_to_wav(&path, &options).is_err());
+        assert_eq!(fs::read(&path).unwrap(), b"previous");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_seed_changes_output() {
+        let options = RenderOptions {
+            duration_secs: 2.0,
+            ..Default::default()
+        };
+        let other = RenderOptions {
+            seed: 1,
+            ..options.clone()
+        };
+        assert_eq!(render(&options), render(&options));
+        assert_ne!(render(&options), render(&other));
+    }
+}
@@ -0,0 +1,342 @@
+use rand::Rng;
+use std::fmt;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU32, Ordering, fence};
+
+/// Upper limit for any tone or pause duration.
+pub const MAX_DURATION_MS: f32 = 60_000.0;
+
+/// Distribution of segment durations in milliseconds.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum DurationDistribution {
+    Fixed {
+        ms: f32,
+    },
+    Uniform {
+        min_ms: f32,
+        max_ms: f32,
+    },
+    /// Exponential distribution with the given mean, truncated at `max_ms`.
+    Exponential {
+        mean_ms: f32,
+        max_ms: f32,
+    },
+}
+
+/// Timing of the tone sequence shared by both voices.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Schedule {
+    /// Probability that a segment is a pause instead of a tone.
+    pub silence_probability: f32,
+    pub tone_duration: DurationDistribution,
+    pub pause_duration: DurationDistribution,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ScheduleError {
+    InvalidProbability,
+    InvalidDuration,
+    UnknownDistribution,
+}
+
+impl fmt::Display for ScheduleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScheduleError::InvalidProbability => write!(f, "probability must be within 0..=1"),
+            ScheduleError::InvalidDuration => write!(
+                f,
+                "durations must be positive, ordered and at most {MAX_DURATION_MS} ms"
+            ),
+            ScheduleError::UnknownDistribution => write!(f, "unknown distribution kind"),
+        }
+    }
+}
+
+impl std::error::Error for ScheduleError {}
+
+fn is_valid_duration(ms: f32) -> bool {
+    ms.is_finite() && ms > 0.0 && ms <= MAX_DURATION_MS
+}
+
+impl DurationDistribution {
+    /// Builds a distribution from its FFI representation: kind 0 is fixed
+    /// (`a` ms), 1 is uniform (`a` to `b` ms) and 2 is exponential (mean `a`
+    /// ms, truncated at `b` ms).
+    pub fn from_raw(kind: i32, a: f32, b: f32) -> Result<Self, ScheduleError> {
+        let distribution = match kind {
+            0 => DurationDistribution::Fixed { ms: a },
+            1 => DurationDistribution::Uniform {
+                min_ms: a,
+                max_ms: b,
+            },
+            2 => DurationDistribution::Exponential {
+                mean_ms: a,
+                max_ms: b,
+            },
+            _ => return Err(ScheduleError::UnknownDistribution),
+        };
+        distribution.validate()?;
+        Ok(distribution)
+    }
+
+    fn to_raw(self) -> (u32, f32, f32) {
+        match self {
+            DurationDistribution::Fixed { ms } => (0, ms, ms),
+            DurationDistribution::Uniform { min_ms, max_ms } => (1, min_ms, max_ms),
+            DurationDistribution::Exponential { mean_ms, max_ms } => (2, mean_ms, max_ms),
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), ScheduleError> {
+        let valid = match *self {
+            DurationDistribution::Fixed { ms } => is_valid_duration(ms),
+            DurationDistribution::Uniform { min_ms, max_ms } => {
+                is_valid_duration(min_ms) && is_valid_duration(max_ms) && min_ms <= max_ms
+            }
+            DurationDistribution::Exponential { mean_ms, max_ms } => {
+                is_valid_duration(mean_ms) && is_valid_duration(max_ms) && mean_ms <= max_ms
+            }
+        };
+        if valid {
+            Ok(())
+        } else {
+            Err(ScheduleError::InvalidDuration)
+        }
+    }
+
+    pub fn sample_ms<R: Rng>(&self, rng: &mut R) -> f32 {
+        match *self {
+            DurationDistribution::Fixed { ms } => ms,
+            DurationDistribution::Uniform { min_ms, max_ms } => {
+                min_ms + rng.random::<f32>() * (max_ms - min_ms)
+            }
+            DurationDistribution::Exponential { mean_ms, max_ms } => {
+                let u = rng.random::<f32>();
+                (-mean_ms * (1.0 - u).ln()).min(max_ms)
+            }
+        }
+    }
+
+    /// Draws a duration in samples. Durations are at least one sample long.
+    pub fn sample_samples<R: Rng>(&self, rng: &mut R, sample_rate: f32) -> u64 {
+        ((self.sample_ms(rng) / 1000.0 * sample_rate) as u64).max(1)
+    }
+}
+
+impl Schedule {
+    pub fn validate(&self) -> Result<(), ScheduleError> {
+        if !(0.0..=1.0).contains(&self.silence_probability) {
+            return Err(ScheduleError::InvalidProbability);
+        }
+        self.tone_duration.validate()?;
+        self.pause_duration.validate()
+    }
+}
+
+impl Default for Schedule {
+    fn default() -> Self {
+        Self {
+            silence_probability: 0.1,
+            tone_duration: DurationDistribution::Uniform {
+                min_ms: 150.0,
+                max_ms: 400.0,
+            },
+            pause_duration: DurationDistribution::Uniform {
+                min_ms: 150.0,
+                max_ms: 400.0,
+            },
+        }
+    }
+}
+
+const SCHEDULE_WORDS: usize = 7;
+
+/// Lock-free storage for a `Schedule`, shared with the audio thread.
+///
+/// Writers are serialized by a mutex and publish through a sequence counter,
+/// so the audio thread never observes a partially written schedule.
+pub struct ScheduleParams {
+    sequence: AtomicU32,
+    words: [AtomicU32; SCHEDULE_WORDS],
+    writer: Mutex<()>,
+}
+
+impl ScheduleParams {
+    pub fn new(schedule: Schedule) -> Self {
+        Self {
+            sequence: AtomicU32::new(0),
+            words: Self::encode(&schedule).map(AtomicU32::new),
+            writer: Mutex::new(()),
+        }
+    }
+
+    fn encode(schedule: &Schedule) -> [u32; SCHEDULE_WORDS] {
+        let (tone_kind, tone_a, tone_b) = schedule.tone_duration.to_raw();
+        let (pause_kind, pause_a, pause_b) = schedule.pause_duration.to_raw();
+        [
+            schedule.silence_probability.to_bits(),
+            tone_kind,
+            tone_a.to_bits(),
+            tone_b.to_bits(),
+            pause_kind,
+            pause_a.to_bits(),
+            pause_b.to_bits(),
+        ]
+    }
+
+    fn decode(words: [u32; SCHEDULE_WORDS]) -> Schedule {
+        let distribution = |kind: u32, a: u32, b: u32| {
+            DurationDistribution::from_raw(kind as i32, f32::from_bits(a), f32::from_bits(b))
+                .unwrap_or(Schedule::default().tone_duration)
+        };
+        Schedule {
+            silence_probability: f32::from_bits(words[0]),
+            tone_duration: distribution(words[1], words[2], words[3]),
+            pause_duration: distribution(words[4], words[5], words[6]),
+        }
+    }
+
+    /// Returns a consistent copy of the current schedule. Waits for a write
+    /// in progress, so the audio thread uses `try_snapshot` instead.
+    pub fn snapshot(&self) -> Schedule {
+        let _guard = self.writer.lock().unwrap_or_else(|e| e.into_inner());
+        self.read()
+    }
+
+    /// Returns a consistent copy of the current schedule, or `None` while a
+    /// write is in progress. Never blocks.
+    pub fn try_snapshot(&self) -> Option<Schedule> {
+        let before = self.sequence.load(Ordering::Acquire);
+        if before % 2 == 1 {
+            return None;
+        }
+        let words = std::array::from_fn(|i| self.words[i].load(Ordering::Relaxed));
+        fence(Ordering::Acquire);
+        (self.sequence.load(Ordering::Relaxed) == before).then(|| Self::decode(words))
+    }
+
+    /// Reads the schedule while holding the writer lock.
+    fn read(&self) -> Schedule {
+        Self::decode(std::array::from_fn(|i| {
+            self.words[i].load(Ordering::Relaxed)
+        }))
+    }
+
+    pub fn set(&self, schedule: Schedule) -> Result<(), ScheduleError> {
+        schedule.validate()?;
+        let _guard = self.writer.lock().unwrap_or_else(|e| e.into_inner());
+        self.publish(&schedule);
+        Ok(())
+    }
+
+    /// Validates and applies a modification of the current schedule.
+    pub fn update(&self, f: impl FnOnce(&mut Schedule)) -> Result<(), ScheduleError> {
+        let _guard = self.writer.lock().unwrap_or_else(|e| e.into_inner());
+        let mut schedule = self.read();
+        f(&mut schedule);
+        schedule.validate()?;
+        self.publish(&schedule);
+        Ok(())
+    }
+
+    fn publish(&self, schedule: &Schedule) {
+        self.sequence.fetch_add(1, Ordering::Relaxed);
+        fence(Ordering::Release);
+        for (word, value) in self.words.iter().zip(Self::encode(schedule)) {
+            word.store(value, Ordering::Relaxed);
+        }
+        self.sequence.fetch_add(1, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::taus88::{SeedableRng, Taus88};
+
+    #[test]
+    fn test_validation() {
+        assert!(Schedule::default().validate().is_ok());
+        assert_eq!(
+            DurationDistribution::from_raw(1, 400.0, 150.0),
+            Err(ScheduleError::InvalidDuration)
+        );
+        assert_eq!(
+            DurationDistribution::from_raw(0, 0.0, 0.0),
+            Err(ScheduleError::InvalidDuration)
+        );
+        assert_eq!(
+            DurationDistribution::from_raw(2, f32::NAN, 1000.0),
+            Err(ScheduleError::InvalidDuration)
+        );
+        assert_eq!(
+            DurationDistribution::from_raw(0, MAX_DURATION_MS * 2.0, 0.0),
+            Err(ScheduleError::InvalidDuration)
+        );
+        assert_eq!(
+            DurationDistribution::from_raw(3, 100.0, 200.0),
+            Err(ScheduleError::UnknownDistribution)
+        );
+
+        let schedule = Schedule {
+            silence_probability: 1.5,
+            ..Default::default()
+        };
+        assert_eq!(schedule.validate(), Err(ScheduleError::InvalidProbability));
+    }
+
+    #[test]
+    fn test_samples_within_bounds() {
+        let mut rng = Taus88::seed_from_u64(7);
+        let uniform = DurationDistribution::Uniform {
+            min_ms: 50.0,
+            max_ms: 80.0,
+        };
+        let exponential = DurationDistribution::Exponential {
+            mean_ms: 300.0,
+            max_ms: 2000.0,
+        };
+
+        let mut sum = 0.0;
+        for _ in 0..10000 {
+            assert!((50.0..=80.0).contains(&uniform.sample_ms(&mut rng)));
+            let ms = exponential.sample_ms(&mut rng);
+            assert!((0.0..=2000.0).contains(&ms));
+            sum += ms;
+        }
+        // Truncation lowers the mean only slightly
+        let mean = sum / 10000.0;
+        assert!((270.0..=320.0).contains(&mean));
+
+        let fixed = DurationDistribution::Fixed { ms: 0.001 };
+        assert_eq!(fixed.sample_samples(&mut rng, 44100.0), 1);
+    }
+
+    #[test]
+    fn test_params_roundtrip() {
+        let params = ScheduleParams::new(Schedule::default());
+        assert_eq!(params.snapshot(), Schedule::default());
+
+        let schedule = Schedule {
+            silence_probability: 0.5,
+            tone_duration: DurationDistribution::Fixed { ms: 250.0 },
+            pause_duration: DurationDistribution::Exponential {
+                mean_ms: 1000.0,
+                max_ms: 5000.0,
+            },
+        };
+        params.set(schedule).unwrap();
+        assert_eq!(params.snapshot(), schedule);
+        assert_eq!(params.try_snapshot(), Some(schedule));
+
+        // A write in progress is not waited for
+        params.sequence.fetch_add(1, Ordering::Relaxed);
+        assert_eq!(params.try_snapshot(), None);
+        params.sequence.fetch_add(1, Ordering::Relaxed);
+        assert_eq!(params.try_snapshot(), Some(schedule));
+
+        let result = params.update(|s| s.silence_probability = -1.0);
+        assert_eq!(result, Err(ScheduleError::InvalidProbability));
+        assert_eq!(params.snapshot(), schedule);
+    }
+}
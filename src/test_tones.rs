@@ -0,0 +1,397 @@
+use crate::calibration::CalibrationParams;
+use crate::error::lock;
+use crate::{Channel, FadeCurve, GainUnit, oscillator};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering, fence};
+
+/// Most tones in a pattern.
+pub(crate) const MAX_PATTERN_TONES: usize = 4;
+
+const PATTERN_WORDS: usize = MAX_PATTERN_TONES + 8;
+
+/// A short sequence of tones of equal length played instead of the tone
+/// sequence, e.g. a pitch matching pair or a pulsed audiometry tone.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub(crate) struct TonePattern {
+    /// Frequency of each tone, of which the first `tones` are played.
+    pub frequencies: [f32; MAX_PATTERN_TONES],
+    pub tones: usize,
+    pub tone_ms: f32,
+    pub gap_ms: f32,
+    /// Silence before the first tone.
+    pub delay_ms: f32,
+    pub fade_ms: f32,
+    /// Ear the tones are played on, `None` for both.
+    pub channel: Option<Channel>,
+    pub level_db: f32,
+    pub unit: GainUnit,
+}
+
+impl Default for TonePattern {
+    fn default() -> Self {
+        Self {
+            frequencies: [0.0; MAX_PATTERN_TONES],
+            tones: 0,
+            tone_ms: 0.0,
+            gap_ms: 0.0,
+            delay_ms: 0.0,
+            fade_ms: 0.0,
+            channel: None,
+            level_db: f32::NEG_INFINITY,
+            unit: GainUnit::Dbfs,
+        }
+    }
+}
+
+/// Test tones shared with the audio thread. Every `play` is played once,
+/// including repeats of the same pattern.
+pub struct TestToneParams {
+    active: AtomicBool,
+    // Odd while a pattern is written, and advanced by every `play`
+    sequence: AtomicU32,
+    words: [AtomicU32; PATTERN_WORDS],
+    writer: Mutex<()>,
+}
+
+impl TestToneParams {
+    pub fn new() -> Self {
+        Self {
+            active: AtomicBool::new(false),
+            sequence: AtomicU32::new(0),
+            words: Self::encode(&TonePattern::default()).map(AtomicU32::new),
+            writer: Mutex::new(()),
+        }
+    }
+
+    fn encode(pattern: &TonePattern) -> [u32; PATTERN_WORDS] {
+        let mut words = [0; PATTERN_WORDS];
+        for (word, frequency_hz) in words.iter_mut().zip(pattern.frequencies) {
+            *word = frequency_hz.to_bits();
+        }
+        words[MAX_PATTERN_TONES..].copy_from_slice(&[
+            pattern.tones.min(MAX_PATTERN_TONES) as u32,
+            pattern.tone_ms.to_bits(),
+            pattern.gap_ms.to_bits(),
+            pattern.delay_ms.to_bits(),
+            pattern.fade_ms.to_bits(),
+            pattern.channel.map_or(2, |c| c as u32),
+            pattern.level_db.to_bits(),
+            pattern.unit as u32,
+        ]);
+        words
+    }
+
+    fn decode(words: [u32; PATTERN_WORDS]) -> TonePattern {
+        let float = |i: usize| f32::from_bits(words[MAX_PATTERN_TONES + i]);
+        TonePattern {
+            frequencies: std::array::from_fn(|i| f32::from_bits(words[i])),
+            tones: words[MAX_PATTERN_TONES] as usize,
+            tone_ms: float(1),
+            gap_ms: float(2),
+            delay_ms: float(3),
+            fade_ms: float(4),
+            channel: Channel::from_index(words[MAX_PATTERN_TONES + 5] as i32),
+            level_db: float(6),
+            unit: GainUnit::from_index(words[MAX_PATTERN_TONES + 7] as i32).unwrap_or_default(),
+        }
+    }
+
+    /// Plays `pattern` instead of the tone sequence, from the start even if
+    /// it is already playing.
+    pub(crate) fn play(&self, pattern: &TonePattern) {
+        let _guard = lock(&self.writer);
+        self.sequence.fetch_add(1, Ordering::Relaxed);
+        fence(Ordering::Release);
+        for (word, value) in self.words.iter().zip(Self::encode(pattern)) {
+            word.store(value, Ordering::Relaxed);
+        }
+        self.sequence.fetch_add(1, Ordering::Release);
+        self.active.store(true, Ordering::Release);
+    }
+
+    pub(crate) fn stop(&self) {
+        self.active.store(false, Ordering::Release);
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active.load(Ordering::Acquire)
+    }
+
+    /// The requested pattern and its sequence number while active, or `None`
+    /// while a new pattern is being written. Never blocks.
+    fn try_requested(&self) -> Option<Option<(u32, TonePattern)>> {
+        if !self.is_active() {
+            return Some(None);
+        }
+        let before = self.sequence.load(Ordering::Acquire);
+        if before % 2 == 1 {
+            return None;
+        }
+        let words = std::array::from_fn(|i| self.words[i].load(Ordering::Relaxed));
+        fence(Ordering::Acquire);
+        (self.sequence.load(Ordering::Relaxed) == before)
+            .then(|| Some((before, Self::decode(words))))
+    }
+}
+
+impl Default for TestToneParams {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Plays the test tones on the audio thread. A new pattern or a stop fades
+/// out the tone that is playing first.
+pub(crate) struct TestTonePlayer {
+    oscillator: oscillator::Oscillator,
+    sample_rate: u32,
+    // Sequence number and pattern being played
+    current: Option<(u32, TonePattern)>,
+    played_sequence: Option<u32>,
+    // Samples since the start of the pattern
+    position: u64,
+    gains: [f32; 2],
+    // Set per buffer, like the voice gain limits
+    pub(crate) fallback_spl_db: f32,
+    pub(crate) ceiling: f32,
+}
+
+/// Layout of a pattern in samples.
+struct Layout {
+    delay: u64,
+    tone: u64,
+    gap: u64,
+    fade: u64,
+    tones: u64,
+}
+
+impl Layout {
+    fn end(&self) -> u64 {
+        self.delay + self.tones * (self.tone + self.gap) - self.gap.min(self.tones * self.gap)
+    }
+
+    /// Index of the tone at `position` and the offset into it, `None` in
+    /// silence.
+    fn tone_at(&self, position: u64) -> Option<(usize, u64)> {
+        let from_first = position.checked_sub(self.delay)?;
+        let index = from_first / (self.tone + self.gap);
+        let offset = from_first % (self.tone + self.gap);
+        (index < self.tones && offset < self.tone).then_some((index as usize, offset))
+    }
+}
+
+impl TestTonePlayer {
+    pub(crate) fn new(sample_rate: u32) -> Self {
+        Self {
+            oscillator: oscillator::Oscillator::new(sample_rate as f32),
+            sample_rate,
+            current: None,
+            played_sequence: None,
+            position: 0,
+            gains: [0.0; 2],
+            fallback_spl_db: crate::DEFAULT_FULL_SCALE_SPL_DB,
+            ceiling: 1.0,
+        }
+    }
+
+    pub(crate) fn set_sample_rate(&mut self, sample_rate: u32) {
+        // Keep the position in time
+        self.position = self.position * sample_rate as u64 / self.sample_rate.max(1) as u64;
+        self.sample_rate = sample_rate;
+        self.oscillator.set_sample_rate(sample_rate as f32);
+    }
+
+    fn layout(&self, pattern: &TonePattern) -> Layout {
+        let samples = |ms: f32| (ms.max(0.0) * self.sample_rate as f32 / 1000.0) as u64;
+        let tone = samples(pattern.tone_ms).max(2);
+        Layout {
+            delay: samples(pattern.delay_ms),
+            tone,
+            gap: samples(pattern.gap_ms),
+            fade: samples(pattern.fade_ms).clamp(1, tone / 2),
+            tones: pattern.tones.min(MAX_PATTERN_TONES) as u64,
+        }
+    }
+
+    fn start_tone(&mut self, calibration: &CalibrationParams, pattern: &TonePattern, index: usize) {
+        let frequency_hz = pattern.frequencies[index];
+        self.oscillator.set_freq(frequency_hz, 0);
+        let linear_gain = 10.0_f32.powf(pattern.level_db / 20.0);
+        for ear in Channel::ALL {
+            let plays = pattern.channel.is_none_or(|channel| channel == ear);
+            self.gains[ear as usize] = if plays {
+                linear_gain
+                    * calibration.tone_gain(pattern.unit, ear, frequency_hz, self.fallback_spl_db)
+            } else {
+                0.0
+            };
+        }
+    }
+
+    /// Adds the requested pattern to the interleaved `data`, unless it has
+    /// been played already. Fades out if the request changes or is not
+    /// `enabled`. Returns true if a pattern has been played to its end.
+    pub(crate) fn render(
+        &mut self,
+        params: &TestToneParams,
+        calibration: &CalibrationParams,
+        enabled: bool,
+        data: &mut [f32],
+        num_channels: usize,
+    ) -> bool {
+        // Keep playing the current pattern rather than wait for a write
+        let requested = params
+            .try_requested()
+            .unwrap_or(self.current)
+            .filter(|_| enabled);
+        let requested_sequence = requested.map(|(sequence, _)| sequence);
+        let mut played = false;
+        for frame in data.chunks_mut(num_channels) {
+            if let Some((sequence, pattern)) = self.current
+                && Some(sequence) != requested_sequence
+            {
+                let layout = self.layout(&pattern);
+                match layout.tone_at(self.position) {
+                    // Jump to the release where it has the same level
+                    Some((_, offset)) if offset < layout.tone - layout.fade => {
+                        let edge = offset.min(layout.fade);
+                        self.position += layout.tone - 1 - edge - offset;
+                    }
+                    Some(_) => {}
+                    None => self.current = None,
+                }
+            }
+            if self.current.is_none() {
+                match requested {
+                    Some((sequence, _)) if self.played_sequence != Some(sequence) => {
+                        self.current = requested;
+                        self.position = 0;
+                    }
+                    _ => break,
+                }
+            }
+            let Some((sequence, pattern)) = self.current else {
+                break;
+            };
+            let layout = self.layout(&pattern);
+
+            if let Some((index, offset)) = layout.tone_at(self.position) {
+                if offset == 0 {
+                    self.start_tone(calibration, &pattern, index);
+                }
+                let edge = offset.min(layout.tone - 1 - offset);
+                let fade = if edge < layout.fade {
+                    FadeCurve::RaisedCosine.gain((edge + 1) as f32 / (layout.fade + 1) as f32)
+                } else {
+                    1.0
+                };
+                let value = self.oscillator.next_sample() * fade;
+                for ear in Channel::ALL {
+                    // On mono outputs both ears are mixed, as for the voices
+                    let output_channel = (ear as usize).min(num_channels - 1);
+                    frame[output_channel] += value * self.gains[ear as usize].min(self.ceiling);
+                }
+            }
+
+            self.position += 1;
+            if self.position >= layout.end() {
+                self.current = None;
+                if Some(sequence) == requested_sequence {
+                    self.played_sequence = Some(sequence);
+                    played = true;
+                }
+            }
+        }
+        played
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::peak;
+
+    fn zero_crossings(data: &[f32], frames: std::ops::Range<usize>) -> i32 {
+        let left: Vec<f32> = data[frames.start * 2..frames.end * 2]
+            .iter()
+            .step_by(2)
+            .copied()
+            .collect();
+        left.windows(2)
+            .filter(|w| (w[0] < 0.0) != (w[1] < 0.0))
+            .count() as i32
+    }
+
+    #[test]
+    fn test_pattern_round_trip() {
+        let params = TestToneParams::new();
+        assert_eq!(params.try_requested(), Some(None));
+        let pattern = TonePattern {
+            frequencies: [1000.0, 2000.0, 0.0, 0.0],
+            tones: 2,
+            tone_ms: 1000.0,
+            gap_ms: 500.0,
+            delay_ms: 0.0,
+            fade_ms: 50.0,
+            channel: Some(Channel::Right),
+            level_db: 30.0,
+            unit: GainUnit::DbHl,
+        };
+        params.play(&pattern);
+        let (sequence, requested) = params.try_requested().flatten().unwrap();
+        assert_eq!(requested, pattern);
+        params.play(&pattern);
+        assert_ne!(params.try_requested().flatten().unwrap().0, sequence);
+        params.sequence.fetch_add(1, Ordering::Relaxed);
+        assert_eq!(params.try_requested(), None);
+        params.sequence.fetch_add(1, Ordering::Relaxed);
+        params.stop();
+        assert_eq!(params.try_requested(), Some(None));
+    }
+
+    #[test]
+    fn test_player() {
+        let params = TestToneParams::new();
+        let calibration = CalibrationParams::new();
+        let mut player = TestTonePlayer::new(48000);
+        let mut data = vec![0.0; 48000 * 3 * 2];
+        assert!(!player.render(&params, &calibration, true, &mut data, 2));
+        assert!(data.iter().all(|&s| s == 0.0));
+
+        let pattern = TonePattern {
+            frequencies: [1000.0, 2000.0, 0.0, 0.0],
+            tones: 2,
+            tone_ms: 1000.0,
+            gap_ms: 500.0,
+            delay_ms: 100.0,
+            fade_ms: 50.0,
+            channel: Some(Channel::Left),
+            level_db: -20.0,
+            unit: GainUnit::Dbfs,
+        };
+        params.play(&pattern);
+        assert!(player.render(&params, &calibration, true, &mut data, 2));
+        // A delay, 1 kHz, a gap, then 2 kHz, on the left ear only
+        assert_eq!(peak(&data[..4800 * 2], 0), 0.0);
+        assert!((peak(&data[9600 * 2..48000 * 2], 0) - 0.1).abs() < 1e-3);
+        assert!((zero_crossings(&data, 9600..48000) - 1600).abs() <= 2);
+        assert_eq!(peak(&data[52800 * 2..76800 * 2], 0), 0.0);
+        assert!((zero_crossings(&data, 81600..120000) - 3200).abs() <= 2);
+        assert_eq!(peak(&data[124800 * 2..144000 * 2], 0), 0.0);
+        assert_eq!(peak(&data, 1), 0.0);
+
+        // Played once until requested again
+        data.fill(0.0);
+        assert!(!player.render(&params, &calibration, true, &mut data, 2));
+        assert!(data.iter().all(|&s| s == 0.0));
+
+        // Stopping fades out within the release
+        params.play(&pattern);
+        data.fill(0.0);
+        player.render(&params, &calibration, true, &mut data[..9600 * 2], 2);
+        params.stop();
+        assert!(!player.render(&params, &calibration, true, &mut data[9600 * 2..], 2));
+        assert!(peak(&data[9600 * 2..9700 * 2], 0) > 0.09);
+        assert_eq!(peak(&data[(9600 + 2401) * 2..], 0), 0.0);
+    }
+}